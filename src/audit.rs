@@ -0,0 +1,129 @@
+use crate::bof::BOFIndex;
+use crate::manifest::ManifestFile;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+// How a file of a release compares with the previous one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    Changed,
+    Added,
+    Removed,
+    // Same content but written again, e.g. by a reproducible build
+    Rebuilt,
+    Unchanged,
+}
+
+impl Verdict {
+    const ALL: [Verdict; 5] = [
+        Verdict::Changed,
+        Verdict::Added,
+        Verdict::Removed,
+        Verdict::Rebuilt,
+        Verdict::Unchanged,
+    ];
+
+    fn title(&self) -> &'static str {
+        match self {
+            Verdict::Changed => "Changed",
+            Verdict::Added => "Added",
+            Verdict::Removed => "Removed",
+            Verdict::Rebuilt => "Rebuilt with the same content",
+            Verdict::Unchanged => "Unchanged",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Changed => write!(f, "changed"),
+            Verdict::Added => write!(f, "added"),
+            Verdict::Removed => write!(f, "removed"),
+            Verdict::Rebuilt => write!(f, "rebuilt"),
+            Verdict::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Audited {
+    // Relative to the release directory
+    pub path: PathBuf,
+    pub verdict: Verdict,
+}
+
+// Compares the files indexed under `from_dir` in `from` with those under `to_dir` in `to`, by
+// their path relative to these directories, in path order
+pub fn audit(from: &BOFIndex, from_dir: &Path, to: &BOFIndex, to_dir: &Path) -> Vec<Audited> {
+    let old = release_files(from, from_dir);
+    let mut new = release_files(to, to_dir);
+    let mut audited = old
+        .into_iter()
+        .map(|(path, old)| {
+            let verdict = match new.remove(&path) {
+                None => Verdict::Removed,
+                // Catalogued without hashes, only the metadata can tell
+                Some(new) if new.key.is_empty() || old.key.is_empty() => {
+                    if new.size == old.size && new.mtime == old.mtime {
+                        Verdict::Unchanged
+                    } else {
+                        Verdict::Changed
+                    }
+                }
+                Some(new) if new.key != old.key => Verdict::Changed,
+                Some(new) if new.mtime != old.mtime => Verdict::Rebuilt,
+                Some(_) => Verdict::Unchanged,
+            };
+            Audited { path, verdict }
+        })
+        .collect::<Vec<_>>();
+    audited.extend(new.into_keys().map(|path| Audited {
+        path,
+        verdict: Verdict::Added,
+    }));
+    audited.sort_by(|a, b| a.path.cmp(&b.path));
+    audited
+}
+
+fn release_files(index: &BOFIndex, dir: &Path) -> HashMap<PathBuf, ManifestFile> {
+    index
+        .manifest_files()
+        .into_iter()
+        .filter_map(|file| {
+            let relative = file.path.strip_prefix(dir).ok()?.to_path_buf();
+            Some((relative, file))
+        })
+        .collect()
+}
+
+// A Markdown section for release notes: counts per verdict, then the files of each verdict
+pub fn markdown(audited: &[Audited], from: &str, to: &str) -> Vec<String> {
+    let mut by_verdict: BTreeMap<Verdict, Vec<&Path>> = BTreeMap::new();
+    for file in audited {
+        by_verdict.entry(file.verdict).or_default().push(&file.path);
+    }
+    let mut lines = vec![
+        format!("## Changes from {} to {}", from, to),
+        String::new(),
+        "| Files | Count |".to_string(),
+        "|---|---:|".to_string(),
+    ];
+    for verdict in Verdict::ALL {
+        let count = by_verdict.get(&verdict).map_or(0, Vec::len);
+        lines.push(format!("| {} | {} |", verdict.title(), count));
+    }
+    lines.push(format!("| Total | {} |", audited.len()));
+    for (verdict, paths) in by_verdict {
+        lines.push(String::new());
+        lines.push(format!("### {}", verdict.title()));
+        lines.push(String::new());
+        lines.extend(paths.iter().map(|path| format!("- `{}`", path.display())));
+    }
+    lines
+}
@@ -1,32 +1,264 @@
+use crate::cache::{HashCache, Identity};
+use crate::catalog::{self, CatalogFile, Content};
+use crate::dedupe::{DedupeMatch, Keep};
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::filter::{Filter, Subject};
+use crate::hash::{self, HashAlgorithm, HashPolicy};
+use crate::ignore::{self, IgnoreRules};
+use crate::labels::Classifier;
+use crate::manifest::{self, CasSummary, ManifestFile, ManifestFormat};
+use crate::mime;
+use crate::pack;
+use crate::platform::{self, LinkKind};
+use crate::policy::{Policy, PolicyAction, PolicyMatch, Selector};
+use crate::preset::{self, Preset};
+use crate::preview::{self, Source};
+use crate::registry::Registry;
+use crate::serve::{Access, ServeToken};
+use crate::settings;
+use crate::storage::{self, Compression, IndexFormat};
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant, SystemTime};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File, Metadata},
-    io::{self},
-    os::unix::fs::MetadataExt,
+    io::{self, BufWriter},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
 };
 
-fn generate_key(ident: String) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(ident.as_bytes());
-    format!("{:x}", hasher.finalize())
+fn generate_key(ident: &str, config: &BOFConfig) -> String {
+    config.hash_algorithm.hash_bytes(ident.as_bytes())
 }
 
-#[derive(Debug)]
-enum QueueItem {
-    DirEntry(DirEntry),
-    BOFEntry(BOFEntry),
+// Key of a file catalogued with `no_hash`, until `bof rehash` reads it
+const UNHASHED: &str = "";
+const PROFILE_DIR: &str = "profiles/{profile}";
+
+// Digests of a file besides its key, see BOFConfig::extra_hashes
+pub(crate) type Hashes = BTreeMap<HashAlgorithm, String>;
+
+// Algorithms of the extra hashes to record, the one of the key aside
+fn extra_algorithms(config: &BOFConfig) -> Vec<HashAlgorithm> {
+    let mut algorithms = config
+        .extra_hashes
+        .iter()
+        .copied()
+        .filter(|algorithm| *algorithm != config.hash_algorithm)
+        .collect::<Vec<_>>();
+    algorithms.sort();
+    algorithms.dedup();
+    algorithms
+}
+
+// Key of the file along with its extra hashes, all from one read
+fn digests(path: &Path, config: &BOFConfig) -> io::Result<(String, Hashes)> {
+    let extra = extra_algorithms(config);
+    let mut algorithms = vec![config.hash_algorithm];
+    algorithms.extend(&extra);
+    let mut digests = config.hashing.hash_file_with(&algorithms, path)?;
+    let key = digests.remove(0);
+    Ok((key, extra.into_iter().zip(digests).collect()))
+}
+
+// Hash of the file's content, unless only metadata is recorded for files of its size
+fn content_key(path: &Path, size: u64, config: &BOFConfig) -> io::Result<(String, Hashes)> {
+    if config.no_hash || config.hashing.is_lazy(size) {
+        Ok((UNHASHED.to_string(), Hashes::new()))
+    } else {
+        digests(path, config)
+    }
+}
+
+// Whether the file was hashed before extra_hashes asked for what it has now
+fn hashes_changed(file_meta: &FileMetaData, config: &BOFConfig) -> bool {
+    !config.no_hash
+        && !config.hashing.is_lazy(file_meta.size)
+        && !file_meta
+            .hashes
+            .keys()
+            .copied()
+            .eq(extra_algorithms(config))
+}
+
+// Directory entries handed to the workers at a time
+const READ_DIR_BATCH: usize = 4096;
+// MiB read per second with `background` unless `background_read_rate` says otherwise
+const BACKGROUND_READ_RATE: u64 = 50;
+// Threads walking directories for the parallel index while the rayon pool hashes
+const WALK_THREADS: usize = 4;
+// Files found and not hashed yet, past which the walk waits
+const HASH_QUEUE: usize = 1024;
+// JSON indexes of more entries than this are saved without indentation unless `compact` is set
+const COMPACT_ABOVE: usize = 10_000;
+
+// What a parallel walk found below a directory, merged into the index once the walk is done
+// so workers never hold a lock on it
+#[derive(Debug, Default)]
+struct Shard {
+    entries: Vec<BOFEntry>,
+    // Paths of indexed files that were found again under another name
+    moved: Vec<PathBuf>,
+    skipped: Vec<Skipped>,
+}
+
+impl Shard {
+    fn extend(&mut self, other: Shard) {
+        self.entries.extend(other.entries);
+        self.moved.extend(other.moved);
+        self.skipped.extend(other.skipped);
+    }
+
+    fn skip(&mut self, path: &Path, reason: SkipReason) {
+        self.skipped.push(Skipped {
+            path: path.to_path_buf(),
+            reason,
+        });
+    }
+}
+
+// Why a path was left out of the index during the last run
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum SkipReason {
+    IgnoredPath,
+    IgnoreRule { pattern: String, source: PathBuf },
+    Symlink,
+    Junction,
+    ReparsePoint,
+    SymlinkLoop,
+    DanglingSymlink,
+    Unreadable(String),
+    // Neither a file, a directory nor a symlink, e.g. a socket or a device
+    Unsupported,
+    // Files out of min_size and max_size, with their size
+    TooSmall(u64),
+    TooLarge(u64),
+    NotIncluded,
+    Excluded,
+    // A directory below max_depth
+    TooDeep,
+    // A directory mounted from another filesystem than its root, with one_file_system
+    OtherFileSystem,
+    // A trash directory, unless include_trash
+    Trash,
+}
+
+impl SkipReason {
+    // Left out by the include and exclude patterns or the size limits, which only apply to files
+    fn is_filtered(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::TooSmall(_)
+                | SkipReason::TooLarge(_)
+                | SkipReason::NotIncluded
+                | SkipReason::Excluded
+        )
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::IgnoredPath => write!(f, "listed in ignore_paths"),
+            SkipReason::IgnoreRule { pattern, source } => {
+                write!(f, "ignored by rule {} in {}", pattern, source.display())
+            }
+            SkipReason::Symlink => write!(f, "symlink, skipped by the symlinks setting"),
+            SkipReason::Junction => write!(f, "junction, skipped by the junctions setting"),
+            SkipReason::ReparsePoint => {
+                write!(f, "reparse point, skipped by the reparse_points setting")
+            }
+            SkipReason::SymlinkLoop => write!(f, "symlink looping back into its own tree"),
+            SkipReason::DanglingSymlink => write!(f, "dangling symlink"),
+            SkipReason::Unreadable(e) => write!(f, "could not be read: {}", e),
+            SkipReason::Unsupported => write!(f, "neither file nor directory"),
+            SkipReason::TooSmall(size) => write!(f, "{} bytes, smaller than min_size", size),
+            SkipReason::TooLarge(size) => write!(f, "{} bytes, larger than max_size", size),
+            SkipReason::NotIncluded => write!(f, "matching none of include_patterns"),
+            SkipReason::Excluded => write!(f, "matching exclude_patterns"),
+            SkipReason::TooDeep => write!(f, "directory deeper than max_depth"),
+            SkipReason::OtherFileSystem => {
+                write!(
+                    f,
+                    "directory on another filesystem, skipped by one_file_system"
+                )
+            }
+            SkipReason::Trash => write!(f, "trash directory, skipped unless include_trash"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub(crate) struct BOFIndex {
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BOFIndex {
     entries: HashMap<PathBuf, BOFEntry>,
     inverse_table: HashMap<String, Vec<PathBuf>>,
+    #[serde(default)]
+    header: IndexHeader,
+    // Existing keys can't be trusted, e.g. they were made with another hash algorithm
+    #[serde(skip)]
+    rehash: bool,
+    // Roots this process indexed, every other root is owned by whoever saved it last
+    #[serde(skip)]
+    claimed_roots: Vec<PathBuf>,
+    // Modification time of index.json when it was loaded
+    #[serde(skip)]
+    loaded_at: Option<SystemTime>,
+    // (device, inode) -> path of indexed files, to recognize them once moved
+    #[serde(skip)]
+    inodes: HashMap<(u64, u64), PathBuf>,
+    // Only a segment of the index was loaded, see BOFIndex::load_segment
+    #[serde(skip)]
+    partial: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct IndexHeader {
+    // Counts up with every save, see storage::Generation. 0 for indexes saved before.
+    #[serde(default)]
+    generation: u64,
+    #[serde(default)]
+    hash_algorithm: Option<HashAlgorithm>,
+    #[serde(default)]
+    roots: Vec<RootInfo>,
+    #[serde(default)]
+    last_run: Option<RunStats>,
+    // Paths the runs that produced this index left out
+    #[serde(default)]
+    skipped: Vec<Skipped>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RootInfo {
+    pub path: PathBuf,
+    pub owner: String,
+    pub host: String,
+    pub updated: SystemTime,
+    // Filesystem the root was on when last indexed, not known for indexes made before
+    #[serde(default)]
+    pub device: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct RunStats {
+    files: u64,
+    bytes: u64,
+    duration: Duration,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -34,26 +266,94 @@ struct BOFEntry {
     key: String,
     path: PathBuf,
     metadata: MetaData,
+    // Labels from the classification rules of the configuration
+    #[serde(default)]
+    labels: Vec<String>,
+}
+
+impl BOFEntry {
+    fn is_hashed(&self) -> bool {
+        self.key != UNHASHED
+    }
+
+    fn subject(&self) -> Subject<'_> {
+        let (kind, file_meta) = match &self.metadata {
+            MetaData::File(file_meta) => ("file", Some(file_meta)),
+            MetaData::Directory(_) => ("directory", None),
+            MetaData::Link(_) => ("link", None),
+        };
+        Subject {
+            path: &self.path,
+            kind,
+            key: &self.key,
+            size: file_meta.map(|file_meta| file_meta.size),
+            mtime: file_meta.map(|file_meta| file_meta.mtime),
+            ctime: file_meta.and_then(|file_meta| file_meta.ctime),
+            labels: &self.labels,
+            mime: file_meta.and_then(|file_meta| file_meta.mime.as_deref()),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) enum MetaData {
     Directory(DirMetaData),
     File(FileMetaData),
+    Link(LinkMetaData),
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct FileMetaData {
-    ctime: SystemTime,
+    // Birth time isn't available on every filesystem
+    #[serde(default)]
+    ctime: Option<SystemTime>,
     mtime: SystemTime,
     size: u64,
     inode: u64,
+    #[serde(default)]
+    device: u64,
+    #[serde(default)]
+    streams: Vec<StreamMetaData>,
+    #[serde(default)]
+    hashes: Hashes,
+    // Recorded with the `permissions` setting, on Unix
+    #[serde(default)]
+    permissions: Option<Permissions>,
+    // Type of the content by its first bytes, recorded with the `mime` setting
+    #[serde(default)]
+    mime: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct Permissions {
+    // Including the file type bits, like st_mode
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    // Values that aren't UTF-8 are written as 0x followed by hex digits
+    #[serde(default)]
+    xattrs: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct StreamMetaData {
+    name: String,
+    size: u64,
+    key: String,
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub(crate) struct DirMetaData {
     data: Vec<DirEntry>,
     inode: u64,
+    #[serde(default)]
+    device: u64,
+}
+
+// A symlink recorded as is, see SymlinkPolicy::Record
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub(crate) struct LinkMetaData {
+    target: PathBuf,
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
@@ -62,14 +362,310 @@ struct DirEntry {
     data: MetaData,
 }
 
-impl From<&Metadata> for FileMetaData {
-    fn from(val: &Metadata) -> FileMetaData {
+impl MetaData {
+    // Bytes allocated for this metadata beyond its own size, for BOFIndex::memory_estimate
+    fn heap_size(&self) -> u64 {
+        match self {
+            MetaData::Directory(dir) => {
+                dir.data
+                    .iter()
+                    .map(|entry| entry.name.capacity() as u64 + entry.data.heap_size())
+                    .sum::<u64>()
+                    + (dir.data.capacity() * size_of::<DirEntry>()) as u64
+            }
+            MetaData::File(file) => {
+                file.streams
+                    .iter()
+                    .map(|stream| (stream.name.capacity() + stream.key.capacity()) as u64)
+                    .sum::<u64>()
+                    + (file.streams.capacity() * size_of::<StreamMetaData>()) as u64
+            }
+            MetaData::Link(link) => link.target.capacity() as u64,
+        }
+    }
+}
+
+impl FileMetaData {
+    fn new(path: &Path, val: &Metadata, config: &BOFConfig) -> FileMetaData {
+        let id = platform::file_id(path, val);
         Self {
-            ctime: val.created().unwrap(),  // Should be supported in our system
+            ctime: val.created().ok(),
             mtime: val.modified().unwrap(), // Should be supported in our system
             size: val.len(),
-            inode: val.ino(),
+            inode: id.inode,
+            device: id.device,
+            streams: read_streams(path, config),
+            hashes: Hashes::new(),
+            permissions: read_permissions(path, val, config),
+            mime: read_mime(path, config),
+        }
+    }
+}
+
+fn read_mime(path: &Path, config: &BOFConfig) -> Option<String> {
+    if !config.mime {
+        return None;
+    }
+    match mime::sniff(path) {
+        Ok(mime) => mime.map(str::to_string),
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to read the type of {}: {}",
+                path.display(),
+                e
+            )));
+            None
+        }
+    }
+}
+
+// Files indexed before `mime` was set get their type on the next update
+fn mime_missing(file_meta: &FileMetaData, config: &BOFConfig) -> bool {
+    config.mime && file_meta.mime.is_none() && file_meta.size > 0
+}
+
+// Mode bits, owner and extended attributes of a file, when enabled
+fn read_permissions(path: &Path, metadata: &Metadata, config: &BOFConfig) -> Option<Permissions> {
+    if !config.permissions {
+        return None;
+    }
+    let (mode, uid, gid) = platform::owner(metadata)?;
+    let xattrs = match platform::xattrs(path) {
+        Ok(xattrs) => xattrs,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to read extended attributes of {}: {}",
+                path.display(),
+                e
+            )));
+            Vec::new()
+        }
+    };
+    Some(Permissions {
+        mode,
+        uid,
+        gid,
+        xattrs: xattrs
+            .into_iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8(value).unwrap_or_else(|e| {
+                    let hex = e.as_bytes().iter().map(|byte| format!("{:02x}", byte));
+                    format!("0x{}", hex.collect::<String>())
+                });
+                (name, value)
+            })
+            .collect(),
+    })
+}
+
+// Mode bits, owner and group of the file as it is now, or as recorded with `permissions` when it
+// can't be read
+fn owner(path: &Path, file_meta: &FileMetaData) -> Option<(u32, u32, u32)> {
+    fs::symlink_metadata(path)
+        .ok()
+        .and_then(|metadata| platform::owner(&metadata))
+        .or_else(|| {
+            let permissions = file_meta.permissions.as_ref()?;
+            Some((permissions.mode, permissions.uid, permissions.gid))
+        })
+}
+
+fn permissions_changed(
+    file_meta: &FileMetaData,
+    path: &Path,
+    metadata: &Metadata,
+    config: &BOFConfig,
+) -> bool {
+    config.permissions && file_meta.permissions != read_permissions(path, metadata, config)
+}
+
+// Alternate data streams / resource forks of a file, when enabled
+fn read_streams(path: &Path, config: &BOFConfig) -> Vec<StreamMetaData> {
+    if !config.streams {
+        return Vec::new();
+    }
+    let streams = match platform::streams(path) {
+        Ok(streams) => streams,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to list streams of {}: {}",
+                path.display(),
+                e
+            )));
+            return Vec::new();
+        }
+    };
+    streams
+        .into_iter()
+        .filter_map(|stream| {
+            match config
+                .hashing
+                .hash_file(config.hash_algorithm, &stream.path)
+            {
+                Ok(key) => Some(StreamMetaData {
+                    name: stream.name,
+                    size: stream.size,
+                    key,
+                }),
+                Err(e) => {
+                    events::emit(Event::Error(format!(
+                        "Failed to read stream {} of {}: {}",
+                        stream.name,
+                        path.display(),
+                        e
+                    )));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn streams_changed(file_meta: &FileMetaData, path: &Path, config: &BOFConfig) -> bool {
+    config.streams && file_meta.streams != read_streams(path, config)
+}
+
+// Whether an indexed file has to be read again on update: it was modified, or it lacks something
+// the configuration now records
+fn needs_rehash(
+    file_meta: &FileMetaData,
+    path: &Path,
+    metadata: &Metadata,
+    config: &BOFConfig,
+) -> bool {
+    file_meta.mtime != metadata.modified().unwrap()
+        || streams_changed(file_meta, path, config)
+        || permissions_changed(file_meta, path, metadata, config)
+        || mime_missing(file_meta, config)
+        || hashes_changed(file_meta, config)
+}
+
+impl DirMetaData {
+    fn new(path: &Path, val: &Metadata, data: Vec<DirEntry>) -> DirMetaData {
+        let id = platform::file_id(path, val);
+        Self {
+            data,
+            inode: id.inode,
+            device: id.device,
+        }
+    }
+}
+
+// (device, inode) of the directories above the one being walked, so following a symlink
+// never loops back into one of them
+struct Visited<'a> {
+    id: (u64, u64),
+    parent: Option<&'a Visited<'a>>,
+    // 0 for the indexed root
+    depth: usize,
+    // Filesystem of the indexed root, which one_file_system keeps to
+    root_device: u64,
+}
+
+impl<'a> Visited<'a> {
+    fn new(path: &Path, metadata: &Metadata, parent: Option<&'a Visited<'a>>) -> Visited<'a> {
+        let id = platform::file_id(path, metadata);
+        Visited {
+            id: (id.device, id.inode),
+            parent,
+            depth: parent.map_or(0, |parent| parent.depth + 1),
+            root_device: parent.map_or(id.device, |parent| parent.root_device),
+        }
+    }
+
+    fn contains(&self, path: &Path, metadata: &Metadata) -> bool {
+        let id = platform::file_id(path, metadata);
+        let id = (id.device, id.inode);
+        let mut visited = Some(self);
+        while let Some(dir) = visited {
+            if dir.id == id {
+                return true;
+            }
+            visited = dir.parent;
+        }
+        false
+    }
+}
+
+// What to do with a symlink found while walking, according to the configured policy
+enum Link {
+    Skip(SkipReason),
+    Follow(Metadata),
+    Record(BOFEntry),
+}
+
+fn symlink(path: &Path, kind: LinkKind, visited: &Visited, config: &BOFConfig) -> Link {
+    let policy = match kind {
+        LinkKind::Symlink => config.symlinks,
+        LinkKind::Junction => config.junctions.unwrap_or(config.symlinks),
+        LinkKind::ReparsePoint => match config.reparse_points {
+            ReparsePolicy::Skip => return Link::Skip(SkipReason::ReparsePoint),
+            ReparsePolicy::Follow => SymlinkPolicy::Follow,
+        },
+    };
+    match policy {
+        SymlinkPolicy::Skip if kind == LinkKind::Junction => Link::Skip(SkipReason::Junction),
+        SymlinkPolicy::Skip => Link::Skip(SkipReason::Symlink),
+        SymlinkPolicy::Follow => match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() && visited.contains(path, &metadata) => {
+                events::emit(Event::Notice(format!(
+                    "Not following symlink loop at {}",
+                    path.display()
+                )));
+                Link::Skip(SkipReason::SymlinkLoop)
+            }
+            Ok(metadata) => Link::Follow(metadata),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                events::emit(Event::Notice(format!(
+                    "Skipping dangling symlink {}",
+                    path.display()
+                )));
+                Link::Skip(SkipReason::DanglingSymlink)
+            }
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to follow symlink {}: {}",
+                    path.display(),
+                    e
+                )));
+                Link::Skip(SkipReason::Unreadable(e.to_string()))
+            }
+        },
+        SymlinkPolicy::Record => match fs::read_link(path) {
+            Ok(target) => Link::Record(BOFEntry {
+                key: generate_key(&target.to_string_lossy(), config),
+                path: path.to_path_buf(),
+                metadata: MetaData::Link(LinkMetaData { target }),
+                labels: Vec::new(),
+            }),
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to read symlink {}: {}",
+                    path.display(),
+                    e
+                )));
+                Link::Skip(SkipReason::Unreadable(e.to_string()))
+            }
+        },
+    }
+}
+
+// Reports a recorded symlink the way files are, unless it still points to the same target
+fn report_link(entry: &BOFEntry, previous: Option<&BOFEntry>) {
+    match previous {
+        Some(previous)
+            if matches!(previous.metadata, MetaData::Link(_)) && previous.key == entry.key =>
+        {
+            events::emit(Event::Unchanged(entry.path.clone()))
         }
+        Some(_) => events::emit(Event::Updated {
+            path: entry.path.clone(),
+            size: 0,
+        }),
+        None => events::emit(Event::Indexed {
+            path: entry.path.clone(),
+            size: 0,
+        }),
     }
 }
 
@@ -78,6 +674,133 @@ impl BOFIndex {
         Self {
             entries: HashMap::new(),
             inverse_table: HashMap::new(),
+            header: IndexHeader::default(),
+            rehash: false,
+            claimed_roots: Vec::new(),
+            loaded_at: None,
+            inodes: HashMap::new(),
+            partial: false,
+        }
+    }
+
+    // Totals of the run that produced this index, used to estimate the next one,
+    // and ownership of the roots it went through
+    fn record_run(&mut self, started: Instant, roots: &[PathBuf]) {
+        self.claim_roots(roots);
+
+        let mut stats = RunStats {
+            duration: started.elapsed(),
+            ..Default::default()
+        };
+        for entry in self.entries.values() {
+            if let MetaData::File(file_meta) = &entry.metadata {
+                stats.files += 1;
+                stats.bytes += file_meta.size;
+            }
+        }
+        self.header.last_run = Some(stats);
+    }
+
+    // Records the current user as the last to update `roots`, so their changes win when saving
+    pub(crate) fn claim_roots(&mut self, roots: &[PathBuf]) {
+        let owner = platform::current_user();
+        let host = platform::hostname();
+        for root in roots {
+            self.header.roots.retain(|info| &info.path != root);
+            self.header.roots.push(RootInfo {
+                path: root.clone(),
+                owner: owner.clone(),
+                host: host.clone(),
+                updated: SystemTime::now(),
+                device: fs::metadata(root)
+                    .ok()
+                    .map(|metadata| platform::file_id(root, &metadata).device),
+            });
+            self.claimed_roots.push(root.clone());
+        }
+    }
+
+    fn rebuild_inverse_table(&mut self) {
+        self.inverse_table.clear();
+        for entry in self.entries.values() {
+            if let MetaData::File(_) = entry.metadata {
+                let parent_dir = entry.path.parent().unwrap_or_else(|| Path::new("."));
+                self.inverse_table
+                    .entry(entry.key.clone())
+                    .or_default()
+                    .push(parent_dir.to_path_buf());
+            }
+        }
+    }
+
+    // Another process saved the index since we loaded it: keep what it wrote for every root we
+    // didn't index ourselves, so users updating different roots of a shared index don't clobber each other
+    fn merge_concurrent(&mut self, on_disk: BOFIndex) {
+        let claimed = |path: &Path| self.claimed_roots.iter().any(|root| path.starts_with(root));
+
+        let theirs = on_disk
+            .entries
+            .into_iter()
+            .filter(|(path, _)| !claimed(path))
+            .collect::<Vec<_>>();
+        let roots = on_disk
+            .header
+            .roots
+            .into_iter()
+            .filter(|info| !claimed(&info.path))
+            .collect::<Vec<_>>();
+        let skipped = on_disk
+            .header
+            .skipped
+            .into_iter()
+            .filter(|skipped| !claimed(&skipped.path))
+            .collect::<Vec<_>>();
+
+        self.entries
+            .retain(|path, _| self.claimed_roots.iter().any(|root| path.starts_with(root)));
+        self.entries.extend(theirs);
+        self.header.roots.retain(|info| {
+            self.claimed_roots
+                .iter()
+                .any(|root| info.path.starts_with(root))
+        });
+        self.header.roots.extend(roots);
+        self.header.skipped.retain(|skipped| {
+            self.claimed_roots
+                .iter()
+                .any(|root| skipped.path.starts_with(root))
+        });
+        self.header.skipped.extend(skipped);
+        self.rebuild_inverse_table();
+    }
+
+    // Applies what parallel workers found in path order, so the result doesn't depend on
+    // how the work was split between threads
+    fn merge_shards(&mut self, shards: Vec<Shard>) {
+        let mut merged = Shard::default();
+        shards.into_iter().for_each(|shard| merged.extend(shard));
+        for path in &merged.moved {
+            self.remove_entry(path);
+        }
+        // Files now left out by their name or size don't stay indexed either
+        for skipped in &merged.skipped {
+            if skipped.reason.is_filtered() {
+                self.remove_entry(&skipped.path);
+            }
+        }
+        merged.skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        self.header.skipped.extend(merged.skipped);
+        merged.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        for entry in merged.entries {
+            self.remove_entry(&entry.path);
+            if let MetaData::File(_) = entry.metadata {
+                let parent_dir = entry.path.parent().unwrap_or_else(|| Path::new("."));
+                self.inverse_table
+                    .entry(entry.key.clone())
+                    .or_default()
+                    .push(parent_dir.to_path_buf());
+            }
+            self.entries.insert(entry.path.clone(), entry);
         }
     }
 
@@ -87,6 +810,7 @@ impl BOFIndex {
         key: String,
         metadata: &Metadata,
         dir_entries: Option<Vec<DirEntry>>,
+        config: &BOFConfig,
     ) -> MetaData {
         let parent_dir = path
             .parent()
@@ -95,13 +819,14 @@ impl BOFIndex {
             .to_string();
 
         if metadata.is_file() {
-            let metadata: FileMetaData = metadata.into();
+            let metadata = FileMetaData::new(path, metadata, config);
             self.entries.insert(
                 path.to_path_buf(),
                 BOFEntry {
                     key: key.clone(),
                     path: path.to_path_buf(),
                     metadata: MetaData::File(metadata.clone()),
+                    labels: Vec::new(),
                 },
             );
             self.inverse_table
@@ -110,10 +835,11 @@ impl BOFIndex {
                 .push(parent_dir.into());
             MetaData::File(metadata)
         } else {
-            MetaData::Directory(DirMetaData {
-                data: dir_entries.unwrap(), // Should be Some,
-                inode: metadata.ino(),
-            })
+            MetaData::Directory(DirMetaData::new(
+                path,
+                metadata,
+                dir_entries.unwrap(), // Should be Some
+            ))
         }
     }
 
@@ -137,6 +863,7 @@ impl BOFIndex {
                         key: key.clone(),
                         path: path.to_path_buf(),
                         metadata: metadata.clone(),
+                        labels: Vec::new(),
                     },
                 );
                 self.inverse_table
@@ -149,265 +876,665 @@ impl BOFIndex {
                 MetaData::Directory(DirMetaData {
                     data: dir_entries.unwrap(), // Should be Some,
                     inode: dir_meta.inode,
+                    device: dir_meta.device,
                 })
             }
+            MetaData::Link(_) => {
+                self.entries.insert(
+                    path.to_path_buf(),
+                    BOFEntry {
+                        key,
+                        path: path.to_path_buf(),
+                        metadata: metadata.clone(),
+                        labels: Vec::new(),
+                    },
+                );
+                metadata.clone()
+            }
         }
     }
 
-    fn update_entry(&mut self, path: &Path, key: String, metadata: &Metadata) -> MetaData {
-        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.1.path == path) {
-            entry.1.key = key;
-            entry.1.metadata = MetaData::File(metadata.into());
-        }
-        println!("Updated an entry {}", path.display());
-        MetaData::File(metadata.into())
+    fn skip(&mut self, path: &Path, reason: SkipReason) {
+        self.header.skipped.push(Skipped {
+            path: path.to_path_buf(),
+            reason,
+        });
     }
-}
-
-#[derive(Debug, Deserialize, Default)]
-pub(crate) struct BOFConfig {
-    #[serde(default = "BOFConfig::default_output_dir")]
-    pub output_dir: PathBuf,
-    #[serde(default = "BOFConfig::default_ignore_paths")]
-    pub ignore_paths: Vec<PathBuf>,
-    #[serde(default)]
-    pub parallel: bool,
-}
 
-impl BOFConfig {
-    fn default_output_dir() -> PathBuf {
-        PathBuf::from(".bof")
-    }
-    fn default_ignore_paths() -> Vec<PathBuf> {
-        vec![PathBuf::from(".git")]
+    // Forgets why paths under `roots` were skipped, before walking them again
+    fn clear_skipped(&mut self, roots: &[PathBuf]) {
+        self.header
+            .skipped
+            .retain(|skipped| !roots.iter().any(|root| skipped.path.starts_with(root)));
     }
-}
-
-pub(crate) fn load_config() -> BOFConfig {
-    let settings = config::Config::builder()
-        .add_source(config::File::with_name("Config").required(false))
-        .build()
-        .unwrap();
 
-    settings.try_deserialize::<BOFConfig>().unwrap_or_default()
-}
+    fn classify(&mut self, config: &BOFConfig) -> Result<()> {
+        let classifier = Classifier::new(&config.labels)?;
+        for entry in self.entries.values_mut() {
+            entry.labels = match entry.metadata {
+                MetaData::File(_) if !classifier.is_empty() => classifier.classify(&entry.path),
+                _ => Vec::new(),
+            };
+        }
+        Ok(())
+    }
 
-pub(crate) fn init(config: &mut BOFConfig) -> io::Result<()> {
-    fs::create_dir_all(&config.output_dir)?;
-    println!(
-        "Initialized .bof directory at: {}",
-        &config.output_dir.display()
-    );
-    Ok(())
-}
+    fn index_inodes(&mut self) {
+        self.inodes = self
+            .entries
+            .values()
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) if file_meta.inode != 0 => {
+                    Some(((file_meta.device, file_meta.inode), entry.path.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+    }
 
-fn index(path: &Path, bof_index: &mut BOFIndex, config: &BOFConfig) -> io::Result<MetaData> {
-    let metadata = fs::metadata(path)?;
-    if !metadata.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Path is not a directory",
-        ));
+    // An indexed file that is gone from its path, with the same identity and content as this new one
+    fn moved_from(&self, key: &str, file_meta: &FileMetaData) -> Option<PathBuf> {
+        if file_meta.inode == 0 {
+            return None;
+        }
+        let from = self.inodes.get(&(file_meta.device, file_meta.inode))?;
+        let entry = self.entries.get(from)?;
+        (entry.key == key && fs::symlink_metadata(from).is_err()).then(|| from.clone())
     }
 
-    if config.ignore_paths.contains(&path.to_path_buf()) {
-        println!("Skipping ignored path: {}", path.display());
-        return Ok(MetaData::Directory(DirMetaData {
-            data: Vec::new(),
-            inode: metadata.ino(),
-        }));
+    fn remove_entry(&mut self, path: &Path) -> Option<BOFEntry> {
+        let entry = self.entries.remove(path)?;
+        let parent_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        if let Some(dirs) = self.inverse_table.get_mut(&entry.key) {
+            if let Some(position) = dirs.iter().position(|dir| dir == parent_dir) {
+                dirs.remove(position);
+            }
+            if dirs.is_empty() {
+                self.inverse_table.remove(&entry.key);
+            }
+        }
+        Some(entry)
     }
 
-    let dir_key = generate_key(path.to_string_lossy().to_string());
-    let mut dir_entries = DirMetaData {
-        data: Vec::new(),
-        inode: metadata.ino(),
-    };
+    // Drops a file deleted on purpose, the next update takes care of its directory
+    pub(crate) fn forget(&mut self, path: &Path) {
+        self.remove_entry(path);
+    }
 
-    fs::read_dir(path)?
-        .inspect(|entry| {
-            if let Err(ref e) = entry {
-                eprintln!("Invalid entry in directory {}: {}", path.display(), e);
+    // Brings `path` up to date after a watcher saw it change: files are hashed again, directories
+    // walked like an update and whatever is gone is dropped along with everything below it.
+    // Symlinks are left to the next update.
+    pub(crate) fn apply_change(&mut self, path: &Path, config: &BOFConfig) {
+        let Some(root) = self
+            .header
+            .roots
+            .iter()
+            .map(|info| info.path.clone())
+            .find(|root| path.starts_with(root))
+        else {
+            return;
+        };
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let gone = self
+                    .entries
+                    .keys()
+                    .filter(|entry| entry.starts_with(path))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for entry in gone {
+                    self.remove_entry(&entry);
+                    events::emit(Event::Removed(entry));
+                }
+                return;
             }
-        })
-        .filter_map(|e| e.ok())
-        .for_each(|entry| {
-            let name = entry.file_name().to_string_lossy().to_string();
-            let path = entry.path();
-            let metadata = match entry.metadata() {
-                Ok(m) => m,
-                Err(e) => {
-                    eprintln!("Failed to get metadata for {}: {}", path.display(), e);
-                    return;
-                }
-            };
-
-            if config.ignore_paths.contains(&path.to_path_buf()) {
-                println!("Skipping ignored path: {}", path.display());
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to get metadata for {}: {}",
+                    path.display(),
+                    e
+                )));
                 return;
             }
+        };
+        let Some(rules) = rules_for(path, &root, config) else {
+            return;
+        };
+        if is_ignored(path, metadata.is_dir(), config, &rules) {
+            return;
+        }
+        if file_reason(path, &metadata, config, &rules).is_some() {
+            self.remove_entry(path);
+            return;
+        }
+        let result = if metadata.is_dir() {
+            update_index(path, self, config, &rules, None).map(|_| ())
+        } else if metadata.is_file() {
+            touch_file(path, &metadata, self, config)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            events::emit(Event::Error(format!(
+                "Failed to update {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    }
 
-            if metadata.is_file() {
-                let key = match fs::read_to_string(&path) {
-                    Ok(content) => generate_key(content + &name),
-                    Err(e) => {
-                        eprintln!("Failed to read file {}: {}", path.display(), e);
-                        return;
-                    }
-                };
-                let file_meta = bof_index.add_entry(&path, key, &metadata, None);
-                dir_entries.data.push(DirEntry {
-                    name,
-                    data: file_meta,
-                });
-            } else if metadata.is_dir() {
-                match index(&entry.path(), bof_index, config) {
-                    Ok(subdir_meta) => dir_entries.data.push(DirEntry {
-                        name,
-                        data: subdir_meta,
-                    }),
-                    Err(e) => eprintln!("Failed to index directory {}: {}", path.display(), e),
-                };
-            } else {
-                eprintln!("Neither file nor directory! {}", path.display());
-                return;
-            }
+    fn update_entry(
+        &mut self,
+        path: &Path,
+        (key, hashes): (String, Hashes),
+        metadata: &Metadata,
+        config: &BOFConfig,
+    ) -> MetaData {
+        let file_meta = MetaData::File(FileMetaData {
+            hashes,
+            ..FileMetaData::new(path, metadata, config)
+        });
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.1.path == path) {
+            entry.1.key = key;
+            entry.1.metadata = file_meta.clone();
+        }
+        events::emit(Event::Updated {
+            path: path.to_path_buf(),
+            size: metadata.len(),
         });
+        file_meta
+    }
 
-    Ok(bof_index.add_entry(path, dir_key, &metadata, Some(dir_entries.data)))
-}
+    // Indexed paths under `prefix`, in path order
+    pub fn entries(&self, prefix: &Path) -> Vec<EntryInfo> {
+        let mut entries = self
+            .entries
+            .values()
+            .filter(|entry| entry.path.starts_with(prefix))
+            .map(|entry| {
+                let (kind, size, mtime, target, hashes) = match &entry.metadata {
+                    MetaData::File(file_meta) => (
+                        "file",
+                        Some(file_meta.size),
+                        Some(file_meta.mtime),
+                        None,
+                        file_meta.hashes.clone(),
+                    ),
+                    MetaData::Directory(_) => ("directory", None, None, None, Hashes::new()),
+                    MetaData::Link(link_meta) => (
+                        "link",
+                        None,
+                        None,
+                        Some(link_meta.target.clone()),
+                        Hashes::new(),
+                    ),
+                };
+                EntryInfo {
+                    path: entry.path.clone(),
+                    kind,
+                    key: entry.key.clone(),
+                    size,
+                    mtime,
+                    target,
+                    hashes,
+                    labels: entry.labels.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
 
-fn index_parallel(
-    path: &Path,
-    bof_index: Arc<Mutex<BOFIndex>>,
-    config: &BOFConfig,
-) -> io::Result<MetaData> {
-    let metadata = fs::metadata(path)?;
-    if !metadata.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Path is not a directory",
-        ));
+    // Indexed files with the given key, in path order
+    pub(crate) fn files_with_key(&self, key: &str) -> Vec<PathBuf> {
+        let mut paths = self
+            .entries
+            .values()
+            .filter(|entry| {
+                matches!(entry.metadata, MetaData::File(_)) && entry.is_hashed() && entry.key == key
+            })
+            .map(|entry| entry.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
     }
 
-    if config.ignore_paths.contains(&path.to_path_buf()) {
-        println!("Skipping ignored path: {}", path.display());
-        return Ok(MetaData::Directory(DirMetaData {
-            data: Vec::new(),
-            inode: metadata.ino(),
-        }));
+    // Whether the index on disk was saved since this one was loaded
+    pub fn is_stale(&self, output_dir: &Path, name: &str) -> bool {
+        let path = storage::find_index(output_dir, name);
+        fs::metadata(path).and_then(|m| m.modified()).ok() != self.loaded_at
     }
+}
 
-    let dir_key = generate_key(path.to_string_lossy().to_string());
-    let queue = crossbeam_queue::SegQueue::new();
+// An indexed path as shown to other programs, e.g. by `bof serve`
+#[derive(Debug, Serialize)]
+pub struct EntryInfo {
+    pub path: PathBuf,
+    // file, directory or link
+    pub kind: &'static str,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    // Extra hashes of a file, by algorithm
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<HashAlgorithm, String>,
+    pub labels: Vec<String>,
+}
 
-    let entries = fs::read_dir(path)?
-        .inspect(|entry| {
-            if let Err(ref e) = entry {
-                eprintln!("Invalid entry in directory {}: {}", path.display(), e);
-            }
-        })
-        .filter_map(|e| e.ok())
-        .collect::<Vec<_>>();
+// Indexed files with the same content
+#[derive(Debug, Serialize)]
+pub struct DuplicateGroup {
+    pub key: String,
+    pub size: u64,
+    // Distinct files on disk, hardlinks of one file are a single copy
+    pub copies: u64,
+    pub paths: Vec<PathBuf>,
+}
 
-    entries.par_iter().for_each(|entry| {
-        let name = entry.file_name().to_string_lossy().to_string();
-        let path = entry.path();
-        let metadata = match entry.metadata() {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!("Failed to get metadata for {}: {}", path.display(), e);
-                return;
-            }
-        };
+// Whether two file entries have the same size and modification time
+fn same_file(a: &BOFEntry, b: &BOFEntry) -> bool {
+    match (&a.metadata, &b.metadata) {
+        (MetaData::File(a), MetaData::File(b)) => a.size == b.size && a.mtime == b.mtime,
+        _ => false,
+    }
+}
 
-        if config.ignore_paths.contains(&path.to_path_buf()) {
-            println!("Skipping ignored path: {}", path.display());
-            return;
-        }
+// Most wasted space first, paths in order within each group
+pub(crate) fn sort_duplicates(groups: &mut [DuplicateGroup]) {
+    groups
+        .par_iter_mut()
+        .for_each(|group| group.paths.sort_unstable());
+    groups.par_sort_by(|a, b| {
+        b.wasted()
+            .cmp(&a.wasted())
+            .then_with(|| a.paths.cmp(&b.paths))
+    });
+}
 
-        if metadata.is_file() {
-            let key = match fs::read_to_string(&path) {
-                Ok(content) => generate_key(content + &name),
-                Err(e) => {
-                    eprintln!("Failed to read file {}: {}", path.display(), e);
-                    return;
-                }
-            };
-            let file_meta = FileMetaData::from(&metadata);
-            queue.push(QueueItem::DirEntry(DirEntry {
-                name,
-                data: MetaData::File(file_meta.clone()),
-            }));
+impl DuplicateGroup {
+    // Space that would be freed by keeping a single copy
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.copies - 1)
+    }
+}
 
-            let bof_entry = BOFEntry {
-                key,
-                path: path.clone(),
-                metadata: MetaData::File(file_meta),
-            };
-            queue.push(QueueItem::BOFEntry(bof_entry));
-        } else if metadata.is_dir() {
-            match index_parallel(&path, bof_index.clone(), config) {
-                Ok(subdir_meta) => queue.push(QueueItem::DirEntry(DirEntry {
-                    name,
-                    data: subdir_meta,
-                })),
-                Err(e) => eprintln!("Failed to index directory {}: {}", path.display(), e),
-            };
-        } else {
-            eprintln!("Neither file nor directory! {}", path.display());
-            return;
-        }
-    });
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    // Index what the link points to, under the link's path
+    Follow,
+    // Keep the link itself, with its target
+    Record,
+}
 
-    let mut index_lock = bof_index.lock().unwrap();
-    let mut dir_entries = Vec::new();
+// What to do with Windows reparse points that are neither symlinks nor junctions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReparsePolicy {
+    Skip,
+    // Index them as the files or directories they appear to be
+    #[default]
+    Follow,
+}
 
-    while let Some(item) = queue.pop() {
-        match item {
-            QueueItem::DirEntry(entry) => dir_entries.push(entry),
-            QueueItem::BOFEntry(bof_entry) => {
-                index_lock.add_entry_meta(
-                    &bof_entry.path,
-                    bof_entry.key,
-                    &bof_entry.metadata,
-                    None,
-                );
-            }
+// How the parallel walk treats a directory and everything below it
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Subtree {
+    // Threads walking it and hashing its files, rather than queueing them with the rest. 0 or 1
+    // for one entry at a time.
+    #[serde(default)]
+    pub threads: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct BOFConfig {
+    #[serde(default = "BOFConfig::default_output_dir")]
+    pub output_dir: PathBuf,
+    // The index is saved as <index_name>.json in output_dir, whatever its format
+    #[serde(default = "BOFConfig::default_index_name")]
+    pub index_name: String,
+    // An index of its own, with its snapshots and catalog, in a directory under output_dir
+    #[serde(default)]
+    pub profile: Option<String>,
+    // Where profiles are kept under output_dir, {profile} being the name of the profile
+    #[serde(default = "BOFConfig::default_profile_dir")]
+    pub profile_dir: String,
+    #[serde(default = "BOFConfig::default_ignore_paths")]
+    pub ignore_paths: Vec<PathBuf>,
+    // .bofignore-style patterns applied under every indexed root
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    // Files smaller than this are left out, e.g. 1 for empty ones
+    #[serde(default)]
+    pub min_size: u64,
+    // Files larger than this are left out, e.g. VM images, 0 for no limit
+    #[serde(default)]
+    pub max_size: u64,
+    // Levels of directories walked, counting each root itself, 0 for no limit
+    #[serde(default)]
+    pub max_depth: usize,
+    // `bof index` brings its checkpoint to disk every this many files or seconds, 0 for 10000
+    // files and 60 seconds
+    #[serde(default)]
+    pub checkpoint_files: u64,
+    #[serde(default)]
+    pub checkpoint_secs: u64,
+    // Directories mounted from another filesystem than their root are left out, e.g. /proc under /
+    #[serde(default)]
+    pub one_file_system: bool,
+    // Trash directories like .Trash or $RECYCLE.BIN are indexed and deduplicated too
+    #[serde(default)]
+    pub include_trash: bool,
+    // Only files matching one of these globs are indexed, when there are any, e.g. ["*.raw"]
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    // Files matching these globs are left out, directories are walked whatever their name
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    #[serde(default)]
+    pub parallel: bool,
+    // Threads hashing and walking in parallel, 0 for one per core
+    #[serde(default)]
+    pub threads: usize,
+    // Files read at once, however many threads there are, 0 for no limit. 2 to 4 suit spinning
+    // disks, which seek back and forth between more.
+    #[serde(default)]
+    pub open_files: usize,
+    // Run at the lowest CPU and IO priority, reading at most background_read_rate MiB per second
+    // (0 for 50), so the machine stays usable meanwhile
+    #[serde(default)]
+    pub background: bool,
+    #[serde(default)]
+    pub background_read_rate: u64,
+    // Hint the kernel that files hashed are read once, and drop them from the page cache after,
+    // so indexing a huge archive doesn't evict what the rest of the system has cached
+    #[serde(default)]
+    pub cache_friendly: bool,
+    // Directories walked and hashed by threads of their own, e.g. a FUSE mount of a cloud drive
+    // that has to be walked serially, by path as given to `bof index`
+    #[serde(default)]
+    pub subtrees: BTreeMap<PathBuf, Subtree>,
+    #[serde(default)]
+    pub estimate: bool,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    // Digests recorded beside the key, e.g. for other tools. Keys are always hash_algorithm's.
+    #[serde(default)]
+    pub extra_hashes: Vec<HashAlgorithm>,
+    // Record only path, size, mtime and inode of files, `bof rehash` fills in their hashes later
+    #[serde(default)]
+    pub no_hash: bool,
+    // How files are read and hashed by their size
+    #[serde(default)]
+    pub hashing: HashPolicy,
+    #[serde(default)]
+    pub streams: bool,
+    // Record mode bits, owner and extended attributes of files, e.g. to audit permission drift
+    #[serde(default)]
+    pub permissions: bool,
+    // Record the type of files by their first bytes, e.g. to find every video
+    #[serde(default)]
+    pub mime: bool,
+    // Leave files other processes have open alone when deduping or applying policies
+    #[serde(default)]
+    pub skip_open: bool,
+    // Make previews of images and videos after index and update, see preview::generate
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default = "BOFConfig::default_preview_size")]
+    pub preview_size: u32,
+    // The ffmpeg previews are made with, looked up in PATH unless it is a path
+    #[serde(default = "BOFConfig::default_ffmpeg")]
+    pub ffmpeg: String,
+    #[serde(default)]
+    pub index_format: IndexFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    // Save a JSON index without indentation, by default only once it has more than COMPACT_ABOVE
+    // entries
+    #[serde(default)]
+    pub compact: Option<bool>,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    // Windows junctions, handled like symlinks unless set
+    #[serde(default)]
+    pub junctions: Option<SymlinkPolicy>,
+    #[serde(default)]
+    pub reparse_points: ReparsePolicy,
+    // Also save the keys in 256 buckets by prefix, see catalog
+    #[serde(default)]
+    pub catalog: bool,
+    // Secret `bof export --salted` hashes the keys with, so that only those sharing it can tell
+    // which files they have in common. Keep it out of shared config files.
+    #[serde(default)]
+    pub key_salt: String,
+    // Also keep the keys in .bof/cache by device, inode, size and mtime, for `bof index` to reuse
+    // whatever index it starts from, see cache
+    #[serde(default)]
+    pub hash_cache: bool,
+    // Take a snapshot of the index every time it is saved
+    #[serde(default)]
+    pub auto_snapshot: bool,
+    // How many snapshots to keep, the oldest are removed first. 0 keeps them all.
+    #[serde(default)]
+    pub keep_snapshots: usize,
+    // Label -> patterns of the files it applies to, e.g. `media = ["*.jpg", "*.mp4"]`
+    #[serde(default)]
+    pub labels: BTreeMap<String, Vec<String>>,
+    // Named policies for what may be done with the files they select, see `bof policy run`
+    #[serde(default)]
+    pub policies: BTreeMap<String, Policy>,
+    // Command aliases, e.g. `dups = "find --all-repos"`, expanded like git aliases
+    #[serde(default)]
+    pub alias: BTreeMap<String, String>,
+    // Name -> token and access of a client of `bof serve`. Without any, serve only listens on a
+    // loopback address.
+    #[serde(default)]
+    pub serve_tokens: BTreeMap<String, ServeToken>,
+    // Endpoint -> access it needs, e.g. `"/entries" = "admin"`. /update needs admin, the rest read.
+    #[serde(default)]
+    pub serve_routes: BTreeMap<String, Access>,
+    // MiB the index may take in `bof daemon` and `bof watch` before it is compacted, 0 for no limit
+    #[serde(default = "BOFConfig::default_memory_limit")]
+    pub memory_limit: u64,
+}
+
+impl BOFConfig {
+    fn default_output_dir() -> PathBuf {
+        PathBuf::from(".bof")
+    }
+    fn default_index_name() -> String {
+        storage::INDEX_NAME.to_string()
+    }
+
+    // The name the index is saved and loaded under
+    pub fn index_name(&self) -> &str {
+        or_default(&self.index_name, storage::INDEX_NAME)
+    }
+    fn default_profile_dir() -> String {
+        PROFILE_DIR.to_string()
+    }
+    fn default_memory_limit() -> u64 {
+        2048
+    }
+    fn default_preview_size() -> u32 {
+        preview::PREVIEW_SIZE
+    }
+    fn default_ffmpeg() -> String {
+        preview::FFMPEG.to_string()
+    }
+    fn default_ignore_paths() -> Vec<PathBuf> {
+        vec![PathBuf::from(".git")]
+    }
+
+    // The directory the index of the profile is kept in, output_dir without a profile
+    pub fn index_dir(&self) -> PathBuf {
+        match &self.profile {
+            Some(profile) => self
+                .output_dir
+                .join(or_default(&self.profile_dir, PROFILE_DIR).replace("{profile}", profile)),
+            None => self.output_dir.clone(),
         }
     }
 
-    let meta_data = MetaData::Directory(DirMetaData {
-        data: dir_entries.clone(),
-        inode: metadata.ino(),
-    });
+    // Whether a JSON index of `entries` entries is indented
+    pub(crate) fn pretty_json(&self, entries: usize) -> bool {
+        !self.compact.unwrap_or(entries > COMPACT_ABOVE)
+    }
+}
 
-    index_lock.add_entry(path, dir_key, &metadata, Some(dir_entries));
-    Ok(meta_data)
+// Settings left empty, e.g. by BOFConfig::default()
+fn or_default<'a>(setting: &'a str, default: &'a str) -> &'a str {
+    if setting.is_empty() {
+        default
+    } else {
+        setting
+    }
 }
 
-pub(crate) fn index_directories(paths: Vec<PathBuf>, config: &BOFConfig) -> io::Result<()> {
-    let bof_index = Arc::new(Mutex::new(BOFIndex::new()));
+// Settings are layered: built-in defaults, then the user's config.toml in the configuration
+// directory, then the project's Config in the current directory. Command line flags are applied
+// on top by the caller.
+pub fn load_config() -> Result<BOFConfig> {
+    let mut builder = config::Config::builder();
+    if let Some(dir) = platform::config_dir() {
+        builder = builder.add_source(
+            config::File::from(dir.join(settings::USER_CONFIG_FILE))
+                .format(config::FileFormat::Toml)
+                .required(false),
+        );
+    }
+    let settings = builder
+        .add_source(config::File::with_name("Config").required(false))
+        .build()
+        .map_err(BofError::config)?;
 
-    if config.parallel {
-        paths.par_iter().for_each(|path| {
-            if let Err(e) = index_parallel(path, bof_index.clone(), config) {
-                eprintln!("Error indexing directory {}: {}", path.display(), e);
-            }
-        });
+    settings
+        .try_deserialize::<BOFConfig>()
+        .map_err(BofError::config)
+}
 
-        let bof_index_lock = bof_index.lock().unwrap();
-        save_index((*bof_index_lock).clone(), config)
+pub fn init(config: &mut BOFConfig, preset: Option<Preset>, interactive: bool) -> Result<()> {
+    let config_file = Path::new(settings::CONFIG_FILE);
+    if config_file.exists() {
+        if preset.is_some() || interactive {
+            return Err(BofError::Config(format!(
+                "{} already exists",
+                config_file.display()
+            )));
+        }
     } else {
-        let mut bof_index = BOFIndex::new();
-        for path in paths {
-            index(&path, &mut bof_index, config)?;
+        if let Some(preset) = preset {
+            preset.apply(config);
+        }
+        if interactive {
+            preset::interactive(config)?;
         }
-        save_index(bof_index, config)
+        fs::write(config_file, settings::commented(config, &load_config()?)?)?;
+        events::emit(Event::Notice(format!(
+            "Wrote configuration to {}",
+            config_file.display()
+        )));
+    }
+
+    let index_dir = config.index_dir();
+    fs::create_dir_all(&index_dir)?;
+    events::emit(Event::Notice(format!(
+        "Initialized .bof directory at: {}",
+        index_dir.display()
+    )));
+    // The repository, whatever profile it was initialized with, `--profile` picks one in it
+    match Registry::add_default(&config.output_dir) {
+        Ok(Some(name)) => events::emit(Event::Notice(format!("Registered repository as {}", name))),
+        Ok(None) => {}
+        Err(e) => events::emit(Event::Error(format!(
+            "Failed to register repository: {}",
+            e
+        ))),
+    }
+    Ok(())
+}
+
+fn is_ignored(path: &Path, is_dir: bool, config: &BOFConfig, rules: &IgnoreRules) -> bool {
+    config.ignore_paths.iter().any(|p| p == path) || rules.is_ignored(path, is_dir)
+}
+
+// Why a file is left out by the include and exclude patterns, min_size or max_size, if it is
+fn file_reason(
+    path: &Path,
+    metadata: &Metadata,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+) -> Option<SkipReason> {
+    let size = metadata.len();
+    if !metadata.is_file() {
+        None
+    } else if rules.is_not_included(path) {
+        Some(SkipReason::NotIncluded)
+    } else if rules.is_excluded(path) {
+        Some(SkipReason::Excluded)
+    } else if size < config.min_size {
+        Some(SkipReason::TooSmall(size))
+    } else if config.max_size != 0 && size > config.max_size {
+        Some(SkipReason::TooLarge(size))
+    } else {
+        None
+    }
+}
+
+// Directories `max_depth` levels below the root, or mounted from another filesystem with
+// `one_file_system`, are left unwalked, so their content stays out
+fn dir_reason(
+    path: &Path,
+    metadata: &Metadata,
+    visited: &Visited,
+    config: &BOFConfig,
+) -> Option<SkipReason> {
+    if !metadata.is_dir() {
+        None
+    } else if config.max_depth != 0 && visited.depth + 1 >= config.max_depth {
+        Some(SkipReason::TooDeep)
+    } else if config.one_file_system
+        && platform::file_id(path, metadata).device != visited.root_device
+    {
+        Some(SkipReason::OtherFileSystem)
+    } else {
+        None
+    }
+}
+
+fn ignore_reason(
+    path: &Path,
+    is_dir: bool,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+) -> Option<SkipReason> {
+    if config.ignore_paths.iter().any(|p| p == path) {
+        return Some(SkipReason::IgnoredPath);
     }
+    if rules.is_trash(path, is_dir) {
+        return Some(SkipReason::Trash);
+    }
+    rules
+        .matching_rule(path, is_dir)
+        .map(|(pattern, source)| SkipReason::IgnoreRule { pattern, source })
 }
 
-fn update_index(path: &Path, bof_index: &mut BOFIndex, config: &BOFConfig) -> io::Result<MetaData> {
+fn index(
+    path: &Path,
+    bof_index: &mut BOFIndex,
+    run: &IndexRun,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    visited: Option<&Visited>,
+) -> io::Result<MetaData> {
     let metadata = fs::metadata(path)?;
     if !metadata.is_dir() {
         return Err(io::Error::new(
@@ -415,25 +1542,41 @@ fn update_index(path: &Path, bof_index: &mut BOFIndex, config: &BOFConfig) -> io
             "Path is not a directory",
         ));
     }
+    // Listings of directories aren't kept, only the entries found in them
+    if let Some(walked) = run.checkpoint.take(path) {
+        bof_index.merge_shards(vec![walked]);
+        return Ok(MetaData::Directory(DirMetaData::new(
+            path,
+            &metadata,
+            Vec::new(),
+        )));
+    }
 
-    if config.ignore_paths.contains(&path.to_path_buf()) {
-        println!("Skipping ignored path: {}", path.display());
-        return Ok(MetaData::Directory(DirMetaData {
-            data: Vec::new(),
-            inode: metadata.ino(),
-        }));
+    if let Some(reason) = ignore_reason(path, true, config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        bof_index.skip(path, reason);
+        return Ok(MetaData::Directory(DirMetaData::new(
+            path,
+            &metadata,
+            Vec::new(),
+        )));
     }
+    let rules = rules.for_dir(path);
+    let visited = Visited::new(path, &metadata, visited);
+    events::emit(Event::Directory(path.to_path_buf()));
 
-    let dir_key = generate_key(path.to_string_lossy().to_string());
-    let mut dir_entries = DirMetaData {
-        data: Vec::new(),
-        inode: metadata.ino(),
-    };
+    let dir_key = generate_key(&path.to_string_lossy(), config);
+    let mut dir_entries = DirMetaData::new(path, &metadata, Vec::new());
+    let skipped_before = bof_index.header.skipped.len();
 
     fs::read_dir(path)?
         .inspect(|entry| {
             if let Err(ref e) = entry {
-                eprintln!("Invalid entry in directory {}: {}", path.display(), e);
+                events::emit(Event::Error(format!(
+                    "Invalid entry in directory {}: {}",
+                    path.display(),
+                    e
+                )));
             }
         })
         .filter_map(|e| e.ok())
@@ -443,70 +1586,240 @@ fn update_index(path: &Path, bof_index: &mut BOFIndex, config: &BOFConfig) -> io
             let metadata = match entry.metadata() {
                 Ok(m) => m,
                 Err(e) => {
-                    eprintln!("Failed to get metadata for {}: {}", path.display(), e);
-                    return;
+                    events::emit(Event::Error(format!(
+                        "Failed to get metadata for {}: {}",
+                        path.display(),
+                        e
+                    )));
+                    return bof_index.skip(&path, SkipReason::Unreadable(e.to_string()));
                 }
             };
 
-            match bof_index.entries.get_mut(&path) {
-                Some(entry) => match &entry.metadata {
-                    MetaData::Directory(_) => {
-                        eprintln!("This entry is a directory! {}", path.display());
-                    }
-                    MetaData::File(file_meta) => {
-                        if file_meta.mtime != metadata.modified().unwrap() {
-                            let key = match fs::read_to_string(&path) {
-                                Ok(content) => generate_key(content + &name),
-                                Err(e) => {
-                                    eprintln!("Failed to read file {}: {}", path.display(), e);
-                                    return;
-                                }
-                            };
-                            bof_index.update_entry(&path, key, &metadata);
-                        }
-                    }
-                },
-                None => {
-                    if metadata.is_file() {
-                        let key = match fs::read_to_string(&path) {
-                            Ok(content) => generate_key(content + &name),
-                            Err(e) => {
-                                eprintln!("Failed to read file {}: {}", path.display(), e);
-                                return;
-                            }
-                        };
-                        let file_meta = bof_index.add_entry(&path, key, &metadata, None);
-                        dir_entries.data.push(DirEntry {
-                            name,
-                            data: file_meta,
-                        });
-                    } else if metadata.is_dir() {
-                        if let Ok(subdir_meta) = update_index(&path, &mut bof_index.clone(), config)
-                        {
-                            dir_entries.data.push(DirEntry {
-                                name,
-                                data: subdir_meta,
-                            });
-                        }
-                    } else {
-                        eprintln!("Neither file nor directory! {}", path.display());
+            if let Some(reason) = ignore_reason(&path, metadata.is_dir(), config, &rules) {
+                events::emit(Event::Ignored(path.to_path_buf()));
+                return bof_index.skip(&path, reason);
+            }
+            let metadata = if let Some(kind) = platform::link_kind(&path, &metadata) {
+                match symlink(&path, kind, &visited, config) {
+                    Link::Follow(metadata) => metadata,
+                    Link::Record(link) => {
+                        report_link(&link, run.previous.get(&path));
+                        let data = bof_index.add_entry_meta(&path, link.key, &link.metadata, None);
+                        dir_entries.data.push(DirEntry { name, data });
                         return;
                     }
+                    Link::Skip(reason) => return bof_index.skip(&path, reason),
                 }
+            } else {
+                metadata
+            };
+
+            if let Some(reason) = file_reason(&path, &metadata, config, &rules)
+                .or_else(|| dir_reason(&path, &metadata, &visited, config))
+            {
+                events::emit(Event::Ignored(path.to_path_buf()));
+                return bof_index.skip(&path, reason);
             }
-        });
-    if let Some(entry) = bof_index.entries.iter().find(|entry| entry.1.path == path) {
-        Ok(entry.1.metadata.clone())
-    } else {
-        Ok(bof_index.add_entry(path, dir_key, &metadata, Some(dir_entries.data)))
+            if metadata.is_file() {
+                let mut file_meta = FileMetaData::new(&path, &metadata, config);
+                let key = match file_key(&path, &mut file_meta, run, config) {
+                    Ok(key) => key,
+                    Err(reason) => return bof_index.skip(&path, reason),
+                };
+                let file_meta = MetaData::File(file_meta);
+                let file_meta = bof_index.add_entry_meta(&path, key, &file_meta, None);
+                dir_entries.data.push(DirEntry {
+                    name,
+                    data: file_meta,
+                });
+            } else if metadata.is_dir() {
+                match index(&path, bof_index, run, config, &rules, Some(&visited)) {
+                    Ok(subdir_meta) => dir_entries.data.push(DirEntry {
+                        name,
+                        data: subdir_meta,
+                    }),
+                    Err(e) => {
+                        events::emit(Event::Error(format!(
+                            "Failed to index directory {}: {}",
+                            path.display(),
+                            e
+                        )));
+                        bof_index.skip(&path, SkipReason::Unreadable(e.to_string()));
+                    }
+                };
+            } else {
+                events::emit(Event::Error(format!(
+                    "Neither file nor directory! {}",
+                    path.display()
+                )));
+                bof_index.skip(&path, SkipReason::Unsupported);
+            }
+        });
+
+    run.checkpoint.record(
+        path,
+        dir_entries
+            .data
+            .iter()
+            .filter(|entry| !matches!(entry.data, MetaData::Directory(_)))
+            .filter_map(|entry| bof_index.entries.get(&path.join(&entry.name)).cloned())
+            .collect(),
+        bof_index.header.skipped[skipped_before..]
+            .iter()
+            .filter(|skipped| skipped.path.parent() == Some(path))
+            .cloned()
+            .collect(),
+    );
+    Ok(bof_index.add_entry(path, dir_key, &metadata, Some(dir_entries.data), config))
+}
+
+// A directory of the parallel walk. It is done once its own files are hashed and its
+// subdirectories are done, which is when it is checkpointed and merged into the index.
+struct Walking {
+    path: PathBuf,
+    parent: Option<Arc<Walking>>,
+    // Files being hashed and subdirectories not done yet, plus one while it is being listed
+    pending: AtomicUsize,
+    // What is right in this directory
+    shard: Mutex<Shard>,
+    // In one of `subtrees`, whose files are hashed by the threads walking it
+    subtree: bool,
+}
+
+impl Walking {
+    fn new(path: &Path, parent: Option<&Arc<Walking>>, subtree: bool) -> Arc<Walking> {
+        if let Some(parent) = parent {
+            parent.pending.fetch_add(1, Ordering::AcqRel);
+        }
+        Arc::new(Walking {
+            path: path.to_path_buf(),
+            parent: parent.cloned(),
+            pending: AtomicUsize::new(1),
+            shard: Mutex::new(Shard::default()),
+            subtree: subtree || parent.is_some_and(|parent| parent.subtree),
+        })
+    }
+
+    fn skip(&self, path: &Path, reason: SkipReason) {
+        self.shard.lock().unwrap().skip(path, reason);
     }
 }
 
-fn update_index_parallel(
-    path: &Path,
-    bof_index: Arc<Mutex<BOFIndex>>,
+// A file found by the walk, waiting in the queue to be hashed
+struct Found {
+    path: PathBuf,
+    metadata: Metadata,
+    dir: Arc<Walking>,
+}
+
+// What the walking threads and the hashing pool share
+struct Pipeline<'a> {
+    run: &'a IndexRun<'a>,
+    config: &'a BOFConfig,
+    done: Mutex<Vec<Shard>>,
+}
+
+impl Pipeline<'_> {
+    fn hash(&self, found: Found) {
+        let mut file_meta = FileMetaData::new(&found.path, &found.metadata, self.config);
+        match file_key(&found.path, &mut file_meta, self.run, self.config) {
+            Ok(key) => found.dir.shard.lock().unwrap().entries.push(BOFEntry {
+                key,
+                path: found.path,
+                metadata: MetaData::File(file_meta),
+                labels: Vec::new(),
+            }),
+            Err(reason) => found.dir.skip(&found.path, reason),
+        }
+        self.finish(&found.dir);
+    }
+
+    // One thing `dir` waited for is done, and with it maybe `dir` and its parents
+    fn finish(&self, dir: &Arc<Walking>) {
+        let mut dir = dir;
+        while dir.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let shard = std::mem::take(&mut *dir.shard.lock().unwrap());
+            self.run
+                .checkpoint
+                .record(&dir.path, shard.entries.clone(), shard.skipped.clone());
+            self.done.lock().unwrap().push(shard);
+            match &dir.parent {
+                Some(parent) => dir = parent,
+                None => return,
+            }
+        }
+    }
+
+    // A directory that won't be walked belongs to its parent's checkpoint
+    fn skip_dir(&self, parent: Option<&Arc<Walking>>, path: &Path, reason: SkipReason) {
+        match parent {
+            Some(parent) => parent.skip(path, reason),
+            None => {
+                let mut shard = Shard::default();
+                shard.skip(path, reason);
+                self.done.lock().unwrap().push(shard);
+            }
+        }
+    }
+}
+
+// Walks `paths` on WALK_THREADS threads of their own, fewer with `threads`, which queue the files they find for the
+// rayon pool to hash, so a huge file never holds up the walk and a huge directory is hashed by
+// every thread. The queue is bounded: the walk waits when hashing falls behind.
+fn index_pipeline(
+    paths: &[PathBuf],
+    run: &IndexRun,
     config: &BOFConfig,
-) -> io::Result<MetaData> {
+    rules: &IgnoreRules,
+) -> Result<Vec<Shard>> {
+    let walkers = rayon::ThreadPoolBuilder::new()
+        .num_threads(match config.threads {
+            0 => WALK_THREADS,
+            threads => threads.min(WALK_THREADS),
+        })
+        .thread_name(|i| format!("bof-walk-{}", i))
+        .build()
+        .map_err(io::Error::other)?;
+    let (files, found) = mpsc::sync_channel(HASH_QUEUE);
+    let pipeline = Pipeline {
+        run,
+        config,
+        done: Mutex::new(Vec::new()),
+    };
+    thread::scope(|scope| {
+        let pipeline = &pipeline;
+        // The queue closes once the walk is over and the sender dropped
+        scope.spawn(move || {
+            walkers.install(|| {
+                paths.par_iter().for_each(|path| {
+                    if let Err(e) = walk_dir(path, pipeline, &files, rules, None, None) {
+                        events::emit(Event::Error(format!(
+                            "Error indexing directory {}: {}",
+                            path.display(),
+                            e
+                        )));
+                    }
+                })
+            })
+        });
+        found
+            .into_iter()
+            .par_bridge()
+            .for_each(|found| pipeline.hash(found));
+    });
+    Ok(pipeline.done.into_inner().unwrap())
+}
+
+// Lists `path` and queues its files, walking its subdirectories in parallel
+fn walk_dir(
+    path: &Path,
+    pipeline: &Pipeline,
+    files: &mpsc::SyncSender<Found>,
+    rules: &IgnoreRules,
+    visited: Option<&Visited>,
+    parent: Option<&Arc<Walking>>,
+) -> io::Result<()> {
+    let (run, config) = (pipeline.run, pipeline.config);
     let metadata = fs::metadata(path)?;
     if !metadata.is_dir() {
         return Err(io::Error::new(
@@ -514,181 +1827,3161 @@ fn update_index_parallel(
             "Path is not a directory",
         ));
     }
-
-    if config.ignore_paths.contains(&path.to_path_buf()) {
-        println!("Skipping ignored path: {}", path.display());
-        return Ok(MetaData::Directory(DirMetaData {
-            data: Vec::new(),
-            inode: metadata.ino(),
-        }));
+    // Finished with its whole subtree by the interrupted run
+    if let Some(walked) = run.checkpoint.take(path) {
+        pipeline.done.lock().unwrap().push(walked);
+        return Ok(());
     }
 
-    let dir_key = generate_key(path.to_string_lossy().to_string());
-    let queue = crossbeam_queue::SegQueue::new();
+    if let Some(reason) = ignore_reason(path, true, config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        pipeline.skip_dir(parent, path, reason);
+        return Ok(());
+    }
+    let rules = rules.for_dir(path);
+    let visited = Visited::new(path, &metadata, visited);
+    events::emit(Event::Directory(path.to_path_buf()));
+    let subtree = config.subtrees.get(path);
+    let dir = Walking::new(path, parent, subtree.is_some());
 
-    let entries = fs::read_dir(path)?
-        .inspect(|entry| {
-            if let Err(ref e) = entry {
-                eprintln!("Invalid entry in directory {}: {}", path.display(), e);
+    let list = || {
+        fs::read_dir(path).map(|entries| {
+            let mut entries = entries
+                .inspect(|entry| {
+                    if let Err(ref e) = entry {
+                        events::emit(Event::Error(format!(
+                            "Invalid entry in directory {}: {}",
+                            path.display(),
+                            e
+                        )));
+                    }
+                })
+                .filter_map(|e| e.ok());
+            let mut batch = Vec::with_capacity(READ_DIR_BATCH);
+            loop {
+                batch.extend(entries.by_ref().take(READ_DIR_BATCH));
+                if batch.is_empty() {
+                    break;
+                }
+                batch
+                    .par_drain(..)
+                    .for_each(|entry| walk_entry(&entry, pipeline, files, &rules, &visited, &dir));
             }
         })
-        .filter_map(|e| e.ok())
-        .collect::<Vec<_>>();
-    dbg!(&path);
-    entries.par_iter().for_each(|entry| {
-        let name = entry.file_name().to_string_lossy().to_string();
-        let path = entry.path();
-        let metadata = match entry.metadata() {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!("Failed to get metadata for {}: {}", path.display(), e);
+    };
+    // Everything below is walked within the pool of the subtree, on as many threads as it allows
+    let listed = match subtree {
+        Some(subtree) => rayon::ThreadPoolBuilder::new()
+            .num_threads(subtree.threads.max(1))
+            .thread_name(|i| format!("bof-subtree-{}", i))
+            .build()
+            .map_err(io::Error::other)
+            .and_then(|pool| pool.install(list)),
+        None => list(),
+    };
+    // Its parent waits for it, whether it could be listed or not
+    pipeline.finish(&dir);
+    listed
+}
+
+fn walk_entry(
+    entry: &fs::DirEntry,
+    pipeline: &Pipeline,
+    files: &mpsc::SyncSender<Found>,
+    rules: &IgnoreRules,
+    visited: &Visited,
+    dir: &Arc<Walking>,
+) {
+    let (run, config) = (pipeline.run, pipeline.config);
+    let path = entry.path();
+    let metadata = match entry.metadata() {
+        Ok(m) => m,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to get metadata for {}: {}",
+                path.display(),
+                e
+            )));
+            return dir.skip(&path, SkipReason::Unreadable(e.to_string()));
+        }
+    };
+
+    if let Some(reason) = ignore_reason(&path, metadata.is_dir(), config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        return dir.skip(&path, reason);
+    }
+    let metadata = if let Some(kind) = platform::link_kind(&path, &metadata) {
+        match symlink(&path, kind, visited, config) {
+            Link::Follow(metadata) => metadata,
+            Link::Record(link) => {
+                report_link(&link, run.previous.get(&path));
+                dir.shard.lock().unwrap().entries.push(link);
                 return;
             }
+            Link::Skip(reason) => return dir.skip(&path, reason),
+        }
+    } else {
+        metadata
+    };
+
+    if let Some(reason) = file_reason(&path, &metadata, config, rules)
+        .or_else(|| dir_reason(&path, &metadata, visited, config))
+    {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        return dir.skip(&path, reason);
+    }
+    if metadata.is_file() {
+        dir.pending.fetch_add(1, Ordering::AcqRel);
+        let found = Found {
+            path,
+            metadata,
+            dir: dir.clone(),
         };
+        if dir.subtree {
+            pipeline.hash(found);
+        } else {
+            // Only closed when hashing failed altogether, nothing left to wait for then
+            let _ = files.send(found);
+        }
+    } else if metadata.is_dir() {
+        if let Err(e) = walk_dir(&path, pipeline, files, rules, Some(visited), Some(dir)) {
+            events::emit(Event::Error(format!(
+                "Failed to index directory {}: {}",
+                path.display(),
+                e
+            )));
+            dir.skip(&path, SkipReason::Unreadable(e.to_string()));
+        }
+    } else {
+        events::emit(Event::Error(format!(
+            "Neither file nor directory! {}",
+            path.display()
+        )));
+        dir.skip(&path, SkipReason::Unsupported);
+    }
+}
 
-        let mut index_lock = bof_index.lock().unwrap();
-        match index_lock.entries.get_mut(&path) {
-            Some(entry) => match &entry.metadata {
-                MetaData::Directory(_) => {
-                    eprintln!("This entry is a directory! {}", path.display());
-                    return;
-                }
-                MetaData::File(file_meta) => {
-                    if file_meta.mtime != metadata.modified().unwrap() {
-                        let key = match fs::read_to_string(&path) {
-                            Ok(content) => generate_key(content + &name),
-                            Err(e) => {
-                                eprintln!("Failed to read file {}: {}", path.display(), e);
-                                return;
-                            }
-                        };
+// Visits the entries of a directory in parallel, each worker filling its own listing and shard,
+// which are concatenated at the end. The listing is sorted by name.
+// Entries are read in bounded batches so huge directories are never held in memory all at once.
+fn walk_parallel<F>(path: &Path, visit: F) -> io::Result<(Vec<DirEntry>, Shard)>
+where
+    F: Fn(&fs::DirEntry, &mut Vec<DirEntry>, &mut Shard) + Sync + Send,
+{
+    let mut entries = fs::read_dir(path)?
+        .inspect(|entry| {
+            if let Err(ref e) = entry {
+                events::emit(Event::Error(format!(
+                    "Invalid entry in directory {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        })
+        .filter_map(|e| e.ok());
 
-                        index_lock.update_entry(&path, key, &metadata);
-                    }
-                }
-            },
-            None => {
-                if metadata.is_file() {
-                    let key = match fs::read_to_string(&path) {
-                        Ok(content) => generate_key(content + &name),
-                        Err(e) => {
-                            eprintln!("Failed to read file {}: {}", path.display(), e);
-                            return;
-                        }
-                    };
-                    let file_meta = FileMetaData::from(&metadata);
-                    queue.push(QueueItem::DirEntry(DirEntry {
-                        name,
-                        data: MetaData::File(file_meta.clone()),
-                    }));
+    let mut dir_entries = Vec::new();
+    let mut shard = Shard::default();
+    let mut batch = Vec::with_capacity(READ_DIR_BATCH);
+    loop {
+        batch.extend(entries.by_ref().take(READ_DIR_BATCH));
+        if batch.is_empty() {
+            break;
+        }
+        let (more_dir_entries, more) = batch
+            .par_drain(..)
+            .fold(
+                || (Vec::new(), Shard::default()),
+                |(mut dir_entries, mut shard), entry| {
+                    visit(&entry, &mut dir_entries, &mut shard);
+                    (dir_entries, shard)
+                },
+            )
+            .reduce(
+                || (Vec::new(), Shard::default()),
+                |(mut dir_entries, mut shard), (more_dir_entries, more)| {
+                    dir_entries.extend(more_dir_entries);
+                    shard.extend(more);
+                    (dir_entries, shard)
+                },
+            );
+        dir_entries.extend(more_dir_entries);
+        shard.extend(more);
+    }
+    dir_entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((dir_entries, shard))
+}
 
-                    let bof_entry = BOFEntry {
-                        key,
-                        path: path.clone(),
-                        metadata: MetaData::File(file_meta),
-                    };
-                    queue.push(QueueItem::BOFEntry(bof_entry));
-                } else if metadata.is_dir() {
-                    if let Ok(subdir_meta) = update_index_parallel(&path, bof_index.clone(), config)
-                    {
-                        queue.push(QueueItem::DirEntry(DirEntry {
-                            name,
-                            data: subdir_meta,
-                        }));
-                    }
-                } else {
-                    eprintln!("Neither file nor directory! {}", path.display());
-                    return;
-                }
+// Reuses the key of a previous run, or else of the hash cache, when the file still has the same
+// size, mtime and inode, otherwise hashes it. Reports the file either way, and why if it couldn't
+// be read.
+fn file_key(
+    path: &Path,
+    file_meta: &mut FileMetaData,
+    run: &IndexRun,
+    config: &BOFConfig,
+) -> std::result::Result<String, SkipReason> {
+    let identity = Identity {
+        device: file_meta.device,
+        inode: file_meta.inode,
+        size: file_meta.size,
+        mtime: file_meta.mtime,
+    };
+    if let Some(BOFEntry {
+        key,
+        metadata: MetaData::File(old),
+        ..
+    }) = run.previous.get(path)
+    {
+        if old.size == file_meta.size
+            && old.mtime == file_meta.mtime
+            && old.inode == file_meta.inode
+            && !hashes_changed(old, config)
+        {
+            events::emit(Event::Unchanged(path.to_path_buf()));
+            file_meta.hashes = old.hashes.clone();
+            if let Some(cache) = run.cache.filter(|_| key != UNHASHED) {
+                cache.insert(identity, key, &old.hashes);
             }
+            return Ok(key.clone());
         }
-    });
+    }
+    if let Some((key, hashes)) = run.cache.and_then(|cache| cache.get(&identity)) {
+        file_meta.hashes = hashes;
+        if !hashes_changed(file_meta, config) {
+            events::emit(Event::Unchanged(path.to_path_buf()));
+            return Ok(key);
+        }
+    }
 
-    let mut index_lock = bof_index.lock().unwrap();
-    let mut dir_entries = Vec::new();
+    match content_key(path, file_meta.size, config) {
+        Ok((key, hashes)) => {
+            events::emit(Event::Indexed {
+                path: path.to_path_buf(),
+                size: file_meta.size,
+            });
+            if let Some(cache) = run.cache.filter(|_| key != UNHASHED) {
+                cache.insert(identity, &key, &hashes);
+            }
+            file_meta.hashes = hashes;
+            Ok(key)
+        }
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to read file {}: {}",
+                path.display(),
+                e
+            )));
+            Err(SkipReason::Unreadable(e.to_string()))
+        }
+    }
+}
+
+// Counts files and bytes under `path` without reading any content
+fn prescan(
+    path: &Path,
+    visited: &Visited,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    stats: &mut RunStats,
+) {
+    let rules = rules.for_dir(path);
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if is_ignored(&path, metadata.is_dir(), config, &rules) {
+            continue;
+        }
+        if metadata.is_file() {
+            stats.files += 1;
+            stats.bytes += metadata.len();
+        } else if metadata.is_dir() && dir_reason(&path, &metadata, visited, config).is_none() {
+            let visited = Visited::new(&path, &metadata, Some(visited));
+            prescan(&path, &visited, config, &rules, stats);
+        }
+    }
+}
+
+// Regular files under `path` with their metadata, without reading any content or following links
+pub(crate) fn scan_files(
+    path: &Path,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    files: &mut Vec<(PathBuf, Metadata)>,
+) {
+    if is_ignored(path, true, config, rules) {
+        return;
+    }
+    let rules = rules.for_dir(path);
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            )));
+            return;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if is_ignored(&path, metadata.is_dir(), config, &rules)
+            || file_reason(&path, &metadata, config, &rules).is_some()
+        {
+            continue;
+        }
+        if metadata.is_file() {
+            files.push((path, metadata));
+        } else if metadata.is_dir() {
+            scan_files(&path, config, &rules, files);
+        }
+    }
+}
 
-    while let Some(item) = queue.pop() {
-        match item {
-            QueueItem::DirEntry(entry) => dir_entries.push(entry),
-            QueueItem::BOFEntry(bof_entry) => {
-                index_lock.add_entry_meta(
-                    &bof_entry.path,
-                    bof_entry.key,
-                    &bof_entry.metadata,
-                    None,
+// Announces how much work to expect, from a pre-scan if enabled or else from the previous run
+fn estimate(paths: &[PathBuf], config: &BOFConfig, previous: Option<RunStats>) {
+    let stats = if config.estimate {
+        let mut stats = RunStats::default();
+        let rules = IgnoreRules::new(config);
+        for path in paths {
+            if is_ignored(path, true, config, &rules) {
+                continue;
+            }
+            if let Ok(metadata) = fs::metadata(path) {
+                prescan(
+                    path,
+                    &Visited::new(path, &metadata, None),
+                    config,
+                    &rules,
+                    &mut stats,
                 );
             }
         }
+        Some(stats)
+    } else {
+        previous
+    };
+
+    if let Some(stats) = stats {
+        events::emit(Event::Estimate {
+            files: stats.files,
+            bytes: stats.bytes,
+        });
     }
+}
+
+const INDEX_CHECKPOINT_FILE: &str = "index.checkpoint";
+// The checkpoint is brought to disk once this many files were indexed since the last time, or
+// this many seconds went by, unless checkpoint_files or checkpoint_secs say otherwise
+const CHECKPOINT_FILES: u64 = 10_000;
+const CHECKPOINT_SECS: u64 = 60;
 
-    let meta_data = {
-        let mut index = bof_index.lock().unwrap();
-        if let Some(entry) = index.entries.iter().find(|entry| entry.1.path == path) {
-            entry.1.metadata.clone()
+// First line of a checkpoint, telling which run it belongs to
+#[derive(Deserialize, PartialEq, Serialize)]
+struct CheckpointHeader {
+    paths: Vec<PathBuf>,
+    hash_algorithm: HashAlgorithm,
+}
+
+// A directory whose whole subtree was walked: its own files and links, and what was left out of it
+#[derive(Deserialize, Serialize)]
+struct WalkedDir {
+    path: PathBuf,
+    entries: Vec<BOFEntry>,
+    skipped: Vec<Skipped>,
+}
+
+// Directories `bof index` finished, a line each appended to .bof/index.checkpoint, so
+// `index --resume` doesn't walk them again after an interruption. A directory is only written
+// once everything below it was, so one found there stands for its whole subtree.
+struct Checkpoint {
+    path: PathBuf,
+    writer: Mutex<CheckpointWriter>,
+    // Finished by the interrupted run, taken as the walk reaches them
+    resumed: Mutex<BTreeMap<PathBuf, WalkedDir>>,
+    files_every: u64,
+    every: Duration,
+}
+
+struct CheckpointWriter {
+    // None once writing failed, the run goes on without checkpoints
+    file: Option<BufWriter<File>>,
+    files: u64,
+    synced: Instant,
+}
+
+impl Checkpoint {
+    fn start(paths: &[PathBuf], resume: bool, config: &BOFConfig) -> Result<Checkpoint> {
+        let path = config.output_dir.join(INDEX_CHECKPOINT_FILE);
+        let header = CheckpointHeader {
+            paths: paths.to_vec(),
+            hash_algorithm: config.hash_algorithm,
+        };
+        let (resumed, kept) = if resume {
+            Checkpoint::load(&path, &header)?
         } else {
-            index.add_entry(path, dir_key, &metadata, Some(dir_entries))
+            (BTreeMap::new(), 0)
+        };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        // A line cut short by the interruption goes
+        file.set_len(kept)?;
+        io::Seek::seek(&mut file, io::SeekFrom::End(0))?;
+        let mut file = BufWriter::new(file);
+        if kept == 0 {
+            serde_json::to_writer(&mut file, &header).map_err(BofError::serialization)?;
+            io::Write::write_all(&mut file, b"\n")?;
         }
-    };
 
-    Ok(meta_data)
-}
+        let or_default = |value: u64, default: u64| if value == 0 { default } else { value };
+        Ok(Checkpoint {
+            path,
+            writer: Mutex::new(CheckpointWriter {
+                file: Some(file),
+                files: 0,
+                synced: Instant::now(),
+            }),
+            resumed: Mutex::new(resumed),
+            files_every: or_default(config.checkpoint_files, CHECKPOINT_FILES),
+            every: Duration::from_secs(or_default(config.checkpoint_secs, CHECKPOINT_SECS)),
+        })
+    }
+
+    // The directories an interrupted run over the same paths finished, and how many bytes of
+    // the checkpoint hold them
+    fn load(path: &Path, header: &CheckpointHeader) -> Result<(BTreeMap<PathBuf, WalkedDir>, u64)> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                events::emit(Event::Notice(
+                    "No interrupted index to resume, starting over".to_string(),
+                ));
+                return Ok((BTreeMap::new(), 0));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = io::BufReader::new(file);
+        let mut line = Vec::new();
+        io::BufRead::read_until(&mut lines, b'\n', &mut line)?;
+        if serde_json::from_slice::<CheckpointHeader>(&line)
+            .ok()
+            .as_ref()
+            != Some(header)
+        {
+            events::emit(Event::Notice(
+                "Interrupted index was for other paths, starting over".to_string(),
+            ));
+            return Ok((BTreeMap::new(), 0));
+        }
+        let mut kept = line.len() as u64;
+        let mut walked = BTreeMap::new();
+        loop {
+            line.clear();
+            if io::BufRead::read_until(&mut lines, b'\n', &mut line)? == 0 {
+                break;
+            }
+            let Some(dir) = line
+                .strip_suffix(b"\n")
+                .and_then(|line| serde_json::from_slice::<WalkedDir>(line).ok())
+            else {
+                break;
+            };
+            kept += line.len() as u64;
+            walked.insert(dir.path.clone(), dir);
+        }
+        events::emit(Event::Notice(format!(
+            "Resuming index, {} directories were already walked",
+            walked.len()
+        )));
+        Ok((walked, kept))
+    }
 
-pub(crate) fn update_directories(paths: Vec<PathBuf>, config: &BOFConfig) -> io::Result<()> {
-    let mut existing_indices = load_indices(&config.output_dir)?;
+    // Everything the interrupted run found under `path` if it finished it, for the walk to skip it
+    fn take(&self, path: &Path) -> Option<Shard> {
+        let mut resumed = self.resumed.lock().unwrap();
+        if !resumed.contains_key(path) {
+            return None;
+        }
+        // In path order, a directory comes right before everything under it
+        let dirs = resumed
+            .range(path.to_path_buf()..)
+            .map(|(dir, _)| dir)
+            .take_while(|dir| dir.starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut shard = Shard::default();
+        for dir in dirs.iter().filter_map(|dir| resumed.remove(dir)) {
+            shard.entries.extend(dir.entries);
+            shard.skipped.extend(dir.skipped);
+        }
+        Some(shard)
+    }
 
-    if config.parallel {
-        paths.par_iter().for_each(|path| {
-            if let Err(e) =
-                update_index_parallel(path, Arc::new(Mutex::new(existing_indices.clone())), config)
-            {
-                eprintln!("Error updating directory {}: {}", path.display(), e);
+    // Records that `path` was walked with everything below it
+    fn record(&self, path: &Path, entries: Vec<BOFEntry>, skipped: Vec<Skipped>) {
+        let mut writer = self.writer.lock().unwrap();
+        let dir = WalkedDir {
+            path: path.to_path_buf(),
+            entries,
+            skipped,
+        };
+        writer.files += dir.entries.len() as u64;
+        let due = writer.files >= self.files_every || writer.synced.elapsed() >= self.every;
+        let Some(file) = &mut writer.file else {
+            return;
+        };
+        let written = serde_json::to_writer(&mut *file, &dir)
+            .map_err(io::Error::other)
+            .and_then(|_| io::Write::write_all(file, b"\n"))
+            .and_then(|_| {
+                if due {
+                    io::Write::flush(file)?;
+                    file.get_ref().sync_data()?;
+                }
+                Ok(())
+            });
+        match written {
+            Ok(()) if due => {
+                writer.files = 0;
+                writer.synced = Instant::now();
             }
-        });
-        save_index(existing_indices, config)
-    } else {
-        let mut bof_indices = Vec::new();
-        for path in paths {
-            update_index(&path, &mut existing_indices, config)?;
-            bof_indices.push(existing_indices.clone());
+            Ok(()) => {}
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to write {}, indexing goes on without checkpoints: {}",
+                    self.path.display(),
+                    e
+                )));
+                writer.file = None;
+            }
+        }
+    }
+
+    // The index was saved, nothing is left to resume
+    fn finish(self) -> Result<()> {
+        drop(self.writer);
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
-        save_index(existing_indices, config)
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct IntBOFIndex {
-    entries: Vec<BOFEntry>,
-    inverse_table: HashMap<String, Vec<PathBuf>>,
+// What every directory of an index walk reads: the previous index and the hash cache, whose keys
+// are reused for unchanged files, and the checkpoint
+struct IndexRun<'a> {
+    previous: &'a HashMap<PathBuf, BOFEntry>,
+    cache: Option<&'a HashCache>,
+    checkpoint: &'a Checkpoint,
 }
 
-pub(crate) fn save_index(bof_indices: BOFIndex, config: &BOFConfig) -> io::Result<()> {
-    let file = File::create(config.output_dir.join(PathBuf::from("index.json")))?;
-    serde_json::to_writer_pretty(
-        file,
-        &IntBOFIndex {
-            entries: bof_indices.entries.values().cloned().collect::<Vec<_>>(),
-            inverse_table: bof_indices.inverse_table,
-        },
-    )?;
-    println!("BOF saved to {}/index.json", config.output_dir.display());
+// Sizes the rayon pool by `threads`, bounds the files read at once by `open_files`, keeps them
+// out of the page cache with `cache_friendly` and, with `background`, lowers the priority of the
+// threads started from now on and how fast they read. The pool can only be sized once in a
+// process, before it is first used.
+pub(crate) fn configure_process(config: &BOFConfig) {
+    hash::limit_open_files(config.open_files);
+    hash::spare_page_cache(config.cache_friendly);
+    if config.background {
+        if let Err(e) = platform::lower_priority() {
+            events::emit(Event::Error(format!("Failed to lower the priority: {}", e)));
+        }
+        let rate = match config.background_read_rate {
+            0 => BACKGROUND_READ_RATE,
+            rate => rate,
+        };
+        hash::limit_read_rate(rate * 1024 * 1024);
+    }
+    if config.threads > 0 {
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .build_global();
+    }
+}
 
-    Ok(())
+// With `resume`, directories an interrupted run over the same paths finished aren't walked again
+pub fn index_directories(paths: Vec<PathBuf>, resume: bool, config: &BOFConfig) -> Result<()> {
+    let started = Instant::now();
+    let previous = load_indices(&config.output_dir, config.index_name()).ok();
+    estimate(
+        &paths,
+        config,
+        previous.as_ref().and_then(|index| index.header.last_run),
+    );
+    // Keys made with another hash algorithm can't be reused
+    let previous = previous
+        .filter(|index| index.header.hash_algorithm == Some(config.hash_algorithm))
+        .map(|index| index.entries)
+        .unwrap_or_default();
+
+    let mut bof_index = BOFIndex::new();
+    bof_index.header.hash_algorithm = Some(config.hash_algorithm);
+    let rules = IgnoreRules::new(config);
+    let checkpoint = Checkpoint::start(&paths, resume, config)?;
+    let cache = config
+        .hash_cache
+        .then(|| HashCache::load(&config.output_dir, config.hash_algorithm));
+    let run = IndexRun {
+        previous: &previous,
+        cache: cache.as_ref(),
+        checkpoint: &checkpoint,
+    };
+
+    if config.parallel {
+        bof_index.merge_shards(index_pipeline(&paths, &run, config, &rules)?);
+    } else {
+        for path in &paths {
+            index(path, &mut bof_index, &run, config, &rules, None)
+                .map_err(BofError::traversal(path))?;
+        }
+    }
+    bof_index.record_run(started, &paths);
+    save_index(bof_index, config)?;
+    if let Err(e) = cache.as_ref().map_or(Ok(()), HashCache::save) {
+        events::emit(Event::Error(format!(
+            "Failed to save the hash cache: {}",
+            e
+        )));
+    }
+    checkpoint.finish()
 }
 
-pub fn load_indices(output_dir: &Path) -> io::Result<BOFIndex> {
-    let path = output_dir.join("index.json");
-    let file = File::open(path)?;
+fn update_index(
+    path: &Path,
+    bof_index: &mut BOFIndex,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    visited: Option<&Visited>,
+) -> io::Result<MetaData> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Path is not a directory",
+        ));
+    }
 
-    let entries: IntBOFIndex = serde_json::from_reader(file)?;
+    if let Some(reason) = ignore_reason(path, true, config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        bof_index.skip(path, reason);
+        return Ok(MetaData::Directory(DirMetaData::new(
+            path,
+            &metadata,
+            Vec::new(),
+        )));
+    }
+    let rules = rules.for_dir(path);
+    let visited = Visited::new(path, &metadata, visited);
+    events::emit(Event::Directory(path.to_path_buf()));
 
-    let entries_map: HashMap<PathBuf, BOFEntry> = entries
-        .entries
-        .into_iter()
-        .map(|entry| (entry.path.clone(), entry))
-        .collect();
+    let dir_key = generate_key(&path.to_string_lossy(), config);
+    let mut dir_entries = DirMetaData::new(path, &metadata, Vec::new());
 
-    Ok(BOFIndex {
-        entries: entries_map,
-        inverse_table: entries.inverse_table,
-    })
+    fs::read_dir(path)?
+        .inspect(|entry| {
+            if let Err(ref e) = entry {
+                events::emit(Event::Error(format!(
+                    "Invalid entry in directory {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        })
+        .filter_map(|e| e.ok())
+        .for_each(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(m) => m,
+                Err(e) => {
+                    events::emit(Event::Error(format!(
+                        "Failed to get metadata for {}: {}",
+                        path.display(),
+                        e
+                    )));
+                    return bof_index.skip(&path, SkipReason::Unreadable(e.to_string()));
+                }
+            };
+
+            if let Some(reason) = ignore_reason(&path, metadata.is_dir(), config, &rules) {
+                events::emit(Event::Ignored(path.to_path_buf()));
+                return bof_index.skip(&path, reason);
+            }
+            let metadata = if let Some(kind) = platform::link_kind(&path, &metadata) {
+                match symlink(&path, kind, &visited, config) {
+                    Link::Follow(metadata) => metadata,
+                    Link::Record(link) => {
+                        report_link(&link, bof_index.entries.get(&path));
+                        bof_index.remove_entry(&path);
+                        let data = bof_index.add_entry_meta(&path, link.key, &link.metadata, None);
+                        dir_entries.data.push(DirEntry { name, data });
+                        return;
+                    }
+                    Link::Skip(reason) => return bof_index.skip(&path, reason),
+                }
+            } else {
+                metadata
+            };
+
+            if let Some(reason) = file_reason(&path, &metadata, config, &rules) {
+                events::emit(Event::Ignored(path.to_path_buf()));
+                bof_index.remove_entry(&path);
+                return bof_index.skip(&path, reason);
+            }
+            // What was indexed below stays as it was
+            if let Some(reason) = dir_reason(&path, &metadata, &visited, config) {
+                events::emit(Event::Ignored(path.to_path_buf()));
+                return bof_index.skip(&path, reason);
+            }
+            match bof_index.entries.get_mut(&path) {
+                Some(entry) => match &entry.metadata {
+                    MetaData::Directory(_) => {
+                        events::emit(Event::Error(format!(
+                            "This entry is a directory! {}",
+                            path.display()
+                        )));
+                    }
+                    MetaData::Link(_) => {
+                        events::emit(Event::Error(format!(
+                            "This entry is a symlink! {}",
+                            path.display()
+                        )));
+                    }
+                    MetaData::File(file_meta) => {
+                        if bof_index.rehash || needs_rehash(file_meta, &path, &metadata, config) {
+                            let digests = match content_key(&path, metadata.len(), config) {
+                                Ok(digests) => digests,
+                                Err(e) => {
+                                    events::emit(Event::Error(format!(
+                                        "Failed to read file {}: {}",
+                                        path.display(),
+                                        e
+                                    )));
+                                    return;
+                                }
+                            };
+                            bof_index.update_entry(&path, digests, &metadata, config);
+                        } else {
+                            events::emit(Event::Unchanged(path.clone()));
+                        }
+                    }
+                },
+                None => {
+                    if metadata.is_file() {
+                        let (key, hashes) = match content_key(&path, metadata.len(), config) {
+                            Ok(digests) => digests,
+                            Err(e) => {
+                                events::emit(Event::Error(format!(
+                                    "Failed to read file {}: {}",
+                                    path.display(),
+                                    e
+                                )));
+                                return bof_index
+                                    .skip(&path, SkipReason::Unreadable(e.to_string()));
+                            }
+                        };
+                        let file_meta = FileMetaData {
+                            hashes,
+                            ..FileMetaData::new(&path, &metadata, config)
+                        };
+                        match bof_index.moved_from(&key, &file_meta) {
+                            Some(from) => {
+                                bof_index.remove_entry(&from);
+                                events::emit(Event::Renamed {
+                                    from,
+                                    to: path.clone(),
+                                });
+                            }
+                            None => events::emit(Event::Indexed {
+                                path: path.clone(),
+                                size: metadata.len(),
+                            }),
+                        }
+                        let file_meta =
+                            bof_index.add_entry_meta(&path, key, &MetaData::File(file_meta), None);
+                        dir_entries.data.push(DirEntry {
+                            name,
+                            data: file_meta,
+                        });
+                    } else if metadata.is_dir() {
+                        match update_index(&path, bof_index, config, &rules, Some(&visited)) {
+                            Ok(subdir_meta) => dir_entries.data.push(DirEntry {
+                                name,
+                                data: subdir_meta,
+                            }),
+                            Err(e) => bof_index.skip(&path, SkipReason::Unreadable(e.to_string())),
+                        }
+                    } else {
+                        events::emit(Event::Error(format!(
+                            "Neither file nor directory! {}",
+                            path.display()
+                        )));
+                        bof_index.skip(&path, SkipReason::Unsupported);
+                    }
+                }
+            }
+        });
+    if let Some(entry) = bof_index.entries.iter().find(|entry| entry.1.path == path) {
+        Ok(entry.1.metadata.clone())
+    } else {
+        Ok(bof_index.add_entry(path, dir_key, &metadata, Some(dir_entries.data), config))
+    }
+}
+
+// Walks like walk_dir but hashes as it goes, compares against the loaded index without modifying it and hands
+// the differences back, so no lock is ever held while recursing into subdirectories
+fn update_index_parallel(
+    path: &Path,
+    bof_index: &BOFIndex,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    visited: Option<&Visited>,
+) -> io::Result<(MetaData, Shard)> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Path is not a directory",
+        ));
+    }
+
+    if let Some(reason) = ignore_reason(path, true, config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        let mut shard = Shard::default();
+        shard.skip(path, reason);
+        return Ok((
+            MetaData::Directory(DirMetaData::new(path, &metadata, Vec::new())),
+            shard,
+        ));
+    }
+    let rules = rules.for_dir(path);
+    let visited = Visited::new(path, &metadata, visited);
+    events::emit(Event::Directory(path.to_path_buf()));
+
+    let (dir_entries, shard) = walk_parallel(path, |entry, dir_entries, shard| {
+        update_entry_parallel(
+            entry,
+            bof_index,
+            config,
+            &rules,
+            &visited,
+            dir_entries,
+            shard,
+        )
+    })?;
+
+    Ok((
+        MetaData::Directory(DirMetaData::new(path, &metadata, dir_entries)),
+        shard,
+    ))
+}
+
+fn update_entry_parallel(
+    entry: &fs::DirEntry,
+    bof_index: &BOFIndex,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    visited: &Visited,
+    dir_entries: &mut Vec<DirEntry>,
+    shard: &mut Shard,
+) {
+    let name = entry.file_name().to_string_lossy().to_string();
+    let path = entry.path();
+    let metadata = match entry.metadata() {
+        Ok(m) => m,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to get metadata for {}: {}",
+                path.display(),
+                e
+            )));
+            return shard.skip(&path, SkipReason::Unreadable(e.to_string()));
+        }
+    };
+
+    if let Some(reason) = ignore_reason(&path, metadata.is_dir(), config, rules) {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        return shard.skip(&path, reason);
+    }
+    let metadata = if let Some(kind) = platform::link_kind(&path, &metadata) {
+        match symlink(&path, kind, visited, config) {
+            Link::Follow(metadata) => metadata,
+            Link::Record(link) => {
+                report_link(&link, bof_index.entries.get(&path));
+                dir_entries.push(DirEntry {
+                    name,
+                    data: link.metadata.clone(),
+                });
+                shard.entries.push(link);
+                return;
+            }
+            Link::Skip(reason) => return shard.skip(&path, reason),
+        }
+    } else {
+        metadata
+    };
+
+    if let Some(reason) = file_reason(&path, &metadata, config, rules)
+        .or_else(|| dir_reason(&path, &metadata, visited, config))
+    {
+        events::emit(Event::Ignored(path.to_path_buf()));
+        return shard.skip(&path, reason);
+    }
+    match bof_index.entries.get(&path) {
+        Some(entry) => match &entry.metadata {
+            MetaData::Directory(_) => {
+                events::emit(Event::Error(format!(
+                    "This entry is a directory! {}",
+                    path.display()
+                )));
+            }
+            MetaData::Link(_) => {
+                events::emit(Event::Error(format!(
+                    "This entry is a symlink! {}",
+                    path.display()
+                )));
+            }
+            MetaData::File(file_meta) => {
+                if bof_index.rehash || needs_rehash(file_meta, &path, &metadata, config) {
+                    let (key, hashes) = match content_key(&path, metadata.len(), config) {
+                        Ok(digests) => digests,
+                        Err(e) => {
+                            events::emit(Event::Error(format!(
+                                "Failed to read file {}: {}",
+                                path.display(),
+                                e
+                            )));
+                            return;
+                        }
+                    };
+                    shard.entries.push(BOFEntry {
+                        key,
+                        path: path.clone(),
+                        metadata: MetaData::File(FileMetaData {
+                            hashes,
+                            ..FileMetaData::new(&path, &metadata, config)
+                        }),
+                        labels: Vec::new(),
+                    });
+                    events::emit(Event::Updated {
+                        path,
+                        size: metadata.len(),
+                    });
+                } else {
+                    events::emit(Event::Unchanged(path));
+                }
+            }
+        },
+        None => {
+            if metadata.is_file() {
+                let (key, hashes) = match content_key(&path, metadata.len(), config) {
+                    Ok(digests) => digests,
+                    Err(e) => {
+                        events::emit(Event::Error(format!(
+                            "Failed to read file {}: {}",
+                            path.display(),
+                            e
+                        )));
+                        return shard.skip(&path, SkipReason::Unreadable(e.to_string()));
+                    }
+                };
+                let file_meta = FileMetaData {
+                    hashes,
+                    ..FileMetaData::new(&path, &metadata, config)
+                };
+                match bof_index.moved_from(&key, &file_meta) {
+                    Some(from) => {
+                        shard.moved.push(from.clone());
+                        events::emit(Event::Renamed {
+                            from,
+                            to: path.clone(),
+                        });
+                    }
+                    None => events::emit(Event::Indexed {
+                        path: path.clone(),
+                        size: metadata.len(),
+                    }),
+                }
+                dir_entries.push(DirEntry {
+                    name,
+                    data: MetaData::File(file_meta.clone()),
+                });
+                shard.entries.push(BOFEntry {
+                    key,
+                    path,
+                    metadata: MetaData::File(file_meta),
+                    labels: Vec::new(),
+                });
+            } else if metadata.is_dir() {
+                match update_index_parallel(&path, bof_index, config, rules, Some(visited)) {
+                    Ok((subdir_meta, subdir_shard)) => {
+                        dir_entries.push(DirEntry {
+                            name,
+                            data: subdir_meta,
+                        });
+                        shard.extend(subdir_shard);
+                    }
+                    Err(e) => {
+                        events::emit(Event::Error(format!(
+                            "Failed to update directory {}: {}",
+                            path.display(),
+                            e
+                        )));
+                        shard.skip(&path, SkipReason::Unreadable(e.to_string()));
+                    }
+                }
+            } else {
+                events::emit(Event::Error(format!(
+                    "Neither file nor directory! {}",
+                    path.display()
+                )));
+                shard.skip(&path, SkipReason::Unsupported);
+            }
+        }
+    }
+}
+
+pub fn update_directories(paths: Vec<PathBuf>, config: &BOFConfig) -> Result<()> {
+    let started = Instant::now();
+    let mut existing_indices = load_indices(&config.output_dir, config.index_name())?;
+    estimate(&paths, config, existing_indices.header.last_run);
+    if existing_indices.header.hash_algorithm != Some(config.hash_algorithm) {
+        events::emit(Event::Notice(format!(
+            "Index was not hashed with {}, rehashing every file",
+            config.hash_algorithm
+        )));
+        existing_indices.rehash = true;
+        existing_indices.header.hash_algorithm = Some(config.hash_algorithm);
+    }
+    existing_indices.index_inodes();
+    existing_indices.clear_skipped(&paths);
+    let rules = IgnoreRules::new(config);
+
+    if config.parallel {
+        let shards = paths
+            .par_iter()
+            .filter_map(|path| {
+                match update_index_parallel(path, &existing_indices, config, &rules, None) {
+                    Ok((_, shard)) => Some(shard),
+                    Err(e) => {
+                        events::emit(Event::Error(format!(
+                            "Error updating directory {}: {}",
+                            path.display(),
+                            e
+                        )));
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        existing_indices.merge_shards(shards);
+    } else {
+        for path in &paths {
+            update_index(path, &mut existing_indices, config, &rules, None)
+                .map_err(BofError::traversal(path))?;
+        }
+    }
+    existing_indices.record_run(started, &paths);
+    save_index(existing_indices, config)
+}
+
+// Ignore rules in effect in the parent directory of `path`, as a walk from its indexed root
+// would have built them. None when a directory on the way is ignored.
+fn rules_for(path: &Path, root: &Path, config: &BOFConfig) -> Option<IgnoreRules> {
+    let mut rules = IgnoreRules::new(config);
+    if path == root {
+        return Some(rules);
+    }
+    let mut dir = root.to_path_buf();
+    let parent = path.parent()?;
+    loop {
+        if is_ignored(&dir, true, config, &rules) {
+            return None;
+        }
+        rules = rules.for_dir(&dir);
+        if dir == parent {
+            return Some(rules);
+        }
+        let next = parent.strip_prefix(&dir).ok()?.components().next()?;
+        dir.push(next);
+    }
+}
+
+fn touch_file(
+    path: &Path,
+    metadata: &Metadata,
+    bof_index: &mut BOFIndex,
+    config: &BOFConfig,
+) -> io::Result<()> {
+    let (key, hashes) = digests(path, config)?;
+    let file_meta = FileMetaData {
+        hashes,
+        ..FileMetaData::new(path, metadata, config)
+    };
+    let size = file_meta.size;
+    if bof_index.remove_entry(path).is_some() {
+        events::emit(Event::Updated {
+            path: path.to_path_buf(),
+            size,
+        });
+    } else {
+        events::emit(Event::Indexed {
+            path: path.to_path_buf(),
+            size,
+        });
+    }
+    bof_index.add_entry_meta(path, key, &MetaData::File(file_meta), None);
+    Ok(())
+}
+
+// Re-hashes files and directories under indexed roots whatever their mtime says, e.g. after
+// restoring them from a backup that preserved timestamps. Ignore rules still apply.
+pub fn touch(paths: Vec<PathBuf>, config: &BOFConfig) -> Result<()> {
+    let mut bof_index = load_indices(&config.output_dir, config.index_name())?;
+    if bof_index.header.hash_algorithm != Some(config.hash_algorithm) {
+        return Err(BofError::Config(format!(
+            "Index was not hashed with {}, run `bof update` first",
+            config.hash_algorithm
+        )));
+    }
+    bof_index.rehash = true;
+    bof_index.index_inodes();
+    bof_index.clear_skipped(&paths);
+
+    for path in &paths {
+        let Some(root) = bof_index
+            .header
+            .roots
+            .iter()
+            .map(|info| info.path.clone())
+            .find(|root| path.starts_with(root))
+        else {
+            events::emit(Event::Error(format!(
+                "{} is not under an indexed root",
+                path.display()
+            )));
+            continue;
+        };
+        let metadata = fs::metadata(path).map_err(BofError::traversal(path))?;
+        let Some(rules) = rules_for(path, &root, config) else {
+            events::emit(Event::Ignored(path.clone()));
+            continue;
+        };
+
+        if !metadata.is_dir() {
+            if is_ignored(path, false, config, &rules)
+                || file_reason(path, &metadata, config, &rules).is_some()
+            {
+                events::emit(Event::Ignored(path.clone()));
+                continue;
+            }
+            touch_file(path, &metadata, &mut bof_index, config)
+                .map_err(BofError::traversal(path))?;
+        } else if config.parallel {
+            let (_, shard) = update_index_parallel(path, &bof_index, config, &rules, None)
+                .map_err(BofError::traversal(path))?;
+            bof_index.merge_shards(vec![shard]);
+        } else {
+            update_index(path, &mut bof_index, config, &rules, None)
+                .map_err(BofError::traversal(path))?;
+        }
+        // Only what was touched here wins over a concurrent save
+        bof_index.claimed_roots.push(path.clone());
+    }
+    save_index(bof_index, config)
+}
+
+// Hashes files catalogued with `no_hash`, only those under `paths` unless it is empty, and
+// returns how many were hashed. Files that changed meanwhile get their new metadata too.
+pub fn rehash(paths: Vec<PathBuf>, config: &BOFConfig) -> Result<u64> {
+    let mut bof_index = load_indices(&config.output_dir, config.index_name())?;
+    if bof_index.header.hash_algorithm != Some(config.hash_algorithm) {
+        return Err(BofError::Config(format!(
+            "Index was not hashed with {}, run `bof update` first",
+            config.hash_algorithm
+        )));
+    }
+    let unhashed = bof_index
+        .entries
+        .values()
+        .filter(|entry| {
+            !entry.is_hashed()
+                && matches!(entry.metadata, MetaData::File(_))
+                && (paths.is_empty() || paths.iter().any(|path| entry.path.starts_with(path)))
+        })
+        .map(|entry| entry.path.clone())
+        .collect::<Vec<_>>();
+
+    let hash = |path: PathBuf| {
+        let hashed =
+            fs::metadata(&path).and_then(|metadata| Ok((digests(&path, config)?, metadata)));
+        match hashed {
+            Ok(((key, hashes), metadata)) => Some((path, key, hashes, metadata)),
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to read file {}: {}",
+                    path.display(),
+                    e
+                )));
+                None
+            }
+        }
+    };
+    let hashed = if config.parallel {
+        unhashed
+            .into_par_iter()
+            .filter_map(hash)
+            .collect::<Vec<_>>()
+    } else {
+        unhashed.into_iter().filter_map(hash).collect::<Vec<_>>()
+    };
+
+    let count = hashed.len() as u64;
+    for (path, key, hashes, metadata) in hashed {
+        let file_meta = FileMetaData {
+            hashes,
+            ..FileMetaData::new(&path, &metadata, config)
+        };
+        events::emit(Event::Indexed {
+            path: path.clone(),
+            size: file_meta.size,
+        });
+        bof_index.remove_entry(&path);
+        bof_index.add_entry_meta(&path, key, &MetaData::File(file_meta), None);
+    }
+    // Only the hashed files win over a concurrent save
+    let claimed = if paths.is_empty() {
+        bof_index
+            .header
+            .roots
+            .iter()
+            .map(|root| root.path.clone())
+            .collect()
+    } else {
+        paths
+    };
+    bof_index.claimed_roots.extend(claimed);
+    if count > 0 {
+        save_index(bof_index, config)?;
+    }
+    Ok(count)
+}
+
+// Removes everything indexed under `paths` from the index, along with the roots among them,
+// without touching the files. Returns how many entries were removed.
+pub fn remove(paths: Vec<PathBuf>, config: &BOFConfig) -> Result<u64> {
+    let mut bof_index = load_indices(&config.output_dir, config.index_name())?;
+    let under = |path: &Path| paths.iter().any(|removed| path.starts_with(removed));
+    for path in &paths {
+        let indexed = bof_index
+            .entries
+            .keys()
+            .any(|entry| entry.starts_with(path))
+            || bof_index
+                .header
+                .roots
+                .iter()
+                .any(|root| root.path.starts_with(path));
+        if !indexed {
+            events::emit(Event::Error(format!(
+                "Nothing indexed under {}",
+                path.display()
+            )));
+        }
+    }
+
+    let removed = bof_index
+        .entries
+        .keys()
+        .filter(|path| under(path))
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in &removed {
+        bof_index.remove_entry(path);
+    }
+    let roots = bof_index.header.roots.len();
+    bof_index.header.roots.retain(|root| !under(&root.path));
+    bof_index
+        .header
+        .skipped
+        .retain(|skipped| !under(&skipped.path));
+
+    if !removed.is_empty() || roots != bof_index.header.roots.len() {
+        // What was removed stays removed even if someone else saved meanwhile
+        bof_index.claimed_roots.extend(paths.iter().cloned());
+        save_index(bof_index, config)?;
+    }
+    Ok(removed.len() as u64)
+}
+
+// What `bof prune` took out of the index
+#[derive(Debug, Default)]
+pub struct PruneSummary {
+    pub entries: u64,
+    pub roots: u64,
+    // How much smaller the saved index got
+    pub bytes: u64,
+}
+
+// Removes the entries of paths that are gone, which updates keep until their directory is
+// walked again, and rebuilds the inverse table from what is left
+pub fn prune(config: &BOFConfig) -> Result<PruneSummary> {
+    let mut bof_index = load_indices(&config.output_dir, config.index_name())?;
+    let exists = |path: &PathBuf| match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        // Anything else, e.g. a share that is offline, doesn't prove the path is gone
+        _ => true,
+    };
+    let gone = if config.parallel {
+        bof_index
+            .entries
+            .par_iter()
+            .map(|(path, _)| path)
+            .filter(|path| !exists(path))
+            .cloned()
+            .collect::<HashSet<_>>()
+    } else {
+        bof_index
+            .entries
+            .keys()
+            .filter(|path| !exists(path))
+            .cloned()
+            .collect::<HashSet<_>>()
+    };
+
+    bof_index.claimed_roots = bof_index
+        .header
+        .roots
+        .iter()
+        .map(|root| root.path.clone())
+        .collect();
+    bof_index.entries.retain(|path, _| !gone.contains(path));
+    let roots = bof_index.header.roots.len();
+    bof_index.header.roots.retain(|root| exists(&root.path));
+    bof_index.rebuild_inverse_table();
+
+    let size = || {
+        fs::metadata(storage::find_index(&config.output_dir, config.index_name()))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    };
+    let before = size();
+    let summary = PruneSummary {
+        entries: gone.len() as u64,
+        roots: (roots - bof_index.header.roots.len()) as u64,
+        bytes: 0,
+    };
+    save_index(bof_index, config)?;
+    Ok(PruneSummary {
+        bytes: before.saturating_sub(size()),
+        ..summary
+    })
+}
+
+#[derive(Debug)]
+pub enum Change {
+    Added(PathBuf),
+    Modified(PathBuf),
+    Streams(PathBuf),
+    // Mode bits, owner or extended attributes, for files recorded with them
+    Permissions(PathBuf),
+    Deleted(PathBuf),
+    // Same content under another path, only told apart when comparing two indexes
+    Moved { from: PathBuf, to: PathBuf },
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Added(path)
+            | Change::Modified(path)
+            | Change::Streams(path)
+            | Change::Permissions(path)
+            | Change::Deleted(path)
+            | Change::Moved { to: path, .. } => path,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Change::Added(_) => "added",
+            Change::Modified(_) => "modified",
+            Change::Streams(_) => "streams changed",
+            Change::Permissions(_) => "permissions changed",
+            Change::Deleted(_) => "deleted",
+            Change::Moved { .. } => "moved",
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Moved { from, to } => write!(
+                f,
+                "{}: {} -> {}",
+                self.label(),
+                from.display(),
+                to.display()
+            ),
+            _ => write!(f, "{}: {}", self.label(), self.path().display()),
+        }
+    }
+}
+
+fn status_dir(
+    path: &Path,
+    bof_index: &BOFIndex,
+    config: &BOFConfig,
+    rules: &IgnoreRules,
+    visited: Option<&Visited>,
+    changes: &mut Vec<Change>,
+) -> io::Result<()> {
+    if is_ignored(path, true, config, rules) {
+        return Ok(());
+    }
+    let rules = rules.for_dir(path);
+    let visited = Visited::new(path, &fs::metadata(path)?, visited);
+
+    for entry in fs::read_dir(path)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to get metadata for {}: {}",
+                    path.display(),
+                    e
+                )));
+                continue;
+            }
+        };
+        if is_ignored(&path, metadata.is_dir(), config, &rules) {
+            continue;
+        }
+        let metadata = if let Some(kind) = platform::link_kind(&path, &metadata) {
+            match symlink(&path, kind, &visited, config) {
+                Link::Follow(metadata) => metadata,
+                Link::Record(link) => {
+                    match bof_index.entries.get(&path) {
+                        None => changes.push(Change::Added(path)),
+                        Some(entry)
+                            if !matches!(entry.metadata, MetaData::Link(_))
+                                || entry.key != link.key =>
+                        {
+                            changes.push(Change::Modified(path))
+                        }
+                        Some(_) => {}
+                    }
+                    continue;
+                }
+                Link::Skip(_) => continue,
+            }
+        } else {
+            metadata
+        };
+        if file_reason(&path, &metadata, config, &rules).is_some() {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if let Err(e) = status_dir(&path, bof_index, config, &rules, Some(&visited), changes) {
+                events::emit(Event::Error(format!(
+                    "Failed to read directory {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+            continue;
+        }
+
+        match bof_index.entries.get(&path).map(|entry| &entry.metadata) {
+            None => changes.push(Change::Added(path)),
+            Some(MetaData::File(file_meta)) => {
+                if file_meta.size != metadata.len()
+                    || file_meta.mtime != metadata.modified().unwrap()
+                {
+                    changes.push(Change::Modified(path));
+                } else if streams_changed(file_meta, &path, config) {
+                    changes.push(Change::Streams(path));
+                } else if permissions_changed(file_meta, &path, &metadata, config) {
+                    changes.push(Change::Permissions(path));
+                }
+            }
+            Some(MetaData::Directory(_) | MetaData::Link(_)) => {
+                changes.push(Change::Modified(path))
+            }
+        }
+    }
+    Ok(())
+}
+
+// Compares the given directories with the index without modifying it
+pub fn status(paths: Vec<PathBuf>, config: &BOFConfig) -> Result<Vec<Change>> {
+    let bof_index = load_indices(&config.output_dir, config.index_name())?;
+    let rules = IgnoreRules::new(config);
+    let mut changes = Vec::new();
+
+    for path in &paths {
+        status_dir(path, &bof_index, config, &rules, None, &mut changes)
+            .map_err(BofError::traversal(path))?;
+    }
+    for entry in bof_index.entries.values() {
+        if paths.iter().any(|path| entry.path.starts_with(path))
+            && fs::symlink_metadata(&entry.path).is_err()
+        {
+            changes.push(Change::Deleted(entry.path.clone()));
+        }
+    }
+
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(changes)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Mismatch {
+    // Content changed although mtime and size didn't: bitrot or tampering
+    Corrupted(PathBuf),
+    Modified(PathBuf),
+    Missing(PathBuf),
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Corrupted(path) => write!(f, "corrupted: {}", path.display()),
+            Mismatch::Modified(path) => {
+                write!(f, "modified since last update: {}", path.display())
+            }
+            Mismatch::Missing(path) => write!(f, "missing: {}", path.display()),
+        }
+    }
+}
+
+fn verify_entry(
+    entry: &BOFEntry,
+    algorithm: HashAlgorithm,
+    policy: &HashPolicy,
+) -> Option<Mismatch> {
+    let MetaData::File(file_meta) = &entry.metadata else {
+        return None;
+    };
+    // Nothing to check it against yet
+    if !entry.is_hashed() {
+        return None;
+    }
+    let path = &entry.path;
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Some(Mismatch::Missing(path.clone()));
+        }
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to get metadata for {}: {}",
+                path.display(),
+                e
+            )));
+            return None;
+        }
+    };
+    if metadata.len() != file_meta.size || metadata.modified().ok() != Some(file_meta.mtime) {
+        return Some(Mismatch::Modified(path.clone()));
+    }
+
+    let key = match policy.hash_file(algorithm, path) {
+        Ok(key) => key,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to read file {}: {}",
+                path.display(),
+                e
+            )));
+            return None;
+        }
+    };
+    events::emit(Event::Verified {
+        path: path.clone(),
+        size: file_meta.size,
+    });
+    (key != entry.key).then(|| Mismatch::Corrupted(path.clone()))
+}
+
+// Entries verified between two checkpoints of the cursor
+const VERIFY_CHECKPOINT: usize = 1024;
+const VERIFY_CURSOR_FILE: &str = "verify.cursor";
+
+// How far an interrupted verify got, so `verify --resume` can pick up from there
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct VerifyCursor {
+    paths: Vec<PathBuf>,
+    // Entries are verified in path order, everything up to this one is done
+    last: Option<PathBuf>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl VerifyCursor {
+    fn load(output_dir: &Path) -> Result<Option<VerifyCursor>> {
+        match fs::read(output_dir.join(VERIFY_CURSOR_FILE)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(BofError::serialization),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, output_dir: &Path) -> Result<()> {
+        let contents = serde_json::to_vec(self).map_err(BofError::serialization)?;
+        let path = output_dir.join(VERIFY_CURSOR_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+// Re-hashes indexed files under `paths` (everything when empty) and reports those that don't match.
+// Progress is checkpointed, `resume` continues an interrupted run over the same paths.
+pub fn verify(paths: Vec<PathBuf>, resume: bool, config: &BOFConfig) -> Result<Vec<Mismatch>> {
+    let bof_index = load_indices(&config.output_dir, config.index_name())?;
+    let Some(algorithm) = bof_index.header.hash_algorithm else {
+        return Err(BofError::Serialization(
+            "index doesn't record its hash algorithm, run `bof index` again".to_string(),
+        ));
+    };
+
+    let mut cursor = VerifyCursor {
+        paths: paths.clone(),
+        ..Default::default()
+    };
+    if resume {
+        match VerifyCursor::load(&config.output_dir)? {
+            Some(previous) if previous.paths == paths => {
+                if let Some(last) = &previous.last {
+                    events::emit(Event::Notice(format!(
+                        "Resuming verify after {}",
+                        last.display()
+                    )));
+                }
+                cursor = previous;
+            }
+            Some(_) => events::emit(Event::Notice(
+                "Interrupted verify was for other paths, starting over".to_string(),
+            )),
+            None => events::emit(Event::Notice(
+                "No interrupted verify to resume, starting over".to_string(),
+            )),
+        }
+    }
+
+    let mut entries = bof_index
+        .entries
+        .values()
+        .filter(|entry| paths.is_empty() || paths.iter().any(|p| entry.path.starts_with(p)))
+        .filter(|entry| cursor.last.as_ref().is_none_or(|last| entry.path > *last))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    events::emit(Event::Estimate {
+        files: entries.len() as u64,
+        bytes: entries
+            .iter()
+            .map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) => file_meta.size,
+                MetaData::Directory(_) | MetaData::Link(_) => 0,
+            })
+            .sum(),
+    });
+
+    for chunk in entries.chunks(VERIFY_CHECKPOINT) {
+        let mismatches = if config.parallel {
+            chunk
+                .par_iter()
+                .filter_map(|entry| verify_entry(entry, algorithm, &config.hashing))
+                .collect::<Vec<_>>()
+        } else {
+            chunk
+                .iter()
+                .filter_map(|entry| verify_entry(entry, algorithm, &config.hashing))
+                .collect::<Vec<_>>()
+        };
+        cursor.mismatches.extend(mismatches);
+        cursor.last = chunk.last().map(|entry| entry.path.clone());
+        cursor.save(&config.output_dir)?;
+    }
+
+    match fs::remove_file(config.output_dir.join(VERIFY_CURSOR_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut mismatches = cursor.mismatches;
+    mismatches.sort_by_key(|mismatch| mismatch.to_string());
+    Ok(mismatches)
+}
+
+// Re-hashes `count` files picked at random under `paths` (everything when empty), catching bit-rot
+// early without a verify of everything. Only corrupted files are reported, files that changed are
+// for update and status to tell.
+pub fn spot_check(paths: &[PathBuf], count: usize, config: &BOFConfig) -> Result<Vec<Mismatch>> {
+    let bof_index = load_indices(&config.output_dir, config.index_name())?;
+    let Some(algorithm) = bof_index.header.hash_algorithm else {
+        return Ok(Vec::new());
+    };
+    let mut entries = bof_index
+        .entries
+        .values()
+        .filter(|entry| matches!(entry.metadata, MetaData::File(_)) && entry.is_hashed())
+        .filter(|entry| paths.is_empty() || paths.iter().any(|p| entry.path.starts_with(p)))
+        .collect::<Vec<_>>();
+    // A new random order on every run
+    let order = RandomState::new();
+    if entries.len() > count {
+        entries.select_nth_unstable_by_key(count, |entry| order.hash_one(&entry.path));
+        entries.truncate(count);
+    }
+
+    let verify = |entry: &&BOFEntry| match verify_entry(entry, algorithm, &config.hashing) {
+        Some(mismatch @ Mismatch::Corrupted(_)) => Some(mismatch),
+        _ => None,
+    };
+    let mut mismatches = if config.parallel {
+        entries.par_iter().filter_map(verify).collect::<Vec<_>>()
+    } else {
+        entries.iter().filter_map(verify).collect::<Vec<_>>()
+    };
+    events::emit(Event::Notice(format!(
+        "Spot-checked {} files, {} corrupted",
+        entries.len(),
+        mismatches.len()
+    )));
+    mismatches.sort_by_key(|mismatch| mismatch.to_string());
+    Ok(mismatches)
+}
+
+// An index as read, its entries going straight into the map by path instead of a list first
+#[derive(Debug, Deserialize)]
+struct IntBOFIndex {
+    #[serde(default)]
+    header: IndexHeader,
+    #[serde(deserialize_with = "entries_by_path")]
+    entries: HashMap<PathBuf, BOFEntry>,
+    inverse_table: HashMap<String, Vec<PathBuf>>,
+}
+
+// An index as saved, borrowing everything from the BOFIndex so saving never copies the entries.
+// The serializer writes them out one by one as it goes.
+#[derive(Serialize)]
+struct SavedIndex<'a> {
+    header: &'a IndexHeader,
+    // In path order
+    entries: Vec<&'a BOFEntry>,
+    #[serde(serialize_with = "sorted_table")]
+    inverse_table: &'a HashMap<String, Vec<PathBuf>>,
+}
+
+fn entries_by_path<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<HashMap<PathBuf, BOFEntry>, D::Error> {
+    EntriesSeed(None).deserialize(deserializer)
+}
+
+// The inverse table in key order, each key's paths in path order, so saving the same index twice
+// writes the same bytes
+fn sorted_table<S: Serializer>(
+    table: &HashMap<String, Vec<PathBuf>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        table
+            .iter()
+            .map(|(key, paths)| {
+                let mut paths = paths.iter().collect::<Vec<_>>();
+                paths.sort();
+                (key, paths)
+            })
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+// Part of the index to load when a query only needs that much
+#[derive(Clone, Debug)]
+pub enum Segment {
+    KeyPrefix(String),
+    PathPrefix(PathBuf),
+}
+
+impl Segment {
+    // The directories a glob matching whole paths is confined to, if any
+    pub fn for_pattern(pattern: &str) -> Option<Segment> {
+        if !pattern.contains('/') {
+            return None;
+        }
+        let parts = pattern.split('/').collect::<Vec<_>>();
+        // The last part names the files themselves
+        let dirs = parts[..parts.len() - 1]
+            .iter()
+            .take_while(|part| !part.contains(['*', '?', '[', '{', '\\']))
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/");
+        (!dirs.is_empty()).then(|| Segment::PathPrefix(PathBuf::from(dirs)))
+    }
+
+    fn contains(&self, entry: &BOFEntry) -> bool {
+        match self {
+            Segment::KeyPrefix(prefix) => entry.key.starts_with(prefix.as_str()),
+            Segment::PathPrefix(prefix) => entry.path.starts_with(prefix),
+        }
+    }
+}
+
+// Deserializes an index keeping only the entries in the segment, so the others are dropped as
+// they are read instead of all being held at once
+struct SegmentSeed<'a>(&'a Segment);
+
+impl<'de> DeserializeSeed<'de> for SegmentSeed<'_> {
+    type Value = IntBOFIndex;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<IntBOFIndex, D::Error> {
+        deserializer.deserialize_struct(
+            "IntBOFIndex",
+            &["header", "entries", "inverse_table"],
+            self,
+        )
+    }
+}
+
+impl<'de> Visitor<'de> for SegmentSeed<'_> {
+    type Value = IntBOFIndex;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an index")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<IntBOFIndex, A::Error> {
+        let mut header = None;
+        let mut entries = None;
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "header" => header = Some(map.next_value()?),
+                "entries" => entries = Some(map.next_value_seed(EntriesSeed(Some(self.0)))?),
+                // Rebuilt from the entries kept
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(IntBOFIndex {
+            header: header.unwrap_or_default(),
+            entries: entries.ok_or_else(|| de::Error::missing_field("entries"))?,
+            inverse_table: HashMap::new(),
+        })
+    }
+
+    // Formats without field names, i.e. bincode
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<IntBOFIndex, A::Error> {
+        let header = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let entries = seq
+            .next_element_seed(EntriesSeed(Some(self.0)))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        seq.next_element::<HashMap<String, Vec<PathBuf>>>()?;
+        Ok(IntBOFIndex {
+            header,
+            entries,
+            inverse_table: HashMap::new(),
+        })
+    }
+}
+
+// Entries by path, only those in the segment if there is one
+struct EntriesSeed<'a>(Option<&'a Segment>);
+
+impl<'de> DeserializeSeed<'de> for EntriesSeed<'_> {
+    type Value = HashMap<PathBuf, BOFEntry>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<HashMap<PathBuf, BOFEntry>, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for EntriesSeed<'_> {
+    type Value = HashMap<PathBuf, BOFEntry>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a list of entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<HashMap<PathBuf, BOFEntry>, A::Error> {
+        let mut entries = HashMap::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(entry) = seq.next_element::<BOFEntry>()? {
+            if self.0.is_none_or(|segment| segment.contains(&entry)) {
+                entries.insert(entry.path.clone(), entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+pub(crate) fn save_index(mut bof_indices: BOFIndex, config: &BOFConfig) -> Result<()> {
+    // Saving it would drop everything that wasn't loaded
+    if bof_indices.partial {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only a segment of the index was loaded, it can't be saved",
+        )
+        .into());
+    }
+    // Saves of other processes and threads take turns, each merging what the one before saved
+    let _lock = storage::lock(&config.output_dir, true)?;
+    let name = config.index_name();
+    let existing = storage::find_index(&config.output_dir, name);
+    let modified = fs::metadata(&existing).and_then(|m| m.modified()).ok();
+    if modified.is_some() && modified != bof_indices.loaded_at {
+        match load_indices(&config.output_dir, name) {
+            Ok(on_disk) => bof_indices.merge_concurrent(on_disk),
+            Err(e) => events::emit(Event::Error(format!(
+                "Failed to merge with {}: {}",
+                existing.display(),
+                e
+            ))),
+        }
+    }
+
+    bof_indices.classify(config)?;
+    let contents = config.catalog.then(|| bof_indices.catalog_contents());
+    bof_indices.header.generation =
+        storage::next_generation(&config.output_dir, name, bof_indices.header.generation)?;
+    let mut generation = storage::Generation {
+        generation: bof_indices.header.generation,
+        saved: SystemTime::now(),
+        files: 0,
+        bytes: 0,
+        snapshots: Vec::new(),
+    };
+    for entry in bof_indices.entries.values() {
+        if let MetaData::File(file_meta) = &entry.metadata {
+            generation.files += 1;
+            generation.bytes += file_meta.size;
+        }
+    }
+
+    let path = storage::index_path(&config.output_dir, name, config.compression)?;
+    let backup = storage::backup_path(&path);
+    let tmp = config
+        .output_dir
+        .join(format!(".{}.tmp-{}", name, std::process::id()));
+
+    // Write everything to a temporary file first so a crash never leaves a torn index behind
+    let write = || -> Result<()> {
+        let mut entries = bof_indices.entries.values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let pretty = config.pretty_json(entries.len());
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        storage::write_index(
+            &mut writer,
+            &SavedIndex {
+                header: &bof_indices.header,
+                entries,
+                inverse_table: &bof_indices.inverse_table,
+            },
+            config.index_format,
+            config.compression,
+            pretty,
+        )?;
+        writer.into_inner().map_err(io::Error::from)?.sync_all()?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    // Keep the previous index around, linking it so there is never a moment without an index,
+    // and renaming the link over the last backup so readers falling back to it always find one
+    if existing.exists() {
+        let backup_tmp =
+            config
+                .output_dir
+                .join(format!(".{}.bak-tmp-{}", name, std::process::id()));
+        let _ = fs::remove_file(&backup_tmp);
+        if fs::hard_link(&existing, &backup_tmp).is_err() {
+            fs::copy(&existing, &backup_tmp)?;
+        }
+        fs::rename(&backup_tmp, &backup)?;
+    }
+    fs::rename(&tmp, &path)?;
+    // Saved with another compression than before, drop the outdated file
+    if existing != path {
+        let _ = fs::remove_file(&existing);
+        let _ = fs::remove_file(storage::backup_path(&existing));
+    }
+    sync_dir(&config.output_dir);
+
+    events::emit(Event::Saved(path));
+
+    // The log only lists generations, the index knows its own
+    if let Err(e) = storage::log_generation(&config.output_dir, name, generation) {
+        events::emit(Event::Error(format!("Failed to log the generation: {}", e)));
+    }
+
+    // Like snapshots, the catalog is only reported when it fails, lookups fall back to the index
+    let saved = match contents {
+        Some(contents) => catalog::write(&config.output_dir, contents, config),
+        None => catalog::remove(&config.output_dir, name),
+    };
+    if let Err(e) = saved {
+        events::emit(Event::Error(format!("Failed to save the catalog: {}", e)));
+    }
+
+    // The index itself is saved by now, so failing to snapshot it is only reported
+    if config.auto_snapshot {
+        if let Err(e) = take_snapshot(config) {
+            events::emit(Event::Error(format!("Failed to take a snapshot: {}", e)));
+        }
+    }
+
+    Ok(())
+}
+
+// The saved index of snapshot `id`, or of a generation given by its number, snapshot ids being
+// timestamps
+fn snapshot_path(output_dir: &Path, name: &str, id: &str) -> Result<PathBuf> {
+    match id.parse::<u64>() {
+        Ok(generation) => storage::find_generation(output_dir, name, generation),
+        Err(_) => Ok(storage::find_snapshot(output_dir, name, id)?.path),
+    }
+}
+
+pub(crate) fn take_snapshot(config: &BOFConfig) -> Result<storage::Snapshot> {
+    let snapshot = storage::snapshot(&config.output_dir, config.index_name())?;
+    if config.keep_snapshots > 0 {
+        storage::prune_snapshots(
+            &config.output_dir,
+            config.index_name(),
+            config.keep_snapshots,
+        )?;
+    }
+    Ok(snapshot)
+}
+
+// Makes the rename durable, best effort since not every platform can open directories
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+fn read_index(path: &Path) -> Result<IntBOFIndex> {
+    storage::read_index(path)
+}
+
+// Only the entries of the index at `path` that are in the segment
+fn read_segment(file: File, path: &Path, segment: &Segment) -> Result<IntBOFIndex> {
+    match storage::read_index_seed(file.try_clone()?, path, SegmentSeed(segment))? {
+        Some(index) => Ok(index),
+        None => {
+            let mut index: IntBOFIndex = storage::read_index_file(file, path)?;
+            index.entries.retain(|_, entry| segment.contains(entry));
+            Ok(index)
+        }
+    }
+}
+
+pub fn load_indices(output_dir: &Path, name: &str) -> Result<BOFIndex> {
+    let (entries, loaded_at) = read_current(output_dir, name, storage::read_index_file)?;
+    Ok(from_saved(entries, loaded_at))
+}
+
+// Reads the last committed index with `read`, falling back to the backup when it is damaged.
+// Saves going on meanwhile don't change what is read, nor which save `loaded_at` says it is.
+fn read_current(
+    output_dir: &Path,
+    name: &str,
+    read: impl Fn(File, &Path) -> Result<IntBOFIndex>,
+) -> Result<(IntBOFIndex, Option<SystemTime>)> {
+    let storage::Committed {
+        path,
+        file,
+        modified,
+    } = storage::open_committed(output_dir, name)?;
+    let entries = match read(file, &path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let backup = storage::backup_path(&path);
+            events::emit(Event::Error(format!(
+                "Failed to read {}: {}, falling back to {}",
+                path.display(),
+                e,
+                backup.display()
+            )));
+            File::open(&backup)
+                .map_err(BofError::from)
+                .and_then(|file| read(file, &backup))
+                .map_err(|_| e)?
+        }
+    };
+    Ok((entries, modified))
+}
+
+// A segment of a saved index, its inverse table rebuilt from the entries kept
+fn from_segment(entries: IntBOFIndex, loaded_at: Option<SystemTime>) -> BOFIndex {
+    let mut index = from_saved(entries, loaded_at);
+    index.rebuild_inverse_table();
+    index.partial = true;
+    index
+}
+
+fn from_saved(entries: IntBOFIndex, loaded_at: Option<SystemTime>) -> BOFIndex {
+    BOFIndex {
+        entries: entries.entries,
+        inverse_table: entries.inverse_table,
+        header: entries.header,
+        rehash: false,
+        claimed_roots: Vec::new(),
+        loaded_at,
+        inodes: HashMap::new(),
+        partial: false,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexSummary {
+    pub files: u64,
+    pub bytes: u64,
+    // Space taken by extra copies of the same content, hardlinks don't count as copies
+    pub duplicate_bytes: u64,
+    pub updated: Option<SystemTime>,
+}
+
+// What `bof stats` reports about an index
+#[derive(Debug, Serialize)]
+pub struct IndexStats {
+    pub files: u64,
+    // Directories holding indexed entries, up to the roots
+    pub directories: u64,
+    pub links: u64,
+    pub bytes: u64,
+    pub duplicate_groups: u64,
+    pub wasted_bytes: u64,
+    // Largest files first
+    pub largest: Vec<(PathBuf, u64)>,
+    // Most bytes first
+    pub extensions: Vec<ExtensionStats>,
+    // Most bytes first
+    pub volumes: Vec<VolumeStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtensionStats {
+    // Lowercase, empty for files without one
+    pub extension: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+// Indexed files on a filesystem, by the device recorded with them
+#[derive(Debug, Serialize)]
+pub struct VolumeStats {
+    // 0 where it couldn't be read
+    pub device: u64,
+    // Where it is mounted on this machine and its type, when it is
+    pub mount_point: Option<PathBuf>,
+    pub filesystem: Option<String>,
+    // Indexed roots last seen on it
+    pub roots: Vec<PathBuf>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+// Part of IndexStats, summed up over some of the entries
+#[derive(Default)]
+struct Totals<'a> {
+    files: u64,
+    links: u64,
+    bytes: u64,
+    directories: HashSet<&'a Path>,
+    largest: Vec<(&'a Path, u64)>,
+    extensions: HashMap<String, (u64, u64)>,
+    volumes: HashMap<u64, (u64, u64)>,
+}
+
+impl<'a> Totals<'a> {
+    fn add(&mut self, entry: &'a BOFEntry, roots: &[RootInfo]) {
+        let root = roots.iter().find(|root| entry.path.starts_with(&root.path));
+        // Directories holding an entry, up to its root. Once one is known, so are those above it.
+        for dir in entry.path.ancestors().skip(1) {
+            let under_root = root.is_none_or(|root| dir.starts_with(&root.path));
+            if dir.as_os_str().is_empty() || !under_root || !self.directories.insert(dir) {
+                break;
+            }
+        }
+        match &entry.metadata {
+            MetaData::File(file_meta) => {
+                self.files += 1;
+                self.bytes += file_meta.size;
+                self.largest.push((&entry.path, file_meta.size));
+                let extension = entry
+                    .path
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                let totals = self.extensions.entry(extension).or_default();
+                totals.0 += 1;
+                totals.1 += file_meta.size;
+                let totals = self.volumes.entry(file_meta.device).or_default();
+                totals.0 += 1;
+                totals.1 += file_meta.size;
+            }
+            MetaData::Link(_) => self.links += 1,
+            MetaData::Directory(_) => {}
+        }
+    }
+
+    fn merge(mut self, other: Totals<'a>) -> Totals<'a> {
+        self.files += other.files;
+        self.links += other.links;
+        self.bytes += other.bytes;
+        self.directories.extend(other.directories);
+        self.largest.extend(other.largest);
+        for (extension, (files, bytes)) in other.extensions {
+            let totals = self.extensions.entry(extension).or_default();
+            totals.0 += files;
+            totals.1 += bytes;
+        }
+        for (device, (files, bytes)) in other.volumes {
+            let totals = self.volumes.entry(device).or_default();
+            totals.0 += files;
+            totals.1 += bytes;
+        }
+        self
+    }
+}
+
+// What the last run knows about a path that was expected in the index
+#[derive(Debug)]
+pub enum WhyMissing {
+    Indexed,
+    // The path, or the directory containing it, was left out
+    Skipped(Skipped),
+    NotUnderRoot,
+    // Under an indexed root, but wasn't there when it was last walked
+    NotFound(RootInfo),
+}
+
+// Indexed paths that are the same file on disk
+#[derive(Debug, Serialize)]
+pub struct HardlinkGroup {
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+}
+
+impl BOFIndex {
+    // The index saved as `name` in `output_dir`, see BOFConfig::index_name
+    pub fn load(output_dir: &Path, name: &str) -> Result<BOFIndex> {
+        load_indices(output_dir, name)
+    }
+
+    // Only the entries in `segment`, for queries that don't need the rest. Such an index can't
+    // be saved.
+    pub fn load_segment(output_dir: &Path, name: &str, segment: &Segment) -> Result<BOFIndex> {
+        let (entries, loaded_at) = read_current(output_dir, name, |file, path| {
+            read_segment(file, path, segment)
+        })?;
+        Ok(from_segment(entries, loaded_at))
+    }
+
+    pub fn load_snapshot_segment(
+        output_dir: &Path,
+        name: &str,
+        id: &str,
+        segment: &Segment,
+    ) -> Result<BOFIndex> {
+        let path = snapshot_path(output_dir, name, id)?;
+        Ok(from_segment(
+            read_segment(File::open(&path)?, &path, segment)?,
+            None,
+        ))
+    }
+
+    // An index saved at `path`, e.g. copied from another machine
+    pub fn load_file(path: &Path) -> Result<BOFIndex> {
+        Ok(from_saved(read_index(path)?, None))
+    }
+
+    // An index given as the path of an index file or of the directory holding it, otherwise as the
+    // id of a snapshot or a generation in `output_dir`. Either way, of the index saved as `name`.
+    pub fn open(output_dir: &Path, name: &str, index: &str) -> Result<BOFIndex> {
+        let path = Path::new(index);
+        if path.is_dir() {
+            BOFIndex::load(path, name)
+        } else if path.is_file() {
+            BOFIndex::load_file(path)
+        } else {
+            BOFIndex::load_snapshot(output_dir, name, index)
+        }
+    }
+
+    // The index as it was when snapshot `id` was taken, or as generation `id` when it is a number
+    pub fn load_snapshot(output_dir: &Path, name: &str, id: &str) -> Result<BOFIndex> {
+        Ok(from_saved(
+            read_index(&snapshot_path(output_dir, name, id)?)?,
+            None,
+        ))
+    }
+
+    pub fn save(self, config: &BOFConfig) -> Result<()> {
+        save_index(self, config)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Rough number of bytes the index takes in memory: its tables and what they point to,
+    // leaving out the allocator's own overhead
+    pub(crate) fn memory_estimate(&self) -> u64 {
+        let path = |path: &PathBuf| path.capacity() as u64;
+        let entries = self.entries.capacity() as u64 * size_of::<(PathBuf, BOFEntry)>() as u64
+            + self
+                .entries
+                .iter()
+                .map(|(key, entry)| {
+                    path(key)
+                        + path(&entry.path)
+                        + entry.key.capacity() as u64
+                        + entry
+                            .labels
+                            .iter()
+                            .map(|label| (size_of::<String>() + label.capacity()) as u64)
+                            .sum::<u64>()
+                        + entry.metadata.heap_size()
+                })
+                .sum::<u64>();
+        let inverse_table = self.inverse_table.capacity() as u64
+            * size_of::<(String, Vec<PathBuf>)>() as u64
+            + self
+                .inverse_table
+                .iter()
+                .map(|(key, paths)| {
+                    key.capacity() as u64
+                        + (paths.capacity() * size_of::<PathBuf>()) as u64
+                        + paths.iter().map(path).sum::<u64>()
+                })
+                .sum::<u64>();
+        let inodes = self.inodes.capacity() as u64 * size_of::<((u64, u64), PathBuf)>() as u64
+            + self.inodes.values().map(path).sum::<u64>();
+        entries + inverse_table + inodes
+    }
+
+    // Hashed files by key, see catalog
+    fn catalog_contents(&self) -> HashMap<String, Content> {
+        let mut contents: HashMap<String, Content> = HashMap::new();
+        for entry in self.entries.values().filter(|entry| entry.is_hashed()) {
+            let MetaData::File(file_meta) = &entry.metadata else {
+                continue;
+            };
+            contents
+                .entry(entry.key.clone())
+                .or_insert_with(|| Content {
+                    size: file_meta.size,
+                    files: Vec::new(),
+                })
+                .files
+                .push(CatalogFile {
+                    path: entry.path.clone(),
+                    device: file_meta.device,
+                    inode: file_meta.inode,
+                });
+        }
+        contents
+    }
+
+    // Gives back the room the tables kept for entries that are gone
+    pub(crate) fn compact(&mut self) {
+        self.entries.shrink_to_fit();
+        self.inverse_table.shrink_to_fit();
+        for paths in self.inverse_table.values_mut() {
+            paths.shrink_to_fit();
+        }
+        self.inodes.shrink_to_fit();
+    }
+
+    pub fn hash_algorithm(&self) -> Option<HashAlgorithm> {
+        self.header.hash_algorithm
+    }
+
+    // Indexed roots, only those last updated by the current user if `mine`
+    pub fn roots(&self, mine: bool) -> Vec<RootInfo> {
+        let owner = platform::current_user();
+        let mut roots = self
+            .header
+            .roots
+            .iter()
+            .filter(|info| !mine || info.owner == owner)
+            .cloned()
+            .collect::<Vec<_>>();
+        roots.sort_by(|a, b| a.path.cmp(&b.path));
+        roots
+    }
+
+    pub fn summary(&self) -> IndexSummary {
+        let (files, bytes) = self
+            .entries
+            .values()
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file) => Some(file.size),
+                MetaData::Directory(_) | MetaData::Link(_) => None,
+            })
+            .fold((0, 0), |(files, bytes), size| (files + 1, bytes + size));
+        let updated = self
+            .header
+            .roots
+            .iter()
+            .map(|root| root.updated)
+            .max()
+            .or(self.loaded_at);
+        IndexSummary {
+            files,
+            bytes,
+            duplicate_bytes: self.duplicate_bytes(),
+            updated,
+        }
+    }
+
+    // Totals, duplicates, the `top` largest files and bytes by extension
+    pub fn stats(&self, top: usize) -> IndexStats {
+        // Each rayon job sums up its share of the entries, the shares are merged after
+        let totals = self
+            .entries
+            .par_iter()
+            .fold(Totals::default, |mut totals, (_, entry)| {
+                totals.add(entry, &self.header.roots);
+                totals
+            })
+            .reduce(Totals::default, Totals::merge);
+        let groups = self.duplicates(0, 2, None);
+
+        let mut largest = totals.largest;
+        largest.par_sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        largest.truncate(top);
+        let mut extensions = totals
+            .extensions
+            .into_iter()
+            .map(|(extension, (files, bytes))| ExtensionStats {
+                extension,
+                files,
+                bytes,
+            })
+            .collect::<Vec<_>>();
+        extensions.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.extension.cmp(&b.extension))
+        });
+        let mounts = platform::mounts();
+        let mut volumes = totals
+            .volumes
+            .into_iter()
+            .map(|(device, (files, bytes))| {
+                let mount = mounts.get(&device).filter(|_| device != 0);
+                VolumeStats {
+                    device,
+                    mount_point: mount.map(|mount| mount.path.clone()),
+                    filesystem: mount.map(|mount| mount.filesystem.clone()),
+                    roots: self
+                        .header
+                        .roots
+                        .iter()
+                        .filter(|root| root.device == Some(device))
+                        .map(|root| root.path.clone())
+                        .collect(),
+                    files,
+                    bytes,
+                }
+            })
+            .collect::<Vec<_>>();
+        volumes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.device.cmp(&b.device)));
+        IndexStats {
+            files: totals.files,
+            directories: totals.directories.len() as u64,
+            links: totals.links,
+            bytes: totals.bytes,
+            duplicate_groups: groups.len() as u64,
+            wasted_bytes: groups.iter().map(DuplicateGroup::wasted).sum(),
+            largest: largest
+                .into_iter()
+                .map(|(path, size)| (path.to_path_buf(), size))
+                .collect(),
+            extensions,
+            volumes,
+        }
+    }
+
+    // Indexed files by (device, inode), the inode is 0 where it couldn't be read
+    fn files_by_inode(&self) -> HashMap<(u64, u64), Vec<(&PathBuf, &FileMetaData)>> {
+        let mut inodes: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in self.entries.values() {
+            if let MetaData::File(file_meta) = &entry.metadata {
+                if file_meta.inode != 0 {
+                    inodes
+                        .entry((file_meta.device, file_meta.inode))
+                        .or_default()
+                        .push((&entry.path, file_meta));
+                }
+            }
+        }
+        inodes
+    }
+
+    // Files indexed at more than one path, largest first
+    pub fn hardlinks(&self) -> Vec<HardlinkGroup> {
+        let mut groups = self
+            .files_by_inode()
+            .into_values()
+            .filter(|files| files.len() > 1)
+            .map(|files| {
+                let mut paths = files
+                    .iter()
+                    .map(|(path, _)| path.to_path_buf())
+                    .collect::<Vec<_>>();
+                paths.sort();
+                HardlinkGroup {
+                    size: files[0].1.size,
+                    paths,
+                }
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+        groups
+    }
+
+    // Content found in at least `min_copies` files of at least `min_size` bytes, only counting
+    // files carrying `label` if given. The groups wasting the most space come first.
+    pub fn duplicates(
+        &self,
+        min_size: u64,
+        min_copies: u64,
+        label: Option<&str>,
+    ) -> Vec<DuplicateGroup> {
+        // Grouped by sorting on the key rather than through a map, which rayon spreads over
+        // every core on indexes of millions of files
+        let mut files = Vec::with_capacity(self.entries.len());
+        files.par_extend(self.entries.par_iter().filter_map(|(_, entry)| {
+            let MetaData::File(file_meta) = &entry.metadata else {
+                return None;
+            };
+            let labelled = label.is_none_or(|label| entry.labels.iter().any(|l| l == label));
+            (entry.is_hashed() && file_meta.size >= min_size && labelled)
+                .then_some((entry, file_meta))
+        }));
+        files.par_sort_unstable_by(|a, b| a.0.key.cmp(&b.0.key));
+        let mut groups = files
+            .chunk_by(|a, b| a.0.key == b.0.key)
+            .filter(|files| files.len() > 1)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .filter_map(|files| {
+                let mut group = DuplicateGroup {
+                    key: files[0].0.key.clone(),
+                    size: files[0].1.size,
+                    copies: 0,
+                    paths: Vec::with_capacity(files.len()),
+                };
+                let mut inodes = HashSet::with_capacity(files.len());
+                for (entry, file_meta) in files {
+                    group.paths.push(entry.path.clone());
+                    // Without an inode every path has to be taken for a copy of its own
+                    if file_meta.inode == 0 || inodes.insert((file_meta.device, file_meta.inode)) {
+                        group.copies += 1;
+                    }
+                }
+                (group.copies >= min_copies.max(2)).then_some(group)
+            })
+            .collect::<Vec<_>>();
+        sort_duplicates(&mut groups);
+        groups
+    }
+
+    // Files selected by each policy in turn. Files already deleted by an earlier policy are left out,
+    // and for duplicated files a copy that isn't selected and isn't a hardlink of it is kept.
+    pub(crate) fn policy_matches(
+        &self,
+        policies: &BTreeMap<String, Policy>,
+        now: SystemTime,
+    ) -> Result<Vec<PolicyMatch>> {
+        let mut files = self
+            .entries
+            .values()
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) => Some((entry, file_meta)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+        let mut copies: HashMap<&str, Vec<(&BOFEntry, &FileMetaData)>> = HashMap::new();
+        for file in files.iter().filter(|file| file.0.is_hashed()) {
+            copies.entry(&file.0.key).or_default().push(*file);
+        }
+
+        let mut deleted: HashSet<&Path> = HashSet::new();
+        let mut matches = Vec::new();
+        for (name, policy) in policies {
+            let selector = Selector::new(name, policy, now)?;
+            let selected = files
+                .iter()
+                .filter(|(entry, file_meta)| {
+                    !deleted.contains(entry.path.as_path())
+                        && selector.selects(&entry.path, &entry.labels, file_meta.mtime)
+                })
+                .copied()
+                .collect::<Vec<_>>();
+            let selected_paths = selected
+                .iter()
+                .map(|(entry, _)| entry.path.as_path())
+                .collect::<HashSet<_>>();
+            for (entry, file_meta) in selected {
+                let kept = if policy.duplicated {
+                    let Some(candidates) = copies.get(entry.key.as_str()) else {
+                        continue;
+                    };
+                    let copy = candidates.iter().find(|(other, other_meta)| {
+                        !selected_paths.contains(other.path.as_path())
+                            && !deleted.contains(other.path.as_path())
+                            && (file_meta.inode == 0
+                                || (other_meta.device, other_meta.inode)
+                                    != (file_meta.device, file_meta.inode))
+                    });
+                    match copy {
+                        Some((other, _)) => Some(other.path.clone()),
+                        None => continue,
+                    }
+                } else {
+                    None
+                };
+                if policy.action == PolicyAction::Delete {
+                    deleted.insert(&entry.path);
+                }
+                matches.push(PolicyMatch {
+                    policy: name.clone(),
+                    action: policy.action,
+                    path: entry.path.clone(),
+                    size: file_meta.size,
+                    kept,
+                    key: entry.key.clone(),
+                    mtime: file_meta.mtime,
+                });
+            }
+        }
+        Ok(matches)
+    }
+
+    // For every content in at least `min_copies` files, the copy `keep` picks and the other files
+    // having it, to replace by that copy. Hardlinks of the kept copy are left out, there is nothing
+    // to reclaim, and so are copies in a trash directory unless `trash`. Unless `across_owners`,
+    // copies are only matched with copies of the same owner, group and mode, as a hardlink has one
+    // of each for all its paths and deleting or linking another user's copy takes it from them.
+    pub(crate) fn dedupe_matches(
+        &self,
+        min_size: u64,
+        min_copies: u64,
+        keep: Keep,
+        trash: bool,
+        across_owners: bool,
+    ) -> Vec<DedupeMatch> {
+        let mut matches = Vec::new();
+        for group in self.duplicates(min_size, min_copies.max(2), None) {
+            let mut owners = BTreeMap::<_, Vec<_>>::new();
+            for (path, file_meta) in group
+                .paths
+                .iter()
+                .filter(|path| trash || !ignore::in_trash(path))
+                .filter_map(|path| match &self.entries.get(path)?.metadata {
+                    MetaData::File(file_meta) => Some((path, file_meta)),
+                    _ => None,
+                })
+            {
+                let owner = if across_owners {
+                    None
+                } else {
+                    owner(path, file_meta)
+                };
+                owners.entry(owner).or_default().push((path, file_meta));
+            }
+            // Copies left out or matched apart no longer count towards min_copies
+            for mut files in owners
+                .into_values()
+                .filter(|files| files.len() as u64 >= min_copies.max(2))
+            {
+                files.sort_by(|a, b| a.0.cmp(b.0));
+                // Ties go to the first in path order
+                let kept = match keep {
+                    Keep::First => files.first(),
+                    Keep::Oldest => files.iter().min_by_key(|(_, file_meta)| file_meta.mtime),
+                    Keep::Newest => files
+                        .iter()
+                        .rev()
+                        .max_by_key(|(_, file_meta)| file_meta.mtime),
+                };
+                let Some(&(kept, kept_meta)) = kept else {
+                    continue;
+                };
+                for &(path, file_meta) in &files {
+                    let hardlink = kept_meta.inode != 0
+                        && (file_meta.device, file_meta.inode)
+                            == (kept_meta.device, kept_meta.inode);
+                    if path == kept || hardlink {
+                        continue;
+                    }
+                    matches.push(DedupeMatch {
+                        path: path.clone(),
+                        kept: kept.clone(),
+                        size: file_meta.size,
+                        key: group.key.clone(),
+                        mtime: file_meta.mtime,
+                    });
+                }
+            }
+        }
+        matches
+    }
+
+    // Records that `path` was replaced by a file with the same content and modification time
+    pub(crate) fn replaced(&mut self, path: &Path, id: platform::FileId) {
+        if let Some(BOFEntry {
+            metadata: MetaData::File(file_meta),
+            ..
+        }) = self.entries.get_mut(path)
+        {
+            file_meta.device = id.device;
+            file_meta.inode = id.inode;
+        }
+    }
+
+    // Records that `path` was replaced by a hardlink of `kept`
+    pub(crate) fn link_to(&mut self, path: &Path, kept: &Path) {
+        let Some(metadata) = self.entries.get(kept).map(|entry| entry.metadata.clone()) else {
+            return;
+        };
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.metadata = metadata;
+        }
+    }
+
+    // What changed from this index to `other`. Files that disappeared with their content showing
+    // up under a new path are reported as moved.
+    pub fn diff(&self, other: &BOFIndex) -> Vec<Change> {
+        let is_file = |entry: &&BOFEntry| !matches!(entry.metadata, MetaData::Directory(_));
+        let mut changes = Vec::new();
+        let mut removed: HashMap<&str, Vec<&Path>> = HashMap::new();
+        let mut added = Vec::new();
+        for entry in self.entries.values().filter(is_file) {
+            match other.entries.get(&entry.path) {
+                Some(new) if !matches!(new.metadata, MetaData::Directory(_)) => {
+                    if new.key != entry.key || (!entry.is_hashed() && !same_file(entry, new)) {
+                        changes.push(Change::Modified(entry.path.clone()));
+                    } else if let (MetaData::File(old), MetaData::File(new)) =
+                        (&entry.metadata, &new.metadata)
+                    {
+                        if old.streams != new.streams {
+                            changes.push(Change::Streams(entry.path.clone()));
+                        } else if old.permissions.is_some()
+                            && new.permissions.is_some()
+                            && old.permissions != new.permissions
+                        {
+                            changes.push(Change::Permissions(entry.path.clone()));
+                        }
+                    }
+                }
+                // Without a hash a move can't be told from a delete
+                _ if !entry.is_hashed() => changes.push(Change::Deleted(entry.path.clone())),
+                _ => removed.entry(&entry.key).or_default().push(&entry.path),
+            }
+        }
+        for entry in other.entries.values().filter(is_file) {
+            if !matches!(self.entries.get(&entry.path), Some(old) if is_file(&old)) {
+                added.push(entry);
+            }
+        }
+
+        // Pair them up in path order, so the same two indexes always give the same moves
+        added.sort_by(|a, b| a.path.cmp(&b.path));
+        for paths in removed.values_mut() {
+            paths.sort();
+            paths.reverse();
+        }
+        for entry in added {
+            let from = if entry.is_hashed() {
+                removed.get_mut(entry.key.as_str()).and_then(Vec::pop)
+            } else {
+                None
+            };
+            match from {
+                Some(from) => changes.push(Change::Moved {
+                    from: from.to_path_buf(),
+                    to: entry.path.clone(),
+                }),
+                None => changes.push(Change::Added(entry.path.clone())),
+            }
+        }
+        for paths in removed.into_values() {
+            changes.extend(
+                paths
+                    .into_iter()
+                    .map(|path| Change::Deleted(path.to_path_buf())),
+            );
+        }
+
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        changes
+    }
+
+    fn duplicate_bytes(&self) -> u64 {
+        self.duplicates(0, 2, None)
+            .iter()
+            .map(DuplicateGroup::wasted)
+            .sum()
+    }
+
+    pub fn why_missing(&self, path: &Path) -> WhyMissing {
+        if self.entries.contains_key(path) {
+            return WhyMissing::Indexed;
+        }
+        if let Some(skipped) = self
+            .header
+            .skipped
+            .iter()
+            .find(|skipped| path.starts_with(&skipped.path))
+        {
+            return WhyMissing::Skipped(skipped.clone());
+        }
+        match self
+            .header
+            .roots
+            .iter()
+            .find(|root| path.starts_with(&root.path))
+        {
+            Some(root) => WhyMissing::NotFound(root.clone()),
+            None => WhyMissing::NotUnderRoot,
+        }
+    }
+
+    // What is indexed directly in `dir`, with everything deeper summed up into the directory
+    // holding it, or every entry below `dir` when recursive
+    pub fn list(&self, dir: &Path, recursive: bool) -> Vec<EntryInfo> {
+        let entries = self.entries(dir);
+        if recursive {
+            return entries;
+        }
+        let mut listed: Vec<EntryInfo> = Vec::new();
+        for entry in entries {
+            let mut components = entry
+                .path
+                .strip_prefix(dir)
+                .unwrap_or(&entry.path)
+                .components();
+            // `dir` itself, when it is a file, or an entry right in it
+            let (Some(first), Some(_)) = (components.next(), components.next()) else {
+                listed.push(entry);
+                continue;
+            };
+            // Entries are in path order, so those under one directory come one after another
+            let path = dir.join(first);
+            match listed.last_mut() {
+                Some(last) if last.kind == "directory" && last.path == path => {
+                    last.size = Some(last.size.unwrap_or(0) + entry.size.unwrap_or(0));
+                    last.mtime = last.mtime.max(entry.mtime);
+                }
+                _ => listed.push(EntryInfo {
+                    path,
+                    kind: "directory",
+                    key: UNHASHED.to_string(),
+                    size: Some(entry.size.unwrap_or(0)),
+                    mtime: entry.mtime,
+                    target: None,
+                    hashes: Hashes::new(),
+                    labels: Vec::new(),
+                }),
+            }
+        }
+        listed
+    }
+
+    // Indexed paths matching a glob, against the file name unless the pattern contains a separator,
+    // and carrying `label` and of type `mime` (e.g. `video` or `image/png`) if given
+    pub fn find(
+        &self,
+        pattern: &str,
+        label: Option<&str>,
+        mime: Option<&str>,
+    ) -> Result<Vec<PathBuf>> {
+        let matcher = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        let whole_path = pattern.contains('/');
+        let mut paths = self
+            .entries
+            .values()
+            .filter(|entry| label.is_none_or(|label| entry.labels.iter().any(|l| l == label)))
+            .filter(|entry| {
+                mime.is_none_or(|pattern| match &entry.metadata {
+                    MetaData::File(file_meta) => file_meta
+                        .mime
+                        .as_deref()
+                        .is_some_and(|mime| mime::matches(mime, pattern)),
+                    _ => false,
+                })
+            })
+            .map(|entry| &entry.path)
+            .filter(|path| {
+                if whole_path {
+                    matcher.is_match(path)
+                } else {
+                    path.file_name().is_some_and(|name| matcher.is_match(name))
+                }
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    // Indexed files and links matching an expression of `bof query`, in path order
+    pub fn filter(&self, filter: &Filter) -> Vec<PathBuf> {
+        let mut paths = self
+            .entries
+            .values()
+            .filter(|entry| filter.matches(&entry.subject()))
+            .map(|entry| entry.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    // Key of the file at `path`, hashing it only when an indexed file has its size, as no other
+    // can have its content
+    fn key_of_file(&self, path: &Path) -> Result<Option<String>> {
+        let size = fs::metadata(path)?.len();
+        let sized = self.entries.values().any(
+            |entry| matches!(&entry.metadata, MetaData::File(file_meta) if file_meta.size == size),
+        );
+        if !sized {
+            return Ok(None);
+        }
+        let algorithm = self.header.hash_algorithm.unwrap_or_default();
+        Ok(Some(algorithm.hash_file(path)?))
+    }
+
+    // Indexed files with the given key, or with the same content as the given file
+    pub fn lookup(&self, needle: &str) -> Result<Vec<PathBuf>> {
+        let file = Path::new(needle);
+        let key = if file.is_file() {
+            match self.key_of_file(file)? {
+                Some(key) => key,
+                None => return Ok(Vec::new()),
+            }
+        } else {
+            needle.to_lowercase()
+        };
+        Ok(self.files_with_key(&key))
+    }
+
+    // Files and links indexed under `subtree`, in path order
+    pub(crate) fn pack_items(&self, subtree: &Path) -> Vec<(PathBuf, pack::Item)> {
+        let mut items = self
+            .entries
+            .values()
+            .filter(|entry| entry.path.starts_with(subtree))
+            .filter_map(|entry| {
+                let item = match &entry.metadata {
+                    MetaData::Directory(_) => return None,
+                    MetaData::File(file_meta) => pack::Item::File {
+                        key: entry.key.clone(),
+                        size: file_meta.size,
+                        mtime: file_meta.mtime,
+                    },
+                    MetaData::Link(link_meta) => pack::Item::Link {
+                        target: link_meta.target.clone(),
+                    },
+                };
+                Some((entry.path.clone(), item))
+            })
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        items
+    }
+
+    // Keys of every indexed file, e.g. of content a pack can leave out
+    pub(crate) fn file_keys(&self) -> HashSet<&str> {
+        self.entries
+            .values()
+            .filter(|entry| matches!(entry.metadata, MetaData::File(_)) && entry.is_hashed())
+            .map(|entry| entry.key.as_str())
+            .collect()
+    }
+
+    // Hashed files with their key and type, in path order
+    pub(crate) fn preview_sources(&self) -> Vec<Source> {
+        let mut sources = self
+            .entries
+            .values()
+            .filter(|entry| entry.is_hashed())
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) => Some(Source {
+                    key: entry.key.clone(),
+                    path: entry.path.clone(),
+                    mime: file_meta.mime.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        sources.sort_by(|a, b| a.path.cmp(&b.path));
+        sources
+    }
+
+    // Every indexed file with its key as a checksum manifest, in path order
+    // The manifest lists the keys, or the extra hashes made with `algorithm`, hashed again with
+    // `salt` when there is one
+    pub fn manifest(
+        &self,
+        format: ManifestFormat,
+        algorithm: Option<HashAlgorithm>,
+        salt: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let key_algorithm = self.header.hash_algorithm.unwrap_or_default();
+        let (mut files, algorithm) = match algorithm.filter(|algorithm| *algorithm != key_algorithm)
+        {
+            None => (self.hashed_files(), key_algorithm),
+            Some(algorithm) => (self.files_hashed_with(algorithm), algorithm),
+        };
+        match salt {
+            None => manifest::lines(&files, format, algorithm),
+            Some("") => Err(BofError::Config(
+                "salting keys needs a key_salt in the config, the same as the other side's"
+                    .to_string(),
+            )),
+            Some(salt) => {
+                manifest::salt_keys(&mut files, salt);
+                manifest::salted_lines(&files, format, algorithm)
+            }
+        }
+    }
+
+    // Every indexed file in `dir` under the name of its key, with a mapping of the paths
+    pub fn cas_layout(&self, dir: &Path) -> Result<CasSummary> {
+        manifest::cas_layout(&self.hashed_files(), dir)
+    }
+
+    // Files catalogued without their hash have no place in a manifest
+    fn hashed_files(&self) -> Vec<ManifestFile> {
+        let (files, unhashed): (Vec<_>, Vec<_>) = self
+            .manifest_files()
+            .into_iter()
+            .partition(|file| file.key != UNHASHED);
+        if !unhashed.is_empty() {
+            events::emit(Event::Notice(format!(
+                "Leaving out {} files that aren't hashed yet, see `bof rehash`",
+                unhashed.len()
+            )));
+        }
+        files
+    }
+
+    // Files with an extra hash made with `algorithm`, under that hash rather than their key
+    fn files_hashed_with(&self, algorithm: HashAlgorithm) -> Vec<ManifestFile> {
+        let mut missing = 0;
+        let mut files = self
+            .entries
+            .values()
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) => match file_meta.hashes.get(&algorithm) {
+                    Some(hash) => Some(ManifestFile {
+                        path: entry.path.clone(),
+                        size: file_meta.size,
+                        mtime: file_meta.mtime,
+                        key: hash.clone(),
+                    }),
+                    None => {
+                        missing += 1;
+                        None
+                    }
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if missing > 0 {
+            events::emit(Event::Notice(format!(
+                "Leaving out {} files without a {} hash, see extra_hashes",
+                missing, algorithm
+            )));
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    pub(crate) fn manifest_files(&self) -> Vec<ManifestFile> {
+        let mut files = self
+            .entries
+            .values()
+            .filter_map(|entry| match &entry.metadata {
+                MetaData::File(file_meta) => Some(ManifestFile {
+                    path: entry.path.clone(),
+                    size: file_meta.size,
+                    mtime: file_meta.mtime,
+                    key: entry.key.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    // Indexed files with the same content as the file at `path`, other than itself
+    pub fn copies_of(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let Some(key) = self.key_of_file(path)? else {
+            return Ok(Vec::new());
+        };
+        let itself = fs::canonicalize(path)?;
+        let mut paths = self
+            .entries
+            .values()
+            .filter(|entry| matches!(entry.metadata, MetaData::File(_)) && entry.key == key)
+            .filter(|entry| {
+                entry.path != path && fs::canonicalize(&entry.path).ok().as_ref() != Some(&itself)
+            })
+            .map(|entry| entry.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
 }
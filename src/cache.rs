@@ -0,0 +1,134 @@
+use crate::bof::Hashes;
+use crate::error::{BofError, Result};
+use crate::hash::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Keys by what identifies a file's content without reading it, kept apart from the index so that
+// indexing into a fresh one, under another name or format, still finds the files it hashed before
+const CACHE_DIR: &str = "cache";
+// Entries no run has used for this many days are dropped when saving
+const MAX_AGE_DAYS: u32 = 90;
+
+// The file a key was made from, as long as none of it changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub(crate) struct Identity {
+    pub(crate) device: u64,
+    pub(crate) inode: u64,
+    pub(crate) size: u64,
+    pub(crate) mtime: SystemTime,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Cached {
+    key: String,
+    hashes: Hashes,
+    // Days since the epoch
+    used: u32,
+}
+
+pub(crate) struct HashCache {
+    path: PathBuf,
+    today: u32,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    keys: HashMap<Identity, Cached>,
+    changed: bool,
+}
+
+fn today() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    (secs / (24 * 60 * 60)) as u32
+}
+
+impl HashCache {
+    // One cache per key algorithm, as keys made with another can't be reused. A cache that is
+    // missing or can't be read starts empty.
+    pub(crate) fn load(output_dir: &Path, algorithm: HashAlgorithm) -> Self {
+        let path = output_dir
+            .join(CACHE_DIR)
+            .join(format!("{}.bin", algorithm));
+        let keys = File::open(&path)
+            .ok()
+            .and_then(|file| bincode::deserialize_from(BufReader::new(file)).ok())
+            .unwrap_or_default();
+        HashCache {
+            path,
+            today: today(),
+            entries: Mutex::new(Entries {
+                keys,
+                changed: false,
+            }),
+        }
+    }
+
+    // Files without an inode, on filesystems that have none, could be anything
+    pub(crate) fn get(&self, identity: &Identity) -> Option<(String, Hashes)> {
+        if identity.inode == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { keys, changed } = &mut *entries;
+        let cached = keys.get_mut(identity)?;
+        if cached.used != self.today {
+            cached.used = self.today;
+            *changed = true;
+        }
+        Some((cached.key.clone(), cached.hashes.clone()))
+    }
+
+    pub(crate) fn insert(&self, identity: Identity, key: &str, hashes: &Hashes) {
+        if identity.inode == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let unchanged = entries.keys.get(&identity).is_some_and(|cached| {
+            cached.used == self.today && cached.key == key && cached.hashes == *hashes
+        });
+        if !unchanged {
+            entries.keys.insert(
+                identity,
+                Cached {
+                    key: key.to_string(),
+                    hashes: hashes.clone(),
+                    used: self.today,
+                },
+            );
+            entries.changed = true;
+        }
+    }
+
+    // Writes the cache when anything was added or used for the first time today
+    pub(crate) fn save(&self) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.changed {
+            return Ok(());
+        }
+        let oldest = self.today.saturating_sub(MAX_AGE_DAYS);
+        entries.keys.retain(|_, cached| cached.used >= oldest);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self
+            .path
+            .with_extension(format!("tmp-{}", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        bincode::serialize_into(&mut writer, &entries.keys).map_err(BofError::serialization)?;
+        writer.into_inner().map_err(|e| e.into_error())?;
+        fs::rename(&tmp, &self.path)?;
+        entries.changed = false;
+        Ok(())
+    }
+}
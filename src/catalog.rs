@@ -0,0 +1,199 @@
+use crate::bof::{self, BOFConfig, DuplicateGroup};
+use crate::error::Result;
+use crate::storage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// Keys and the paths having them, split by the first byte of the key so a lookup reads 1/256th
+// of it and listing duplicates never reads the entries
+const CATALOG_DIR: &str = "catalog";
+const BUCKETS: usize = 256;
+
+// Which save of the index a bucket was written along with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CatalogFile {
+    pub(crate) path: PathBuf,
+    pub(crate) device: u64,
+    pub(crate) inode: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Content {
+    pub(crate) size: u64,
+    pub(crate) files: Vec<CatalogFile>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Bucket {
+    stamp: Option<Stamp>,
+    keys: BTreeMap<String, Content>,
+}
+
+fn catalog_dir(output_dir: &Path, name: &str) -> PathBuf {
+    storage::named(output_dir, name, CATALOG_DIR)
+}
+
+fn bucket_path(output_dir: &Path, name: &str, bucket: usize) -> PathBuf {
+    catalog_dir(output_dir, name).join(format!("{:02x}", bucket))
+}
+
+// Keys are lowercase hex, anything else isn't in the catalog
+fn bucket_of(key: &str) -> Option<usize> {
+    key.get(..2)
+        .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+}
+
+fn stamp(output_dir: &Path, name: &str) -> Option<Stamp> {
+    let metadata = fs::metadata(storage::find_index(output_dir, name)).ok()?;
+    Some(Stamp {
+        len: metadata.len(),
+        modified: metadata.modified().ok()?,
+    })
+}
+
+// Writes every bucket for the index just saved, each replaced at once like the index itself
+pub(crate) fn write(
+    output_dir: &Path,
+    contents: impl IntoIterator<Item = (String, Content)>,
+    config: &BOFConfig,
+) -> Result<()> {
+    let mut buckets = (0..BUCKETS)
+        .map(|_| Bucket {
+            stamp: stamp(output_dir, config.index_name()),
+            keys: BTreeMap::new(),
+        })
+        .collect::<Vec<_>>();
+    for (key, content) in contents {
+        if let Some(bucket) = bucket_of(&key) {
+            buckets[bucket].keys.insert(key, content);
+        }
+    }
+
+    let dir = catalog_dir(output_dir, config.index_name());
+    fs::create_dir_all(&dir)?;
+    for (n, bucket) in buckets.iter().enumerate() {
+        let path = bucket_path(output_dir, config.index_name(), n);
+        let tmp = dir.join(format!(".{:02x}.tmp-{}", n, std::process::id()));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        // Only ever read by BOF, so never indented
+        storage::write(
+            &mut writer,
+            bucket,
+            config.index_format,
+            config.compression,
+            false,
+        )?;
+        writer.into_inner().map_err(io::Error::from)?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(())
+}
+
+// Without the setting a catalog left behind would only go stale
+pub(crate) fn remove(output_dir: &Path, name: &str) -> Result<()> {
+    match fs::remove_dir_all(catalog_dir(output_dir, name)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// The bucket, unless it is missing or wasn't written along with the current index
+fn read_bucket(
+    output_dir: &Path,
+    name: &str,
+    bucket: usize,
+    stamp: Stamp,
+) -> Result<Option<Bucket>> {
+    let file = match File::open(bucket_path(output_dir, name, bucket)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let bucket: Bucket = storage::read(BufReader::new(file))?;
+    Ok((bucket.stamp == Some(stamp)).then_some(bucket))
+}
+
+// Indexed files with the given key, None when there is no up to date catalog to tell
+pub fn lookup(output_dir: &Path, name: &str, key: &str) -> Result<Option<Vec<PathBuf>>> {
+    let key = key.to_lowercase();
+    let (Some(bucket), Some(stamp)) = (bucket_of(&key), stamp(output_dir, name)) else {
+        return Ok(None);
+    };
+    Ok(
+        read_bucket(output_dir, name, bucket, stamp)?.map(|mut bucket| {
+            let mut paths = bucket
+                .keys
+                .remove(&key)
+                .map(|content| content.files.into_iter().map(|file| file.path).collect())
+                .unwrap_or_else(Vec::new);
+            paths.sort();
+            paths
+        }),
+    )
+}
+
+// Same as BOFIndex::duplicates without labels, None when there is no up to date catalog. Buckets
+// are read and grouped in parallel, a bucket at a time per thread.
+pub fn duplicates(
+    output_dir: &Path,
+    name: &str,
+    min_size: u64,
+    min_copies: u64,
+) -> Result<Option<Vec<DuplicateGroup>>> {
+    let Some(stamp) = stamp(output_dir, name) else {
+        return Ok(None);
+    };
+    let buckets = (0..BUCKETS)
+        .into_par_iter()
+        .map(|n| {
+            Ok(read_bucket(output_dir, name, n, stamp)?
+                .map(|bucket| bucket_duplicates(bucket, min_size, min_copies)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let Some(buckets) = buckets.into_iter().collect::<Option<Vec<_>>>() else {
+        return Ok(None);
+    };
+    let mut groups = buckets.into_iter().flatten().collect::<Vec<_>>();
+    bof::sort_duplicates(&mut groups);
+    Ok(Some(groups))
+}
+
+fn bucket_duplicates(bucket: Bucket, min_size: u64, min_copies: u64) -> Vec<DuplicateGroup> {
+    let mut groups = Vec::new();
+    for (key, content) in bucket.keys {
+        if content.size < min_size || content.files.len() < 2 {
+            continue;
+        }
+        let mut group = DuplicateGroup {
+            key,
+            size: content.size,
+            copies: 0,
+            paths: Vec::with_capacity(content.files.len()),
+        };
+        let mut inodes = HashSet::with_capacity(content.files.len());
+        for file in content.files {
+            // Without an inode every path has to be taken for a copy of its own
+            if file.inode == 0 || inodes.insert((file.device, file.inode)) {
+                group.copies += 1;
+            }
+            group.paths.push(file.path);
+        }
+        if group.copies >= min_copies.max(2) {
+            groups.push(group);
+        }
+    }
+    groups
+}
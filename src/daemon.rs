@@ -0,0 +1,358 @@
+use crate::bof::{self, BOFConfig, BOFIndex};
+use crate::error::{BofError, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+const SOCKET_FILE: &str = "daemon.sock";
+
+// Held by the update running, so that updates requested meanwhile wait for it instead of walking
+// the same files at the same time
+static UPDATING: Mutex<()> = Mutex::new(());
+
+// What `call` answers
+pub const METHODS: &[&str] = &[
+    "find",
+    "lookup",
+    "duplicates",
+    "hardlinks",
+    "roots",
+    "summary",
+    "update",
+    "reload",
+    "shutdown",
+];
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+pub(crate) fn socket_path(output_dir: &Path) -> PathBuf {
+    output_dir.join(SOCKET_FILE)
+}
+
+// One JSON-RPC 2.0 request per line, answered by one response per line
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindParams {
+    pattern: String,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    mime: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupParams {
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DuplicatesParams {
+    #[serde(default)]
+    min_size: u64,
+    #[serde(default)]
+    min_copies: u64,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootsParams {
+    #[serde(default)]
+    mine: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateParams {
+    paths: Vec<PathBuf>,
+}
+
+type RpcResult = std::result::Result<Value, (i64, String)>;
+
+fn params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, (i64, String)> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn server_error(e: impl std::fmt::Display) -> (i64, String) {
+    (SERVER_ERROR, e.to_string())
+}
+
+fn to_value<T: Serialize>(value: &T) -> RpcResult {
+    serde_json::to_value(value).map_err(server_error)
+}
+
+// Answers a request from the resident index, reloading it first if it was saved meanwhile
+fn call(request: Request, index: &std::sync::RwLock<BOFIndex>, config: &BOFConfig) -> RpcResult {
+    if index
+        .read()
+        .unwrap()
+        .is_stale(&config.output_dir, config.index_name())
+        || request.method == "reload"
+    {
+        *index.write().unwrap() =
+            BOFIndex::load(&config.output_dir, config.index_name()).map_err(server_error)?;
+    }
+    match request.method.as_str() {
+        "find" => {
+            let FindParams {
+                pattern,
+                label,
+                mime,
+            } = params(request.params)?;
+            let paths = index
+                .read()
+                .unwrap()
+                .find(&pattern, label.as_deref(), mime.as_deref())
+                .map_err(server_error)?;
+            to_value(&paths)
+        }
+        "lookup" => {
+            let LookupParams { hash } = params(request.params)?;
+            let paths = index.read().unwrap().lookup(&hash).map_err(server_error)?;
+            to_value(&paths)
+        }
+        "duplicates" => {
+            let DuplicatesParams {
+                min_size,
+                min_copies,
+                label,
+            } = params(request.params)?;
+            to_value(
+                &index
+                    .read()
+                    .unwrap()
+                    .duplicates(min_size, min_copies, label.as_deref()),
+            )
+        }
+        "hardlinks" => to_value(&index.read().unwrap().hardlinks()),
+        "roots" => {
+            let RootsParams { mine } = params(request.params)?;
+            to_value(&index.read().unwrap().roots(mine))
+        }
+        "summary" => to_value(&index.read().unwrap().summary()),
+        // Walks the paths like `bof update`, then serves the saved result
+        "update" => {
+            let UpdateParams { paths } = params(request.params)?;
+            let _turn = UPDATING.lock().unwrap_or_else(PoisonError::into_inner);
+            bof::update_directories(paths, config).map_err(server_error)?;
+            *index.write().unwrap() =
+                BOFIndex::load(&config.output_dir, config.index_name()).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "reload" | "shutdown" => Ok(Value::Null),
+        method => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+    }
+}
+
+fn response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err((code, message)) => {
+            json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use unix::{query, serve};
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use crate::events::{self, Event};
+    use crate::memory::{self, Monitor};
+    use crate::session::SessionTally;
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        sync::{Arc, OnceLock, RwLock},
+        thread,
+    };
+
+    // Why the daemon stops, once something asked it to
+    type Stop = Arc<OnceLock<&'static str>>;
+
+    // Keeps the index in memory and answers requests on the socket until told to shut down or
+    // interrupted, then reports and logs what its updates applied
+    pub(crate) fn serve(config: &BOFConfig, drift: bool) -> Result<()> {
+        let path = socket_path(&config.output_dir);
+        if path.exists() {
+            // A socket that still answers belongs to a running daemon, otherwise it was left behind
+            if UnixStream::connect(&path).is_ok() {
+                return Err(BofError::Daemon(format!(
+                    "Already running on {}",
+                    path.display()
+                )));
+            }
+            fs::remove_file(&path)?;
+        }
+        let index = Arc::new(RwLock::new(BOFIndex::load(
+            &config.output_dir,
+            config.index_name(),
+        )?));
+        let listener = UnixListener::bind(&path)?;
+        let stop: Stop = Arc::new(OnceLock::new());
+        let handler_stop = stop.clone();
+        let socket = path.clone();
+        ctrlc::set_handler(move || wake(&handler_stop, "interrupted", &socket))
+            .map_err(|e| BofError::Daemon(e.to_string()))?;
+        let tally = SessionTally::start("daemon");
+        events::emit(Event::Notice(format!("Listening on {}", path.display())));
+
+        if drift {
+            let roots = index
+                .read()
+                .unwrap()
+                .roots(false)
+                .into_iter()
+                .map(|root| root.path)
+                .collect();
+            let config = config.clone();
+            thread::spawn(move || {
+                if let Err(e) = crate::drift::track(roots, &config) {
+                    events::emit(Event::Error(format!(
+                        "Failed to keep count of changes: {}",
+                        e
+                    )));
+                }
+            });
+        }
+
+        let resident = index.clone();
+        let mut monitor = Monitor::new(config);
+        let (output_dir, name) = (config.output_dir.clone(), config.index_name().to_string());
+        thread::spawn(move || loop {
+            thread::sleep(memory::CHECK_EVERY);
+            if !monitor.check(&resident.read().unwrap()) {
+                continue;
+            }
+            // Requests only ever read the index, so what was saved is all there is to keep
+            match BOFIndex::load(&output_dir, &name) {
+                Ok(mut loaded) => {
+                    loaded.compact();
+                    monitor.compacted(&loaded);
+                    *resident.write().unwrap() = loaded;
+                }
+                Err(e) => events::emit(Event::Error(format!("Failed to reload the index: {}", e))),
+            }
+        });
+
+        for stream in listener.incoming() {
+            if stop.get().is_some() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let index = index.clone();
+                    let config = config.clone();
+                    let stop = stop.clone();
+                    let socket = path.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &index, &config, &stop, &socket) {
+                            events::emit(Event::Error(format!("Daemon connection failed: {}", e)));
+                        }
+                    });
+                }
+                Err(e) => events::emit(Event::Error(format!("Daemon connection failed: {}", e))),
+            }
+        }
+        let _ = fs::remove_file(&path);
+        tally.finish(config, stop.get().copied().unwrap_or("stopped"));
+        Ok(())
+    }
+
+    // Connecting wakes up the loop accepting connections, so that it sees it has to stop
+    fn wake(stop: &Stop, reason: &'static str, socket: &Path) {
+        let _ = stop.set(reason);
+        let _ = UnixStream::connect(socket);
+    }
+
+    fn handle(
+        stream: UnixStream,
+        index: &RwLock<BOFIndex>,
+        config: &BOFConfig,
+        stop: &Stop,
+        socket: &Path,
+    ) -> std::io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (id, result, shutdown) = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let shutdown = request.method == "shutdown";
+                    (request.id.clone(), call(request, index, config), shutdown)
+                }
+                Err(e) => (Value::Null, Err((PARSE_ERROR, e.to_string())), false),
+            };
+            writeln!(writer, "{}", response(id, result))?;
+            writer.flush()?;
+            if shutdown {
+                wake(stop, "shut down", socket);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends one request to the daemon serving `output_dir` and returns its result
+    pub(crate) fn query(output_dir: &Path, method: &str, params: Value) -> Result<Value> {
+        let path = socket_path(output_dir);
+        let stream = UnixStream::connect(&path).map_err(|e| {
+            BofError::Daemon(format!(
+                "No daemon on {} ({}), start one with `bof daemon`",
+                path.display(),
+                e
+            ))
+        })?;
+        let mut writer = stream.try_clone()?;
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        writeln!(writer, "{}", request)?;
+        writer.flush()?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        let mut response: Value = serde_json::from_str(&line).map_err(BofError::serialization)?;
+        match response.get("error") {
+            Some(error) => Err(BofError::Daemon(
+                error["message"]
+                    .as_str()
+                    .unwrap_or("unknown error")
+                    .to_string(),
+            )),
+            None => Ok(response["result"].take()),
+        }
+    }
+}
+
+// Unix domain sockets are the only transport so far
+#[cfg(not(unix))]
+pub(crate) fn serve(_config: &BOFConfig, _drift: bool) -> Result<()> {
+    Err(BofError::Daemon(
+        "Not supported on this platform".to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn query(_output_dir: &Path, _method: &str, _params: Value) -> Result<Value> {
+    Err(BofError::Daemon(
+        "Not supported on this platform".to_string(),
+    ))
+}
@@ -0,0 +1,453 @@
+use crate::bof::{self, BOFConfig, BOFIndex, DuplicateGroup};
+use crate::error::Result;
+use crate::events::{self, Event};
+use crate::hash::{HashPolicy, Strategy};
+use crate::ignore::IgnoreRules;
+use crate::platform::{self, FileId};
+use rayon::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const DEDUPE_LOG: &str = "dedupe.log";
+
+// What becomes of the copies of a content other than the one kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupeAction {
+    // Only possible on the filesystem of the kept copy
+    Hardlink,
+    // Copy-on-write clone of the kept copy, still a file of its own, on Btrfs, XFS and the like
+    Reflink,
+    Symlink,
+    Delete,
+}
+
+impl fmt::Display for DedupeAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DedupeAction::Hardlink => write!(f, "hardlink"),
+            DedupeAction::Reflink => write!(f, "reflink"),
+            DedupeAction::Symlink => write!(f, "symlink"),
+            DedupeAction::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+// Which copy of a content is kept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Keep {
+    Newest,
+    Oldest,
+    // The first in path order
+    #[default]
+    First,
+}
+
+// An indexed copy to replace by the kept one
+#[derive(Clone, Debug)]
+pub struct DedupeMatch {
+    pub path: PathBuf,
+    pub kept: PathBuf,
+    pub size: u64,
+    // What the index recorded, checked again before acting
+    pub(crate) key: String,
+    pub(crate) mtime: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Planned,
+    Done,
+    // Left alone because the files no longer look like what was indexed, or can't be linked
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Deduped {
+    pub action: DedupeAction,
+    pub matched: DedupeMatch,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Deduped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let done = match self.action {
+            DedupeAction::Hardlink => "hardlinked",
+            DedupeAction::Reflink => "reflinked",
+            DedupeAction::Symlink => "symlinked",
+            DedupeAction::Delete => "deleted",
+        };
+        write!(f, "{}\t", self.matched.path.display())?;
+        match &self.outcome {
+            Outcome::Planned => write!(f, "would {}", self.action)?,
+            Outcome::Done => write!(f, "{}", done)?,
+            Outcome::Skipped(reason) => write!(f, "skipped: {}", reason)?,
+            Outcome::Failed(e) => write!(f, "failed: {}", e)?,
+        }
+        write!(f, " (keeping {})", self.matched.kept.display())
+    }
+}
+
+// Replaces or deletes every indexed copy of a content but the one `keep` picks, among copies of
+// the same owner, group and mode unless `across_owners`. Unless `dry_run`, logs every action to
+// dedupe.log and records the changes in the index.
+pub fn run(
+    action: DedupeAction,
+    keep: Keep,
+    min_size: u64,
+    min_copies: u64,
+    dry_run: bool,
+    across_owners: bool,
+    config: &BOFConfig,
+) -> Result<Vec<Deduped>> {
+    let mut index = BOFIndex::load(&config.output_dir, config.index_name())?;
+    let matches = index.dedupe_matches(
+        min_size,
+        min_copies,
+        keep,
+        config.include_trash,
+        across_owners,
+    );
+    if dry_run {
+        return Ok(matches
+            .into_iter()
+            .map(|matched| Deduped {
+                action,
+                matched,
+                outcome: Outcome::Planned,
+            })
+            .collect());
+    }
+
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config.output_dir.join(DEDUPE_LOG))?;
+    let open = open_files(config);
+    // Each kept copy is checked once, however many copies it replaces
+    let mut kept = HashMap::new();
+    let mut deduped = Vec::new();
+    let mut changed = false;
+    for matched in matches {
+        let outcome = apply(
+            action,
+            &matched,
+            &index,
+            &mut kept,
+            open.as_ref(),
+            across_owners,
+            &config.hashing,
+        );
+        if let Outcome::Done = outcome {
+            match action {
+                DedupeAction::Hardlink => index.link_to(&matched.path, &matched.kept),
+                DedupeAction::Reflink => {
+                    if let Ok(metadata) = fs::symlink_metadata(&matched.path) {
+                        index.replaced(&matched.path, platform::file_id(&matched.path, &metadata));
+                    }
+                }
+                DedupeAction::Symlink | DedupeAction::Delete => index.forget(&matched.path),
+            }
+            changed = true;
+        }
+        let entry = Deduped {
+            action,
+            matched,
+            outcome,
+        };
+        writeln!(
+            log,
+            "{}\t{}\t{}",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            action,
+            entry
+        )?;
+        deduped.push(entry);
+    }
+    log.sync_all()?;
+
+    if changed {
+        index.save(config)?;
+    }
+    Ok(deduped)
+}
+
+// Mode bits, owner and group of a file, where the platform has them
+type Owner = Option<(u32, u32, u32)>;
+
+// Acts on the copy if it is still what was indexed and the kept copy still has the same content,
+// and the same owner unless `across_owners`. The kept copy itself is never written to.
+fn apply(
+    action: DedupeAction,
+    matched: &DedupeMatch,
+    index: &BOFIndex,
+    kept: &mut HashMap<PathBuf, std::result::Result<(FileId, Owner), String>>,
+    open: Option<&HashSet<FileId>>,
+    across_owners: bool,
+    policy: &HashPolicy,
+) -> Outcome {
+    let metadata = match fs::symlink_metadata(&matched.path) {
+        Ok(metadata)
+            if metadata.is_file()
+                && metadata.len() == matched.size
+                && metadata.modified().ok() == Some(matched.mtime) =>
+        {
+            metadata
+        }
+        Ok(_) => return Outcome::Skipped("changed since it was indexed".to_string()),
+        Err(e) => return Outcome::Skipped(e.to_string()),
+    };
+    let id = platform::file_id(&matched.path, &metadata);
+    if open.is_some_and(|open| open.contains(&id)) {
+        return Outcome::Skipped("open by another process".to_string());
+    }
+    let (kept_id, kept_owner) = match kept
+        .entry(matched.kept.clone())
+        .or_insert_with(|| check_kept(matched, index, policy))
+    {
+        Ok(kept) => *kept,
+        Err(reason) => return Outcome::Skipped(reason.clone()),
+    };
+    if !across_owners && platform::owner(&metadata) != kept_owner {
+        return Outcome::Skipped(
+            "another owner, group or mode than the kept copy, see --across-owners".to_string(),
+        );
+    }
+    if id.inode != 0 && id == kept_id {
+        return Outcome::Skipped("already a hardlink of the kept copy".to_string());
+    }
+    let linked = matches!(action, DedupeAction::Hardlink | DedupeAction::Reflink);
+    if linked && id.device != kept_id.device {
+        return Outcome::Skipped("on another filesystem than the kept copy".to_string());
+    }
+
+    let result = match action {
+        DedupeAction::Delete => fs::remove_file(&matched.path),
+        DedupeAction::Hardlink => replace(&matched.path, |tmp| fs::hard_link(&matched.kept, tmp)),
+        DedupeAction::Reflink => replace(&matched.path, |tmp| {
+            platform::reflink(&matched.kept, tmp)?;
+            // Unlike a hardlink it keeps its own permissions and modification time
+            let file = File::options().write(true).open(tmp)?;
+            file.set_permissions(metadata.permissions())?;
+            file.set_modified(matched.mtime)
+        }),
+        // Absolute, so the link doesn't depend on where either copy is
+        DedupeAction::Symlink => fs::canonicalize(&matched.kept)
+            .and_then(|target| replace(&matched.path, |tmp| platform::symlink(&target, tmp))),
+    };
+    match result {
+        Ok(()) => Outcome::Done,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+// Identity and owner of the kept copy, if it is still a file with the content that was indexed
+fn check_kept(
+    matched: &DedupeMatch,
+    index: &BOFIndex,
+    policy: &HashPolicy,
+) -> std::result::Result<(FileId, Owner), String> {
+    let kept = &matched.kept;
+    let metadata = match fs::symlink_metadata(kept) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Err(format!("{} is no longer a file", kept.display())),
+        Err(e) => return Err(format!("{}: {}", kept.display(), e)),
+    };
+    let Some(algorithm) = index.hash_algorithm() else {
+        return Err("the index doesn't record its hash algorithm".to_string());
+    };
+    match policy.hash_file(algorithm, kept) {
+        Ok(key) if key == matched.key => Ok((
+            platform::file_id(kept, &metadata),
+            platform::owner(&metadata),
+        )),
+        Ok(_) => Err(format!("{} changed since it was indexed", kept.display())),
+        Err(e) => Err(format!("{}: {}", kept.display(), e)),
+    }
+}
+
+// Files other processes have open, left alone with `skip_open` so running programs don't break
+pub(crate) fn open_files(config: &BOFConfig) -> Option<HashSet<FileId>> {
+    if !config.skip_open {
+        return None;
+    }
+    let open = platform::open_files();
+    if open.is_none() {
+        events::emit(Event::Notice(
+            "Open files can't be told on this platform, skip_open has no effect".to_string(),
+        ));
+    }
+    open
+}
+
+// Puts what `create` makes at a temporary path in place of `path` with a rename, so there is
+// never a moment without a file there
+fn replace(path: &Path, create: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.dedupe-{}", name, std::process::id()));
+    create(&tmp)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+// A file on disk, under every path that is a hardlink of it
+struct Candidate {
+    size: u64,
+    paths: Vec<PathBuf>,
+}
+
+// Finds duplicates under `paths` without an index, reading as little as possible: only files
+// sharing their size with another are read, first at both ends, and only those still alike
+// after that are hashed in full
+pub(crate) fn scan(
+    paths: &[PathBuf],
+    min_size: u64,
+    min_copies: u64,
+    config: &BOFConfig,
+) -> Vec<DuplicateGroup> {
+    let rules = IgnoreRules::new(config);
+    let mut files = Vec::new();
+    for path in paths {
+        bof::scan_files(path, config, &rules, &mut files);
+    }
+    let scanned = files.len();
+
+    let mut by_id: HashMap<platform::FileId, Candidate> = HashMap::new();
+    let mut candidates = Vec::new();
+    for (path, metadata) in files {
+        if metadata.len() < min_size {
+            continue;
+        }
+        let id = platform::file_id(&path, &metadata);
+        // Without an inode every path has to be taken for a file of its own
+        if id.inode == 0 {
+            candidates.push(Candidate {
+                size: metadata.len(),
+                paths: vec![path],
+            });
+        } else {
+            by_id
+                .entry(id)
+                .or_insert_with(|| Candidate {
+                    size: metadata.len(),
+                    paths: Vec::new(),
+                })
+                .paths
+                .push(path);
+        }
+    }
+    candidates.extend(by_id.into_values());
+
+    let algorithm = config.hash_algorithm;
+    let policy = &config.hashing;
+    let same_size = alike(
+        candidates
+            .into_iter()
+            .map(|candidate| (candidate.size, candidate)),
+    );
+    let read = same_size.len();
+    // Small files are hashed in full right away, reading their ends would read all of them anyway
+    let (small, large): (Vec<_>, Vec<_>) = same_size
+        .into_iter()
+        .partition(|candidate| policy.strategy(candidate.size) == Strategy::Full);
+    let same_ends = alike(hash_all(large, |candidate| {
+        policy
+            .partial_hash(algorithm, &candidate.paths[0], candidate.size)
+            .map(Option::unwrap_or_default)
+    }));
+    let hashed = small.len() + same_ends.len();
+    let mut groups = alike_keyed(hash_all(
+        small.into_iter().chain(same_ends).collect(),
+        |candidate| policy.hash_file(algorithm, &candidate.paths[0]),
+    ))
+    .into_iter()
+    .fold(
+        HashMap::<String, DuplicateGroup>::new(),
+        |mut groups, ((_, key), candidate)| {
+            let group = groups.entry(key.clone()).or_insert_with(|| DuplicateGroup {
+                key,
+                size: candidate.size,
+                copies: 0,
+                paths: Vec::new(),
+            });
+            group.copies += 1;
+            group.paths.extend(candidate.paths);
+            groups
+        },
+    )
+    .into_values()
+    .filter(|group| group.copies >= min_copies.max(2))
+    .collect::<Vec<_>>();
+    bof::sort_duplicates(&mut groups);
+
+    events::emit(Event::Notice(format!(
+        "Scanned {} files: {} shared their size with another, {} were hashed in full",
+        scanned, read, hashed
+    )));
+    groups
+}
+
+// Candidates whose key is also the key of another one
+fn alike<K, I>(keyed: I) -> Vec<Candidate>
+where
+    K: std::hash::Hash + Eq + Clone,
+    I: IntoIterator<Item = (K, Candidate)>,
+{
+    alike_keyed(keyed)
+        .into_iter()
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+// The same, along with their key
+fn alike_keyed<K, I>(keyed: I) -> Vec<(K, Candidate)>
+where
+    K: std::hash::Hash + Eq + Clone,
+    I: IntoIterator<Item = (K, Candidate)>,
+{
+    let mut groups: HashMap<K, Vec<Candidate>> = HashMap::new();
+    for (key, candidate) in keyed {
+        groups.entry(key).or_default().push(candidate);
+    }
+    groups
+        .into_iter()
+        .filter(|(_, candidates)| candidates.len() > 1)
+        .flat_map(|(key, candidates)| {
+            candidates
+                .into_iter()
+                .map(move |candidate| (key.clone(), candidate))
+        })
+        .collect()
+}
+
+// Hashes the candidates in parallel, leaving out those that can't be read. The size goes in
+// the key too, a partial hash only covers part of the file.
+fn hash_all<F>(candidates: Vec<Candidate>, hash: F) -> Vec<((u64, String), Candidate)>
+where
+    F: Fn(&Candidate) -> io::Result<String> + Sync,
+{
+    candidates
+        .into_par_iter()
+        .filter_map(|candidate| match hash(&candidate) {
+            Ok(key) => Some(((candidate.size, key), candidate)),
+            Err(e) => {
+                events::emit(Event::Error(format!(
+                    "Failed to read {}: {}",
+                    candidate.paths[0].display(),
+                    e
+                )));
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,143 @@
+use crate::bof::{self, BOFConfig};
+use crate::error::Result;
+use crate::storage;
+use crate::watch::{relative_to_roots, watch_error};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+const DRIFT_FILE: &str = "drift.count";
+// The count on disk is brought up to date once things have been quiet for this long, or after
+// MAX_DELAY while they keep changing
+const QUIET: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+// What `bof prompt-info` reads, so it never has to walk anything
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+struct Saved {
+    changed: u64,
+    // When the index the changes are counted against was saved
+    index_saved: Option<SystemTime>,
+}
+
+fn index_saved(output_dir: &Path, name: &str) -> Option<SystemTime> {
+    fs::metadata(storage::find_index(output_dir, name))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// How many paths changed under the indexed roots since the index was saved, None when neither
+// `bof watch` nor `bof daemon --drift` keeps count. Only reads a couple of small files.
+pub(crate) fn drift(output_dir: &Path, name: &str) -> Option<u64> {
+    let saved: Saved = serde_json::from_slice(&fs::read(output_dir.join(DRIFT_FILE)).ok()?).ok()?;
+    // Saved again since, by an update that took the changes in
+    if saved.index_saved != index_saved(output_dir, name) {
+        return Some(0);
+    }
+    Some(saved.changed)
+}
+
+// Paths changed since the index was last saved, with when they last did
+pub(crate) struct Tracker {
+    output_dir: PathBuf,
+    name: String,
+    changed: HashMap<PathBuf, SystemTime>,
+    index_saved: Option<SystemTime>,
+    written: Option<Saved>,
+}
+
+impl Tracker {
+    pub(crate) fn new(config: &BOFConfig) -> Self {
+        Tracker {
+            output_dir: config.output_dir.clone(),
+            name: config.index_name().to_string(),
+            changed: HashMap::new(),
+            index_saved: index_saved(&config.output_dir, config.index_name()),
+            written: None,
+        }
+    }
+
+    pub(crate) fn changed(&mut self, path: PathBuf) {
+        self.changed.insert(path, SystemTime::now());
+    }
+
+    // Writes the count for `bof prompt-info` when it is not what was written last
+    pub(crate) fn save(&mut self) {
+        let index_saved = index_saved(&self.output_dir, &self.name);
+        if index_saved != self.index_saved {
+            // Only what changed after the save is still missing from the index
+            if let Some(index_saved) = index_saved {
+                self.changed.retain(|_, changed| *changed > index_saved);
+            }
+            self.index_saved = index_saved;
+        }
+        let saved = Saved {
+            changed: self.changed.len() as u64,
+            index_saved,
+        };
+        if self.written.as_ref() == Some(&saved) {
+            return;
+        }
+        let path = self.output_dir.join(DRIFT_FILE);
+        let tmp = path.with_extension("tmp");
+        let written = serde_json::to_vec(&saved)
+            .map_err(std::io::Error::other)
+            .and_then(|contents| fs::write(&tmp, contents))
+            .and_then(|_| fs::rename(&tmp, &path));
+        // Tried again with the next change
+        if written.is_ok() {
+            self.written = Some(saved);
+        }
+    }
+}
+
+// Counts what changed under `roots` for as long as the process runs: what `bof status` finds
+// first, then every path the filesystem reports as changed
+pub(crate) fn track(roots: Vec<PathBuf>, config: &BOFConfig) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    let mut canonical = Vec::new();
+    for root in &roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+        canonical.push((fs::canonicalize(root)?, root.clone()));
+    }
+    let output_dir = fs::canonicalize(&config.output_dir)?;
+
+    let mut tracker = Tracker::new(config);
+    for change in bof::status(roots, config)? {
+        tracker.changed(change.path().to_path_buf());
+    }
+    tracker.save();
+    let mut saved = Instant::now();
+    loop {
+        let quiet = match receiver.recv_timeout(QUIET) {
+            Ok(Ok(event)) if !matches!(event.kind, EventKind::Access(_)) => {
+                for path in event.paths {
+                    // Saving the index shows up as changes too
+                    if path.starts_with(&output_dir) {
+                        continue;
+                    }
+                    if let Some(path) = relative_to_roots(&path, &canonical) {
+                        tracker.changed(path);
+                    }
+                }
+                false
+            }
+            Ok(_) => false,
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if quiet || saved.elapsed() >= MAX_DELAY {
+            tracker.save();
+            saved = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::{io, path::PathBuf};
+
+pub type Result<T> = std::result::Result<T, BofError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BofError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    // The index couldn't be encoded or decoded in its format
+    #[error("Invalid index data: {0}")]
+    Serialization(String),
+    // The index file doesn't match its checksum, e.g. a truncated copy
+    #[error("{} is damaged: {reason}", path.display())]
+    Corrupt { path: PathBuf, reason: String },
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    // Walking a tree failed at its root, errors below it are reported as events
+    #[error("Failed to walk {}: {source}", path.display())]
+    Traversal { path: PathBuf, source: io::Error },
+    // Reported by, or about reaching, a running `bof daemon`
+    #[error("Daemon error: {0}")]
+    Daemon(String),
+    #[error("Invalid pattern: {0}")]
+    Pattern(#[from] globset::Error),
+    // An expression of `bof query` that doesn't parse
+    #[error("Invalid query: {0}")]
+    Query(String),
+    // Another process holds .bof/lock, see storage::lock
+    #[error(
+        "Another bof{} is changing the index",
+        pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+    )]
+    Locked { pid: Option<u32> },
+}
+
+impl BofError {
+    pub(crate) fn serialization(e: impl std::fmt::Display) -> Self {
+        BofError::Serialization(e.to_string())
+    }
+
+    pub(crate) fn config(e: impl std::fmt::Display) -> Self {
+        BofError::Config(e.to_string())
+    }
+
+    pub(crate) fn traversal(path: &std::path::Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| BofError::Traversal {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use serde_json::json;
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+// Progress of a traversal, consumed by whatever is rendering it (plain prints, dashboard, ...)
+#[derive(Debug)]
+pub enum Event {
+    Estimate { files: u64, bytes: u64 },
+    Directory(PathBuf),
+    Indexed { path: PathBuf, size: u64 },
+    Updated { path: PathBuf, size: u64 },
+    Unchanged(PathBuf),
+    Renamed { from: PathBuf, to: PathBuf },
+    // Dropped from the index, the file being gone
+    Removed(PathBuf),
+    Verified { path: PathBuf, size: u64 },
+    Ignored(PathBuf),
+    Notice(String),
+    Error(String),
+    Saved(PathBuf),
+}
+
+pub trait Subscriber: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+static SUBSCRIBERS: RwLock<Vec<Arc<dyn Subscriber>>> = RwLock::new(Vec::new());
+
+pub fn subscribe(subscriber: Arc<dyn Subscriber>) {
+    SUBSCRIBERS.write().unwrap().push(subscriber);
+}
+
+pub fn emit(event: Event) {
+    for subscriber in SUBSCRIBERS.read().unwrap().iter() {
+        subscriber.on_event(&event);
+    }
+}
+
+// Reports events as plain lines on stderr, leaving stdout to the output of commands
+pub struct Printer;
+
+impl Printer {
+    // What is printed for an event, if anything
+    fn message(event: &Event) -> Option<String> {
+        let message = match event {
+            Event::Updated { path, .. } => format!("Updated an entry {}", path.display()),
+            Event::Renamed { from, to } => {
+                format!("Renamed {} -> {}", from.display(), to.display())
+            }
+            Event::Removed(path) => format!("Removed an entry {}", path.display()),
+            Event::Ignored(path) => format!("Skipping ignored path: {}", path.display()),
+            Event::Notice(message) => message.clone(),
+            Event::Error(message) => message.clone(),
+            Event::Saved(path) => format!("BOF saved to {}", path.display()),
+            Event::Estimate { .. }
+            | Event::Directory(_)
+            | Event::Indexed { .. }
+            | Event::Unchanged(_)
+            | Event::Verified { .. } => return None,
+        };
+        Some(message)
+    }
+}
+
+impl Subscriber for Printer {
+    fn on_event(&self, event: &Event) {
+        if let Some(message) = Printer::message(event) {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+// Reports every event as one JSON object per line on stdout, for scripts
+pub struct JsonPrinter;
+
+impl JsonPrinter {
+    // Nothing more can be printed once stdout is closed, e.g. by `head`
+    pub fn print(value: serde_json::Value) {
+        let _ = writeln!(io::stdout().lock(), "{}", value);
+    }
+}
+
+impl Subscriber for JsonPrinter {
+    fn on_event(&self, event: &Event) {
+        JsonPrinter::print(match event {
+            Event::Estimate { files, bytes } => {
+                json!({"event": "estimate", "files": files, "bytes": bytes})
+            }
+            Event::Directory(path) => json!({"event": "directory", "path": path}),
+            Event::Indexed { path, size } => {
+                json!({"event": "indexed", "path": path, "size": size})
+            }
+            Event::Updated { path, size } => {
+                json!({"event": "updated", "path": path, "size": size})
+            }
+            Event::Unchanged(path) => json!({"event": "unchanged", "path": path}),
+            Event::Renamed { from, to } => json!({"event": "renamed", "from": from, "to": to}),
+            Event::Removed(path) => json!({"event": "removed", "path": path}),
+            Event::Verified { path, size } => {
+                json!({"event": "verified", "path": path, "size": size})
+            }
+            Event::Ignored(path) => json!({"event": "ignored", "path": path}),
+            Event::Notice(message) => json!({"event": "notice", "message": message}),
+            Event::Error(message) => json!({"event": "error", "message": message}),
+            Event::Saved(path) => json!({"event": "saved", "path": path}),
+        });
+    }
+}
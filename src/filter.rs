@@ -0,0 +1,395 @@
+use crate::error::{BofError, Result};
+use crate::mime;
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::{cmp::Ordering, path::Path, time::SystemTime};
+
+// An expression of `bof query`: comparisons of the fields of an entry joined with `and`, `or`,
+// `not` and parentheses, e.g. `size > 100MB and ext == "mp4" and mtime < 2023-01-01`
+#[derive(Debug)]
+pub struct Filter(Expr);
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Path,
+    Name,
+    Ext,
+    Dir,
+    Size,
+    Mtime,
+    Ctime,
+    Key,
+    Kind,
+    Label,
+    Mime,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("path", Field::Path),
+    ("name", Field::Name),
+    ("ext", Field::Ext),
+    ("dir", Field::Dir),
+    ("size", Field::Size),
+    ("mtime", Field::Mtime),
+    ("ctime", Field::Ctime),
+    ("key", Field::Key),
+    ("hash", Field::Key),
+    ("kind", Field::Kind),
+    ("label", Field::Label),
+    ("mime", Field::Mime),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // Glob match, `name ~ "*.jpg"`
+    Glob,
+}
+
+#[derive(Debug)]
+enum Value {
+    Number(u64),
+    Time(SystemTime),
+    Text(String),
+    Glob(globset::GlobMatcher),
+}
+
+// Decimal units like disks are sold in, and binary ones like `bof stats` prints
+const UNITS: &[(&str, u64)] = &[
+    ("b", 1),
+    ("k", 1000),
+    ("kb", 1000),
+    ("m", 1_000_000),
+    ("mb", 1_000_000),
+    ("g", 1_000_000_000),
+    ("gb", 1_000_000_000),
+    ("t", 1_000_000_000_000),
+    ("tb", 1_000_000_000_000),
+    ("kib", 1 << 10),
+    ("mib", 1 << 20),
+    ("gib", 1 << 30),
+    ("tib", 1 << 40),
+];
+
+// What an expression is evaluated against, an indexed file or link
+pub(crate) struct Subject<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) kind: &'static str,
+    pub(crate) key: &'a str,
+    pub(crate) size: Option<u64>,
+    pub(crate) mtime: Option<SystemTime>,
+    pub(crate) ctime: Option<SystemTime>,
+    pub(crate) labels: &'a [String],
+    pub(crate) mime: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Op(Op),
+    Word(String),
+    Quoted(String),
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Filter> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(Filter(expr)),
+            Some(token) => Err(invalid(format!("unexpected {}", describe(token)))),
+        }
+    }
+
+    pub(crate) fn matches(&self, subject: &Subject) -> bool {
+        self.0.matches(subject)
+    }
+}
+
+fn invalid(message: String) -> BofError {
+    BofError::Query(message)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Open => "`(`".to_string(),
+        Token::Close => "`)`".to_string(),
+        Token::Op(op) => format!("`{}`", op.symbol()),
+        Token::Word(word) => format!("`{}`", word),
+        Token::Quoted(text) => format!("\"{}\"", text),
+    }
+}
+
+impl Op {
+    const ALL: [(&'static str, Op); 8] = [
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+        ("=", Op::Eq),
+        ("~", Op::Glob),
+    ];
+
+    fn symbol(self) -> &'static str {
+        Op::ALL
+            .iter()
+            .find(|(_, op)| *op == self)
+            .map_or("?", |(symbol, _)| symbol)
+    }
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Glob => false,
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some((symbol, op)) = Op::ALL.iter().find(|(symbol, _)| rest.starts_with(symbol)) {
+            tokens.push(Token::Op(*op));
+            rest = &rest[symbol.len()..];
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| invalid(format!("unterminated string {}", rest)))?;
+            tokens.push(Token::Quoted(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "()\"'=!<>~".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(invalid(format!("unexpected `{}`", c)));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.next), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn take(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.next);
+        self.next += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.peek_keyword("or") {
+            self.next += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.peek_keyword("and") {
+            self.next += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.peek_keyword("not") {
+            self.next += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.tokens.get(self.next) == Some(&Token::Open) {
+            self.next += 1;
+            let expr = self.or()?;
+            return match self.take() {
+                Some(Token::Close) => Ok(expr),
+                _ => Err(invalid("missing `)`".to_string())),
+            };
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let field = match self.take() {
+            Some(Token::Word(word)) => {
+                let name = word.to_lowercase();
+                FIELDS
+                    .iter()
+                    .find(|(field, _)| *field == name)
+                    .map(|(_, field)| *field)
+                    .ok_or_else(|| {
+                        let fields = FIELDS.iter().map(|(field, _)| *field);
+                        invalid(format!(
+                            "unknown field `{}`, expected one of {}",
+                            word,
+                            fields.collect::<Vec<_>>().join(", ")
+                        ))
+                    })?
+            }
+            Some(token) => {
+                return Err(invalid(format!(
+                    "expected a field, found {}",
+                    describe(token)
+                )))
+            }
+            None => return Err(invalid("expected a field".to_string())),
+        };
+        let op = match self.take() {
+            Some(Token::Op(op)) => *op,
+            _ => {
+                let name = FIELDS
+                    .iter()
+                    .find(|(_, f)| *f == field)
+                    .map_or("", |(name, _)| name);
+                return Err(invalid(format!("expected an operator after `{}`", name)));
+            }
+        };
+        let value = match self.take() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value.clone(),
+            _ => return Err(invalid(format!("expected a value after `{}`", op.symbol()))),
+        };
+        Ok(Expr::Compare(field, op, parse_value(field, op, &value)?))
+    }
+}
+
+fn parse_value(field: Field, op: Op, value: &str) -> Result<Value> {
+    match field {
+        Field::Size => parse_size(value)
+            .map(Value::Number)
+            .ok_or_else(|| invalid(format!("invalid size {}", value))),
+        Field::Mtime | Field::Ctime => parse_time(value).map(Value::Time),
+        _ if op == Op::Glob => Ok(Value::Glob(
+            globset::GlobBuilder::new(value)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher(),
+        )),
+        _ if op != Op::Eq && op != Op::Ne => Err(invalid(format!(
+            "`{}` only compares size, mtime and ctime",
+            op.symbol()
+        ))),
+        Field::Ext => Ok(Value::Text(value.trim_start_matches('.').to_lowercase())),
+        _ => Ok(Value::Text(value.to_string())),
+    }
+}
+
+// A size in bytes, with an optional unit as in 10mb or 4gib
+pub fn parse_size(value: &str) -> Option<u64> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, multiplier)| *multiplier)?
+    };
+    let number = number.parse::<f64>().ok()?;
+    Some((number * multiplier as f64) as u64)
+}
+
+// A date, or a date and time, in local time
+fn parse_time(value: &str) -> Result<SystemTime> {
+    let datetime = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M"))
+        .map_err(|_| invalid(format!("invalid date {}, expected e.g. 2023-01-01", value)))?;
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(SystemTime::from)
+        .ok_or_else(|| invalid(format!("{} doesn't exist in the local time zone", value)))
+}
+
+impl Expr {
+    fn matches(&self, subject: &Subject) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(subject) && b.matches(subject),
+            Expr::Or(a, b) => a.matches(subject) || b.matches(subject),
+            Expr::Not(expr) => !expr.matches(subject),
+            Expr::Compare(field, op, value) => compare(subject, *field, *op, value),
+        }
+    }
+}
+
+fn compare(subject: &Subject, field: Field, op: Op, value: &Value) -> bool {
+    match (field, value) {
+        (Field::Size, Value::Number(size)) => subject.size.is_some_and(|s| op.holds(s.cmp(size))),
+        (Field::Mtime, Value::Time(time)) => subject.mtime.is_some_and(|t| op.holds(t.cmp(time))),
+        (Field::Ctime, Value::Time(time)) => subject.ctime.is_some_and(|t| op.holds(t.cmp(time))),
+        (_, Value::Glob(glob)) => texts(subject, field).iter().any(|text| glob.is_match(text)),
+        (_, Value::Text(expected)) => {
+            let found = texts(subject, field).iter().any(|text| match field {
+                Field::Mime => mime::matches(text, expected),
+                _ => text == expected,
+            });
+            found == (op == Op::Eq)
+        }
+        _ => false,
+    }
+}
+
+// Values of a text field, several for labels and none when the entry has none
+fn texts(subject: &Subject, field: Field) -> Vec<String> {
+    let text = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_string());
+    match field {
+        Field::Path => vec![subject.path.to_string_lossy().to_string()],
+        Field::Name => text(subject.path.file_name()).into_iter().collect(),
+        Field::Ext => text(subject.path.extension())
+            .map(|ext| ext.to_lowercase())
+            .into_iter()
+            .collect(),
+        Field::Dir => text(subject.path.parent().map(Path::as_os_str))
+            .into_iter()
+            .collect(),
+        Field::Key => vec![subject.key.to_string()],
+        Field::Kind => vec![subject.kind.to_string()],
+        Field::Label => subject.labels.to_vec(),
+        Field::Mime => subject.mime.map(str::to_string).into_iter().collect(),
+        Field::Size | Field::Mtime | Field::Ctime => Vec::new(),
+    }
+}
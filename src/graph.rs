@@ -0,0 +1,106 @@
+use crate::bof::BOFIndex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+// Content found in more directories than this, like a license file in every project, says
+// nothing about which of them are copies of each other and would add an edge per pair
+const MAX_DIRS: usize = 64;
+
+// Directories sharing content, to see which are near-copies of which
+#[derive(Debug, Default, Serialize)]
+pub struct DuplicateGraph {
+    // Directories with an edge, by path
+    pub nodes: Vec<Node>,
+    // Most bytes shared first
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    pub path: PathBuf,
+    // Files right in the directory, to weigh what it shares against
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Edge {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    // Contents found in both, counted once whatever the number of copies
+    pub shared_files: u64,
+    pub shared_bytes: u64,
+}
+
+// Links every two directories holding a copy of the same content, leaving out pairs sharing fewer
+// than `min_shared` bytes. Empty files are left out, they are copies of everything empty.
+pub fn graph(index: &BOFIndex, min_shared: u64) -> DuplicateGraph {
+    let mut shared: BTreeMap<(&Path, &Path), (u64, u64)> = BTreeMap::new();
+    let groups = index.duplicates(1, 2, None);
+    for group in &groups {
+        let dirs = group
+            .paths
+            .iter()
+            .map(|path| dir(path))
+            .collect::<BTreeSet<_>>();
+        if dirs.len() < 2 || dirs.len() > MAX_DIRS {
+            continue;
+        }
+        let dirs = dirs.into_iter().collect::<Vec<_>>();
+        for (i, from) in dirs.iter().enumerate() {
+            for to in &dirs[i + 1..] {
+                let edge = shared.entry((*from, *to)).or_default();
+                edge.0 += 1;
+                edge.1 += group.size;
+            }
+        }
+    }
+
+    let mut edges = shared
+        .into_iter()
+        .filter(|(_, (_, bytes))| *bytes >= min_shared)
+        .map(|((from, to), (files, bytes))| Edge {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            shared_files: files,
+            shared_bytes: bytes,
+        })
+        .collect::<Vec<_>>();
+    edges.sort_by(|a, b| {
+        b.shared_bytes
+            .cmp(&a.shared_bytes)
+            .then_with(|| (&a.from, &a.to).cmp(&(&b.from, &b.to)))
+    });
+
+    let linked = edges
+        .iter()
+        .flat_map(|edge| [edge.from.as_path(), edge.to.as_path()])
+        .collect::<BTreeSet<_>>();
+    let mut totals: BTreeMap<&Path, (u64, u64)> = BTreeMap::new();
+    let files = index.manifest_files();
+    for file in &files {
+        let dir = dir(&file.path);
+        if linked.contains(dir) {
+            let total = totals.entry(dir).or_default();
+            total.0 += 1;
+            total.1 += file.size;
+        }
+    }
+    let nodes = totals
+        .into_iter()
+        .map(|(path, (files, bytes))| Node {
+            path: path.to_path_buf(),
+            files,
+            bytes,
+        })
+        .collect();
+    DuplicateGraph { nodes, edges }
+}
+
+// Files right under an indexed root have their root as directory, or "" for the current one
+fn dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
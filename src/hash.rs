@@ -0,0 +1,404 @@
+use crate::platform;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Write as _},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const READ_BUFFER: usize = 64 * 1024;
+// Bytes read from each end of a file to tell it apart before hashing it in full
+const PARTIAL: u64 = 64 * 1024;
+
+// Files read at once by every thread of the process, 0 for no limit, see `open_files`
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+static READING: Mutex<usize> = Mutex::new(0);
+static READ_DONE: Condvar = Condvar::new();
+
+// Spinning disks read a few files at once faster than one per thread
+pub(crate) fn limit_open_files(limit: usize) {
+    OPEN_FILES.store(limit, Ordering::Relaxed);
+}
+
+// Bytes read per second by every thread of the process, 0 for no limit, see `background`
+static READ_RATE: AtomicU64 = AtomicU64::new(0);
+// When reading may go on, later than now when reads ran ahead of the rate
+static READ_CLOCK: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub(crate) fn limit_read_rate(bytes_per_sec: u64) {
+    READ_RATE.store(bytes_per_sec, Ordering::Relaxed);
+}
+
+// Files hashed are read once and dropped from the page cache, see `cache_friendly`
+static CACHE_FRIENDLY: AtomicBool = AtomicBool::new(false);
+
+// Hashing a huge archive otherwise evicts what the rest of the system keeps in the page cache
+pub(crate) fn spare_page_cache(enabled: bool) {
+    CACHE_FRIENDLY.store(enabled, Ordering::Relaxed);
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    let file = File::open(path)?;
+    if CACHE_FRIENDLY.load(Ordering::Relaxed) {
+        platform::read_once(&file);
+    }
+    Ok(file)
+}
+
+// Done with `len` bytes of the file from `offset` on, 0 for up to its end
+fn done_with(file: &File, offset: u64, len: u64) {
+    if CACHE_FRIENDLY.load(Ordering::Relaxed) {
+        platform::uncache(file, offset, len);
+    }
+}
+
+// Waits as long as reading `bytes` takes at the rate, after the reads of the other threads
+fn throttle(bytes: usize) {
+    let rate = READ_RATE.load(Ordering::Relaxed);
+    if rate == 0 || bytes == 0 {
+        return;
+    }
+    let until = {
+        let mut clock = READ_CLOCK.lock().unwrap();
+        let now = Instant::now();
+        let from = clock.filter(|at| *at > now).unwrap_or(now);
+        let until = from + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        *clock = Some(until);
+        until
+    };
+    thread::sleep(until.saturating_duration_since(Instant::now()));
+}
+
+// Held while a file is read, waiting for another read to be done when there are enough already
+struct Reading(bool);
+
+impl Reading {
+    fn start() -> Reading {
+        let limit = OPEN_FILES.load(Ordering::Relaxed);
+        if limit == 0 {
+            return Reading(false);
+        }
+        let mut reading = READING.lock().unwrap();
+        while *reading >= limit {
+            reading = READ_DONE.wait(reading).unwrap();
+        }
+        *reading += 1;
+        Reading(true)
+    }
+}
+
+impl Drop for Reading {
+    fn drop(&mut self) {
+        if self.0 {
+            *READING.lock().unwrap() -= 1;
+            READ_DONE.notify_one();
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    Serialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Blake3,
+    Xxh3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        };
+        f.write_str(name)
+    }
+}
+
+pub(crate) trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> String;
+}
+
+struct Sha256(sha2::Sha256);
+struct Sha1(sha1::Sha1);
+struct Blake3(Box<blake3::Hasher>);
+struct Xxh3(Box<xxhash_rust::xxh3::Xxh3>);
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data);
+    }
+    fn finish(self: Box<Self>) -> String {
+        to_hex(&sha2::Digest::finalize(self.0))
+    }
+}
+
+impl Hasher for Sha1 {
+    fn update(&mut self, data: &[u8]) {
+        sha1::Digest::update(&mut self.0, data);
+    }
+    fn finish(self: Box<Self>) -> String {
+        to_hex(&sha1::Digest::finalize(self.0))
+    }
+}
+
+impl Hasher for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finish(self: Box<Self>) -> String {
+        self.0.finalize().to_hex().to_string()
+    }
+}
+
+impl Hasher for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finish(self: Box<Self>) -> String {
+        format!("{:032x}", self.0.digest128())
+    }
+}
+
+impl HashAlgorithm {
+    pub(crate) fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(Sha256(sha2::Digest::new())),
+            HashAlgorithm::Sha1 => Box::new(Sha1(sha1::Digest::new())),
+            HashAlgorithm::Blake3 => Box::new(Blake3(Box::new(blake3::Hasher::new()))),
+            HashAlgorithm::Xxh3 => Box::new(Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new()))),
+        }
+    }
+
+    pub fn hash_bytes(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    // Streams the file through the hasher, so binary and huge files are fine
+    pub fn hash_file(self, path: &Path) -> io::Result<String> {
+        HashPolicy::default().hash_file(self, path)
+    }
+}
+
+// How a file is read by its size, see HashPolicy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    // Read and hashed in one go
+    Full,
+    // Told apart from files of the same size by its ends first, where only matches matter
+    Partial,
+    // Like Partial, and read ahead in large chunks on a thread of its own while hashing
+    Chunked,
+}
+
+// Thresholds in bytes picking how files are hashed by their size, the [hashing] table of the
+// configuration. Keys are always the digest of the whole content, whatever the strategy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HashPolicy {
+    pub partial_above: u64,
+    pub chunked_above: u64,
+    pub chunk_size: u64,
+    // Files larger than this are catalogued without their hash until `bof rehash`, 0 for none
+    pub lazy_above: u64,
+    // Files larger than this are mapped into memory rather than read, 0 for none. Fewer system
+    // calls and copies, which shows on local SSDs.
+    pub mmap_above: u64,
+}
+
+impl Default for HashPolicy {
+    fn default() -> Self {
+        HashPolicy {
+            partial_above: 2 * PARTIAL,
+            chunked_above: 256 * 1024 * 1024,
+            chunk_size: 8 * 1024 * 1024,
+            lazy_above: 0,
+            mmap_above: 0,
+        }
+    }
+}
+
+impl HashPolicy {
+    pub fn strategy(&self, size: u64) -> Strategy {
+        // Reading both ends of a smaller file reads all of it anyway
+        if size <= self.partial_above.max(2 * PARTIAL) {
+            Strategy::Full
+        } else if size <= self.chunked_above {
+            Strategy::Partial
+        } else {
+            Strategy::Chunked
+        }
+    }
+
+    // Whether a file of this size is left for `bof rehash`
+    pub fn is_lazy(&self, size: u64) -> bool {
+        self.lazy_above != 0 && size > self.lazy_above
+    }
+
+    pub fn hash_file(&self, algorithm: HashAlgorithm, path: &Path) -> io::Result<String> {
+        Ok(self.hash_file_with(&[algorithm], path)?.remove(0))
+    }
+
+    // Hashes of the file with each of the algorithms, in their order, reading it only once
+    pub(crate) fn hash_file_with(
+        &self,
+        algorithms: &[HashAlgorithm],
+        path: &Path,
+    ) -> io::Result<Vec<String>> {
+        let _reading = Reading::start();
+        let mut file = open(path)?;
+        let mut hashers = algorithms
+            .iter()
+            .map(|algorithm| algorithm.hasher())
+            .collect::<Vec<_>>();
+        let mut update = |data: &[u8]| {
+            for hasher in &mut hashers {
+                hasher.update(data);
+            }
+        };
+        let size = file.metadata()?.len();
+        let chunk_size = self.chunk_size.max(READ_BUFFER as u64) as usize;
+        if let Some(map) = self.map(&file, size) {
+            for chunk in map.chunks(chunk_size) {
+                update(chunk);
+                throttle(chunk.len());
+            }
+        } else if self.strategy(size) == Strategy::Chunked {
+            read_ahead(&mut file, chunk_size, update)?;
+        } else {
+            let mut buffer = vec![0; READ_BUFFER];
+            loop {
+                match read_full(&mut file, &mut buffer)? {
+                    0 => break,
+                    n => update(&buffer[..n]),
+                }
+            }
+        }
+        done_with(&file, 0, 0);
+        Ok(hashers.into_iter().map(|hasher| hasher.finish()).collect())
+    }
+
+    // The file mapped into memory when it is larger than mmap_above, None to read it instead, also
+    // when it can't be mapped, e.g. on some network filesystems
+    fn map(&self, file: &File, size: u64) -> Option<memmap2::Mmap> {
+        if self.mmap_above == 0 || size <= self.mmap_above {
+            return None;
+        }
+        // A file truncated by another process while mapped brings bof down with SIGBUS on Unix,
+        // which is why mapping is left off by default
+        let map = unsafe { memmap2::Mmap::map(file) }.ok()?;
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);
+        Some(map)
+    }
+
+    // Hash of the first and last PARTIAL bytes of a file, None when it is hashed in full anyway
+    pub(crate) fn partial_hash(
+        &self,
+        algorithm: HashAlgorithm,
+        path: &Path,
+        size: u64,
+    ) -> io::Result<Option<String>> {
+        if self.strategy(size) == Strategy::Full {
+            return Ok(None);
+        }
+        let _reading = Reading::start();
+        let mut file = open(path)?;
+        let mut hasher = algorithm.hasher();
+        let mut buffer = vec![0; PARTIAL as usize];
+        file.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
+        file.seek(SeekFrom::Start(size - PARTIAL))?;
+        file.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
+        throttle(2 * buffer.len());
+        done_with(&file, 0, 0);
+        Ok(Some(hasher.finish()))
+    }
+}
+
+// Fills the buffer unless the file ends first, returning how much was read, no faster than the
+// read rate
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    throttle(read);
+    Ok(read)
+}
+
+// Hands the file to `update` a chunk at a time, the next chunk being read meanwhile. Two
+// buffers go back and forth between the threads, so memory stays at twice the chunk size, and
+// chunks leave the page cache as they are read.
+fn read_ahead(file: &mut File, chunk_size: usize, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+    let (full, chunks) = mpsc::sync_channel::<Vec<u8>>(2);
+    let (empty, buffers) = mpsc::channel::<Vec<u8>>();
+    for _ in 0..2 {
+        let _ = empty.send(vec![0; chunk_size]);
+    }
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || {
+            let mut offset = 0;
+            for mut buffer in buffers {
+                buffer.resize(chunk_size, 0);
+                let n = read_full(file, &mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                done_with(file, offset, n as u64);
+                offset += n as u64;
+                buffer.truncate(n);
+                if full.send(buffer).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        for buffer in chunks {
+            update(&buffer);
+            let _ = empty.send(buffer);
+        }
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("reader thread panicked")))
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
@@ -0,0 +1,205 @@
+use crate::bof::BOFConfig;
+use crate::events::{self, Event};
+use crate::labels::Patterns;
+use globset::{GlobBuilder, GlobMatcher};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub(crate) const IGNORE_FILE: &str = ".bofignore";
+// Where deleted files wait on Linux and macOS volumes, in Windows drives and in XP ones
+const TRASH_DIRS: &[&str] = &[".Trash", ".Trashes", "$RECYCLE.BIN", "RECYCLER"];
+
+#[derive(Debug)]
+struct Rule {
+    // The line as written, to tell users which rule applied
+    pattern: String,
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+}
+
+#[derive(Debug)]
+struct Layer {
+    base: PathBuf,
+    source: PathBuf,
+    rules: Vec<Rule>,
+}
+
+// Rules from every `.bofignore` found from the indexed root down to a directory
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IgnoreRules {
+    layers: Vec<Arc<Layer>>,
+    // Files are only indexed if they match `include`, when set, and don't match `exclude`
+    include: Option<Arc<Patterns>>,
+    exclude: Option<Arc<Patterns>>,
+    // Trash directories are walked too, with include_trash
+    trash: bool,
+}
+
+impl IgnoreRules {
+    // Rules from the configuration, matched under every indexed root
+    pub(crate) fn new(config: &BOFConfig) -> Self {
+        let mut rules = Self {
+            include: patterns(&config.include_patterns, "include_patterns"),
+            exclude: patterns(&config.exclude_patterns, "exclude_patterns"),
+            trash: config.include_trash,
+            ..Self::default()
+        };
+        if !config.ignore.is_empty() {
+            rules.layers.push(Arc::new(Layer {
+                base: PathBuf::new(),
+                source: PathBuf::from("configuration"),
+                rules: parse_rules(&config.ignore.join("\n"), Path::new("configuration")),
+            }));
+        }
+        rules
+    }
+
+    // Returns the rules for `dir`, extended with its `.bofignore` if there is one
+    pub(crate) fn for_dir(&self, dir: &Path) -> Self {
+        let mut rules = self.clone();
+        let ignore_file = dir.join(IGNORE_FILE);
+        match fs::read_to_string(&ignore_file) {
+            Ok(content) => rules.layers.push(Arc::new(Layer {
+                base: dir.to_path_buf(),
+                rules: parse_rules(&content, &ignore_file),
+                source: ignore_file,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => events::emit(Event::Error(format!(
+                "Failed to read {}: {}",
+                ignore_file.display(),
+                e
+            ))),
+        }
+        rules
+    }
+
+    // Whether a file is left out by include_patterns, matching none of them
+    pub(crate) fn is_not_included(&self, path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_some_and(|include| !include.is_match(path))
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(path))
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.is_trash(path, is_dir) || self.matching_rule(path, is_dir).is_some()
+    }
+
+    // Whether `path` is a trash directory left out unless include_trash
+    pub(crate) fn is_trash(&self, path: &Path, is_dir: bool) -> bool {
+        !self.trash && is_dir && is_trash_dir(path)
+    }
+
+    // The rule ignoring `path` and the file it comes from, None if it isn't ignored
+    pub(crate) fn matching_rule(&self, path: &Path, is_dir: bool) -> Option<(String, PathBuf)> {
+        // Like .gitignore, the last matching rule wins and deeper files override their parents
+        let mut matching = None;
+        for layer in &self.layers {
+            let Ok(relative) = path.strip_prefix(&layer.base) else {
+                continue;
+            };
+            for rule in &layer.rules {
+                if rule.dir_only && !is_dir {
+                    continue;
+                }
+                if rule.matcher.is_match(relative) {
+                    matching = (!rule.negated).then_some((rule, layer));
+                }
+            }
+        }
+        matching.map(|(rule, layer)| (rule.pattern.clone(), layer.source.clone()))
+    }
+}
+
+fn is_trash_dir(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    // Per-user trash on other Linux volumes is .Trash-<uid>
+    TRASH_DIRS
+        .iter()
+        .any(|trash| name.eq_ignore_ascii_case(trash))
+        || name.starts_with(".Trash-")
+        || path.ends_with(".local/share/Trash")
+}
+
+// Whether `path` is somewhere in a trash directory
+pub(crate) fn in_trash(path: &Path) -> bool {
+    path.ancestors().any(is_trash_dir)
+}
+
+// Glob patterns of a setting, leaving out invalid ones like .bofignore rules do
+fn patterns(patterns: &[String], setting: &str) -> Option<Arc<Patterns>> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let valid = patterns
+        .iter()
+        .filter(
+            |pattern| match Patterns::new(std::slice::from_ref(pattern)) {
+                Ok(_) => true,
+                Err(e) => {
+                    events::emit(Event::Error(format!(
+                        "Invalid pattern in {}: {}",
+                        setting, e
+                    )));
+                    false
+                }
+            },
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    Patterns::new(&valid).ok().map(Arc::new)
+}
+
+fn parse_rules(content: &str, source: &Path) -> Vec<Rule> {
+    content
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let text = line.to_string();
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            // Patterns without a slash match at any depth, others are anchored to the file's directory
+            let pattern = match line.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if line.contains('/') => line.to_string(),
+                None => format!("**/{}", line),
+            };
+
+            match GlobBuilder::new(&pattern).literal_separator(true).build() {
+                Ok(glob) => Some(Rule {
+                    pattern: text,
+                    matcher: glob.compile_matcher(),
+                    negated,
+                    dir_only,
+                }),
+                Err(e) => {
+                    events::emit(Event::Error(format!(
+                        "Invalid pattern in {}: {}",
+                        source.display(),
+                        e
+                    )));
+                    None
+                }
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,66 @@
+use crate::error::Result;
+use globset::{GlobSet, GlobSetBuilder};
+use std::{collections::BTreeMap, path::Path};
+
+// Glob patterns matched like `bof find` does: against the file name, or the whole path when the
+// pattern contains a /
+#[derive(Debug)]
+pub(crate) struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    pub(crate) fn new(patterns: &[String]) -> Result<Patterns> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = globset::GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+        Ok(Patterns {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    pub(crate) fn is_match(&self, path: &Path) -> bool {
+        self.paths.is_match(path)
+            || path
+                .file_name()
+                .is_some_and(|name| self.names.is_match(Path::new(name)))
+    }
+}
+
+// Labels from the configuration, each given by the patterns of the files it applies to
+pub(crate) struct Classifier {
+    labels: Vec<(String, Patterns)>,
+}
+
+impl Classifier {
+    pub(crate) fn new(labels: &BTreeMap<String, Vec<String>>) -> Result<Classifier> {
+        let labels = labels
+            .iter()
+            .map(|(label, patterns)| Ok((label.clone(), Patterns::new(patterns)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Classifier { labels })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub(crate) fn classify(&self, path: &Path) -> Vec<String> {
+        self.labels
+            .iter()
+            .filter(|(_, patterns)| patterns.is_match(path))
+            .map(|(label, _)| label.clone())
+            .collect()
+    }
+}
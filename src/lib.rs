@@ -0,0 +1,211 @@
+// Box of Files: index files and directories, keep the index up to date and query it.
+// The `bof` binary is a thin command line over this library.
+pub mod audit;
+mod bof;
+mod cache;
+pub mod catalog;
+mod daemon;
+pub mod dedupe;
+mod drift;
+mod error;
+pub mod events;
+pub mod filter;
+pub mod graph;
+pub mod hash;
+mod ignore;
+mod labels;
+pub mod manifest;
+mod memory;
+mod mime;
+pub mod pack;
+mod platform;
+pub mod policy;
+pub mod preset;
+pub mod preview;
+pub mod registry;
+mod serve;
+mod session;
+pub mod settings;
+pub mod storage;
+mod watch;
+
+pub use crate::bof::{
+    load_config, BOFConfig as Config, BOFIndex as Index, Change, DuplicateGroup, EntryInfo,
+    ExtensionStats, HardlinkGroup, IndexStats, IndexSummary, Mismatch, PruneSummary, RootInfo,
+    Segment, SkipReason, Skipped, Subtree, SymlinkPolicy, VolumeStats, WhyMissing,
+};
+pub use crate::daemon::METHODS as DAEMON_METHODS;
+pub use crate::error::{BofError, Result};
+pub use crate::serve::{Access as ServeAccess, ServeToken};
+use preset::Preset;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Runs indexing operations against the index in `config.output_dir`
+pub struct Indexer {
+    config: Config,
+}
+
+impl Indexer {
+    pub fn new(config: Config) -> Self {
+        bof::configure_process(&config);
+        Self { config }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Creates the index directory, writing Config.toml first for a preset or interactive setup
+    pub fn init(&mut self, preset: Option<Preset>, interactive: bool) -> Result<()> {
+        bof::init(&mut self.config, preset, interactive)
+    }
+
+    // Indexes the given directories from scratch, reusing keys of files that didn't change
+    // With `resume`, skips the directories an interrupted run over the same paths finished
+    pub fn index(&self, paths: Vec<PathBuf>, resume: bool) -> Result<()> {
+        bof::index_directories(paths, resume, &self.config)
+    }
+
+    pub fn update(&self, paths: Vec<PathBuf>) -> Result<()> {
+        bof::update_directories(paths, &self.config)
+    }
+
+    // Re-hashes the given files and directories even if they look unchanged
+    pub fn touch(&self, paths: Vec<PathBuf>) -> Result<()> {
+        bof::touch(paths, &self.config)
+    }
+
+    // Hashes files indexed with `no_hash`, only those under `paths` unless it is empty
+    pub fn rehash(&self, paths: Vec<PathBuf>) -> Result<u64> {
+        bof::rehash(paths, &self.config)
+    }
+
+    // Drops whatever is indexed under `paths` from the index, the files stay where they are
+    pub fn remove(&self, paths: Vec<PathBuf>) -> Result<u64> {
+        bof::remove(paths, &self.config)
+    }
+
+    // Drops entries of paths that no longer exist
+    pub fn prune(&self) -> Result<PruneSummary> {
+        bof::prune(&self.config)
+    }
+
+    // Re-hashes indexed files, only those under `paths` unless it is empty. With `resume`,
+    // continues where an interrupted verify over the same paths stopped.
+    pub fn verify(&self, paths: Vec<PathBuf>, resume: bool) -> Result<Vec<Mismatch>> {
+        bof::verify(paths, resume, &self.config)
+    }
+
+    // Duplicates under `paths` found without an index, see `dedupe::scan`
+    pub fn scan_duplicates(
+        &self,
+        paths: &[PathBuf],
+        min_size: u64,
+        min_copies: u64,
+    ) -> Vec<DuplicateGroup> {
+        dedupe::scan(paths, min_size, min_copies, &self.config)
+    }
+
+    // Serves queries from an index kept in memory, on a socket in the index directory. With
+    // `drift`, also counts what changes under the indexed roots for `prompt_info`.
+    pub fn daemon(&self, drift: bool) -> Result<()> {
+        daemon::serve(&self.config, drift)
+    }
+
+    // Paths changed since the index was saved, as counted by `watch` or `daemon`, None when
+    // neither keeps count. Cheap enough for a shell prompt.
+    pub fn prompt_info(&self) -> Option<u64> {
+        drift::drift(&self.config.output_dir, self.config.index_name())
+    }
+
+    // Asks the running daemon, see `daemon`, with a JSON-RPC method and its params
+    pub fn query(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+        daemon::query(&self.config.output_dir, method, params)
+    }
+
+    // Keeps the index live by applying changes to `paths` as they happen, until interrupted
+    pub fn watch(&self, paths: Vec<PathBuf>, flush_every: Duration) -> Result<()> {
+        watch::watch(paths, flush_every, &self.config)
+    }
+
+    // Makes previews of indexed images and videos under `paths`, see `preview::generate`
+    pub fn previews(&self, paths: &[PathBuf]) -> Result<preview::PreviewSummary> {
+        preview::generate(paths, &self.config)
+    }
+
+    // Re-hashes `count` random files that look unchanged, see `bof::spot_check`
+    pub fn spot_check(&self, paths: &[PathBuf], count: usize) -> Result<Vec<Mismatch>> {
+        bof::spot_check(paths, count, &self.config)
+    }
+
+    pub fn status(&self, paths: Vec<PathBuf>) -> Result<Vec<Change>> {
+        bof::status(paths, &self.config)
+    }
+
+    // Serves HTTP endpoints over the index on `listen`, e.g. 0.0.0.0:8080 with serve_tokens
+    pub fn serve(&self, listen: &str) -> Result<()> {
+        serve::serve(listen, &self.config)
+    }
+
+    pub fn load(&self) -> Result<Index> {
+        Index::load(&self.config.output_dir, self.config.index_name())
+    }
+
+    // Every session logged when it stopped, oldest first
+    pub fn sessions(&self) -> Result<Vec<storage::Session>> {
+        storage::sessions(&self.config.output_dir, self.config.index_name())
+    }
+
+    // Archives what is indexed under `subtree` into `out`, with each distinct content stored once
+    // and left out altogether if the index of the destination, `against`, already has it
+    pub fn pack(
+        &self,
+        subtree: &Path,
+        out: &Path,
+        against: Option<&Index>,
+    ) -> Result<pack::PackSummary> {
+        let index = self.load()?;
+        pack::pack(&index, subtree, out, against)
+    }
+
+    // Keeps a copy of the current index, then drops snapshots beyond `keep_snapshots`
+    pub fn snapshot(&self) -> Result<storage::Snapshot> {
+        bof::take_snapshot(&self.config)
+    }
+
+    // Applies the configured policies, only reporting what they would do if `dry_run`
+    pub fn run_policies(&self, dry_run: bool) -> Result<Vec<policy::Applied>> {
+        policy::run(dry_run, &self.config)
+    }
+
+    // Reclaims the space of duplicates, only reporting what would be done if `dry_run`
+    pub fn dedupe(
+        &self,
+        action: dedupe::DedupeAction,
+        keep: dedupe::Keep,
+        min_size: u64,
+        min_copies: u64,
+        dry_run: bool,
+        across_owners: bool,
+    ) -> Result<Vec<dedupe::Deduped>> {
+        dedupe::run(
+            action,
+            keep,
+            min_size,
+            min_copies,
+            dry_run,
+            across_owners,
+            &self.config,
+        )
+    }
+
+    pub fn snapshots(&self) -> Result<Vec<storage::Snapshot>> {
+        storage::snapshots(&self.config.output_dir, self.config.index_name())
+    }
+
+    // Every save of the index still logged, oldest first
+    pub fn log(&self) -> Result<Vec<storage::Generation>> {
+        storage::generations(&self.config.output_dir, self.config.index_name())
+    }
+}
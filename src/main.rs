@@ -1,6 +1,25 @@
-mod bof;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+mod format;
+mod progress;
+mod report;
+mod shell;
+mod top;
+use bof::events::JsonPrinter;
+use bof::{
+    audit, catalog, dedupe, events, filter, graph, hash, manifest, pack, policy, preset, registry,
+    settings, storage, BofError, Change, Config, DuplicateGroup, EntryInfo, Index, IndexStats,
+    Indexer, Mismatch, Segment, SymlinkPolicy, WhyMissing,
+};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "BOF")]
@@ -10,67 +29,1753 @@ struct Cli {
     command: Commands,
     #[arg(long, help = "Set the directory to save the index")]
     output_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "output_dir",
+        help = "Use a repository from the registry"
+    )]
+    repo: Option<String>,
+    #[arg(long, help = "Use the index of a profile, kept apart from the others")]
+    profile: Option<String>,
     #[arg(long, help = "Set paths to ignore while indexing")]
     ignore_paths: Vec<PathBuf>,
+    #[arg(long, value_parser = size_arg, help = "Leave out files smaller than this, e.g. 1 or 10kb")]
+    min_size: Option<u64>,
+    #[arg(long, value_parser = size_arg, help = "Leave out files larger than this, e.g. 4gib")]
+    max_size: Option<u64>,
     #[arg(short = 'p', help = "Enable parallel processing")]
     parallel: Option<bool>,
+    #[arg(long, value_name = "N", help = "Use N threads, 0 for one per core")]
+    threads: Option<usize>,
+    #[arg(long, value_name = "N", help = "Read at most N files at once")]
+    open_files: Option<usize>,
+    #[arg(
+        long,
+        help = "Run at the lowest CPU and IO priority, reading at most background_read_rate MiB/s"
+    )]
+    background: bool,
+    #[arg(
+        long,
+        help = "Wait for another bof changing the index to finish instead of failing"
+    )]
+    wait: bool,
+    #[arg(long, help = "Show a live dashboard while indexing or updating")]
+    top: bool,
+    #[arg(long, help = "Don't show a progress bar")]
+    quiet: bool,
+    #[arg(long, help = "Don't print how long the command took")]
+    no_timing: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "Format of the output")]
+    format: OutputFormat,
+    #[arg(long, help = "Pre-scan the paths to estimate progress")]
+    estimate: bool,
+    #[arg(long, value_enum, help = "Hash algorithm used for file keys")]
+    hash_algorithm: Option<hash::HashAlgorithm>,
+    #[arg(long, help = "Record only the metadata of files, see `bof rehash`")]
+    no_hash: bool,
+    #[arg(long, help = "Record alternate data streams and resource forks")]
+    streams: bool,
+    #[arg(
+        long,
+        help = "Record mode bits, owner and extended attributes of files"
+    )]
+    permissions: bool,
+    #[arg(long, help = "Record the type of files by their content")]
+    mime: bool,
+    #[arg(
+        long,
+        help = "Leave files open by other processes alone when changing files"
+    )]
+    skip_open: bool,
+    #[arg(
+        long,
+        help = "Don't walk into directories on another filesystem than their root"
+    )]
+    one_file_system: bool,
+    #[arg(long, value_enum, help = "Format used to save the index")]
+    index_format: Option<storage::IndexFormat>,
+    #[arg(long, value_enum, help = "Compress the saved index")]
+    compression: Option<storage::Compression>,
+    #[arg(long, help = "Save a JSON index without indentation")]
+    compact: bool,
+    #[arg(long, conflicts_with = "compact", help = "Save a JSON index indented")]
+    pretty: bool,
+    #[arg(long, value_enum, help = "What to do with symlinks")]
+    symlinks: Option<SymlinkPolicy>,
+    #[arg(
+        long,
+        help = "Query a snapshot or a generation instead of the current index"
+    )]
+    snapshot: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    // Newline-delimited JSON events on stdout
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[command(about = "Create a directory .bof for indexing")]
-    Init,
+    Init {
+        #[arg(
+            long,
+            value_enum,
+            help = "Seed Config.toml with settings for a common use case"
+        )]
+        preset: Option<preset::Preset>,
+        #[arg(long, help = "Choose the settings for Config.toml interactively")]
+        interactive: bool,
+    },
 
     #[command(arg_required_else_help = true)]
     #[command(about = "Index directories")]
     Index {
         #[arg(help = "Directories' paths")]
         paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Walk at most N levels of directories, counting the root itself"
+        )]
+        max_depth: Option<usize>,
+        #[arg(
+            long,
+            help = "Continue an interrupted run over the same paths, skipping directories it finished"
+        )]
+        resume: bool,
     },
     #[command(arg_required_else_help = true)]
     #[command(about = "Update existing index")]
     Update {
         #[arg(help = "Directories' paths to update")]
         paths: Vec<PathBuf>,
+        #[arg(long, value_name = "N", help = "Also re-hash N random unchanged files")]
+        spot_check: Option<usize>,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Walk at most N levels of directories, counting the root itself"
+        )]
+        max_depth: Option<usize>,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Re-hash files and directories even if they look unchanged")]
+    Touch {
+        #[arg(help = "Files' or directories' paths under indexed roots")]
+        paths: Vec<PathBuf>,
+    },
+    #[command(about = "Hash files that were indexed with --no-hash")]
+    Rehash {
+        #[arg(help = "Only files under these paths [default: all of them]")]
+        paths: Vec<PathBuf>,
+    },
+    #[command(about = "Make previews of indexed images and videos in .bof/previews")]
+    Previews {
+        #[arg(help = "Only files under these paths [default: all of them]")]
+        paths: Vec<PathBuf>,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Remove paths from the index, leaving the files on disk alone")]
+    Remove {
+        #[arg(help = "Indexed files' or directories' paths")]
+        paths: Vec<PathBuf>,
+    },
+    #[command(about = "Remove entries of paths that no longer exist from the index")]
+    Prune,
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Keep the index up to date as files change")]
+    Watch {
+        #[arg(help = "Indexed directories to watch")]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long,
+            default_value_t = 30,
+            help = "Save the index this often, in seconds, when something changed"
+        )]
+        flush_every: u64,
+    },
+    #[command(about = "Re-hash indexed files to detect silent corruption")]
+    Verify {
+        #[arg(help = "Only verify files under these paths")]
+        paths: Vec<PathBuf>,
+        #[arg(long, help = "Continue an interrupted verify")]
+        resume: bool,
+    },
+    #[command(about = "List indexed roots and who last updated them")]
+    Roots {
+        #[arg(long, help = "Only show roots last updated by the current user")]
+        mine: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Show changes since the last index or update")]
+    Status {
+        #[arg(help = "Directories' paths to compare")]
+        paths: Vec<PathBuf>,
+        #[arg(long, value_name = "N", help = "Also re-hash N random unchanged files")]
+        spot_check: Option<usize>,
+    },
+    #[command(about = "Serve the index over HTTP")]
+    Serve {
+        #[arg(
+            long,
+            default_value = "127.0.0.1:8080",
+            help = "Address and port to listen on, e.g. 0.0.0.0:8080 for other machines with serve_tokens"
+        )]
+        listen: String,
+    },
+    #[command(
+        about = "List the sessions of bof serve, watch and daemon, what each applied and how it ended"
+    )]
+    Sessions,
+    #[command(about = "List indexed entries under a path with their size, mtime and hash")]
+    Ls {
+        #[arg(default_value = ".", help = "Indexed directory or file")]
+        path: PathBuf,
+        #[arg(long, short, help = "List every entry below the directory")]
+        recursive: bool,
+    },
+    #[command(about = "Load the index once and answer queries typed one per line")]
+    Shell,
+    #[command(
+        name = "prompt-info",
+        about = "Print how many paths changed since the last update, quickly enough for a shell prompt"
+    )]
+    PromptInfo,
+    #[command(about = "Show totals, duplicates, the largest files and bytes by extension")]
+    Stats {
+        #[arg(
+            long,
+            default_value_t = 10,
+            help = "How many of the largest files to list"
+        )]
+        top: usize,
+        #[arg(long, help = "Also break files and bytes down by filesystem")]
+        volumes: bool,
+    },
+    #[command(about = "Write a report of the index as a web page, for people who don't run bof")]
+    Report {
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Directory to write index.html to, with everything in it"
+        )]
+        html: PathBuf,
+        #[arg(long, default_value_t = 20, help = "How many entries each list shows")]
+        top: usize,
+        #[arg(
+            long,
+            value_name = "DAYS",
+            default_value_t = 365,
+            help = "Files not modified for this many days are stale"
+        )]
+        stale_days: u64,
+    },
+    #[command(about = "Find indexed files matching a glob pattern")]
+    Find {
+        #[arg(help = "Pattern matched against file names, or whole paths when it contains a /")]
+        pattern: String,
+        #[arg(long, help = "Only files with this label")]
+        label: Option<String>,
+        #[arg(long, help = "Only files of this type, e.g. video or image/png")]
+        mime: Option<String>,
+        #[arg(long, help = "Search every registered repository")]
+        all_repos: bool,
+    },
+    #[command(about = "Find indexed files with a given hash or the same content as a file")]
+    Lookup {
+        #[arg(help = "Hash key, or path of a file to hash")]
+        hash: String,
+        #[arg(long, help = "Search every registered repository")]
+        all_repos: bool,
+    },
+    #[command(arg_required_else_help = true)]
+    #[command(about = "Fail if files have the same content as an indexed file, e.g. from a hook")]
+    CheckNew {
+        #[arg(help = "Files' paths")]
+        paths: Vec<PathBuf>,
+    },
+    #[command(about = "Explain why a file isn't in the index")]
+    WhyMissing {
+        #[arg(help = "Path of the file or directory")]
+        path: PathBuf,
+    },
+    #[command(
+        about = "Print which directories share content as a GraphViz graph, for near-copies"
+    )]
+    Graph {
+        #[arg(
+            long,
+            value_parser = size_arg,
+            default_value = "0",
+            help = "Leave out pairs of directories sharing less than this, e.g. 100mib"
+        )]
+        min_shared: u64,
+        #[arg(long, help = "Print the graph as JSON instead")]
+        json: bool,
+    },
+    #[command(about = "List indexed files with the same content")]
+    Duplicates {
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Leave out files smaller than this many bytes"
+        )]
+        min_group_size: u64,
+        #[arg(
+            long,
+            default_value_t = 2,
+            help = "Leave out content with fewer copies than this"
+        )]
+        min_copies: u64,
+        #[arg(long, help = "Only files with this label")]
+        label: Option<String>,
+        #[arg(
+            long,
+            num_args = 1..,
+            value_name = "PATH",
+            conflicts_with = "label",
+            help = "Look for duplicates under these directories instead of in the index, hashing only what is needed"
+        )]
+        scan: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "Only print how many groups and files there are and the space wasted"
+        )]
+        summary: bool,
+    },
+    #[command(
+        about = "Reclaim the space of duplicates by hardlinking, symlinking or deleting them"
+    )]
+    Dedupe {
+        #[arg(long, value_enum, help = "What to do with every copy but the one kept")]
+        action: dedupe::DedupeAction,
+        #[arg(long, value_enum, default_value_t = dedupe::Keep::First, help = "Which copy to keep")]
+        keep: dedupe::Keep,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Leave out files smaller than this many bytes"
+        )]
+        min_group_size: u64,
+        #[arg(
+            long,
+            default_value_t = 2,
+            help = "Leave out content with fewer copies than this"
+        )]
+        min_copies: u64,
+        #[arg(
+            long,
+            help = "Only list what would be done, the default without --apply"
+        )]
+        dry_run: bool,
+        #[arg(long, conflicts_with = "dry_run", help = "Carry out the action")]
+        apply: bool,
+        #[arg(
+            long,
+            help = "Also replace copies with another owner, group or mode than the kept one"
+        )]
+        across_owners: bool,
+    },
+    #[command(about = "List indexed files that are hardlinks of each other")]
+    Hardlinks,
+    #[command(about = "Archive an indexed subtree, storing each distinct content once")]
+    Pack {
+        #[arg(help = "Indexed directory to archive")]
+        subtree: PathBuf,
+        #[arg(
+            required_unless_present = "out",
+            help = "Path of the archive, e.g. archive.bofpack, or - for stdout"
+        )]
+        target: Option<PathBuf>,
+        #[arg(long, conflicts_with = "target", help = "Path of the archive")]
+        out: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "INDEX",
+            help = "Index of the destination, content it has is left out of the archive"
+        )]
+        against: Option<String>,
+    },
+    #[command(about = "Extract an archive made by bof pack")]
+    Unpack {
+        #[arg(help = "Path of the archive, or - for stdin")]
+        archive: PathBuf,
+        #[arg(long, default_value = ".", help = "Directory to extract into")]
+        into: PathBuf,
+        #[arg(
+            long,
+            value_name = "INDEX",
+            help = "Index to take the content left out of the archive from"
+        )]
+        against: Option<String>,
+    },
+    #[command(about = "Write the index as a checksum manifest or a content-addressed directory")]
+    Export {
+        #[arg(
+            long,
+            value_enum,
+            required_unless_present = "cas_layout",
+            help = "Format of the manifest"
+        )]
+        format: Option<manifest::ManifestFormat>,
+        #[arg(
+            short,
+            long,
+            help = "Write the manifest to this file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            long,
+            value_enum,
+            conflicts_with = "cas_layout",
+            help = "List this hash of the files, one of extra_hashes [default: the key]"
+        )]
+        hash: Option<hash::HashAlgorithm>,
+        #[arg(
+            long,
+            conflicts_with = "cas_layout",
+            help = "Hash the keys again with key_salt, to compare with others using the same salt without revealing them"
+        )]
+        salted: bool,
+        #[arg(
+            long,
+            value_name = "DIR",
+            conflicts_with_all = ["format", "output"],
+            help = "Put every content in DIR under the name of its hash, with paths.json mapping the original paths"
+        )]
+        cas_layout: Option<PathBuf>,
+    },
+    #[command(about = "Show what changed between two indexes or snapshots")]
+    Diff {
+        #[arg(help = "Snapshot id, generation, index file or directory holding an index")]
+        from: String,
+        #[arg(
+            help = "Snapshot id, generation, index file or directory holding an index [default: the current index]"
+        )]
+        to: Option<String>,
+        #[arg(long, help = "Print each change as a JSON object")]
+        json: bool,
+    },
+    #[command(about = "Compare two releases file by file, for release notes")]
+    AuditRelease {
+        #[arg(
+            help = "Snapshot id, generation, index file or directory holding the index of the previous release"
+        )]
+        from: String,
+        #[arg(
+            help = "Snapshot id, generation, index file or directory holding the index of the new release [default: the current index]"
+        )]
+        to: Option<String>,
+        #[arg(
+            long,
+            default_value = "",
+            hide_default_value = true,
+            help = "Release directory in the first index [default: all of it]"
+        )]
+        from_dir: PathBuf,
+        #[arg(
+            long,
+            default_value = "",
+            hide_default_value = true,
+            help = "Release directory in the second index [default: all of it]"
+        )]
+        to_dir: PathBuf,
+        #[arg(long, help = "Print each file as a JSON object")]
+        json: bool,
+    },
+    #[command(about = "Keep the index in memory and answer queries on a local socket")]
+    Daemon {
+        #[arg(
+            long,
+            help = "Also count what changes under the indexed roots, for prompt-info"
+        )]
+        drift: bool,
+    },
+    #[command(
+        about = "Filter the index with an expression, or send a JSON-RPC request to the running daemon"
+    )]
+    Query {
+        #[arg(
+            help = "An expression such as 'size > 100MB and ext == mp4', or a method of the daemon: find, lookup, duplicates, hardlinks, roots, summary, update, reload or shutdown"
+        )]
+        method: String,
+        #[arg(help = "Parameters as a JSON object, e.g. '{\"pattern\": \"*.jpg\"}'")]
+        params: Option<String>,
+    },
+    #[command(about = "Keep a copy of the current index in .bof/snapshots")]
+    Snapshot,
+    #[command(about = "List snapshots of the index")]
+    Snapshots,
+    #[command(about = "List the generations of the index, one per save, the last first")]
+    Log,
+    #[command(about = "Apply the policies of Config.toml to indexed files")]
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommands,
+    },
+    #[command(about = "Read or change settings in Config.toml")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    #[command(about = "Manage the registry of known repositories")]
+    Repos {
+        #[command(subcommand)]
+        command: ReposCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    #[command(about = "Print the value of a setting")]
+    Get {
+        #[arg(help = "Name of the setting, e.g. parallel or alias.everywhere")]
+        key: String,
     },
+    #[command(about = "Change a setting")]
+    Set {
+        #[arg(help = "Name of the setting, e.g. parallel or alias.everywhere")]
+        key: String,
+        #[arg(help = "New value, in TOML syntax for lists and booleans")]
+        value: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PolicyCommands {
+    #[command(about = "Evaluate the policies and carry out their actions")]
+    Run {
+        #[arg(long, help = "Only report what would be done")]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ReposCommands {
+    #[command(about = "List registered repositories")]
+    List,
+    #[command(about = "Register the current repository under a name")]
+    Add {
+        #[arg(help = "Name to use with --repo")]
+        name: String,
+    },
+    #[command(about = "Forget a registered repository")]
+    Remove {
+        #[arg(help = "Name of the repository")]
+        name: String,
+    },
+}
+
+// Counts errors reported while the command ran, for the summary and exit code
+#[derive(Default)]
+struct ErrorCount(AtomicUsize);
+
+impl events::Subscriber for ErrorCount {
+    fn on_event(&self, event: &events::Event) {
+        if let events::Event::Error(_) = event {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn size_arg(value: &str) -> Result<u64, String> {
+    bof::filter::parse_size(value)
+        .ok_or_else(|| format!("invalid size {}, e.g. 500, 10kb or 4gib", value))
+}
+
+// Replaces the command word with its alias from the configuration, unless it is a built-in command
+fn expand_alias(mut args: Vec<String>, aliases: &BTreeMap<String, String>) -> Vec<String> {
+    let command = Cli::command();
+    let takes_value = |option: &str| {
+        command.get_arguments().any(|arg| {
+            let matches = match option.strip_prefix("--") {
+                Some(long) => arg.get_long() == Some(long),
+                None => option.len() == 2 && arg.get_short() == option[1..].chars().next(),
+            };
+            matches && arg.get_action().takes_values()
+        })
+    };
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--" {
+            break;
+        }
+        if arg.starts_with('-') {
+            // Skip the option's value too, so it's never mistaken for the command
+            i += if !arg.contains('=') && takes_value(arg) {
+                2
+            } else {
+                1
+            };
+            continue;
+        }
+        if command.find_subcommand(arg).is_none() {
+            if let Some(expansion) = aliases.get(arg) {
+                let expansion = expansion
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                args.splice(i..=i, expansion);
+            }
+        }
+        break;
+    }
+    args
+}
+
+// Runs a search on the current repository, or on every registered one prefixing results with its name
+fn search(
+    all_repos: bool,
+    config: &Config,
+    search: impl Fn(&Path) -> bof::Result<Vec<PathBuf>>,
+) -> bof::Result<Vec<String>> {
+    if !all_repos {
+        return search(&config.output_dir).map(|paths| {
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect()
+        });
+    }
+    let registry = registry::Registry::load()?;
+    let mut lines = Vec::new();
+    for (name, repo) in registry.repos() {
+        match search(&repo.path) {
+            Ok(paths) => {
+                lines.extend(
+                    paths
+                        .iter()
+                        .map(|path| format!("{}\t{}", name, path.display())),
+                );
+            }
+            Err(e) => events::emit(events::Event::Error(format!(
+                "Error searching {}: {}",
+                name, e
+            ))),
+        }
+    }
+    Ok(lines)
+}
+
+// Indexed paths are kept as given, without a leading ./
+fn listed_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+fn ls(index: &Index, path: &Path, recursive: bool) -> Result<Vec<String>, String> {
+    let dir = listed_dir(path);
+    let entries = index.list(&dir, recursive);
+    if entries.is_empty() && !dir.as_os_str().is_empty() {
+        return Err(format!("Nothing indexed under {}", path.display()));
+    }
+    Ok(entries.iter().map(|entry| ls_line(entry, &dir)).collect())
+}
+
+// Size, modification time, start of the key and path relative to the listed directory
+fn ls_line(entry: &EntryInfo, dir: &Path) -> String {
+    let size = entry.size.map(format::human_bytes).unwrap_or_default();
+    let mtime = entry.mtime.map(format::timestamp).unwrap_or_default();
+    let key = if entry.key.is_empty() {
+        "-"
+    } else {
+        entry.key.get(..12).unwrap_or(&entry.key)
+    };
+    let relative = entry.path.strip_prefix(dir).unwrap_or(&entry.path);
+    let mut name = if relative.as_os_str().is_empty() {
+        entry.path.display().to_string()
+    } else {
+        relative.display().to_string()
+    };
+    match (entry.kind, &entry.target) {
+        ("directory", _) => name.push('/'),
+        (_, Some(target)) => name = format!("{} -> {}", name, target.display()),
+        _ => {}
+    }
+    format!("{:>10}  {:19}  {:12}  {}", size, mtime, key, name)
+}
+
+fn stats_lines(stats: &IndexStats, volumes: bool) -> Vec<String> {
+    let mut lines = vec![
+        format!("Files\t{}", stats.files),
+        format!("Directories\t{}", stats.directories),
+        format!("Links\t{}", stats.links),
+        format!("Size\t{}", format::human_bytes(stats.bytes)),
+        format!(
+            "Duplicates\t{} groups, {} wasted",
+            stats.duplicate_groups,
+            format::human_bytes(stats.wasted_bytes)
+        ),
+        String::new(),
+        "Largest files".to_string(),
+    ];
+    lines.extend(
+        stats
+            .largest
+            .iter()
+            .map(|(path, size)| format!("{:>10}  {}", format::human_bytes(*size), path.display())),
+    );
+    lines.push(String::new());
+    lines.push("By extension".to_string());
+    lines.extend(stats.extensions.iter().map(|extension| {
+        let name = match extension.extension.as_str() {
+            "" => "(none)".to_string(),
+            name => format!(".{}", name),
+        };
+        format!(
+            "{:>10}  {:>8} files  {}",
+            format::human_bytes(extension.bytes),
+            extension.files,
+            name
+        )
+    }));
+    if volumes {
+        lines.push(String::new());
+        lines.push("By volume".to_string());
+        lines.extend(stats.volumes.iter().map(|volume| {
+            let mut name = match (&volume.mount_point, volume.device) {
+                (Some(mount_point), _) => mount_point.display().to_string(),
+                (None, 0) => "(unknown)".to_string(),
+                (None, device) => format!("device {}", device),
+            };
+            if let Some(filesystem) = &volume.filesystem {
+                name.push_str(&format!(" ({})", filesystem));
+            }
+            if !volume.roots.is_empty() {
+                let roots = volume
+                    .roots
+                    .iter()
+                    .map(|root| root.display().to_string())
+                    .collect::<Vec<_>>();
+                name.push_str(&format!(", roots {}", roots.join(", ")));
+            }
+            format!(
+                "{:>10}  {:>8} files  {}",
+                format::human_bytes(volume.bytes),
+                volume.files,
+                name
+            )
+        }));
+    }
+    lines
+}
+
+// A line per group, or a single line with the totals
+fn duplicate_lines(groups: &[DuplicateGroup], summary: bool) -> Vec<String> {
+    if summary {
+        let files = groups.iter().map(|group| group.paths.len()).sum::<usize>();
+        let wasted = groups.iter().map(|group| group.wasted()).sum();
+        return vec![format!(
+            "{} groups, {} files, {} wasted",
+            groups.len(),
+            files,
+            format::human_bytes(wasted)
+        )];
+    }
+    groups
+        .iter()
+        .map(|group| {
+            let paths = group
+                .paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+            format!(
+                "{} x {} ({} wasted)\t{}",
+                format::human_bytes(group.size),
+                group.copies,
+                format::human_bytes(group.wasted()),
+                paths.join("\t")
+            )
+        })
+        .collect()
+}
+
+// The graph in GraphViz's DOT language, e.g. for `dot -Tsvg`. Edges are thicker the more they
+// share, relative to the edge sharing the most.
+fn graph_lines(graph: &graph::DuplicateGraph) -> Vec<String> {
+    let name = |path: &Path| match path.to_string_lossy() {
+        path if path.is_empty() => ".".to_string(),
+        path => path.replace('\\', "\\\\").replace('"', "\\\""),
+    };
+    let most = graph
+        .edges
+        .first()
+        .map_or(1, |edge| edge.shared_bytes.max(1));
+    let mut lines = vec![
+        "graph duplicates {".to_string(),
+        "  node [shape=box];".to_string(),
+    ];
+    lines.extend(graph.nodes.iter().map(|node| {
+        format!(
+            "  \"{0}\" [label=\"{0}\\n{1} files, {2}\"];",
+            name(&node.path),
+            node.files,
+            format::human_bytes(node.bytes)
+        )
+    }));
+    lines.extend(graph.edges.iter().map(|edge| {
+        format!(
+            "  \"{}\" -- \"{}\" [label=\"{}\", penwidth={:.1}];",
+            name(&edge.from),
+            name(&edge.to),
+            format::human_bytes(edge.shared_bytes),
+            1.0 + 7.0 * edge.shared_bytes as f64 / most as f64
+        )
+    }));
+    lines.push("}".to_string());
+    lines
+}
+
+fn why_missing(path: &Path, why: WhyMissing) -> String {
+    match why {
+        WhyMissing::Indexed => format!("{} is indexed", path.display()),
+        WhyMissing::Skipped(skipped) if skipped.path == path => {
+            format!("{} was skipped: {}", path.display(), skipped.reason)
+        }
+        WhyMissing::Skipped(skipped) => format!(
+            "{} is in {}, which was skipped: {}",
+            path.display(),
+            skipped.path.display(),
+            skipped.reason
+        ),
+        WhyMissing::NotUnderRoot => format!("{} is not under an indexed root", path.display()),
+        WhyMissing::NotFound(root) if path.exists() => format!(
+            "{} was not there when {} was last updated ({}), run `bof update`",
+            path.display(),
+            root.path.display(),
+            format::timestamp(root.updated)
+        ),
+        WhyMissing::NotFound(_) => format!("{} doesn't exist", path.display()),
+    }
+}
+
+fn repos(command: ReposCommands, config: &Config) -> Result<Vec<String>, String> {
+    match command {
+        ReposCommands::List => registry::Registry::load()
+            .map(|registry| {
+                registry
+                    .repos()
+                    .map(|(name, repo)| {
+                        match Index::load(&repo.path, config.index_name())
+                            .map(|index| index.summary())
+                        {
+                            Ok(summary) => format!(
+                                "{}\t{}\t{} files, {} ({} duplicated)\t{}",
+                                name,
+                                repo.path.display(),
+                                summary.files,
+                                format::human_bytes(summary.bytes),
+                                format::human_bytes(summary.duplicate_bytes),
+                                summary
+                                    .updated
+                                    .map(format::timestamp)
+                                    .unwrap_or_else(|| "never".to_string())
+                            ),
+                            Err(e) => {
+                                format!("{}\t{}\tunavailable: {}", name, repo.path.display(), e)
+                            }
+                        }
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing repositories: {}", e)),
+        ReposCommands::Add { name } => registry::Registry::add(&name, &config.output_dir)
+            .map(|path| vec![format!("Registered {} at {}", name, path.display())])
+            .map_err(|e| format!("Error registering repository: {}", e)),
+        ReposCommands::Remove { name } => registry::Registry::remove(&name)
+            .map(|repo| vec![format!("Removed {} ({})", name, repo.path.display())])
+            .map_err(|e| format!("Error removing repository: {}", e)),
+    }
+}
+
+// Output of a command, on stdout. Nothing more can be printed once stdout is closed, e.g. by
+// `head`, which is no reason to fail.
+pub(crate) fn print_lines(lines: &[String]) {
+    let mut stdout = io::stdout().lock();
+    for line in lines {
+        if writeln!(stdout, "{}", line).is_err() {
+            return;
+        }
+    }
+}
+
+// Previews of what was just indexed, when `previews` is set
+fn previews_made(indexer: &Indexer, paths: &[PathBuf]) -> Result<Vec<String>, String> {
+    if !indexer.config().previews {
+        return Ok(Vec::new());
+    }
+    previews(indexer, paths)
+}
+
+fn previews(indexer: &Indexer, paths: &[PathBuf]) -> Result<Vec<String>, String> {
+    let summary = indexer
+        .previews(paths)
+        .map_err(|e| format!("Error making previews: {}", e))?;
+    Ok(vec![format!(
+        "Made {} previews, {} failed, {} already made, removed {} of content no longer indexed",
+        summary.made, summary.failed, summary.existing, summary.removed
+    )])
+}
+
+// Corrupted files found by `--spot-check`, checked before update and status look at the files
+fn spot_checked(
+    indexer: &Indexer,
+    paths: &[PathBuf],
+    count: Option<usize>,
+    failed: &mut bool,
+) -> Result<Vec<String>, String> {
+    let Some(count) = count else {
+        return Ok(Vec::new());
+    };
+    let mismatches = indexer
+        .spot_check(paths, count)
+        .map_err(|e| format!("Error spot-checking files: {}", e))?;
+    *failed |= !mismatches.is_empty();
+    Ok(mismatches.iter().map(|m| m.to_string()).collect())
+}
+
+// Commands that save the index, or snapshots of it, which take turns. Watching and the daemon
+// run for long, they take the lock each time they save instead.
+fn changes_index(command: &Commands) -> bool {
+    match command {
+        Commands::Index { .. }
+        | Commands::Update { .. }
+        | Commands::Touch { .. }
+        | Commands::Rehash { .. }
+        | Commands::Remove { .. }
+        | Commands::Prune
+        | Commands::Snapshot => true,
+        Commands::Dedupe { apply, .. } => *apply,
+        Commands::Policy {
+            command: PolicyCommands::Run { dry_run },
+        } => !dry_run,
+        _ => false,
+    }
+}
+
+// Fails right away when another bof holds the lock, or says so and waits for it with `--wait`
+fn lock_index(output_dir: &Path, wait: bool) -> bof::Result<storage::IndexLock> {
+    match storage::lock(output_dir, false) {
+        Err(e @ BofError::Locked { .. }) if wait => {
+            eprintln!("{}, waiting for it", e);
+            storage::lock(output_dir, true)
+        }
+        locked => locked,
+    }
+}
+
+fn exit_with(format: OutputFormat, message: String) -> ! {
+    match format {
+        OutputFormat::Json => JsonPrinter::print(json!({"event": "error", "message": message})),
+        OutputFormat::Text => eprintln!("{}", message),
+    }
+    std::process::exit(1);
 }
 
 fn main() {
     let now = std::time::Instant::now();
 
-    let mut config = bof::load_config();
-    let args = Cli::parse();
+    let mut config = match bof::load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let args = Cli::parse_from(expand_alias(std::env::args().collect(), &config.alias));
 
     if let Some(parallel) = args.parallel {
         config.parallel = parallel;
     }
 
+    if let Some(threads) = args.threads {
+        config.threads = threads;
+    }
+
+    if let Some(open_files) = args.open_files {
+        config.open_files = open_files;
+    }
+
+    if args.background {
+        config.background = true;
+    }
+
+    if args.estimate {
+        config.estimate = true;
+    }
+
+    if args.streams {
+        config.streams = true;
+    }
+
+    if args.permissions {
+        config.permissions = true;
+    }
+
+    if args.mime {
+        config.mime = true;
+    }
+
+    if args.skip_open {
+        config.skip_open = true;
+    }
+
+    if args.one_file_system {
+        config.one_file_system = true;
+    }
+
+    if args.no_hash {
+        config.no_hash = true;
+    }
+
+    if let Some(min_size) = args.min_size {
+        config.min_size = min_size;
+    }
+
+    if let Some(max_size) = args.max_size {
+        config.max_size = max_size;
+    }
+
+    if let Some(hash_algorithm) = args.hash_algorithm {
+        config.hash_algorithm = hash_algorithm;
+    }
+
+    if let Some(index_format) = args.index_format {
+        config.index_format = index_format;
+    }
+
+    if let Some(compression) = args.compression {
+        config.compression = compression;
+    }
+
+    if args.compact || args.pretty {
+        config.compact = Some(args.compact);
+    }
+
+    if let Some(symlinks) = args.symlinks {
+        config.symlinks = symlinks;
+    }
+
     if let Some(output_dir) = args.output_dir {
         config.output_dir = output_dir;
     }
 
+    if let Some(name) = &args.repo {
+        match registry::Registry::load().and_then(|registry| registry.get(name).cloned()) {
+            Ok(repo) => config.output_dir = repo.path,
+            Err(e) => exit_with(args.format, format!("Error finding repository: {}", e)),
+        }
+    }
+
+    if let Some(profile) = args.profile {
+        config.profile = Some(profile);
+    }
+
+    // Past init, which writes the settings as given, commands work in the directory of the profile
+    if !matches!(args.command, Commands::Init { .. }) {
+        config.output_dir = config.index_dir();
+    }
+
     if !args.ignore_paths.is_empty() {
         config.ignore_paths.extend(args.ignore_paths);
     }
 
-    match args.command {
-        Commands::Init => {
-            if let Err(e) = bof::init(&mut config) {
-                println!("Error initializing: {}", e);
+    if let Commands::Index {
+        max_depth: Some(max_depth),
+        ..
+    }
+    | Commands::Update {
+        max_depth: Some(max_depth),
+        ..
+    } = args.command
+    {
+        config.max_depth = max_depth;
+    }
+
+    // Held until the command is done
+    let _lock = match changes_index(&args.command) {
+        true => match lock_index(&config.output_dir, args.wait) {
+            Ok(lock) => Some(lock),
+            Err(e @ BofError::Locked { .. }) => {
+                exit_with(args.format, format!("{}, --wait waits for it to finish", e))
             }
+            Err(e) => exit_with(args.format, format!("Error locking the index: {}", e)),
+        },
+        false => None,
+    };
+
+    let mut indexer = Indexer::new(config);
+    let activity = match &args.command {
+        Commands::Index { .. } => Some("indexing"),
+        Commands::Update { .. } | Commands::Touch { .. } | Commands::Rehash { .. } => {
+            Some("updating")
         }
-        Commands::Index { paths } => {
-            if let Err(e) = bof::index_directories(paths, &config) {
-                println!("Error indexing directories: {}", e);
+        Commands::Verify { .. } => Some("verifying"),
+        _ => None,
+    };
+    let json = args.format == OutputFormat::Json;
+    // A prompt shows whatever is printed, on stdout or not
+    let timed = !args.no_timing && !matches!(&args.command, Commands::PromptInfo);
+    // With an archive written to stdout, everything else goes to stderr
+    let stdout_taken = matches!(
+        &args.command,
+        Commands::Pack { target, out, .. }
+            if out.as_ref().or(target.as_ref()).is_some_and(|out| out == Path::new("-"))
+    );
+    let activity = activity.filter(|_| !json);
+    let dashboard = activity.filter(|_| args.top).map(top::Dashboard::start);
+    let progress = activity
+        .filter(|_| !args.top && !args.quiet)
+        .map(|activity| Arc::new(progress::Progress::start(activity)));
+    match (&dashboard, &progress) {
+        (Some(dashboard), _) => events::subscribe(dashboard.subscriber()),
+        (None, Some(progress)) => events::subscribe(progress.clone()),
+        (None, None) if json => events::subscribe(Arc::new(events::JsonPrinter)),
+        (None, None) => events::subscribe(Arc::new(events::Printer)),
+    }
+    let errors = Arc::new(ErrorCount::default());
+    events::subscribe(errors.clone());
+
+    let snapshot = args.snapshot;
+    // The same index_name in every repository searched
+    let name = indexer.config().index_name().to_string();
+    let load = |output_dir: &Path| match &snapshot {
+        Some(id) => Index::load_snapshot(output_dir, &name, id),
+        None => Index::load(output_dir, &name),
+    };
+    // Queries that only need some of the entries leave the others unloaded
+    let load_segment = |output_dir: &Path, segment: Option<Segment>| match (&snapshot, segment) {
+        (_, None) => load(output_dir),
+        (Some(id), Some(segment)) => Index::load_snapshot_segment(output_dir, &name, id, &segment),
+        (None, Some(segment)) => Index::load_segment(output_dir, &name, &segment),
+    };
+
+    // Command output is printed once the dashboard or progress bar is gone
+    let mut failed = false;
+    let result = match args.command {
+        Commands::Init {
+            preset,
+            interactive,
+        } => indexer
+            .init(preset, interactive)
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error initializing: {}", e)),
+        Commands::Index { paths, resume, .. } => indexer
+            .index(paths.clone(), resume)
+            .map_err(|e| format!("Error indexing directories: {}", e))
+            .and_then(|_| previews_made(&indexer, &paths)),
+        Commands::Update {
+            paths, spot_check, ..
+        } => spot_checked(&indexer, &paths, spot_check, &mut failed).and_then(|mut lines| {
+            indexer
+                .update(paths.clone())
+                .map_err(|e| format!("Error updating directories: {}", e))?;
+            lines.extend(previews_made(&indexer, &paths)?);
+            Ok(lines)
+        }),
+        Commands::Touch { paths } => indexer
+            .touch(paths)
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error touching files: {}", e)),
+        Commands::Rehash { paths } => indexer
+            .rehash(paths)
+            .map(|count| vec![format!("Hashed {} files", count)])
+            .map_err(|e| format!("Error hashing files: {}", e)),
+        Commands::Previews { paths } => previews(&indexer, &paths),
+        Commands::Remove { paths } => indexer
+            .remove(paths)
+            .map(|count| vec![format!("Removed {} entries", count)])
+            .map_err(|e| format!("Error removing paths: {}", e)),
+        Commands::Prune => indexer
+            .prune()
+            .map(|summary| {
+                vec![format!(
+                    "Removed {} entries and {} roots that no longer exist, the index is {} smaller",
+                    summary.entries,
+                    summary.roots,
+                    format::human_bytes(summary.bytes)
+                )]
+            })
+            .map_err(|e| format!("Error pruning the index: {}", e)),
+        Commands::Watch { paths, flush_every } => indexer
+            .watch(paths, std::time::Duration::from_secs(flush_every))
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error watching directories: {}", e)),
+        Commands::Verify { paths, resume } => indexer
+            .verify(paths, resume)
+            .map(|mismatches| {
+                failed = mismatches
+                    .iter()
+                    .any(|mismatch| matches!(mismatch, Mismatch::Corrupted(_)));
+                mismatches.iter().map(|m| m.to_string()).collect()
+            })
+            .map_err(|e| format!("Error verifying: {}", e)),
+        Commands::Roots { mine } => load(&indexer.config().output_dir)
+            .map(|index| {
+                index
+                    .roots(mine)
+                    .iter()
+                    .map(|root| {
+                        format!(
+                            "{}\t{}@{}\t{}",
+                            root.path.display(),
+                            root.owner,
+                            root.host,
+                            format::timestamp(root.updated)
+                        )
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing roots: {}", e)),
+        Commands::Status { paths, spot_check } => {
+            spot_checked(&indexer, &paths, spot_check, &mut failed).and_then(|corrupted| {
+                indexer
+                    .status(paths)
+                    .map(|changes| {
+                        let lines = changes.iter().map(|change| change.to_string());
+                        lines.chain(corrupted).collect()
+                    })
+                    .map_err(|e| format!("Error getting status: {}", e))
+            })
+        }
+        Commands::Serve { listen } => indexer
+            .serve(&listen)
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error serving the index: {}", e)),
+        Commands::Sessions => indexer
+            .sessions()
+            .map(|sessions| {
+                sessions
+                    .iter()
+                    .map(|session| {
+                        format!(
+                            "{}\t{}\t{}\t{} created, {} modified, {} deleted, {} renamed\t{}",
+                            session.session,
+                            format::timestamp(session.started),
+                            format::timestamp(session.stopped),
+                            session.created,
+                            session.modified,
+                            session.deleted,
+                            session.renamed,
+                            session.ended
+                        )
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing sessions: {}", e)),
+        Commands::Ls { path, recursive } => {
+            let dir = listed_dir(&path);
+            let segment = (!dir.as_os_str().is_empty()).then(|| Segment::PathPrefix(dir.clone()));
+            load_segment(&indexer.config().output_dir, segment)
+                .map_err(|e| format!("Error listing {}: {}", path.display(), e))
+                .and_then(|index| ls(&index, &path, recursive))
+        }
+        // Nothing at all when nothing changed or nothing keeps count, so prompts stay short
+        Commands::PromptInfo => Ok(match indexer.prompt_info() {
+            Some(changed) if changed > 0 => vec![format!("{} changed", changed)],
+            _ => Vec::new(),
+        }),
+        Commands::Shell => shell::run(indexer.config(), snapshot.as_deref())
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error in the shell: {}", e)),
+        Commands::Stats { top, volumes } => load(&indexer.config().output_dir)
+            .map(|index| stats_lines(&index.stats(top), volumes))
+            .map_err(|e| format!("Error computing stats: {}", e)),
+        Commands::Report {
+            html,
+            top,
+            stale_days,
+        } => load(&indexer.config().output_dir)
+            .and_then(|index| Ok(report::write_html(&index, &html, top, stale_days)?))
+            .map(|path| vec![format!("Report written to {}", path.display())])
+            .map_err(|e| format!("Error writing the report: {}", e)),
+        Commands::Find {
+            pattern,
+            label,
+            mime,
+            all_repos,
+        } => search(all_repos, indexer.config(), |output_dir| {
+            load_segment(output_dir, Segment::for_pattern(&pattern))?.find(
+                &pattern,
+                label.as_deref(),
+                mime.as_deref(),
+            )
+        })
+        .map_err(|e| format!("Error finding files: {}", e)),
+        Commands::Lookup { hash, all_repos } => search(all_repos, indexer.config(), |output_dir| {
+            // A file has to be hashed with the index's algorithm before its key is known
+            if Path::new(&hash).is_file() {
+                return load(output_dir)?.lookup(&hash);
+            }
+            if snapshot.is_none() {
+                if let Some(paths) = catalog::lookup(output_dir, &name, &hash)? {
+                    return Ok(paths);
+                }
+            }
+            load_segment(output_dir, Some(Segment::KeyPrefix(hash.to_lowercase())))?.lookup(&hash)
+        })
+        .map_err(|e| format!("Error looking up files: {}", e)),
+        Commands::CheckNew { paths } => load(&indexer.config().output_dir)
+            .map(|index| {
+                let mut lines = Vec::new();
+                for path in &paths {
+                    match index.copies_of(path) {
+                        Ok(copies) if copies.is_empty() => {}
+                        Ok(copies) => {
+                            failed = true;
+                            let copies = copies
+                                .iter()
+                                .map(|copy| copy.display().to_string())
+                                .collect::<Vec<_>>();
+                            lines.push(format!(
+                                "{} is already indexed as\t{}",
+                                path.display(),
+                                copies.join("\t")
+                            ));
+                        }
+                        Err(e) => events::emit(events::Event::Error(format!(
+                            "Failed to check {}: {}",
+                            path.display(),
+                            e
+                        ))),
+                    }
+                }
+                lines
+            })
+            .map_err(|e| format!("Error checking files: {}", e)),
+        Commands::WhyMissing { path } => load(&indexer.config().output_dir)
+            .map(|index| vec![why_missing(&path, index.why_missing(&path))])
+            .map_err(|e| format!("Error looking for {}: {}", path.display(), e)),
+        Commands::Graph { min_shared, json } => load(&indexer.config().output_dir)
+            .map(|index| {
+                let graph = graph::graph(&index, min_shared);
+                if json {
+                    vec![json!(graph).to_string()]
+                } else {
+                    graph_lines(&graph)
+                }
+            })
+            .map_err(|e| format!("Error making the graph: {}", e)),
+        Commands::Duplicates {
+            min_group_size,
+            min_copies,
+            label,
+            scan,
+            summary,
+        } => {
+            let output_dir = &indexer.config().output_dir;
+            let from_index = || {
+                load(output_dir)
+                    .map(|index| index.duplicates(min_group_size, min_copies, label.as_deref()))
+            };
+            if !scan.is_empty() {
+                Ok(indexer.scan_duplicates(&scan, min_group_size, min_copies))
+            } else if label.is_some() || snapshot.is_some() {
+                from_index()
+            } else {
+                // The catalog is only a shortcut, the index is still there when it can't be read
+                match catalog::duplicates(output_dir, &name, min_group_size, min_copies) {
+                    Ok(Some(groups)) => Ok(groups),
+                    _ => from_index(),
+                }
             }
         }
-        Commands::Update { paths } => {
-            if let Err(e) = bof::update_directories(paths, &config) {
-                println!("Error updating directories: {}", e);
+        .map(|groups| duplicate_lines(&groups, summary))
+        .map_err(|e| format!("Error listing duplicates: {}", e)),
+        Commands::Dedupe {
+            action,
+            keep,
+            min_group_size,
+            min_copies,
+            dry_run: _,
+            apply,
+            across_owners,
+        } => indexer
+            .dedupe(
+                action,
+                keep,
+                min_group_size,
+                min_copies,
+                !apply,
+                across_owners,
+            )
+            .map(|deduped| {
+                failed = deduped
+                    .iter()
+                    .any(|deduped| matches!(deduped.outcome, dedupe::Outcome::Failed(_)));
+                let (files, bytes) = deduped
+                    .iter()
+                    .filter(|deduped| {
+                        matches!(
+                            deduped.outcome,
+                            dedupe::Outcome::Planned | dedupe::Outcome::Done
+                        )
+                    })
+                    .fold((0, 0), |(files, bytes), deduped| {
+                        (files + 1, bytes + deduped.matched.size)
+                    });
+                let mut lines = deduped
+                    .iter()
+                    .map(|deduped| deduped.to_string())
+                    .collect::<Vec<_>>();
+                lines.push(format!(
+                    "{} {} from {} files",
+                    if !apply { "Would reclaim" } else { "Reclaimed" },
+                    format::human_bytes(bytes),
+                    files
+                ));
+                lines
+            })
+            .map_err(|e| format!("Error deduplicating files: {}", e)),
+        Commands::Hardlinks => load(&indexer.config().output_dir)
+            .map(|index| {
+                index
+                    .hardlinks()
+                    .iter()
+                    .map(|group| {
+                        let paths = group
+                            .paths
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect::<Vec<_>>();
+                        format!("{}\t{}", format::human_bytes(group.size), paths.join("\t"))
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing hardlinks: {}", e)),
+        Commands::Pack {
+            subtree,
+            target,
+            out,
+            against,
+        } => {
+            let out = out.or(target).expect("required by clap");
+            let against = against
+                .as_deref()
+                .map(|spec| Index::open(&indexer.config().output_dir, &name, spec))
+                .transpose();
+            if stdout_taken && json {
+                Err("The archive can't go to stdout with --format json".to_string())
+            } else if stdout_taken && io::stdout().is_terminal() {
+                Err("Refusing to write an archive to a terminal".to_string())
+            } else {
+                against
+                    .and_then(|against| indexer.pack(&subtree, &out, against.as_ref()))
+                    .map(|summary| {
+                        let mut line = format!(
+                            "Packed {} files ({}) into {}, {} stored",
+                            summary.files,
+                            format::human_bytes(summary.bytes),
+                            if stdout_taken {
+                                "stdout".to_string()
+                            } else {
+                                out.display().to_string()
+                            },
+                            format::human_bytes(summary.stored)
+                        );
+                        if summary.present > 0 {
+                            line.push_str(&format!(
+                                ", {} already at the destination",
+                                format::human_bytes(summary.present)
+                            ));
+                        }
+                        vec![line]
+                    })
+                    .map_err(|e| format!("Error packing {}: {}", subtree.display(), e))
             }
         }
-    }
+        Commands::Unpack {
+            archive,
+            into,
+            against,
+        } => against
+            .as_deref()
+            .map(|spec| Index::open(&indexer.config().output_dir, &name, spec))
+            .transpose()
+            .and_then(|against| pack::unpack(&archive, &into, against.as_ref()))
+            .map(|summary| {
+                vec![format!(
+                    "Unpacked {} files ({}) into {}",
+                    summary.files,
+                    format::human_bytes(summary.bytes),
+                    into.display()
+                )]
+            })
+            .map_err(|e| format!("Error unpacking {}: {}", archive.display(), e)),
+        Commands::Export {
+            cas_layout: Some(dir),
+            ..
+        } => load(&indexer.config().output_dir)
+            .and_then(|index| index.cas_layout(&dir))
+            .map(|summary| {
+                vec![format!(
+                    "Exported {} files as {} objects into {}, {} linked and {} copied",
+                    summary.files,
+                    summary.objects,
+                    dir.display(),
+                    summary.linked,
+                    summary.copied
+                )]
+            })
+            .map_err(|e| format!("Error exporting the index: {}", e)),
+        Commands::Export {
+            format,
+            output,
+            hash,
+            salted,
+            ..
+        } => {
+            let salt = &indexer.config().key_salt;
+            load(&indexer.config().output_dir)
+                .and_then(|index| {
+                    index.manifest(
+                        format.expect("required by clap"),
+                        hash,
+                        salted.then_some(salt.as_str()),
+                    )
+                })
+                .and_then(|lines| match output {
+                    Some(output) => {
+                        let mut contents = lines.join("\n");
+                        contents.push('\n');
+                        std::fs::write(output, contents)?;
+                        Ok(Vec::new())
+                    }
+                    None => Ok(lines),
+                })
+                .map_err(|e| format!("Error exporting the index: {}", e))
+        }
+        Commands::Diff { from, to, json } => {
+            let output_dir = &indexer.config().output_dir;
+            Index::open(output_dir, &name, &from)
+                .and_then(|from| {
+                    let to = match &to {
+                        Some(to) => Index::open(output_dir, &name, to)?,
+                        None => Index::load(output_dir, &name)?,
+                    };
+                    Ok(from.diff(&to))
+                })
+                .map(|changes| {
+                    changes
+                        .iter()
+                        .map(|change| match (json, change) {
+                            (false, change) => change.to_string(),
+                            (true, Change::Moved { from, to }) => {
+                                json!({"change": change.label(), "from": from, "path": to})
+                                    .to_string()
+                            }
+                            (true, change) => {
+                                json!({"change": change.label(), "path": change.path()}).to_string()
+                            }
+                        })
+                        .collect()
+                })
+                .map_err(|e| format!("Error comparing indexes: {}", e))
+        }
+        Commands::AuditRelease {
+            from,
+            to,
+            from_dir,
+            to_dir,
+            json,
+        } => {
+            let output_dir = &indexer.config().output_dir;
+            Index::open(output_dir, &name, &from)
+                .and_then(|old| {
+                    let new = match &to {
+                        Some(to) => Index::open(output_dir, &name, to)?,
+                        None => Index::load(output_dir, &name)?,
+                    };
+                    Ok(audit::audit(&old, &from_dir, &new, &to_dir))
+                })
+                .map(|audited| {
+                    if json {
+                        audited.iter().map(|file| json!(file).to_string()).collect()
+                    } else {
+                        let name = |spec: Option<&String>, dir: &Path| {
+                            let spec = spec.map_or("the current index", String::as_str);
+                            if dir.as_os_str().is_empty() {
+                                spec.to_string()
+                            } else {
+                                format!("{} ({})", dir.display(), spec)
+                            }
+                        };
+                        audit::markdown(
+                            &audited,
+                            &name(Some(&from), &from_dir),
+                            &name(to.as_ref(), &to_dir),
+                        )
+                    }
+                })
+                .map_err(|e| format!("Error auditing the release: {}", e))
+        }
+        Commands::Daemon { drift } => indexer
+            .daemon(drift)
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Error running the daemon: {}", e)),
+        // Method names are no valid expression, so anything else is one
+        Commands::Query { method, params } if !bof::DAEMON_METHODS.contains(&method.as_str()) => {
+            match params {
+                Some(_) => {
+                    Err("An expression takes no parameters, quote it as a whole".to_string())
+                }
+                None => filter::Filter::parse(&method)
+                    .and_then(|filter| Ok(load(&indexer.config().output_dir)?.filter(&filter)))
+                    .map(|paths| {
+                        paths
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect()
+                    })
+                    .map_err(|e| format!("Error querying the index: {}", e)),
+            }
+        }
+        Commands::Query { method, params } => params
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(|e| format!("Invalid parameters: {}", e))
+            .and_then(|params| {
+                indexer
+                    .query(&method, params.unwrap_or_default())
+                    .map_err(|e| format!("Error querying the daemon: {}", e))
+            })
+            .map(|result| match result {
+                // Lists of paths print one per line, like the matching commands
+                serde_json::Value::Array(values) if values.iter().all(|v| v.is_string()) => values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect(),
+                serde_json::Value::Null => Vec::new(),
+                result => vec![serde_json::to_string_pretty(&result).unwrap_or_default()],
+            }),
+        Commands::Snapshot => indexer
+            .snapshot()
+            .map(|snapshot| vec![format!("Snapshot {} saved", snapshot.id)])
+            .map_err(|e| format!("Error taking a snapshot: {}", e)),
+        Commands::Snapshots => indexer
+            .snapshots()
+            .map(|snapshots| {
+                snapshots
+                    .iter()
+                    .map(|snapshot| {
+                        let mut line = format!(
+                            "{}\t{}\t{}",
+                            snapshot.id,
+                            format::timestamp(snapshot.created),
+                            format::human_bytes(snapshot.size)
+                        );
+                        if let Some(generation) = snapshot.generation {
+                            line.push_str(&format!("\tgeneration {}", generation));
+                        }
+                        line
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing snapshots: {}", e)),
+        Commands::Log => indexer
+            .log()
+            .map(|generations| {
+                generations
+                    .iter()
+                    .rev()
+                    .map(|generation| {
+                        let mut line = format!(
+                            "{}\t{}\t{} files\t{}",
+                            generation.generation,
+                            format::timestamp(generation.saved),
+                            generation.files,
+                            format::human_bytes(generation.bytes)
+                        );
+                        if !generation.snapshots.is_empty() {
+                            line.push_str(&format!(
+                                "\tsnapshot {}",
+                                generation.snapshots.join(", ")
+                            ));
+                        }
+                        line
+                    })
+                    .collect()
+            })
+            .map_err(|e| format!("Error listing generations: {}", e)),
+        Commands::Policy {
+            command: PolicyCommands::Run { dry_run },
+        } => indexer
+            .run_policies(dry_run)
+            .map(|applied| {
+                failed = applied
+                    .iter()
+                    .any(|applied| matches!(applied.outcome, policy::Outcome::Failed(_)));
+                applied.iter().map(|applied| applied.to_string()).collect()
+            })
+            .map_err(|e| format!("Error applying policies: {}", e)),
+        Commands::Config { command } => match command {
+            ConfigCommands::Get { key } => settings::get(indexer.config(), &key)
+                .map(|value| vec![value])
+                .map_err(|e| format!("Error reading setting: {}", e)),
+            ConfigCommands::Set { key, value } => settings::set(&key, &value)
+                .map(|_| Vec::new())
+                .map_err(|e| format!("Error changing setting: {}", e)),
+        },
+        Commands::Repos { command } => repos(command, indexer.config()),
+    };
 
+    if let Some(dashboard) = dashboard {
+        dashboard.finish();
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
     let elapsed = now.elapsed();
-    println!("Elapsed: {:.2?}", elapsed);
+    let errors = errors.0.load(Ordering::Relaxed);
+    if json {
+        match result {
+            Ok(lines) => lines
+                .into_iter()
+                .for_each(|line| JsonPrinter::print(json!({"event": "output", "line": line}))),
+            Err(e) => {
+                JsonPrinter::print(json!({"event": "error", "message": e}));
+                failed = true;
+            }
+        }
+        failed |= errors > 0;
+        JsonPrinter::print(json!({
+            "event": "done",
+            "success": !failed,
+            "errors": errors,
+            "elapsed_ms": elapsed.as_millis() as u64,
+        }));
+    } else {
+        match result {
+            Ok(lines) if stdout_taken => lines.iter().for_each(|line| eprintln!("{}", line)),
+            Ok(lines) => print_lines(&lines),
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+            }
+        }
+
+        if timed {
+            eprintln!("Elapsed: {:.2?}", elapsed);
+        }
+
+        if errors > 0 {
+            eprintln!(
+                "{} error{} occurred, see above",
+                errors,
+                if errors == 1 { "" } else { "s" }
+            );
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
 }
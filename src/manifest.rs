@@ -0,0 +1,191 @@
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::hash::HashAlgorithm;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// Maps the original paths to their keys in a CAS layout
+const CAS_PATHS: &str = "paths.json";
+
+// Standard checksum manifests, verifiable without BOF
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestFormat {
+    // `sha256sum -c` (or `sha1sum -c` for a sha1 index)
+    Sha256sum,
+    // `hashdeep -a -k`
+    Hashdeep,
+    Csv,
+}
+
+pub(crate) struct ManifestFile {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) mtime: SystemTime,
+    pub(crate) key: String,
+}
+
+pub(crate) fn lines(
+    files: &[ManifestFile],
+    format: ManifestFormat,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<String>> {
+    match format {
+        ManifestFormat::Sha256sum => {
+            if !matches!(algorithm, HashAlgorithm::Sha256 | HashAlgorithm::Sha1) {
+                return Err(unsupported(format, algorithm));
+            }
+            Ok(files.iter().map(sum_line).collect())
+        }
+        ManifestFormat::Hashdeep => {
+            if !matches!(algorithm, HashAlgorithm::Sha256 | HashAlgorithm::Sha1) {
+                return Err(unsupported(format, algorithm));
+            }
+            let mut lines = vec![
+                "%%%% HASHDEEP-1.0".to_string(),
+                format!("%%%% size,{},filename", algorithm),
+            ];
+            if let Ok(dir) = std::env::current_dir() {
+                lines.push(format!("## Invoked from: {}", dir.display()));
+            }
+            lines.push("## $ bof export --format hashdeep".to_string());
+            lines.push("##".to_string());
+            lines.extend(
+                files
+                    .iter()
+                    .map(|file| format!("{},{},{}", file.size, file.key, file.path.display())),
+            );
+            Ok(lines)
+        }
+        ManifestFormat::Csv => {
+            let mut lines = vec![format!("path,size,modified,{}", algorithm)];
+            lines.extend(files.iter().map(|file| {
+                format!(
+                    "{},{},{},{}",
+                    csv_field(&file.path.display().to_string()),
+                    file.size,
+                    chrono::DateTime::<chrono::Utc>::from(file.mtime)
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    file.key
+                )
+            }));
+            Ok(lines)
+        }
+    }
+}
+
+// Keys hashed again with a secret, so that parties sharing it can join their manifests on the key
+// without handing out hashes anyone could look known files up by. The secret is stretched into a
+// BLAKE3 key first, any length of it will do.
+pub(crate) fn salt_keys(files: &mut [ManifestFile], salt: &str) {
+    let secret = blake3::derive_key("bof 2026-10 salted manifest keys", salt.as_bytes());
+    for file in files {
+        file.key = blake3::keyed_hash(&secret, file.key.as_bytes())
+            .to_hex()
+            .to_string();
+    }
+}
+
+// Salted keys match no file's checksum, so only the CSV manifest makes sense for them
+pub(crate) fn salted_lines(
+    files: &[ManifestFile],
+    format: ManifestFormat,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<String>> {
+    if format != ManifestFormat::Csv {
+        return Err(BofError::Config(
+            "salted keys can't be checked against the files, export them with --format csv"
+                .to_string(),
+        ));
+    }
+    let mut lines = lines(files, format, algorithm)?;
+    lines[0] = format!("path,size,modified,salted-{}", algorithm);
+    Ok(lines)
+}
+
+#[derive(Debug, Default)]
+pub struct CasSummary {
+    pub files: u64,
+    // Distinct contents, hardlinked when the directory is on the same filesystem
+    pub objects: u64,
+    pub linked: u64,
+    pub copied: u64,
+}
+
+// Fills `dir` with one file per content, named by its key, and writes paths.json mapping every
+// original path to its key. Contents already there are kept, so exporting again only adds what
+// is new. Files that changed since they were indexed are left out.
+pub(crate) fn cas_layout(files: &[ManifestFile], dir: &Path) -> Result<CasSummary> {
+    fs::create_dir_all(dir)?;
+    let mut paths = BTreeMap::new();
+    let mut objects = HashSet::new();
+    let mut summary = CasSummary::default();
+    for file in files {
+        let object = dir.join(&file.key);
+        if !objects.contains(&file.key) && !object.exists() {
+            match fs::metadata(&file.path) {
+                Ok(metadata)
+                    if metadata.len() == file.size
+                        && metadata.modified().ok() == Some(file.mtime) => {}
+                Ok(_) => {
+                    events::emit(Event::Error(format!(
+                        "{} changed since it was indexed, leaving it out",
+                        file.path.display()
+                    )));
+                    continue;
+                }
+                Err(e) => {
+                    events::emit(Event::Error(format!("{}: {}", file.path.display(), e)));
+                    continue;
+                }
+            }
+            if fs::hard_link(&file.path, &object).is_ok() {
+                summary.linked += 1;
+            } else {
+                fs::copy(&file.path, &object)?;
+                summary.copied += 1;
+            }
+        }
+        objects.insert(file.key.clone());
+        paths.insert(file.path.display().to_string(), file.key.clone());
+        summary.files += 1;
+    }
+    summary.objects = objects.len() as u64;
+    let mapping = serde_json::to_vec_pretty(&paths).map_err(BofError::serialization)?;
+    fs::write(dir.join(CAS_PATHS), mapping)?;
+    Ok(summary)
+}
+
+fn unsupported(format: ManifestFormat, algorithm: HashAlgorithm) -> BofError {
+    let name = match format {
+        ManifestFormat::Sha256sum => "sha256sum",
+        ManifestFormat::Hashdeep => "hashdeep",
+        ManifestFormat::Csv => "csv",
+    };
+    BofError::Config(format!(
+        "{} manifests need a sha256 or sha1 index, this one uses {}",
+        name, algorithm
+    ))
+}
+
+// Names with a backslash or newline are escaped the way coreutils does, flagged by a leading \
+fn sum_line(file: &ManifestFile) -> String {
+    let name = file.path.display().to_string();
+    if name.contains(['\\', '\n']) {
+        let name = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", file.key, name)
+    } else {
+        format!("{}  {}", file.key, name)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
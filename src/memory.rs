@@ -0,0 +1,90 @@
+use crate::bof::{BOFConfig, BOFIndex};
+use crate::events::{self, Event};
+use crate::platform;
+use std::time::{Duration, Instant};
+
+// How often long-running commands look at their memory, and how often they report it
+pub(crate) const CHECK_EVERY: Duration = Duration::from_secs(30);
+const REPORT_EVERY: Duration = Duration::from_secs(10 * 60);
+
+// Keeps an eye on the memory of `bof daemon` and `bof watch`
+pub(crate) struct Monitor {
+    // Bytes the index may take before it has to be compacted, None for no limit
+    limit: Option<u64>,
+    // What the index is held to, the limit or more while the index can't fit under it
+    threshold: Option<u64>,
+    checked: Instant,
+    reported: Option<Instant>,
+}
+
+impl Monitor {
+    pub(crate) fn new(config: &BOFConfig) -> Monitor {
+        let limit = (config.memory_limit > 0).then_some(config.memory_limit * 1024 * 1024);
+        Monitor {
+            limit,
+            threshold: limit,
+            checked: Instant::now(),
+            reported: None,
+        }
+    }
+
+    // Whether it is time to check again
+    pub(crate) fn due(&self) -> bool {
+        self.checked.elapsed() >= CHECK_EVERY
+    }
+
+    // Reports memory use every REPORT_EVERY and tells whether the index grew past the limit
+    pub(crate) fn check(&mut self, index: &BOFIndex) -> bool {
+        self.checked = Instant::now();
+        let estimate = index.memory_estimate();
+        if self
+            .reported
+            .is_none_or(|reported| reported.elapsed() >= REPORT_EVERY)
+        {
+            self.reported = Some(Instant::now());
+            events::emit(Event::Notice(format!(
+                "Memory: {}",
+                report(index, estimate)
+            )));
+        }
+        self.threshold.is_some_and(|threshold| estimate > threshold)
+    }
+
+    // Called with the index once compacted. An index larger than the limit on its own would
+    // be compacted on every check, so it is held to a bit more than it takes until it fits again.
+    pub(crate) fn compacted(&mut self, index: &BOFIndex) {
+        let estimate = index.memory_estimate();
+        events::emit(Event::Notice(format!(
+            "Compacted the index: {}",
+            report(index, estimate)
+        )));
+        if let Some(limit) = self.limit {
+            self.threshold = Some(if estimate > limit {
+                events::emit(Event::Error(format!(
+                    "The index takes {} even once compacted, more than memory_limit allows",
+                    mib(estimate)
+                )));
+                estimate + estimate / 4
+            } else {
+                limit
+            });
+        }
+    }
+}
+
+fn report(index: &BOFIndex, estimate: u64) -> String {
+    let rss = platform::rss()
+        .map(|rss| format!("{} resident, ", mib(rss)))
+        .unwrap_or_default();
+    format!(
+        "{}index about {} for {} entries",
+        rss,
+        mib(estimate),
+        index.len()
+    )
+}
+
+// In MiB, the unit of memory_limit
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
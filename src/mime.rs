@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+// Enough for every signature below, tar's being the furthest in
+const SNIFF_LEN: u64 = 512;
+
+// Magic bytes at the start of a file and the type they stand for, checked in order
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\0", "image/tiff"),
+    (b"MM\0*", "image/tiff"),
+    (b"\0\0\x01\0", "image/vnd.microsoft.icon"),
+    (b"8BPS", "image/vnd.adobe.photoshop"),
+    (b"\x1aE\xdf\xa3", "video/x-matroska"),
+    (b"FLV\x01", "video/x-flv"),
+    (b"\0\0\x01\xba", "video/mpeg"),
+    (b"\0\0\x01\xb3", "video/mpeg"),
+    (b"ID3", "audio/mpeg"),
+    (b"\xff\xfb", "audio/mpeg"),
+    (b"\xff\xf3", "audio/mpeg"),
+    (b"\xff\xf2", "audio/mpeg"),
+    (b"fLaC", "audio/flac"),
+    (b"OggS", "audio/ogg"),
+    (b"MThd", "audio/midi"),
+    (b"%PDF-", "application/pdf"),
+    (b"%!PS", "application/postscript"),
+    (b"{\\rtf", "application/rtf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\0", "application/x-xz"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"\xfe\xed\xfa\xce", "application/x-mach-binary"),
+    (b"\xfe\xed\xfa\xcf", "application/x-mach-binary"),
+    (b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+    (b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (b"\0asm", "application/wasm"),
+    (b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OTTO", "font/otf"),
+    (b"\0\x01\0\0", "font/ttf"),
+];
+
+// Too short to rule out text, so only tried on files that aren't
+const BINARY_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"BM", "image/bmp"),
+    (b"MZ", "application/vnd.microsoft.portable-executable"),
+];
+
+// Containers telling what they hold a few bytes in: RIFF's form type at 8, ISO media's brand
+// after "ftyp" at 4
+const RIFF: &[(&[u8], &str)] = &[
+    (b"WEBP", "image/webp"),
+    (b"AVI ", "video/x-msvideo"),
+    (b"WAVE", "audio/wav"),
+];
+const FTYP: &[(&[u8], &str)] = &[
+    (b"avif", "image/avif"),
+    (b"heic", "image/heic"),
+    (b"heix", "image/heic"),
+    (b"mif1", "image/heif"),
+    (b"qt  ", "video/quicktime"),
+    (b"M4A ", "audio/mp4"),
+    (b"M4V ", "video/x-m4v"),
+    (b"3gp", "video/3gpp"),
+];
+
+// The type of a file by its first bytes, None for an empty file
+pub(crate) fn sniff(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN as usize);
+    File::open(path)?.take(SNIFF_LEN).read_to_end(&mut head)?;
+    Ok((!head.is_empty()).then(|| mime_of(&head)))
+}
+
+fn mime_of(head: &[u8]) -> &'static str {
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        if let Some((_, mime)) = RIFF.iter().find(|(form, _)| &head[8..12] == *form) {
+            return mime;
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return FTYP
+            .iter()
+            .find(|(brand, _)| head[8..].starts_with(brand))
+            .map_or("video/mp4", |(_, mime)| mime);
+    }
+    if head.starts_with(b"FORM") && head.get(8..12) == Some(b"AIFF") {
+        return "audio/aiff";
+    }
+    if head.get(257..262) == Some(b"ustar") {
+        return "application/x-tar";
+    }
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        // Matroska's doctype tells WebM apart
+        if *mime == "video/x-matroska" && contains(head, b"webm") {
+            return "video/webm";
+        }
+        return mime;
+    }
+    text_mime(head)
+        .or_else(|| {
+            BINARY_SIGNATURES
+                .iter()
+                .find(|(magic, _)| head.starts_with(magic))
+                .map(|(_, mime)| *mime)
+        })
+        .unwrap_or("application/octet-stream")
+}
+
+fn text_mime(head: &[u8]) -> Option<&'static str> {
+    if head.contains(&0) {
+        return None;
+    }
+    // The read may stop in the middle of a character
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let start = text.trim_start_matches('\u{feff}').trim_start();
+    let lower = start
+        .chars()
+        .take(16)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    Some(if lower.starts_with("<?xml") {
+        if contains(head, b"<svg") {
+            "image/svg+xml"
+        } else {
+            "application/xml"
+        }
+    } else if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        "text/html"
+    } else if start.starts_with("<svg") {
+        "image/svg+xml"
+    } else if start.starts_with("#!") {
+        "text/x-script"
+    } else {
+        "text/plain"
+    })
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+// `video/mp4` matches itself, `video` and `video/*` every video
+pub(crate) fn matches(mime: &str, pattern: &str) -> bool {
+    let pattern = pattern.strip_suffix("/*").unwrap_or(pattern);
+    mime == pattern
+        || mime
+            .strip_prefix(pattern)
+            .is_some_and(|subtype| subtype.starts_with('/'))
+}
@@ -0,0 +1,430 @@
+use crate::bof::BOFIndex;
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::hash::HashAlgorithm;
+use crate::platform;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+// An archive is written front to back so it can go through a pipe: the magic and hash algorithm,
+// one record per distinct content, then the manifest record and a trailer with the manifest's
+// length, so the manifest can also be found from the end of a file.
+const PACK_MAGIC: &[u8; 8] = b"BOFPACK2";
+const BLOB_RECORD: u8 = b'B';
+const MANIFEST_RECORD: u8 = b'M';
+const COPY_BUFFER: usize = 64 * 1024;
+
+// What the index knows of a path under the packed subtree
+pub(crate) enum Item {
+    File {
+        key: String,
+        size: u64,
+        mtime: SystemTime,
+    },
+    Link {
+        target: PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    // Relative to the packed subtree, directories are created as needed
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    path: PathBuf,
+    kind: Kind,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Kind {
+    File { key: String, mtime: SystemTime },
+    Link { target: PathBuf },
+}
+
+#[derive(Debug, Default)]
+pub struct PackSummary {
+    pub files: u64,
+    // Content of every file, and what was stored once duplicates were left out
+    pub bytes: u64,
+    pub stored: u64,
+    // Content left out because the destination already has it
+    pub present: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct UnpackSummary {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+// Where an archive is written or read from, `-` standing for stdout or stdin
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Writes the indexed subtree to `out`, storing each distinct content once, or not at all if it
+// is in `against`. Keys come from the index; files that changed since they were indexed are
+// hashed again.
+pub(crate) fn pack(
+    index: &BOFIndex,
+    subtree: &Path,
+    out: &Path,
+    against: Option<&BOFIndex>,
+) -> Result<PackSummary> {
+    if is_stdio(out) {
+        let mut writer = BufWriter::new(io::stdout().lock());
+        let summary = pack_to(index, subtree, against, &mut writer)?;
+        writer.flush()?;
+        return Ok(summary);
+    }
+
+    let result = File::create(out).map_err(BofError::from).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let summary = pack_to(index, subtree, against, &mut writer)?;
+        writer.into_inner().map_err(io::Error::from)?.sync_all()?;
+        Ok(summary)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(out);
+    }
+    result
+}
+
+// Writes the archive of what is indexed under `subtree` to `writer`, e.g. a pipe, see pack
+pub fn pack_to<W: Write>(
+    index: &BOFIndex,
+    subtree: &Path,
+    against: Option<&BOFIndex>,
+    writer: &mut W,
+) -> Result<PackSummary> {
+    let items = index.pack_items(subtree);
+    if items.is_empty() {
+        return Err(BofError::Config(format!(
+            "Nothing indexed under {}",
+            subtree.display()
+        )));
+    }
+    let algorithm = index.hash_algorithm().unwrap_or_default();
+    let present = match against {
+        Some(against) => {
+            check_algorithm(against, algorithm)?;
+            against.file_keys()
+        }
+        None => HashSet::new(),
+    };
+    write_pack(items, algorithm, subtree, &present, writer)
+}
+
+fn write_pack<W: Write>(
+    items: Vec<(PathBuf, Item)>,
+    algorithm: HashAlgorithm,
+    subtree: &Path,
+    present: &HashSet<&str>,
+    writer: &mut W,
+) -> Result<PackSummary> {
+    writer.write_all(PACK_MAGIC)?;
+    let name = algorithm.to_string();
+    writer.write_all(&[name.len() as u8])?;
+    writer.write_all(name.as_bytes())?;
+
+    let mut manifest = Manifest {
+        entries: Vec::new(),
+    };
+    let mut stored = HashSet::new();
+    let mut left_out = HashSet::new();
+    let mut summary = PackSummary::default();
+    for (path, item) in items {
+        let relative = path.strip_prefix(subtree).unwrap_or(&path).to_path_buf();
+        let kind = match item {
+            Item::Link { target } => Kind::Link { target },
+            Item::File { key, size, mtime } => {
+                let metadata = fs::metadata(&path)?;
+                let key = if key.is_empty() {
+                    // Catalogued without its hash
+                    algorithm.hash_file(&path)?
+                } else if metadata.len() == size && metadata.modified().ok() == Some(mtime) {
+                    key
+                } else {
+                    events::emit(Event::Notice(format!(
+                        "{} changed since it was indexed, hashing it again",
+                        path.display()
+                    )));
+                    algorithm.hash_file(&path)?
+                };
+                if present.contains(key.as_str()) {
+                    if left_out.insert(key.clone()) {
+                        summary.present += metadata.len();
+                    }
+                } else if !stored.contains(&key) {
+                    // Only the announced size is copied so records stay aligned if the file grows
+                    // meanwhile, any change is caught by the key
+                    writer.write_all(&[BLOB_RECORD, key.len() as u8])?;
+                    writer.write_all(key.as_bytes())?;
+                    writer.write_all(&metadata.len().to_le_bytes())?;
+                    let file = File::open(&path)?.take(metadata.len());
+                    let (copied, size) = copy_hashed(file, writer, algorithm)?;
+                    if copied != key || size != metadata.len() {
+                        return Err(BofError::Io(io::Error::other(format!(
+                            "{} changed while it was packed",
+                            path.display()
+                        ))));
+                    }
+                    stored.insert(key.clone());
+                    summary.stored += size;
+                }
+                summary.files += 1;
+                summary.bytes += metadata.len();
+                Kind::File {
+                    key,
+                    mtime: metadata.modified()?,
+                }
+            }
+        };
+        manifest.entries.push(Entry {
+            path: relative,
+            kind,
+        });
+    }
+
+    let manifest = serde_json::to_vec(&manifest).map_err(BofError::serialization)?;
+    writer.write_all(&[MANIFEST_RECORD])?;
+    writer.write_all(&manifest)?;
+    writer.write_all(&(manifest.len() as u64).to_le_bytes())?;
+    writer.write_all(PACK_MAGIC)?;
+    Ok(summary)
+}
+
+// Recreates the packed tree under `into`, checking every file against its key. Existing files
+// are never overwritten. Contents are kept in a staging directory under `into` until the
+// manifest at the end tells where they go; content the archive left out is copied from the
+// files of `against` that have it.
+pub fn unpack(archive: &Path, into: &Path, against: Option<&BOFIndex>) -> Result<UnpackSummary> {
+    if is_stdio(archive) {
+        return unpack_from(BufReader::new(io::stdin().lock()), into, against);
+    }
+    let file = File::open(archive)?;
+    extract(BufReader::new(file), archive, into, against)
+}
+
+// Extracts an archive read from `reader`, e.g. a pipe, see unpack
+pub fn unpack_from<R: Read>(
+    reader: R,
+    into: &Path,
+    against: Option<&BOFIndex>,
+) -> Result<UnpackSummary> {
+    extract(reader, Path::new("-"), into, against)
+}
+
+fn extract<R: Read>(
+    reader: R,
+    archive: &Path,
+    into: &Path,
+    against: Option<&BOFIndex>,
+) -> Result<UnpackSummary> {
+    fs::create_dir_all(into)?;
+    let staging = into.join(format!(".bofpack-{}", std::process::id()));
+    fs::create_dir(&staging)?;
+    let result = read_pack(reader, archive, into, &staging, against);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn read_pack<R: Read>(
+    mut reader: R,
+    archive: &Path,
+    into: &Path,
+    staging: &Path,
+    against: Option<&BOFIndex>,
+) -> Result<UnpackSummary> {
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| not_a_pack(archive))?;
+    if &magic != PACK_MAGIC {
+        return Err(not_a_pack(archive));
+    }
+    let name = read_string(&mut reader)?;
+    let algorithm = <HashAlgorithm as clap::ValueEnum>::from_str(&name, false)
+        .map_err(|_| BofError::Serialization(format!("Unknown hash algorithm {}", name)))?;
+    if let Some(against) = against {
+        check_algorithm(against, algorithm)?;
+    }
+
+    // Staged under their position rather than the key, which comes from the archive
+    let mut staged: HashMap<String, PathBuf> = HashMap::new();
+    let manifest: Manifest = loop {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            BLOB_RECORD => {
+                let key = read_string(&mut reader)?;
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                let size = u64::from_le_bytes(size);
+                let path = staging.join(staged.len().to_string());
+                let mut file = File::create_new(&path)?;
+                let (copied, written) =
+                    copy_hashed((&mut reader).take(size), &mut file, algorithm)?;
+                if copied != key || written != size {
+                    return Err(BofError::Serialization(format!(
+                        "Corrupted content in {}",
+                        archive.display()
+                    )));
+                }
+                staged.insert(key, path);
+            }
+            MANIFEST_RECORD => {
+                let mut rest = Vec::new();
+                reader.read_to_end(&mut rest)?;
+                let trailer = 8 + PACK_MAGIC.len();
+                if rest.len() < trailer || &rest[rest.len() - PACK_MAGIC.len()..] != PACK_MAGIC {
+                    return Err(not_a_pack(archive));
+                }
+                rest.truncate(rest.len() - trailer);
+                break serde_json::from_slice(&rest).map_err(BofError::serialization)?;
+            }
+            _ => return Err(not_a_pack(archive)),
+        }
+    };
+
+    // The last file with some content gets the staged copy, the others a copy of their own
+    let mut uses: HashMap<&str, usize> = HashMap::new();
+    for entry in &manifest.entries {
+        if let Kind::File { key, .. } = &entry.kind {
+            *uses.entry(key).or_default() += 1;
+        }
+    }
+    let mut summary = UnpackSummary::default();
+    for entry in &manifest.entries {
+        // Only plain relative paths, so an archive can't write outside of `into`
+        if !entry
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(BofError::Serialization(format!(
+                "Invalid path in archive: {}",
+                entry.path.display()
+            )));
+        }
+        let path = into.join(&entry.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &entry.kind {
+            Kind::Link { target } => platform::symlink(target, &path)?,
+            Kind::File { key, mtime } => {
+                match staged.get(key) {
+                    Some(source) => {
+                        let left = uses.get_mut(key.as_str()).expect("counted above");
+                        *left -= 1;
+                        if *left == 0 {
+                            if fs::symlink_metadata(&path).is_ok() {
+                                return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+                            }
+                            fs::rename(source, &path)?;
+                        } else {
+                            io::copy(&mut File::open(source)?, &mut File::create_new(&path)?)?;
+                        }
+                    }
+                    None => copy_from_index(key, &entry.path, &path, algorithm, against)?,
+                }
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_modified(*mtime)?;
+                summary.files += 1;
+                summary.bytes += file.metadata()?.len();
+            }
+        }
+    }
+    Ok(summary)
+}
+
+// Takes content the archive left out from the first file of the index that still has it
+fn copy_from_index(
+    key: &str,
+    entry: &Path,
+    path: &Path,
+    algorithm: HashAlgorithm,
+    against: Option<&BOFIndex>,
+) -> Result<()> {
+    let Some(index) = against else {
+        return Err(BofError::Serialization(format!(
+            "No content for {} in the archive, give the index it was packed against with --against",
+            entry.display()
+        )));
+    };
+    for source in index.files_with_key(key) {
+        let Ok(file) = File::open(&source) else {
+            continue;
+        };
+        let mut out = File::create_new(path)?;
+        match copy_hashed(file, &mut out, algorithm) {
+            Ok((copied, _)) if copied == key => return Ok(()),
+            _ => {
+                drop(out);
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Err(BofError::Serialization(format!(
+        "No indexed file has the content of {} any more",
+        entry.display()
+    )))
+}
+
+// Keys are only comparable between indexes using the same hash algorithm
+fn check_algorithm(against: &BOFIndex, algorithm: HashAlgorithm) -> Result<()> {
+    let other = against.hash_algorithm().unwrap_or_default();
+    if other != algorithm {
+        return Err(BofError::Config(format!(
+            "The index given with --against uses {}, not {}",
+            other, algorithm
+        )));
+    }
+    Ok(())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; len[0] as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(BofError::serialization)
+}
+
+fn not_a_pack(archive: &Path) -> BofError {
+    BofError::Serialization(format!("{} is not a BOF pack", archive.display()))
+}
+
+// Copies everything, returning the key of what was copied and its size
+fn copy_hashed<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    algorithm: HashAlgorithm,
+) -> Result<(String, u64)> {
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0; COPY_BUFFER];
+    let mut size = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                writer.write_all(&buffer[..n])?;
+                size += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok((hasher.finish(), size))
+}
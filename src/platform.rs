@@ -0,0 +1,600 @@
+#[cfg(target_os = "macos")]
+use std::fs;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+};
+
+// Identity of a file on its volume: device/inode on Unix, volume serial/file index on Windows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct FileId {
+    pub device: u64,
+    pub inode: u64,
+}
+
+#[cfg(unix)]
+pub(crate) fn file_id(_path: &Path, metadata: &Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+
+    FileId {
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn file_id(path: &Path, _metadata: &Metadata) -> FileId {
+    use crate::events::{self, Event};
+    use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt, os::windows::io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS,
+    };
+
+    // Metadata doesn't expose the file index on stable, so ask the handle.
+    // Backup semantics are required to open directories.
+    let file = match OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to open {} for identity: {}",
+                path.display(),
+                e
+            )));
+            return FileId::default();
+        }
+    };
+
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+        events::emit(Event::Error(format!(
+            "Failed to get file information for {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        )));
+        return FileId::default();
+    }
+
+    FileId {
+        device: info.dwVolumeSerialNumber as u64,
+        inode: ((info.nFileIndexHigh as u64) << 32) | info.nFileIndexLow as u64,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn file_id(_path: &Path, _metadata: &Metadata) -> FileId {
+    FileId::default()
+}
+
+pub(crate) fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+pub(crate) fn hostname() -> String {
+    if let Ok(host) = std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")) {
+        return host;
+    }
+    std::fs::read_to_string("/etc/hostname")
+        .map(|host| host.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// A named fork of a file's data beside its main content (NTFS alternate data stream, macOS resource fork)
+#[derive(Debug)]
+pub(crate) struct Stream {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn streams(path: &Path) -> io::Result<Vec<Stream>> {
+    let fork = path.join("..namedfork/rsrc");
+    match fs::metadata(&fork) {
+        Ok(metadata) if metadata.len() > 0 => Ok(vec![Stream {
+            name: "rsrc".to_string(),
+            path: fork,
+            size: metadata.len(),
+        }]),
+        Ok(_) => Ok(Vec::new()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn streams(path: &Path) -> io::Result<Vec<Stream>> {
+    use std::{ffi::OsString, os::windows::ffi::OsStrExt, os::windows::ffi::OsStringExt};
+    use windows_sys::Win32::{
+        Foundation::{GetLastError, ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{
+            FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+            WIN32_FIND_STREAM_DATA,
+        },
+    };
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { std::mem::zeroed() };
+    let handle = unsafe {
+        FindFirstStreamW(
+            wide.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut _,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return match unsafe { GetLastError() } {
+            ERROR_HANDLE_EOF => Ok(Vec::new()),
+            _ => Err(io::Error::last_os_error()),
+        };
+    }
+
+    let mut streams = Vec::new();
+    loop {
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let raw = OsString::from_wide(&data.cStreamName[..len])
+            .to_string_lossy()
+            .to_string();
+        // Names look like ":name:$DATA", the unnamed "::$DATA" is the file's main content
+        if let Some(name) = raw
+            .strip_prefix(':')
+            .and_then(|raw| raw.strip_suffix(":$DATA"))
+            .filter(|name| !name.is_empty())
+        {
+            let mut stream_path = path.as_os_str().to_os_string();
+            stream_path.push(format!(":{}", name));
+            streams.push(Stream {
+                name: name.to_string(),
+                path: PathBuf::from(stream_path),
+                size: data.StreamSize as u64,
+            });
+        }
+        if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut _) } == 0 {
+            break;
+        }
+    }
+    unsafe { FindClose(handle) };
+    Ok(streams)
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+pub(crate) fn streams(_path: &Path) -> io::Result<Vec<Stream>> {
+    Ok(Vec::new())
+}
+
+// Recreates a symlink, e.g. when unpacking an archive
+#[cfg(unix)]
+pub(crate) fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Windows tells links to files and directories apart, so look at what it points to
+#[cfg(windows)]
+pub(crate) fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    let resolved = path.parent().unwrap_or(Path::new(".")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Symlinks are not supported on this platform",
+    ))
+}
+
+// Creates `path` as a copy-on-write clone of `source` (FICLONE): a file of its own sharing the
+// same extents on disk, on filesystems with reflinks like Btrfs and XFS
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(source: &Path, path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source_file = std::fs::File::open(source)?;
+    let file = std::fs::File::create_new(path)?;
+    if unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            libc::FICLONE as _,
+            source_file.as_raw_fd(),
+        )
+    } == 0
+    {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    drop(file);
+    let _ = std::fs::remove_file(path);
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::ENOSYS) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "The filesystem of {} doesn't support reflinks",
+                source.display()
+            ),
+        )),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn reflink(_source: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Reflinks are not supported on this platform",
+    ))
+}
+
+// Links as far as walking is concerned, std only tells symlinks (and junctions) apart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) enum LinkKind {
+    Symlink,
+    // Windows directory junction, a mount point reparse point
+    Junction,
+    // Any other Windows reparse point, e.g. a cloud file placeholder or a deduplicated file
+    ReparsePoint,
+}
+
+// `metadata` is that of the path itself, not of what it points to
+#[cfg(windows)]
+pub(crate) fn link_kind(path: &Path, metadata: &Metadata) -> Option<LinkKind> {
+    use std::os::windows::{ffi::OsStrExt, fs::MetadataExt};
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::Storage::FileSystem::{FindClose, FindFirstFileW, WIN32_FIND_DATAW};
+
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+    const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+    const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+
+    if metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT == 0 {
+        return None;
+    }
+    // The reparse tag only comes with a directory listing or the reparse data itself
+    let wide = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    let mut data: WIN32_FIND_DATAW = unsafe { std::mem::zeroed() };
+    let handle = unsafe { FindFirstFileW(wide.as_ptr(), &mut data) };
+    if handle == INVALID_HANDLE_VALUE {
+        return metadata.is_symlink().then_some(LinkKind::Symlink);
+    }
+    unsafe { FindClose(handle) };
+    Some(match data.dwReserved0 {
+        IO_REPARSE_TAG_SYMLINK => LinkKind::Symlink,
+        IO_REPARSE_TAG_MOUNT_POINT => LinkKind::Junction,
+        _ => LinkKind::ReparsePoint,
+    })
+}
+
+#[cfg(not(windows))]
+pub(crate) fn link_kind(_path: &Path, metadata: &Metadata) -> Option<LinkKind> {
+    metadata.is_symlink().then_some(LinkKind::Symlink)
+}
+
+// Mode bits, owner and group of a file, None where the platform has no such thing
+#[cfg(unix)]
+pub(crate) fn owner(metadata: &Metadata) -> Option<(u32, u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.mode(), metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn owner(_metadata: &Metadata) -> Option<(u32, u32, u32)> {
+    None
+}
+
+// Extended attributes of a file (following symlinks, like its metadata) and their values
+#[cfg(target_os = "linux")]
+pub(crate) fn xattrs(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match read_sized(|buf, size| unsafe {
+        libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = Vec::new();
+    for name in names
+        .split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+        let value = read_sized(|buf, size| unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                c_name.as_ptr(),
+                buf as *mut libc::c_void,
+                size,
+            )
+        })?;
+        xattrs.push((String::from_utf8_lossy(name).to_string(), value));
+    }
+    Ok(xattrs)
+}
+
+// Asks for the size first, then reads, again if it grew in between
+#[cfg(target_os = "linux")]
+fn read_sized(read: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0; size as usize];
+        let read_size = read(buf.as_mut_ptr(), buf.len());
+        if read_size >= 0 {
+            buf.truncate(read_size as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn xattrs(_path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+// Files other processes have open or mapped, e.g. a database or a running program. Processes of
+// other users are only seen with the privileges to look into them. None where this can't be told.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_files() -> Option<HashSet<FileId>> {
+    use std::fs;
+
+    let own = std::process::id().to_string();
+    let mut open = HashSet::new();
+    for process in fs::read_dir("/proc").ok()?.filter_map(|entry| entry.ok()) {
+        let name = process.file_name();
+        let is_pid = name
+            .to_str()
+            .is_some_and(|name| name != own && name.bytes().all(|byte| byte.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+        if let Ok(fds) = fs::read_dir(process.path().join("fd")) {
+            for fd in fds.filter_map(|entry| entry.ok()) {
+                // Follows the link to what is open
+                if let Ok(metadata) = fs::metadata(fd.path()) {
+                    if metadata.is_file() {
+                        open.insert(file_id(&fd.path(), &metadata));
+                    }
+                }
+            }
+        }
+        // address perms offset major:minor inode path, the device in hex
+        let maps = fs::read_to_string(process.path().join("maps")).unwrap_or_default();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace().skip(3);
+            let (Some(device), Some(inode)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let (Some((major, minor)), Ok(inode)) = (device.split_once(':'), inode.parse::<u64>())
+            else {
+                continue;
+            };
+            let (Ok(major), Ok(minor)) = (
+                u32::from_str_radix(major, 16),
+                u32::from_str_radix(minor, 16),
+            ) else {
+                continue;
+            };
+            if inode != 0 {
+                open.insert(FileId {
+                    device: libc::makedev(major, minor),
+                    inode,
+                });
+            }
+        }
+    }
+    Some(open)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_files() -> Option<HashSet<FileId>> {
+    None
+}
+
+// Where a filesystem is mounted and its type, e.g. ext4 or nfs
+#[derive(Clone, Debug)]
+pub(crate) struct Mount {
+    pub path: PathBuf,
+    pub filesystem: String,
+}
+
+// Mounts of this machine by the device of their files, the first one for a device mounted twice
+#[cfg(target_os = "linux")]
+pub(crate) fn mounts() -> HashMap<u64, Mount> {
+    let mut mounts = HashMap::new();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    // id parent major:minor root mount-point options... - type source options
+    for line in mountinfo.lines() {
+        let Some((fields, rest)) = line.split_once(" - ") else {
+            continue;
+        };
+        let mut fields = fields.split_whitespace().skip(2);
+        let (Some(device), Some(_), Some(path)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Some((Ok(major), Ok(minor))) = device
+            .split_once(':')
+            .map(|(major, minor)| (major.parse::<u32>(), minor.parse::<u32>()))
+        else {
+            continue;
+        };
+        mounts
+            .entry(libc::makedev(major, minor))
+            .or_insert_with(|| Mount {
+                path: PathBuf::from(unescape_mount(path)),
+                filesystem: rest
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+    }
+    mounts
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn mounts() -> HashMap<u64, Mount> {
+    HashMap::new()
+}
+
+// Mount points have spaces, tabs, newlines and backslashes written as \040 and the like
+#[cfg(target_os = "linux")]
+fn unescape_mount(path: &str) -> String {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match octal {
+            Some(byte) if bytes[i] == b'\\' => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+// Lowers the CPU and IO priority of this thread and the threads it starts from now on, so
+// everything else on the machine goes first
+#[cfg(target_os = "linux")]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    // The idle IO class, shifted by IOPRIO_CLASS_SHIFT, for IOPRIO_WHO_PROCESS
+    const IOPRIO_IDLE: libc::c_int = 3 << 13;
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    // Both are per thread on Linux, inherited by the threads started after
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_IDLE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    // Background mode lowers IO and memory priority along with the CPU's
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not supported on this platform, reads are still throttled",
+    ))
+}
+
+// Tells the kernel the file is about to be read once from start to end, so it reads further ahead
+#[cfg(target_os = "linux")]
+pub(crate) fn read_once(file: &std::fs::File) {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read_once(_file: &std::fs::File) {}
+
+// Drops `len` bytes of the file from `offset` on out of the page cache, 0 for up to its end.
+// Best effort, and pages other processes had cached before go too.
+#[cfg(target_os = "linux")]
+pub(crate) fn uncache(file: &std::fs::File, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn uncache(_file: &std::fs::File, _offset: u64, _len: u64) {}
+
+// Memory of this process currently in RAM, in bytes
+#[cfg(target_os = "linux")]
+pub(crate) fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(windows)]
+pub(crate) fn rss() -> Option<u64> {
+    use windows_sys::Win32::System::{
+        ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        Threading::GetCurrentProcess,
+    };
+
+    let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    if unsafe { GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) } == 0 {
+        return None;
+    }
+    Some(counters.WorkingSetSize as u64)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn rss() -> Option<u64> {
+    None
+}
+
+// Per-user configuration directory for bof, following XDG on Unix and %APPDATA% on Windows
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("bof"))
+}
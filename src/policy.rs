@@ -0,0 +1,239 @@
+use crate::bof::{BOFConfig, BOFIndex};
+use crate::dedupe;
+use crate::error::{BofError, Result};
+use crate::hash::HashPolicy;
+use crate::labels::Patterns;
+use crate::platform::{self, FileId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const POLICY_LOG: &str = "policy.log";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    // Only list the files
+    #[default]
+    Report,
+    Delete,
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyAction::Report => write!(f, "report"),
+            PolicyAction::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+// What to do with the indexed files it selects, e.g. downloads older than 90 days that have a
+// copy elsewhere may be deleted
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Policy {
+    // Patterns of the files it applies to, like labels
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    // Only files last modified more than this many days ago
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+    // Only files with a copy the policy doesn't select, which is kept
+    #[serde(default)]
+    pub duplicated: bool,
+    #[serde(default)]
+    pub action: PolicyAction,
+}
+
+pub(crate) struct Selector<'a> {
+    policy: &'a Policy,
+    patterns: Patterns,
+    modified_before: Option<SystemTime>,
+}
+
+impl<'a> Selector<'a> {
+    pub(crate) fn new(name: &str, policy: &'a Policy, now: SystemTime) -> Result<Selector<'a>> {
+        // A policy without either would apply to every indexed file
+        if policy.paths.is_empty() && policy.label.is_none() {
+            return Err(BofError::Config(format!(
+                "Policy {} selects no files, give it paths or a label",
+                name
+            )));
+        }
+        Ok(Selector {
+            policy,
+            patterns: Patterns::new(&policy.paths)?,
+            modified_before: policy
+                .older_than_days
+                .map(|days| now - Duration::from_secs(days * 24 * 60 * 60)),
+        })
+    }
+
+    pub(crate) fn selects(&self, path: &Path, labels: &[String], mtime: SystemTime) -> bool {
+        (self.policy.paths.is_empty() || self.patterns.is_match(path))
+            && self
+                .policy
+                .label
+                .as_ref()
+                .is_none_or(|label| labels.contains(label))
+            && self.modified_before.is_none_or(|before| mtime < before)
+    }
+}
+
+// An indexed file selected by a policy
+#[derive(Clone, Debug)]
+pub struct PolicyMatch {
+    pub policy: String,
+    pub action: PolicyAction,
+    pub path: PathBuf,
+    pub size: u64,
+    // The other copy, for policies that only apply to duplicated files
+    pub kept: Option<PathBuf>,
+    // What the index recorded, checked again before acting
+    pub(crate) key: String,
+    pub(crate) mtime: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Reported,
+    WouldDelete,
+    Deleted,
+    // Left alone because the files no longer look like what was indexed
+    Skipped(String),
+    Failed(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Reported => write!(f, "reported"),
+            Outcome::WouldDelete => write!(f, "would delete"),
+            Outcome::Deleted => write!(f, "deleted"),
+            Outcome::Skipped(reason) => write!(f, "skipped: {}", reason),
+            Outcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Applied {
+    pub matched: PolicyMatch,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Applied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}",
+            self.matched.policy,
+            self.matched.path.display(),
+            self.outcome
+        )?;
+        if let Some(kept) = &self.matched.kept {
+            write!(f, " (copy at {})", kept.display())?;
+        }
+        Ok(())
+    }
+}
+
+// Evaluates the configured policies against the index. Unless `dry_run`, deletes what they
+// allow to, logs every action to policy.log and drops deleted files from the index.
+pub fn run(dry_run: bool, config: &BOFConfig) -> Result<Vec<Applied>> {
+    let mut index = BOFIndex::load(&config.output_dir, config.index_name())?;
+    let matches = index.policy_matches(&config.policies, SystemTime::now())?;
+    if dry_run {
+        return Ok(matches
+            .into_iter()
+            .map(|matched| Applied {
+                outcome: match matched.action {
+                    PolicyAction::Report => Outcome::Reported,
+                    PolicyAction::Delete => Outcome::WouldDelete,
+                },
+                matched,
+            })
+            .collect());
+    }
+
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config.output_dir.join(POLICY_LOG))?;
+    let open = dedupe::open_files(config);
+    let mut applied = Vec::new();
+    let mut deleted = false;
+    for matched in matches {
+        let outcome = match matched.action {
+            PolicyAction::Report => Outcome::Reported,
+            PolicyAction::Delete => delete(&matched, &index, open.as_ref(), &config.hashing),
+        };
+        if let Outcome::Deleted = outcome {
+            index.forget(&matched.path);
+            deleted = true;
+        }
+        let entry = Applied { matched, outcome };
+        writeln!(
+            log,
+            "{}\t{}\t{}",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.matched.action,
+            entry
+        )?;
+        applied.push(entry);
+    }
+    log.sync_all()?;
+
+    if deleted {
+        index.save(config)?;
+    }
+    Ok(applied)
+}
+
+// Deletes the file if it is still what was indexed and, for duplicated files, the kept copy
+// still has the same content
+fn delete(
+    matched: &PolicyMatch,
+    index: &BOFIndex,
+    open: Option<&HashSet<FileId>>,
+    policy: &HashPolicy,
+) -> Outcome {
+    let metadata = match fs::symlink_metadata(&matched.path) {
+        Ok(metadata)
+            if metadata.is_file()
+                && metadata.len() == matched.size
+                && metadata.modified().ok() == Some(matched.mtime) =>
+        {
+            metadata
+        }
+        Ok(_) => return Outcome::Skipped("changed since it was indexed".to_string()),
+        Err(e) => return Outcome::Skipped(e.to_string()),
+    };
+    if open.is_some_and(|open| open.contains(&platform::file_id(&matched.path, &metadata))) {
+        return Outcome::Skipped("open by another process".to_string());
+    }
+    if let Some(kept) = &matched.kept {
+        let Some(algorithm) = index.hash_algorithm() else {
+            return Outcome::Skipped("the index doesn't record its hash algorithm".to_string());
+        };
+        match policy.hash_file(algorithm, kept) {
+            Ok(key) if key == matched.key => {}
+            Ok(_) => {
+                return Outcome::Skipped(format!("{} changed since it was indexed", kept.display()))
+            }
+            Err(e) => return Outcome::Skipped(format!("{}: {}", kept.display(), e)),
+        }
+    }
+    match fs::remove_file(&matched.path) {
+        Ok(()) => Outcome::Deleted,
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::bof::BOFConfig;
+use crate::hash::HashAlgorithm;
+use crate::storage::Compression;
+use clap::ValueEnum;
+use std::io::{self, BufRead, Write};
+
+// Starting points for `bof init`, tuned for common kinds of trees
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    Photos,
+    Code,
+    Backup,
+}
+
+impl Preset {
+    pub fn apply(self, config: &mut BOFConfig) {
+        let (ignore, hash_algorithm): (&[&str], _) = match self {
+            // Large media files: a fast cryptographic hash, skip OS and NAS thumbnail caches
+            Preset::Photos => (
+                &[
+                    ".DS_Store",
+                    "Thumbs.db",
+                    "desktop.ini",
+                    "@eaDir/",
+                    ".thumbnails/",
+                    "*.tmp",
+                ],
+                HashAlgorithm::Blake3,
+            ),
+            // Many small files that change often: hash for change detection only, skip build output
+            Preset::Code => (
+                &[
+                    ".git/",
+                    "target/",
+                    "node_modules/",
+                    "__pycache__/",
+                    ".venv/",
+                    "build/",
+                    "dist/",
+                    "*.o",
+                    "*.pyc",
+                ],
+                HashAlgorithm::Xxh3,
+            ),
+            // Keep everything that makes up a file, in a compact index
+            Preset::Backup => (
+                &["*.tmp", "*.swp", "~$*", ".Trash-*/", "lost+found/"],
+                HashAlgorithm::Sha256,
+            ),
+        };
+        config.ignore = ignore.iter().map(|pattern| pattern.to_string()).collect();
+        config.hash_algorithm = hash_algorithm;
+        config.estimate = true;
+        if self == Preset::Backup {
+            config.streams = true;
+            config.compression = Compression::Zstd;
+        }
+    }
+}
+
+// Walks through the options on stdin, keeping the current value when the answer is empty
+pub(crate) fn interactive(config: &mut BOFConfig) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    if let Some(preset) = ask(&mut input, "Preset (photos, code, backup)", "none")? {
+        match Preset::from_str(&preset, true) {
+            Ok(preset) => preset.apply(config),
+            Err(_) => eprintln!("Unknown preset {}, starting from the defaults", preset),
+        }
+    }
+    if let Some(algorithm) = ask(
+        &mut input,
+        "Hash algorithm (sha256, sha1, blake3, xxh3)",
+        &config.hash_algorithm.to_string(),
+    )? {
+        match HashAlgorithm::from_str(&algorithm, true) {
+            Ok(algorithm) => config.hash_algorithm = algorithm,
+            Err(_) => eprintln!(
+                "Unknown hash algorithm {}, keeping {}",
+                algorithm, config.hash_algorithm
+            ),
+        }
+    }
+    if let Some(patterns) = ask(
+        &mut input,
+        "Ignore patterns, comma separated",
+        &config.ignore.join(","),
+    )? {
+        config.ignore = patterns
+            .split(',')
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| !pattern.is_empty())
+            .collect();
+    }
+    config.streams = confirm(
+        &mut input,
+        "Record alternate data streams and resource forks?",
+        config.streams,
+    )?;
+    config.estimate = confirm(
+        &mut input,
+        "Pre-scan to estimate progress?",
+        config.estimate,
+    )?;
+    config.compression = if confirm(
+        &mut input,
+        "Compress the index?",
+        config.compression != Compression::None,
+    )? {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+    Ok(())
+}
+
+fn ask(input: &mut impl BufRead, question: &str, current: &str) -> io::Result<Option<String>> {
+    // Questions go to stderr like the rest of what isn't output
+    eprint!("{} [{}]: ", question, current);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok((!answer.is_empty()).then(|| answer.to_string()))
+}
+
+fn confirm(input: &mut impl BufRead, question: &str, current: bool) -> io::Result<bool> {
+    let current_answer = if current { "y" } else { "n" };
+    Ok(
+        match ask(input, &format!("{} (y/n)", question), current_answer)? {
+            Some(answer) => answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"),
+            None => current,
+        },
+    )
+}
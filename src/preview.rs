@@ -0,0 +1,251 @@
+use crate::bof::{BOFConfig, BOFIndex};
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::mime;
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+pub(crate) const PREVIEWS_DIR: &str = "previews";
+pub(crate) const FFMPEG: &str = "ffmpeg";
+// Pixels of the longest side of a preview unless `preview_size` says otherwise
+pub(crate) const PREVIEW_SIZE: u32 = 256;
+
+// Taken for images and videos in indexes that don't record types, see `mime`
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff", "heic", "heif", "avif",
+];
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "m4v", "mov", "mkv", "webm", "avi", "wmv", "flv", "mpg", "mpeg", "3gp",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Media {
+    Image,
+    Video,
+}
+
+// An indexed file that may have a preview
+pub(crate) struct Source {
+    pub(crate) key: String,
+    pub(crate) path: PathBuf,
+    pub(crate) mime: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct PreviewSummary {
+    pub made: u64,
+    // Content that already had its preview
+    pub existing: u64,
+    pub failed: u64,
+    // Previews of content no longer indexed
+    pub removed: u64,
+}
+
+fn media(source: &Source) -> Option<Media> {
+    match source.mime.as_deref() {
+        Some(mime) if mime::matches(mime, "image") => Some(Media::Image),
+        Some(mime) if mime::matches(mime, "video") => Some(Media::Video),
+        Some(_) => None,
+        None => {
+            let extension = source.path.extension()?.to_string_lossy().to_lowercase();
+            if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+                Some(Media::Image)
+            } else if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+                Some(Media::Video)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Where the preview of a content is kept, spread over directories by the first two characters
+// of its key. None for anything that isn't a key, so a request can't reach other files.
+pub fn preview_path(output_dir: &Path, key: &str) -> Option<PathBuf> {
+    if key.len() < 2 || !key.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let key = key.to_lowercase();
+    Some(
+        output_dir
+            .join(PREVIEWS_DIR)
+            .join(&key[..2])
+            .join(format!("{}.jpg", key)),
+    )
+}
+
+// Makes the missing previews of indexed images and videos under `paths` (everything when empty),
+// one per content whichever copy it is made from, and removes those of content no longer indexed
+pub(crate) fn generate(paths: &[PathBuf], config: &BOFConfig) -> Result<PreviewSummary> {
+    let index = BOFIndex::load(&config.output_dir, config.index_name())?;
+    let ffmpeg = if config.ffmpeg.is_empty() {
+        FFMPEG
+    } else {
+        &config.ffmpeg
+    };
+    let size = if config.preview_size == 0 {
+        PREVIEW_SIZE
+    } else {
+        config.preview_size
+    };
+    let sources = index.preview_sources();
+    let mut summary = PreviewSummary {
+        removed: remove_orphans(
+            &config.output_dir,
+            &sources.iter().map(|source| source.key.as_str()).collect(),
+        )?,
+        ..PreviewSummary::default()
+    };
+
+    let mut seen = HashSet::new();
+    let mut wanted = Vec::new();
+    for source in &sources {
+        if !paths.is_empty() && !paths.iter().any(|path| source.path.starts_with(path)) {
+            continue;
+        }
+        let (Some(media), Some(out)) =
+            (media(source), preview_path(&config.output_dir, &source.key))
+        else {
+            continue;
+        };
+        if !seen.insert(source.key.as_str()) {
+            continue;
+        }
+        if out.exists() {
+            summary.existing += 1;
+        } else {
+            wanted.push((source, media, out));
+        }
+    }
+    if wanted.is_empty() {
+        return Ok(summary);
+    }
+    match Command::new(ffmpeg)
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(BofError::Config(format!(
+                "{} was not found, install ffmpeg or set `ffmpeg` in Config.toml",
+                ffmpeg
+            )));
+        }
+        Err(e) => return Err(e.into()),
+        Ok(_) => {}
+    }
+
+    let make = |(source, media, out): &(&Source, Media, PathBuf)| match make_preview(
+        ffmpeg,
+        &source.path,
+        *media,
+        out,
+        size,
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            events::emit(Event::Error(format!(
+                "Failed to make a preview of {}: {}",
+                source.path.display(),
+                e
+            )));
+            false
+        }
+    };
+    let made = if config.parallel {
+        wanted.par_iter().filter(|wanted| make(wanted)).count()
+    } else {
+        wanted.iter().filter(|wanted| make(wanted)).count()
+    } as u64;
+    summary.made = made;
+    summary.failed = wanted.len() as u64 - made;
+    Ok(summary)
+}
+
+// A JPEG of the image, or of a frame of the video, fitting a square of `size` pixels. Written
+// under another name first, so a preview is never seen half made.
+fn make_preview(ffmpeg: &str, path: &Path, media: Media, out: &Path, size: u32) -> io::Result<()> {
+    if let Some(dir) = out.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = out.with_extension(format!("tmp-{}", std::process::id()));
+    // A second in, past fade-ins, unless the video is shorter than that
+    let seeks: &[Option<&str>] = match media {
+        Media::Image => &[None],
+        Media::Video => &[Some("1"), None],
+    };
+    let mut error = String::new();
+    for seek in seeks {
+        // Read as a plain file whatever its name, e.g. one starting with a dash
+        let mut input = OsString::from("file:");
+        input.push(path);
+        let mut command = Command::new(ffmpeg);
+        command.args(["-nostdin", "-v", "error", "-y"]);
+        if let Some(seek) = seek {
+            command.args(["-ss", seek]);
+        }
+        let output = command
+            .arg("-i")
+            .arg(&input)
+            .args(["-frames:v", "1", "-vf"])
+            .arg(format!(
+                "scale={0}:{0}:force_original_aspect_ratio=decrease",
+                size
+            ))
+            .args(["-f", "mjpeg"])
+            .arg(&tmp)
+            .stdin(Stdio::null())
+            .output()?;
+        let made = fs::metadata(&tmp).is_ok_and(|metadata| metadata.len() > 0);
+        if output.status.success() && made {
+            return fs::rename(&tmp, out);
+        }
+        error = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .last()
+            .unwrap_or("no frame could be read")
+            .to_string();
+    }
+    let _ = fs::remove_file(&tmp);
+    if let Some(dir) = out.parent() {
+        // Only goes when empty
+        let _ = fs::remove_dir(dir);
+    }
+    Err(io::Error::other(error))
+}
+
+// Removes previews whose content is no longer indexed, returning how many
+fn remove_orphans(output_dir: &Path, indexed: &HashSet<&str>) -> Result<u64> {
+    let dirs = match fs::read_dir(output_dir.join(PREVIEWS_DIR)) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    for dir in dirs {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let orphan = path
+                .file_stem()
+                .is_some_and(|key| !indexed.contains(key.to_string_lossy().as_ref()));
+            if orphan {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        // Only goes when empty
+        let _ = fs::remove_dir(&dir);
+    }
+    Ok(removed)
+}
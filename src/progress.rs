@@ -0,0 +1,90 @@
+use crate::format::human_bytes;
+use bof::events::{Event, Printer, Subscriber};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+const TICK: Duration = Duration::from_millis(100);
+
+// Progress bar on stderr for index, update and verify. Events are still printed as usual,
+// the bar is lifted out of the way while they are.
+pub(crate) struct Progress {
+    bar: ProgressBar,
+    started: Instant,
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    pub(crate) fn start(verb: &str) -> Progress {
+        let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(&format!(
+                "{{spinner}} {} {{pos}} files, {{msg}} [{{elapsed}}]",
+                verb
+            ))
+            .unwrap(),
+        );
+        bar.set_message(format!("{} hashed", human_bytes(0)));
+        bar.enable_steady_tick(TICK);
+        Progress {
+            bar,
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    fn hashed(&self, size: u64) {
+        let bytes = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+        let seconds = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.bar.set_message(format!(
+            "{} hashed ({}/s)",
+            human_bytes(bytes),
+            human_bytes((bytes as f64 / seconds) as u64)
+        ));
+    }
+}
+
+impl Subscriber for Progress {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::Estimate { files, .. } => {
+                self.bar.set_length(*files);
+                self.bar.set_style(
+                    ProgressStyle::with_template(
+                        "{bar:30} {pos}/{len} files, {msg} [{elapsed}, eta {eta}]",
+                    )
+                    .unwrap(),
+                );
+                return;
+            }
+            Event::Indexed { size, .. } | Event::Verified { size, .. } => {
+                self.bar.inc(1);
+                self.hashed(*size);
+                return;
+            }
+            Event::Unchanged(_) => {
+                self.bar.inc(1);
+                return;
+            }
+            Event::Directory(_) => return,
+            Event::Updated { size, .. } => {
+                self.bar.inc(1);
+                self.hashed(*size);
+            }
+            Event::Renamed { .. } => self.bar.inc(1),
+            Event::Removed(_)
+            | Event::Ignored(_)
+            | Event::Notice(_)
+            | Event::Error(_)
+            | Event::Saved(_) => {}
+        }
+        // Whatever the printer has to say for the rest
+        self.bar.suspend(|| Printer.on_event(event));
+    }
+}
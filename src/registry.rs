@@ -0,0 +1,101 @@
+use crate::error::{BofError, Result};
+use crate::platform;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const REGISTRY_FILE: &str = "repos.toml";
+
+// User-level list of known repositories, so they can be reached by name from anywhere
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Registry {
+    #[serde(default)]
+    repos: BTreeMap<String, Repo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Repo {
+    // The repository's index directory
+    pub path: PathBuf,
+}
+
+fn registry_path() -> Result<PathBuf> {
+    platform::config_dir()
+        .map(|dir| dir.join(REGISTRY_FILE))
+        .ok_or_else(|| {
+            BofError::Config("No configuration directory, set HOME or XDG_CONFIG_HOME".to_string())
+        })
+}
+
+impl Registry {
+    pub fn load() -> Result<Registry> {
+        let path = registry_path()?;
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(BofError::config),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registry::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = registry_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = toml::to_string(self).map_err(BofError::config)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn repos(&self) -> impl Iterator<Item = (&String, &Repo)> {
+        self.repos.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Repo> {
+        self.repos.get(name).ok_or_else(|| {
+            BofError::Config(format!("No repository named {} in the registry", name))
+        })
+    }
+
+    // Names the repository at `output_dir`, replacing whatever was registered under that name
+    pub fn add(name: &str, output_dir: &Path) -> Result<PathBuf> {
+        let path = fs::canonicalize(output_dir)?;
+        let mut registry = Registry::load()?;
+        registry
+            .repos
+            .insert(name.to_string(), Repo { path: path.clone() });
+        registry.save()?;
+        Ok(path)
+    }
+
+    pub fn remove(name: &str) -> Result<Repo> {
+        let mut registry = Registry::load()?;
+        let repo = registry.repos.remove(name).ok_or_else(|| {
+            BofError::Config(format!("No repository named {} in the registry", name))
+        })?;
+        registry.save()?;
+        Ok(repo)
+    }
+
+    // Registers a freshly initialized repository under its parent directory's name, unless taken
+    pub(crate) fn add_default(output_dir: &Path) -> Result<Option<String>> {
+        let path = fs::canonicalize(output_dir)?;
+        let Some(name) = path
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            return Ok(None);
+        };
+        let mut registry = Registry::load()?;
+        if registry.repos.contains_key(&name) || registry.repos.values().any(|r| r.path == path) {
+            return Ok(None);
+        }
+        registry.repos.insert(name.clone(), Repo { path });
+        registry.save()?;
+        Ok(Some(name))
+    }
+}
@@ -0,0 +1,212 @@
+use crate::format;
+use bof::Index;
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const REPORT_FILE: &str = "index.html";
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:70em;color:#222}\
+h1{font-size:1.6em}h2{font-size:1.2em;margin-top:2em;border-bottom:1px solid #ccc}\
+table{border-collapse:collapse;width:100%}td,th{padding:.25em .5em;text-align:left;\
+vertical-align:top}tr:nth-child(even){background:#f4f4f4}td.n,th.n{text-align:right;\
+white-space:nowrap}td.p{word-break:break-all}p.note{color:#666}";
+
+// `bof report --html <dir>`: a single page with the styles inline and no scripts, to open from
+// the disk or put on any web server for people who never run bof. Lists are cut at `top`, files
+// untouched for `stale_days` days are stale.
+pub(crate) fn write_html(
+    index: &Index,
+    dir: &Path,
+    top: usize,
+    stale_days: u64,
+) -> io::Result<PathBuf> {
+    let stats = index.stats(top);
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Files report</title>\n<style>{}</style>\n</head>\n<body>\n<h1>Files report</h1>\n\
+         <p class=\"note\">Made {} from the index of {}</p>\n",
+        STYLE,
+        format::timestamp(SystemTime::now()),
+        escape(&roots(index))
+    );
+
+    section(&mut html, "Summary", &["", ""], |rows| {
+        rows.push(vec!["Files".to_string(), stats.files.to_string()]);
+        rows.push(vec![
+            "Directories".to_string(),
+            stats.directories.to_string(),
+        ]);
+        rows.push(vec!["Links".to_string(), stats.links.to_string()]);
+        rows.push(vec!["Size".to_string(), format::human_bytes(stats.bytes)]);
+        rows.push(vec![
+            "Duplicates".to_string(),
+            format!(
+                "{} groups, {} wasted",
+                stats.duplicate_groups,
+                format::human_bytes(stats.wasted_bytes)
+            ),
+        ]);
+    });
+
+    section(&mut html, "Largest files", &["#Size", "Path"], |rows| {
+        for (path, size) in &stats.largest {
+            rows.push(vec![format::human_bytes(*size), path.display().to_string()]);
+        }
+    });
+
+    let mut groups = index.duplicates(0, 2, None);
+    groups.sort_by_key(|group| std::cmp::Reverse(group.wasted()));
+    section(
+        &mut html,
+        "Duplicates, most space wasted first",
+        &["#Wasted", "#Copies", "Paths"],
+        |rows| {
+            for group in groups.iter().take(top) {
+                let paths = group
+                    .paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>();
+                rows.push(vec![
+                    format::human_bytes(group.wasted()),
+                    group.copies.to_string(),
+                    paths.join("\n"),
+                ]);
+            }
+        },
+    );
+
+    let cutoff = SystemTime::now() - Duration::from_secs(stale_days * 24 * 60 * 60);
+    let mut stale = index
+        .entries(Path::new(""))
+        .into_iter()
+        .filter(|entry| entry.kind == "file" && entry.mtime.is_some_and(|mtime| mtime < cutoff))
+        .collect::<Vec<_>>();
+    stale.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let _ = write!(
+        html,
+        "<h2>Stale files</h2>\n<p class=\"note\">{} files, {}, not modified for {} days or more, \
+         the largest first</p>\n",
+        stale.len(),
+        format::human_bytes(stale.iter().filter_map(|entry| entry.size).sum()),
+        stale_days
+    );
+    table(
+        &mut html,
+        &["#Size", "Modified", "Path"],
+        stale.iter().take(top).map(|entry| {
+            vec![
+                format::human_bytes(entry.size.unwrap_or(0)),
+                entry.mtime.map(format::timestamp).unwrap_or_default(),
+                entry.path.display().to_string(),
+            ]
+        }),
+    );
+
+    section(
+        &mut html,
+        "By extension",
+        &["#Size", "#Files", "Extension"],
+        |rows| {
+            for extension in stats.extensions.iter().take(top) {
+                rows.push(vec![
+                    format::human_bytes(extension.bytes),
+                    extension.files.to_string(),
+                    match extension.extension.as_str() {
+                        "" => "(none)".to_string(),
+                        name => format!(".{}", name),
+                    },
+                ]);
+            }
+        },
+    );
+    html.push_str("</body>\n</html>\n");
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(REPORT_FILE);
+    fs::write(&path, html)?;
+    Ok(path)
+}
+
+fn roots(index: &Index) -> String {
+    let roots = index
+        .roots(false)
+        .iter()
+        .map(|root| root.path.display().to_string())
+        .collect::<Vec<_>>();
+    if roots.is_empty() {
+        "no roots".to_string()
+    } else {
+        roots.join(", ")
+    }
+}
+
+fn section(
+    html: &mut String,
+    title: &str,
+    columns: &[&str],
+    fill: impl FnOnce(&mut Vec<Vec<String>>),
+) {
+    let mut rows = Vec::new();
+    fill(&mut rows);
+    let _ = writeln!(html, "<h2>{}</h2>", escape(title));
+    table(html, columns, rows.into_iter());
+}
+
+// Columns starting with # hold numbers and are aligned right, lines in a cell are kept apart
+fn table(html: &mut String, columns: &[&str], rows: impl Iterator<Item = Vec<String>>) {
+    let numeric = columns
+        .iter()
+        .map(|column| column.starts_with('#'))
+        .collect::<Vec<_>>();
+    let mut body = String::new();
+    for row in rows {
+        body.push_str("<tr>");
+        for (cell, numeric) in row.iter().zip(&numeric) {
+            let class = if *numeric { "n" } else { "p" };
+            let cell = escape(cell).replace('\n', "<br>");
+            let _ = write!(body, "<td class=\"{}\">{}</td>", class, cell);
+        }
+        body.push_str("</tr>\n");
+    }
+    if body.is_empty() {
+        html.push_str("<p class=\"note\">None</p>\n");
+        return;
+    }
+    html.push_str("<table>\n");
+    if columns.iter().any(|column| !column.is_empty()) {
+        html.push_str("<tr>");
+        for (column, numeric) in columns.iter().zip(&numeric) {
+            let class = if *numeric { " class=\"n\"" } else { "" };
+            let _ = write!(
+                html,
+                "<th{}>{}</th>",
+                class,
+                escape(column.trim_start_matches('#'))
+            );
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str(&body);
+    html.push_str("</table>\n");
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,331 @@
+use crate::bof::{self, BOFConfig, BOFIndex, EntryInfo};
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::preview;
+use crate::session::SessionTally;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::ToSocketAddrs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Entries returned by one request to /entries unless asked otherwise
+const DEFAULT_LIMIT: usize = 1000;
+
+// What a client may do, by the token it sends
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    // Query the index
+    #[default]
+    Read,
+    // Also change it, with POST /update
+    Admin,
+}
+
+// A secret clients send as `Authorization: Bearer <token>`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ServeToken {
+    pub token: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+type HttpResult = std::result::Result<Body, (u16, String)>;
+
+// The page of the web UI, over the endpoints below
+#[cfg(feature = "web-ui")]
+const UI: &str = include_str!("ui/index.html");
+
+// What a request is answered with
+enum Body {
+    Json(String),
+    Jpeg(Vec<u8>),
+    #[cfg_attr(not(feature = "web-ui"), allow(dead_code))]
+    Html(&'static str),
+}
+
+// The parameters of a query string, decoded
+struct Query(HashMap<String, String>);
+
+impl Query {
+    fn parse(query: &str) -> Query {
+        Query(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(name), decode(value))
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    fn number<T: std::str::FromStr>(
+        &self,
+        name: &str,
+        default: T,
+    ) -> std::result::Result<T, (u16, String)> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| (400, format!("{} should be a number, not {}", name, value))),
+            None => Ok(default),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EntriesPage {
+    total: usize,
+    entries: Vec<EntryInfo>,
+}
+
+// Answers HTTP requests about the index on `listen` until interrupted, reloading the index
+// whenever it was saved meanwhile. Without serve_tokens, only on a loopback address. What its
+// updates applied is reported and logged when it stops.
+pub(crate) fn serve(listen: &str, config: &BOFConfig) -> Result<()> {
+    if config
+        .serve_tokens
+        .values()
+        .any(|token| token.token.is_empty())
+    {
+        return Err(BofError::Config("serve_tokens can't be empty".to_string()));
+    }
+    if config.serve_tokens.is_empty() && !listen.to_socket_addrs()?.all(|a| a.ip().is_loopback()) {
+        return Err(BofError::Config(format!(
+            "serving the index on {} needs serve_tokens, every indexed path would be listed to anyone",
+            listen
+        )));
+    }
+    let mut index = BOFIndex::load(&config.output_dir, config.index_name())?;
+    let server = Arc::new(Server::http(listen).map_err(|e| BofError::Io(io::Error::other(e)))?);
+    // Interrupting stops taking requests, so that the session can be summed up
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler = (server.clone(), interrupted.clone());
+    ctrlc::set_handler(move || {
+        handler.1.store(true, Ordering::Relaxed);
+        handler.0.unblock();
+    })
+    .map_err(|e| BofError::Io(io::Error::other(e)))?;
+    events::emit(Event::Notice(format!(
+        "Serving the index on http://{}",
+        listen
+    )));
+
+    let tally = SessionTally::start("serve");
+
+    for request in server.incoming_requests() {
+        if index.is_stale(&config.output_dir, config.index_name()) {
+            match BOFIndex::load(&config.output_dir, config.index_name()) {
+                Ok(loaded) => index = loaded,
+                Err(e) => events::emit(Event::Error(format!("Failed to reload the index: {}", e))),
+            }
+        }
+        let (status, body) = match respond(&request, &index, config) {
+            Ok(body) => (200, body),
+            Err((status, message)) => (
+                status,
+                Body::Json(serde_json::json!({ "error": message }).to_string()),
+            ),
+        };
+        let (content_type, body) = match body {
+            Body::Json(json) => ("application/json", json.into_bytes()),
+            Body::Jpeg(jpeg) => ("image/jpeg", jpeg),
+            Body::Html(html) => ("text/html; charset=utf-8", html.as_bytes().to_vec()),
+        };
+        let mut response = Response::from_data(body)
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", content_type).expect("valid header"));
+        if status == 401 {
+            response.add_header(
+                Header::from_bytes("WWW-Authenticate", "Bearer").expect("valid header"),
+            );
+        }
+        if let Err(e) = request.respond(response) {
+            events::emit(Event::Error(format!("Failed to answer a request: {}", e)));
+        }
+    }
+    let ended = if interrupted.load(Ordering::Relaxed) {
+        "interrupted"
+    } else {
+        "stopped taking requests"
+    };
+    tally.finish(config, ended);
+    Ok(())
+}
+
+fn respond(request: &Request, index: &BOFIndex, config: &BOFConfig) -> HttpResult {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let query = Query::parse(query);
+    // The page has nothing of the index in it, it asks for the token to query the rest with
+    if path == "/" && request.method() == &Method::Get {
+        return ui();
+    }
+    let access = authorize(request, config)?;
+    if access < route_access(path, config) {
+        return Err((403, format!("{} needs an admin token", path)));
+    }
+    let method = match path {
+        "/update" => Method::Post,
+        _ => Method::Get,
+    };
+    if request.method() != &method {
+        return Err((405, format!("{} only answers {} requests", path, method)));
+    }
+    match path {
+        "/entries" => {
+            let prefix = PathBuf::from(query.get("prefix").unwrap_or(""));
+            let offset = query.number("offset", 0)?;
+            let limit = query.number("limit", DEFAULT_LIMIT)?;
+            // With children, only what is directly in `prefix` like `bof ls`, to browse the tree
+            // a directory at a time
+            let entries = index.list(&prefix, query.get("children") != Some("true"));
+            to_json(&EntriesPage {
+                total: entries.len(),
+                entries: entries.into_iter().skip(offset).take(limit).collect(),
+            })
+        }
+        "/duplicates" => to_json(&index.duplicates(
+            query.number("min_size", 0)?,
+            query.number("min_copies", 2)?,
+            query.get("label"),
+        )),
+        // Keys only, a client can't have files of the server hashed
+        "/search" => match (query.get("hash"), query.get("pattern")) {
+            (Some(hash), _) => to_json(&index.files_with_key(&hash.to_lowercase())),
+            (None, Some(pattern)) => to_json(
+                &index
+                    .find(pattern, query.get("label"), query.get("mime"))
+                    .map_err(|e| (400, e.to_string()))?,
+            ),
+            (None, None) => Err((400, "Give a hash or a pattern to search for".to_string())),
+        },
+        "/stats" => to_json(&index.summary()),
+        "/roots" => to_json(&index.roots(false)),
+        // Made beforehand with `bof previews`, the server never runs ffmpeg
+        "/preview" => {
+            let hash = query
+                .get("hash")
+                .ok_or_else(|| (400, "Give the hash of a file".to_string()))?;
+            let path = preview::preview_path(&config.output_dir, hash)
+                .ok_or_else(|| (400, format!("{} is not a hash", hash)))?;
+            match fs::read(&path) {
+                Ok(jpeg) => Ok(Body::Jpeg(jpeg)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Err((404, format!("No preview of {}", hash)))
+                }
+                Err(e) => Err((500, e.to_string())),
+            }
+        }
+        // Walks the given directory, or every indexed root, like `bof update`. The next request
+        // is answered from the saved result.
+        "/update" => {
+            let paths = match query.get("path") {
+                Some(path) => vec![PathBuf::from(path)],
+                None => index
+                    .roots(false)
+                    .into_iter()
+                    .map(|root| root.path)
+                    .collect(),
+            };
+            bof::update_directories(paths.clone(), config).map_err(|e| (500, e.to_string()))?;
+            to_json(&serde_json::json!({ "updated": paths }))
+        }
+        _ => Err((404, format!("No such endpoint {}", path))),
+    }
+}
+
+#[cfg(feature = "web-ui")]
+fn ui() -> HttpResult {
+    Ok(Body::Html(UI))
+}
+
+#[cfg(not(feature = "web-ui"))]
+fn ui() -> HttpResult {
+    Err((404, "bof was built without the web-ui feature".to_string()))
+}
+
+// What the token of the request allows. Without serve_tokens, serve only listens on loopback
+// and every local client is trusted.
+fn authorize(request: &Request, config: &BOFConfig) -> std::result::Result<Access, (u16, String)> {
+    if config.serve_tokens.is_empty() {
+        return Ok(Access::Admin);
+    }
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            (
+                401,
+                "Send a token as Authorization: Bearer <token>".to_string(),
+            )
+        })?;
+    config
+        .serve_tokens
+        .values()
+        .find(|known| same_secret(known.token.as_bytes(), token.trim().as_bytes()))
+        .map(|known| known.access)
+        .ok_or_else(|| (401, "Unknown token".to_string()))
+}
+
+// Routes only admins may use, /update unless serve_routes says otherwise
+fn route_access(path: &str, config: &BOFConfig) -> Access {
+    match config.serve_routes.get(path) {
+        Some(access) => *access,
+        None if path == "/update" => Access::Admin,
+        None => Access::Read,
+    }
+}
+
+// Compares every byte, so that how long it takes doesn't tell how much of a token was right
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Percent-decoding, with `+` standing for a space as in forms
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn to_json<T: Serialize>(value: &T) -> HttpResult {
+    serde_json::to_string(value)
+        .map(Body::Json)
+        .map_err(|e| (500, e.to_string()))
+}
@@ -0,0 +1,85 @@
+use crate::bof::BOFConfig;
+use crate::events::{self, Event, Subscriber};
+use crate::storage::{self, Session};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+// Counts what a session applies to the index, by the events of its updates
+pub(crate) struct SessionTally {
+    session: &'static str,
+    started: SystemTime,
+    active: AtomicBool,
+    created: AtomicU64,
+    modified: AtomicU64,
+    deleted: AtomicU64,
+    renamed: AtomicU64,
+}
+
+impl SessionTally {
+    pub(crate) fn start(session: &'static str) -> Arc<SessionTally> {
+        let tally = Arc::new(SessionTally {
+            session,
+            started: SystemTime::now(),
+            active: AtomicBool::new(true),
+            created: AtomicU64::new(0),
+            modified: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            renamed: AtomicU64::new(0),
+        });
+        events::subscribe(tally.clone());
+        tally
+    }
+
+    // Stops counting, reports what was applied and logs it in the index directory
+    pub(crate) fn finish(&self, config: &BOFConfig, ended: &str) {
+        self.active.store(false, Ordering::Relaxed);
+        let session = Session {
+            session: self.session.to_string(),
+            started: self.started,
+            stopped: SystemTime::now(),
+            created: self.created.load(Ordering::Relaxed),
+            modified: self.modified.load(Ordering::Relaxed),
+            deleted: self.deleted.load(Ordering::Relaxed),
+            renamed: self.renamed.load(Ordering::Relaxed),
+            ended: ended.to_string(),
+        };
+        let lasted = session
+            .stopped
+            .duration_since(session.started)
+            .unwrap_or_default();
+        events::emit(Event::Notice(format!(
+            "{} stopped ({}) after {}s: {} created, {} modified, {} deleted, {} renamed",
+            session.session,
+            session.ended,
+            lasted.as_secs(),
+            session.created,
+            session.modified,
+            session.deleted,
+            session.renamed
+        )));
+        if let Err(e) = storage::log_session(&config.output_dir, config.index_name(), &session) {
+            events::emit(Event::Error(format!("Failed to log the session: {}", e)));
+        }
+    }
+}
+
+impl Subscriber for SessionTally {
+    fn on_event(&self, event: &Event) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let count = match event {
+            Event::Indexed { .. } => &self.created,
+            Event::Updated { .. } => &self.modified,
+            Event::Removed(_) => &self.deleted,
+            Event::Renamed { .. } => &self.renamed,
+            _ => return,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::bof::BOFConfig;
+use crate::error::{BofError, Result};
+use std::{fs, io, path::Path};
+use toml_edit::{DocumentMut, Item, Key, Value};
+
+pub(crate) const CONFIG_FILE: &str = "Config.toml";
+// User defaults, in the configuration directory
+pub(crate) const USER_CONFIG_FILE: &str = "config.toml";
+
+// Written above each setting of a generated Config.toml
+const COMMENTS: &[(&str, &str)] = &[
+    ("output_dir", "Directory the index is saved in"),
+    (
+        "index_name",
+        "The index is saved and loaded as <index_name>.json, e.g. \"work\", others beside it are left alone",
+    ),
+    (
+        "profile",
+        "Keep the index in a directory of its own under output_dir, see profile_dir",
+    ),
+    (
+        "profile_dir",
+        "Where profiles are kept under output_dir, {profile} being the name",
+    ),
+    ("ignore_paths", "Paths skipped while indexing"),
+    (
+        "ignore",
+        "Patterns in .bofignore syntax, applied below every indexed root",
+    ),
+    (
+        "include_patterns",
+        "Only index files matching one of these globs, e.g. [\"*.raw\", \"*.jpg\"]",
+    ),
+    (
+        "exclude_patterns",
+        "Leave out files matching these globs, e.g. [\"*.tmp\"]",
+    ),
+    ("min_size", "Leave out files smaller than this many bytes"),
+    (
+        "max_size",
+        "Leave out files larger than this many bytes, 0 for no limit",
+    ),
+    (
+        "max_depth",
+        "Levels of directories walked, counting each root itself, 0 for no limit",
+    ),
+    (
+        "checkpoint_files",
+        "Bring the checkpoint of `bof index --resume` to disk every this many files, 0 for 10000",
+    ),
+    (
+        "checkpoint_secs",
+        "Bring the checkpoint of `bof index --resume` to disk every this many seconds, 0 for 60",
+    ),
+    (
+        "one_file_system",
+        "Stay on the filesystem of each root, leaving out other mounts below it",
+    ),
+    (
+        "include_trash",
+        "Index and deduplicate trash directories like .Trash and $RECYCLE.BIN too",
+    ),
+    ("parallel", "Walk directories in parallel"),
+    ("threads", "Threads walking and hashing in parallel, 0 for one per core"),
+    (
+        "open_files",
+        "Files read at once, 0 for no limit, 2 to 4 for spinning disks",
+    ),
+    (
+        "background",
+        "Run at the lowest CPU and IO priority and throttle reads, like --background",
+    ),
+    (
+        "background_read_rate",
+        "MiB read per second in the background, 0 for 50",
+    ),
+    (
+        "cache_friendly",
+        "Keep files read for hashing out of the page cache, on Linux",
+    ),
+    (
+        "subtrees",
+        "Directories walked and hashed by threads of their own with parallel, e.g. \"/mnt/gdrive\" = { threads = 1 } to walk a FUSE mount serially",
+    ),
+    ("estimate", "Pre-scan the paths to estimate progress"),
+    ("hash_algorithm", "sha256, sha1, blake3 or xxh3"),
+    (
+        "extra_hashes",
+        "More digests to record for each file, e.g. [\"blake3\"], keys stay with hash_algorithm",
+    ),
+    (
+        "no_hash",
+        "Record only metadata of files, `bof rehash` hashes them later",
+    ),
+    (
+        "hashing",
+        "Bytes above which files are compared by their ends first, read ahead in chunks, left for `bof rehash` or mapped into memory (0 for never)",
+    ),
+    (
+        "streams",
+        "Record alternate data streams and resource forks",
+    ),
+    (
+        "permissions",
+        "Record mode bits, owner, group and extended attributes of files, on Unix",
+    ),
+    (
+        "mime",
+        "Record the type of files by their first bytes, for `bof find --mime video`",
+    ),
+    (
+        "skip_open",
+        "Leave files other processes have open alone in dedupe and policy run, on Linux",
+    ),
+    (
+        "previews",
+        "Make previews of images and videos in .bof/previews after index and update, with ffmpeg",
+    ),
+    ("preview_size", "Pixels of the longest side of a preview"),
+    ("ffmpeg", "The ffmpeg used to make previews"),
+    ("index_format", "json, bincode or cbor"),
+    ("compression", "none, zstd or gzip"),
+    (
+        "compact",
+        "Save a JSON index without indentation, unset does so above 10000 entries",
+    ),
+    ("symlinks", "skip, follow or record"),
+    (
+        "junctions",
+        "Windows junctions: skip, follow or record, like symlinks when unset",
+    ),
+    (
+        "reparse_points",
+        "Other Windows reparse points, e.g. cloud placeholders: skip or follow",
+    ),
+    (
+        "catalog",
+        "Also save keys in 256 buckets by hash prefix, for fast lookup and duplicates on huge indexes",
+    ),
+    (
+        "key_salt",
+        "Secret `bof export --salted` hashes keys with, to compare manifests only with those sharing it",
+    ),
+    (
+        "hash_cache",
+        "Keep keys in .bof/cache by inode, size and mtime, reused even when indexing into a fresh index",
+    ),
+    (
+        "auto_snapshot",
+        "Take a snapshot every time the index is saved",
+    ),
+    (
+        "keep_snapshots",
+        "How many snapshots to keep, 0 keeps them all",
+    ),
+    (
+        "labels",
+        "Labels given to files matching patterns, e.g. media = [\"*.jpg\", \"*.mp4\"]",
+    ),
+    (
+        "policies",
+        "What may be done with indexed files, e.g. old-downloads = { paths = [\"Downloads/**\"], older_than_days = 90, duplicated = true, action = \"delete\" }",
+    ),
+    (
+        "memory_limit",
+        "MiB the index may take in the daemon or watch before being compacted, 0 for no limit",
+    ),
+    (
+        "alias",
+        "Command aliases, e.g. everywhere = \"find --all-repos\"",
+    ),
+    (
+        "serve_tokens",
+        "Clients of bof serve, e.g. alice = { token = \"...\", access = \"admin\" }, needed to listen beyond loopback",
+    ),
+    (
+        "serve_routes",
+        "Access each endpoint of bof serve needs, read or admin, e.g. \"/entries\" = \"admin\"",
+    ),
+];
+
+// Config.toml explaining every setting of `config`. Those that are the same as in `defaults` are
+// commented out, so they keep following the user's config.toml.
+pub(crate) fn commented(config: &BOFConfig, defaults: &BOFConfig) -> Result<String> {
+    let document = toml::to_string(config)
+        .map_err(BofError::config)?
+        .parse::<DocumentMut>()
+        .map_err(BofError::config)?;
+    let values = toml::Table::try_from(config).map_err(BofError::config)?;
+    let defaults = toml::Table::try_from(defaults).map_err(BofError::config)?;
+
+    let mut contents = String::new();
+    for (key, item) in document.iter() {
+        let lines = match item {
+            Item::Table(table) => {
+                contents.push('\n');
+                let mut lines = vec![format!("[{}]", Key::new(key))];
+                lines.extend(table.iter().map(|(key, item)| setting(key, item)));
+                lines
+            }
+            item => vec![setting(key, item)],
+        };
+        if let Some((_, comment)) = COMMENTS.iter().find(|(name, _)| *name == key) {
+            contents.push_str(&format!("# {}\n", comment));
+        }
+        let prefix = if values.get(key) == defaults.get(key) {
+            "# "
+        } else {
+            ""
+        };
+        for line in lines {
+            contents.push_str(prefix);
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+    }
+    Ok(contents)
+}
+
+// Nested tables, like a policy, are written inline so they fit on the setting's line
+fn setting(key: &str, item: &Item) -> String {
+    let value = match item.clone().into_value() {
+        Ok(value) => value.to_string(),
+        Err(item) => item.to_string(),
+    };
+    format!("{} = {}", Key::new(key), value.trim())
+}
+
+// Effective value of a setting, defaults included. Strings are returned without quotes.
+pub fn get(config: &BOFConfig, key: &str) -> Result<String> {
+    let settings = toml::Value::try_from(config).map_err(BofError::config)?;
+    let mut value = &settings;
+    for part in key.split('.') {
+        value = value
+            .get(part)
+            .ok_or_else(|| BofError::Config(format!("Unknown setting {}", key)))?;
+    }
+    Ok(match value {
+        toml::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    })
+}
+
+// Changes a setting in Config.toml, keeping the rest of the file as it is. The value is parsed
+// as TOML and taken as a plain string when it isn't valid TOML.
+pub fn set(key: &str, value: &str) -> Result<()> {
+    let known = toml::Table::try_from(BOFConfig::default()).map_err(BofError::config)?;
+    let (parents, name) = match key.rsplit_once('.') {
+        Some((parents, name)) => (parents.split('.').collect::<Vec<_>>(), name),
+        None => (Vec::new(), key),
+    };
+    if !known.contains_key(parents.first().copied().unwrap_or(name)) {
+        return Err(BofError::Config(format!("Unknown setting {}", key)));
+    }
+
+    let path = Path::new(CONFIG_FILE);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut document = contents.parse::<DocumentMut>().map_err(BofError::config)?;
+
+    let mut table = document.as_table_mut();
+    for parent in parents {
+        table = table
+            .entry(parent)
+            .or_insert_with(toml_edit::table)
+            .as_table_mut()
+            .ok_or_else(|| BofError::Config(format!("{} is not a table", parent)))?;
+    }
+    let value = value
+        .parse::<Value>()
+        .unwrap_or_else(|_| Value::from(value));
+    table[name] = Item::Value(value);
+
+    let contents = document.to_string();
+    // Refuse values the configuration can't be loaded with
+    toml::from_str::<BOFConfig>(&contents)
+        .map_err(|e| BofError::Config(format!("{}: {}", key, e.message())))?;
+    fs::write(path, contents)?;
+    Ok(())
+}
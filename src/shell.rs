@@ -0,0 +1,201 @@
+use crate::{duplicate_lines, format, ls, print_lines, stats_lines};
+use bof::{events, filter::Filter, preview, Config, Index};
+use clap::Parser;
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+
+// A line of `bof shell`, the queries of the command line against the index already loaded
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+enum Query {
+    #[command(about = "Find indexed files matching a glob pattern")]
+    Find {
+        pattern: String,
+        #[arg(long, help = "Only files with this label")]
+        label: Option<String>,
+        #[arg(long, help = "Only files of this type, e.g. video or image/png")]
+        mime: Option<String>,
+    },
+    #[command(about = "Find indexed files with a given hash or the same content as a file")]
+    Lookup { hash: String },
+    #[command(
+        name = "query",
+        about = "Filter the index with an expression, e.g. size > 100MB and ext == mp4"
+    )]
+    Filter {
+        #[arg(required = true)]
+        expression: Vec<String>,
+    },
+    #[command(about = "List indexed entries under a path")]
+    Ls {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+        #[arg(long, short)]
+        recursive: bool,
+    },
+    #[command(about = "Show the space taken by what is indexed under a path, largest first")]
+    Du {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    #[command(about = "List indexed files with the same content")]
+    Duplicates {
+        #[arg(long, default_value_t = 0)]
+        min_group_size: u64,
+        #[arg(long, default_value_t = 2)]
+        min_copies: u64,
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        summary: bool,
+    },
+    #[command(about = "Show totals, duplicates, the largest files and bytes by extension")]
+    Stats {
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long)]
+        volumes: bool,
+    },
+    #[command(
+        about = "Show where the preview of an indexed file or a hash is, see `bof previews`"
+    )]
+    Preview { file: String },
+    #[command(about = "Load the index again")]
+    Reload,
+    #[command(alias = "exit", about = "Leave the shell")]
+    Quit,
+}
+
+// Answers queries read from stdin, a line at a time, until `quit` or the end of the input. The
+// index is loaded once, and again only when something else saved it meanwhile.
+pub(crate) fn run(config: &Config, snapshot: Option<&str>) -> bof::Result<()> {
+    let load = || match snapshot {
+        Some(id) => Index::load_snapshot(&config.output_dir, config.index_name(), id),
+        None => Index::load(&config.output_dir, config.index_name()),
+    };
+    let mut index = load()?;
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    if interactive {
+        eprintln!("Type help for the list of queries, quit to leave");
+    }
+
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            eprint!("bof> ");
+            io::stderr().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        let query = match Query::try_parse_from(words) {
+            Ok(query) => query,
+            // Also how help is shown
+            Err(e) => {
+                let _ = e.print();
+                continue;
+            }
+        };
+
+        let stale = snapshot.is_none() && index.is_stale(&config.output_dir, config.index_name());
+        if matches!(query, Query::Reload) || stale {
+            match load() {
+                Ok(loaded) => {
+                    index = loaded;
+                    events::emit(events::Event::Notice("Reloaded the index".to_string()));
+                }
+                Err(e) => eprintln!("Error reloading the index: {}", e),
+            }
+        }
+        let result = match query {
+            Query::Find {
+                pattern,
+                label,
+                mime,
+            } => index
+                .find(&pattern, label.as_deref(), mime.as_deref())
+                .map(|paths| paths_lines(&paths))
+                .map_err(|e| format!("Error finding files: {}", e)),
+            Query::Lookup { hash } => index
+                .lookup(&hash)
+                .map(|paths| paths_lines(&paths))
+                .map_err(|e| format!("Error looking up files: {}", e)),
+            Query::Filter { expression } => Filter::parse(&expression.join(" "))
+                .map(|filter| paths_lines(&index.filter(&filter)))
+                .map_err(|e| format!("Error querying the index: {}", e)),
+            Query::Ls { path, recursive } => ls(&index, &path, recursive),
+            Query::Du { path } => ls_sizes(&index, &path),
+            Query::Duplicates {
+                min_group_size,
+                min_copies,
+                label,
+                summary,
+            } => Ok(duplicate_lines(
+                &index.duplicates(min_group_size, min_copies, label.as_deref()),
+                summary,
+            )),
+            Query::Stats { top, volumes } => Ok(stats_lines(&index.stats(top), volumes)),
+            Query::Preview { file } => preview(config, &index, &file),
+            Query::Reload => Ok(Vec::new()),
+            Query::Quit => break,
+        };
+        match result {
+            Ok(lines) => print_lines(&lines),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+fn paths_lines(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+// The preview is made once per content, so any copy or the hash itself finds it
+fn preview(config: &Config, index: &Index, file: &str) -> Result<Vec<String>, String> {
+    let path = std::path::Path::new(file);
+    let key = index
+        .entries(path)
+        .into_iter()
+        .find(|entry| entry.path == path && entry.kind == "file")
+        .map_or_else(|| file.to_lowercase(), |entry| entry.key);
+    match preview::preview_path(&config.output_dir, &key).filter(|preview| preview.exists()) {
+        Some(preview) => Ok(vec![preview.display().to_string()]),
+        None => Err(format!("No preview of {}, see `bof previews`", file)),
+    }
+}
+
+// What `ls` lists, largest first, then the total
+fn ls_sizes(index: &Index, path: &std::path::Path) -> Result<Vec<String>, String> {
+    let dir = crate::listed_dir(path);
+    let mut entries = index.list(&dir, false);
+    if entries.is_empty() && !dir.as_os_str().is_empty() {
+        return Err(format!("Nothing indexed under {}", path.display()));
+    }
+    entries.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let total = entries.iter().filter_map(|entry| entry.size).sum();
+    let mut lines = entries
+        .iter()
+        .map(|entry| {
+            let suffix = if entry.kind == "directory" { "/" } else { "" };
+            format!(
+                "{:>10}  {}{}",
+                format::human_bytes(entry.size.unwrap_or(0)),
+                entry.path.display(),
+                suffix
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!("{:>10}  total", format::human_bytes(total)));
+    Ok(lines)
+}
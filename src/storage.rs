@@ -0,0 +1,726 @@
+use crate::error::{BofError, Result};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed},
+    Deserialize, Serialize,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
+
+// Indexes are saved as <index_name>.json, then the extension of their compression
+pub(crate) const INDEX_NAME: &str = "index";
+const INDEX_EXTENSION: &str = ".json";
+const SNAPSHOTS_DIR: &str = "snapshots";
+// One line per session that ended, see Session
+const SESSIONS_FILE: &str = "sessions.log";
+// One line per save of the index, see Generation
+const LOG_FILE: &str = "generations.log";
+// Generations kept in the log, the oldest go first unless a snapshot still holds them
+const LOG_LIMIT: usize = 1000;
+// Held by the command changing the index, see lock
+const LOCK_FILE: &str = "lock";
+
+// Binary formats start with a magic so loading can tell them apart from JSON
+const BINCODE_MAGIC: &[u8] = b"BOFBIN1\n";
+// CBOR's own self-describe tag (55799)
+const CBOR_MAGIC: &[u8] = &[0xd9, 0xd9, 0xf7];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+// Saved indexes end with the length and blake3 hash of everything before this line, e.g.
+// "\nBOFSUM1 <16 hex digits> <64 hex digits>\n", so a damaged copy is told apart from bad data
+const CHECKSUM_MAGIC: &[u8] = b"\nBOFSUM1 ";
+const CHECKSUM_LEN: u64 = CHECKSUM_MAGIC.len() as u64 + 16 + 1 + 64 + 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    #[default]
+    Json,
+    Bincode,
+    Cbor,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    const ALL: [Compression; 3] = [Compression::None, Compression::Zstd, Compression::Gzip];
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+        }
+    }
+}
+
+// A name, not a path: profiles are how indexes get directories of their own
+pub(crate) fn index_path(
+    output_dir: &Path,
+    name: &str,
+    compression: Compression,
+) -> Result<PathBuf> {
+    let mut components = Path::new(name).components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) || name.starts_with('.')
+    {
+        return Err(BofError::Config(format!(
+            "index_name {:?} isn't a file name",
+            name
+        )));
+    }
+    Ok(output_dir.join(format!(
+        "{}{}{}",
+        name,
+        INDEX_EXTENSION,
+        compression.extension()
+    )))
+}
+
+// The index saved as `name`, whichever compression it was saved with. Indexes of other names in
+// the directory are left alone, and should one be there under several compressions the newest wins.
+pub(crate) fn find_index(output_dir: &Path, name: &str) -> PathBuf {
+    let path = |compression: &Compression| {
+        output_dir.join(format!(
+            "{}{}{}",
+            name,
+            INDEX_EXTENSION,
+            compression.extension()
+        ))
+    };
+    Compression::ALL
+        .iter()
+        .map(path)
+        .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+        .max()
+        .map(|(_, path)| path)
+        .unwrap_or_else(|| path(&Compression::None))
+}
+
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_os_string();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+// A copy of the index as it was at some point, named after when it was taken. `created` is when
+// that index was saved.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub path: PathBuf,
+    pub created: SystemTime,
+    pub size: u64,
+    // Not known for snapshots taken before generations were logged
+    pub generation: Option<u64>,
+}
+
+// The log, sessions, snapshots and catalog of the index saved as `name`, prefixed with it unless the
+// default
+pub(crate) fn named(output_dir: &Path, name: &str, file: &str) -> PathBuf {
+    if name == INDEX_NAME {
+        output_dir.join(file)
+    } else {
+        output_dir.join(format!("{}.{}", name, file))
+    }
+}
+
+fn snapshots_dir(output_dir: &Path, name: &str) -> PathBuf {
+    named(output_dir, name, SNAPSHOTS_DIR)
+}
+
+// Snapshots in `output_dir`, oldest first
+pub fn snapshots(output_dir: &Path, name: &str) -> Result<Vec<Snapshot>> {
+    let entries = match fs::read_dir(snapshots_dir(output_dir, name)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let generations = generations(output_dir, name)?;
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((id, _)) = name.split_once('.') else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        snapshots.push(Snapshot {
+            id: id.to_string(),
+            path: entry.path(),
+            created: metadata.modified()?,
+            size: metadata.len(),
+            generation: generations
+                .iter()
+                .find(|generation| generation.snapshots.iter().any(|taken| taken == id))
+                .map(|generation| generation.generation),
+        });
+    }
+    // Ids are UTC timestamps, so they sort chronologically
+    snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(snapshots)
+}
+
+pub(crate) fn find_snapshot(output_dir: &Path, name: &str, id: &str) -> Result<Snapshot> {
+    snapshots(output_dir, name)?
+        .into_iter()
+        .find(|snapshot| snapshot.id == id)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No snapshot {}", id)).into()
+        })
+}
+
+// Keeps the index currently on disk under a new snapshot id
+pub(crate) fn snapshot(output_dir: &Path, name: &str) -> Result<Snapshot> {
+    let index = find_index(output_dir, name);
+    if !index.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No index in {} to snapshot", output_dir.display()),
+        )
+        .into());
+    }
+    // From .json on, so the snapshot keeps the compression's extension
+    let suffix = index
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .and_then(|name| name.rfind(INDEX_EXTENSION).map(|at| name[at..].to_string()))
+        .unwrap_or_default();
+    let dir = snapshots_dir(output_dir, name);
+    fs::create_dir_all(&dir)?;
+
+    let taken = snapshots(output_dir, name)?;
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut id = timestamp.clone();
+    let mut n = 1;
+    while taken.iter().any(|snapshot| snapshot.id == id) {
+        id = format!("{}-{}", timestamp, n);
+        n += 1;
+    }
+
+    // The index is only ever replaced by renaming over it, so a hardlink stays a faithful copy
+    let path = dir.join(format!("{}{}", id, suffix));
+    if fs::hard_link(&index, &path).is_err() {
+        fs::copy(&index, &path)?;
+    }
+    let metadata = fs::metadata(&path)?;
+    let mut generations = generations(output_dir, name)?;
+    let generation = generations.last_mut().map(|last| {
+        last.snapshots.push(id.clone());
+        last.generation
+    });
+    write_generations(output_dir, name, &generations)?;
+    Ok(Snapshot {
+        id,
+        path,
+        created: metadata.modified()?,
+        size: metadata.len(),
+        generation,
+    })
+}
+
+// Removes the oldest snapshots beyond the last `keep`, returning them
+pub(crate) fn prune_snapshots(output_dir: &Path, name: &str, keep: usize) -> Result<Vec<Snapshot>> {
+    let mut snapshots = snapshots(output_dir, name)?;
+    let excess = snapshots.len().saturating_sub(keep);
+    let pruned = snapshots.drain(..excess).collect::<Vec<_>>();
+    for snapshot in &pruned {
+        fs::remove_file(&snapshot.path)?;
+    }
+    if !pruned.is_empty() {
+        let mut generations = generations(output_dir, name)?;
+        for generation in &mut generations {
+            generation
+                .snapshots
+                .retain(|id| pruned.iter().all(|snapshot| &snapshot.id != id));
+        }
+        write_generations(output_dir, name, &generations)?;
+    }
+    Ok(pruned)
+}
+
+// A save of the index. Generations count up from 1 with every save, whichever command made it,
+// and are recorded in the index itself too.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Generation {
+    pub generation: u64,
+    pub saved: SystemTime,
+    pub files: u64,
+    pub bytes: u64,
+    // Snapshots taken of it
+    #[serde(default)]
+    pub snapshots: Vec<String>,
+}
+
+// Generations saved in `output_dir`, oldest first. Lines that can't be read are left out.
+pub fn generations(output_dir: &Path, name: &str) -> Result<Vec<Generation>> {
+    let contents = match fs::read_to_string(named(output_dir, name, LOG_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn write_generations(output_dir: &Path, name: &str, generations: &[Generation]) -> Result<()> {
+    let path = named(output_dir, name, LOG_FILE);
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+    let mut contents = Vec::new();
+    for generation in generations {
+        serde_json::to_writer(&mut contents, generation).map_err(io::Error::from)?;
+        contents.push(b'\n');
+    }
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+// The generation the next save gets, after the last logged and the one the index was loaded as
+pub(crate) fn next_generation(output_dir: &Path, name: &str, loaded: u64) -> Result<u64> {
+    let last = generations(output_dir, name)?
+        .last()
+        .map_or(0, |generation| generation.generation);
+    Ok(last.max(loaded) + 1)
+}
+
+// Logs a save of the index, dropping the oldest generations beyond LOG_LIMIT
+pub(crate) fn log_generation(output_dir: &Path, name: &str, generation: Generation) -> Result<()> {
+    let mut generations = generations(output_dir, name)?;
+    generations.push(generation);
+    let mut excess = generations.len().saturating_sub(LOG_LIMIT);
+    generations.retain(|generation| {
+        let drop = excess > 0 && generation.snapshots.is_empty();
+        if drop {
+            excess -= 1;
+        }
+        !drop
+    });
+    write_generations(output_dir, name, &generations)
+}
+
+// Where the index of `generation` is still kept: the current index, the previous one kept as its
+// backup, or a snapshot of it
+pub(crate) fn find_generation(output_dir: &Path, name: &str, generation: u64) -> Result<PathBuf> {
+    let generations = generations(output_dir, name)?;
+    let current = find_index(output_dir, name);
+    let kept = match generations
+        .iter()
+        .rposition(|logged| logged.generation == generation)
+    {
+        Some(at) if at + 1 == generations.len() => Some(current),
+        Some(at) => Some(backup_path(&current))
+            .filter(|backup| at + 2 == generations.len() && backup.exists())
+            .or_else(|| {
+                generations[at]
+                    .snapshots
+                    .iter()
+                    .find_map(|id| find_snapshot(output_dir, name, id).ok())
+                    .map(|snapshot| snapshot.path)
+            }),
+        None => None,
+    };
+    kept.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Generation {} isn't kept, only the last two and those with a snapshot are",
+                generation
+            ),
+        )
+        .into()
+    })
+}
+
+// `pretty` indents JSON, other formats are never meant to be read by people
+pub(crate) fn write<T: Serialize, W: Write>(
+    writer: W,
+    value: &T,
+    format: IndexFormat,
+    compression: Compression,
+    pretty: bool,
+) -> Result<()> {
+    match compression {
+        Compression::None => write_format(writer, value, format, pretty),
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            write_format(&mut encoder, value, format, pretty)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+            write_format(&mut encoder, value, format, pretty)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    }
+}
+
+// Counts and hashes what is written for the checksum
+struct Checksummed<W> {
+    inner: W,
+    hasher: blake3::Hasher,
+    len: u64,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Writes the index followed by its checksum
+pub(crate) fn write_index<T: Serialize, W: Write>(
+    writer: W,
+    value: &T,
+    format: IndexFormat,
+    compression: Compression,
+    pretty: bool,
+) -> Result<()> {
+    let mut checksummed = Checksummed {
+        inner: writer,
+        hasher: blake3::Hasher::new(),
+        len: 0,
+    };
+    write(&mut checksummed, value, format, compression, pretty)?;
+    let Checksummed {
+        mut inner,
+        hasher,
+        len,
+    } = checksummed;
+    inner.write_all(CHECKSUM_MAGIC)?;
+    writeln!(inner, "{:016x} {}", len, hasher.finalize().to_hex())?;
+    Ok(())
+}
+
+fn write_format<T: Serialize, W: Write>(
+    mut writer: W,
+    value: &T,
+    format: IndexFormat,
+    pretty: bool,
+) -> Result<()> {
+    match format {
+        IndexFormat::Json if pretty => {
+            serde_json::to_writer_pretty(writer, value).map_err(BofError::serialization)?
+        }
+        IndexFormat::Json => {
+            serde_json::to_writer(writer, value).map_err(BofError::serialization)?
+        }
+        IndexFormat::Bincode => {
+            writer.write_all(BINCODE_MAGIC)?;
+            bincode::serialize_into(writer, value).map_err(BofError::serialization)?
+        }
+        IndexFormat::Cbor => {
+            writer.write_all(CBOR_MAGIC)?;
+            ciborium::into_writer(value, writer).map_err(BofError::serialization)?
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn detect<R: BufRead>(reader: &mut R) -> io::Result<IndexFormat> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(BINCODE_MAGIC) {
+        Ok(IndexFormat::Bincode)
+    } else if head.starts_with(CBOR_MAGIC) {
+        Ok(IndexFormat::Cbor)
+    } else {
+        Ok(IndexFormat::Json)
+    }
+}
+
+// Decompresses transparently, then reads whatever format the index was written in
+pub(crate) fn read<T: DeserializeOwned, R: BufRead>(reader: R) -> Result<T> {
+    read_format(decompress(reader)?)
+}
+
+// Reads the index through `seed`, e.g. to keep only part of it. None when the format can't be
+// read that way, CBOR only deserializes whole values.
+pub(crate) fn read_seed<T, S, R>(reader: R, seed: S) -> Result<Option<T>>
+where
+    S: for<'de> DeserializeSeed<'de, Value = T>,
+    R: BufRead,
+{
+    let mut reader = decompress(reader)?;
+    match detect(&mut reader)? {
+        IndexFormat::Json => {
+            let mut deserializer = serde_json::Deserializer::from_reader(reader);
+            let value = seed
+                .deserialize(&mut deserializer)
+                .map_err(BofError::serialization)?;
+            deserializer.end().map_err(BofError::serialization)?;
+            Ok(Some(value))
+        }
+        IndexFormat::Bincode => {
+            use bincode::Options;
+
+            reader.consume(BINCODE_MAGIC.len());
+            // The options bincode::deserialize_from uses
+            bincode::options()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .deserialize_from_seed(seed, reader)
+                .map(Some)
+                .map_err(BofError::serialization)
+        }
+        IndexFormat::Cbor => Ok(None),
+    }
+}
+
+// The index as it was last committed, opened so that saves going on meanwhile can't change what
+// is read from it: they write another file and rename it over this one
+pub(crate) struct Committed {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+    // Which save it is, see BOFIndex::is_stale
+    pub(crate) modified: Option<SystemTime>,
+}
+
+// Opens the last committed index. Its file only goes missing when a save under another
+// compression removed it between looking for it and opening it, the new one is opened then.
+pub(crate) fn open_committed(output_dir: &Path, name: &str) -> Result<Committed> {
+    let mut path = find_index(output_dir, name);
+    loop {
+        match File::open(&path) {
+            Ok(file) => {
+                let modified = file.metadata()?.modified().ok();
+                return Ok(Committed {
+                    path,
+                    file,
+                    modified,
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let current = find_index(output_dir, name);
+                if current == path {
+                    return Err(e.into());
+                }
+                path = current;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Reads the index at `path` after checking it against its checksum
+pub(crate) fn read_index<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    read_index_file(File::open(path)?, path)
+}
+
+// Like read_index, from the index at `path` already opened
+pub(crate) fn read_index_file<T: DeserializeOwned>(file: File, path: &Path) -> Result<T> {
+    let (reader, checked) = open_index(file, path)?;
+    read(reader).map_err(|e| unchecked(path, checked, e))
+}
+
+// Like read_seed, for the index at `path` already opened
+pub(crate) fn read_index_seed<T, S>(file: File, path: &Path, seed: S) -> Result<Option<T>>
+where
+    S: for<'de> DeserializeSeed<'de, Value = T>,
+{
+    let (reader, checked) = open_index(file, path)?;
+    read_seed(reader, seed).map_err(|e| unchecked(path, checked, e))
+}
+
+// Held for as long as a command changes the index, released when dropped or when the process
+// ends, however it ends
+pub struct IndexLock {
+    _file: Arc<File>,
+}
+
+thread_local! {
+    // Locks the thread holds by path, taking one again hands out the same lock instead of waiting
+    // for itself: commands hold it from loading the index to saving it, and saving takes it too
+    static HELD: RefCell<HashMap<PathBuf, Weak<File>>> = RefCell::new(HashMap::new());
+}
+
+// Takes .bof/lock so that commands changing the index run one at a time instead of each saving
+// over what the other did, waiting for the one holding it if `wait`. Other threads of the process
+// wait like other processes do. The lock is advisory, reads don't take it.
+pub fn lock(output_dir: &Path, wait: bool) -> Result<IndexLock> {
+    fs::create_dir_all(output_dir)?;
+    let path = fs::canonicalize(output_dir)?.join(LOCK_FILE);
+    if let Some(file) = HELD.with(|held| held.borrow().get(&path).and_then(Weak::upgrade)) {
+        return Ok(IndexLock { _file: file });
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) if wait => file.lock()?,
+        Err(TryLockError::WouldBlock) => {
+            // Unreadable where locks are mandatory, i.e. on Windows
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            return Err(BofError::Locked {
+                pid: holder.trim().parse().ok(),
+            });
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    // Who holds it, for the others to tell
+    file.set_len(0)?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+    let file = Arc::new(file);
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        held.retain(|_, file| file.strong_count() > 0);
+        held.insert(path, Arc::downgrade(&file));
+    });
+    Ok(IndexLock { _file: file })
+}
+
+// The index data without its checksum, and whether there was one to check. Indexes saved before
+// checksums were added have none.
+fn open_index(mut file: File, path: &Path) -> Result<(BufReader<io::Take<File>>, bool)> {
+    let len = file.metadata()?.len();
+    let mut trailer = Vec::new();
+    if len >= CHECKSUM_LEN {
+        file.seek(SeekFrom::End(-(CHECKSUM_LEN as i64)))?;
+        file.read_to_end(&mut trailer)?;
+    }
+    let Some(checksum) = trailer.strip_prefix(CHECKSUM_MAGIC) else {
+        file.rewind()?;
+        return Ok((BufReader::new(file.take(len)), false));
+    };
+
+    let damaged = |reason: String| BofError::Corrupt {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let checksum = std::str::from_utf8(checksum).unwrap_or_default();
+    let (expected_len, expected_hash) = checksum
+        .trim_end_matches('\n')
+        .split_once(' ')
+        .and_then(|(len, hash)| Some((u64::from_str_radix(len, 16).ok()?, hash)))
+        .ok_or_else(|| damaged("its checksum can't be read".to_string()))?;
+    let data_len = len - CHECKSUM_LEN;
+    if expected_len != data_len {
+        return Err(damaged(format!(
+            "it has {} bytes of data where {} were saved",
+            data_len, expected_len
+        )));
+    }
+    file.rewind()?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut (&mut file).take(data_len), &mut hasher)?;
+    if hasher.finalize().to_hex().as_str() != expected_hash {
+        return Err(damaged(
+            "its content doesn't match its checksum".to_string(),
+        ));
+    }
+    file.rewind()?;
+    Ok((BufReader::new(file.take(data_len)), true))
+}
+
+// Without a checksum, invalid data may well be a copy cut short
+fn unchecked(path: &Path, checked: bool, e: BofError) -> BofError {
+    match e {
+        BofError::Serialization(reason) if !checked => BofError::Corrupt {
+            path: path.to_path_buf(),
+            reason: format!(
+                "it has no checksum, so it was either cut short or saved by an older version: {}",
+                reason
+            ),
+        },
+        e => e,
+    }
+}
+
+fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> Result<Box<dyn BufRead + 'a>> {
+    let head = reader.fill_buf()?;
+    Ok(if head.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?))
+    } else if head.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(GzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    })
+}
+
+fn read_format<T: DeserializeOwned, R: BufRead>(mut reader: R) -> Result<T> {
+    match detect(&mut reader)? {
+        IndexFormat::Json => serde_json::from_reader(reader).map_err(BofError::serialization),
+        IndexFormat::Bincode => {
+            reader.consume(BINCODE_MAGIC.len());
+            bincode::deserialize_from(reader).map_err(BofError::serialization)
+        }
+        IndexFormat::Cbor => {
+            reader.consume(CBOR_MAGIC.len());
+            ciborium::from_reader(reader).map_err(BofError::serialization)
+        }
+    }
+}
+
+// What a long-running `bof serve`, `bof watch` or `bof daemon` applied to the index from start
+// to stop, logged when it stops so that unattended sessions can be audited
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Session {
+    // The command that ran, serve, watch or daemon
+    pub session: String,
+    pub started: SystemTime,
+    pub stopped: SystemTime,
+    pub created: u64,
+    pub modified: u64,
+    #[serde(default)]
+    pub deleted: u64,
+    pub renamed: u64,
+    // Why it stopped: interrupted, shut down, or the error it stopped on
+    pub ended: String,
+}
+
+// Sessions over the index saved as `name` that ended, oldest first. Lines that can't be read are
+// left out.
+pub fn sessions(output_dir: &Path, name: &str) -> Result<Vec<Session>> {
+    let contents = match fs::read_to_string(named(output_dir, name, SESSIONS_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+pub(crate) fn log_session(output_dir: &Path, name: &str, session: &Session) -> Result<()> {
+    let mut line = serde_json::to_vec(session).map_err(BofError::serialization)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(named(output_dir, name, SESSIONS_FILE))?
+        .write_all(&line)?;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+use crate::format::human_bytes;
+use bof::events::{Event, Subscriber};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const REFRESH: Duration = Duration::from_millis(250);
+const MAX_PATH_WIDTH: usize = 80;
+
+#[derive(Default)]
+struct State {
+    expected_files: Option<u64>,
+    expected_bytes: u64,
+    current: PathBuf,
+    files: u64,
+    bytes: u64,
+    updated: u64,
+    unchanged: u64,
+    renamed: u64,
+    verified: u64,
+    ignored: u64,
+    errors: u64,
+    last_error: Option<String>,
+    saved: Option<PathBuf>,
+    // Rayon worker index (None for the calling thread) -> path it is working on
+    threads: BTreeMap<Option<usize>, PathBuf>,
+}
+
+impl State {
+    fn seen(&self) -> u64 {
+        self.files + self.updated + self.unchanged + self.renamed + self.verified
+    }
+}
+
+// In-place terminal dashboard fed from the event stream, rendered on stderr
+pub(crate) struct Dashboard {
+    title: String,
+    started: Instant,
+    state: Mutex<State>,
+    done: AtomicBool,
+}
+
+pub(crate) struct DashboardHandle {
+    dashboard: Arc<Dashboard>,
+    renderer: JoinHandle<()>,
+}
+
+impl Subscriber for Dashboard {
+    fn on_event(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        let worker = rayon::current_thread_index();
+        match event {
+            Event::Estimate { files, bytes } => {
+                state.expected_files = Some(*files);
+                state.expected_bytes = *bytes;
+            }
+            Event::Directory(path) => {
+                state.current = path.clone();
+                state.threads.insert(worker, path.clone());
+            }
+            Event::Indexed { path, size } => {
+                state.files += 1;
+                state.bytes += size;
+                state.threads.insert(worker, path.clone());
+            }
+            Event::Updated { path, size } => {
+                state.updated += 1;
+                state.bytes += size;
+                state.threads.insert(worker, path.clone());
+            }
+            Event::Unchanged(path) => {
+                state.unchanged += 1;
+                state.threads.insert(worker, path.clone());
+            }
+            Event::Renamed { to, .. } => {
+                state.renamed += 1;
+                state.threads.insert(worker, to.clone());
+            }
+            Event::Verified { path, size } => {
+                state.verified += 1;
+                state.bytes += size;
+                state.threads.insert(worker, path.clone());
+            }
+            Event::Ignored(_) => state.ignored += 1,
+            Event::Removed(_) | Event::Notice(_) => {}
+            Event::Error(message) => {
+                state.errors += 1;
+                state.last_error = Some(message.clone());
+            }
+            Event::Saved(path) => state.saved = Some(path.clone()),
+        }
+    }
+}
+
+impl Dashboard {
+    pub(crate) fn start(title: &str) -> DashboardHandle {
+        let dashboard = Arc::new(Dashboard {
+            title: title.to_string(),
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+            done: AtomicBool::new(false),
+        });
+
+        let renderer = {
+            let dashboard = dashboard.clone();
+            thread::spawn(move || {
+                let mut drawn = 0;
+                while !dashboard.done.load(Ordering::Relaxed) {
+                    drawn = dashboard.render(drawn);
+                    thread::park_timeout(REFRESH);
+                }
+                dashboard.render(drawn);
+            })
+        };
+
+        DashboardHandle {
+            dashboard,
+            renderer,
+        }
+    }
+
+    // Redraws over the previous `drawn` lines and returns how many lines were written
+    fn render(&self, drawn: usize) -> usize {
+        let lines = self.lines();
+        let mut stderr = io::stderr().lock();
+        if drawn > 0 {
+            let _ = write!(stderr, "\x1b[{}F", drawn);
+        }
+        for line in &lines {
+            let _ = writeln!(stderr, "\x1b[2K{}", line);
+        }
+        // Clear what is left of a taller previous frame
+        let _ = write!(stderr, "\x1b[J");
+        let _ = stderr.flush();
+        lines.len()
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let elapsed = self.started.elapsed();
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+
+        let mut lines = vec![
+            format!("BOF {} - elapsed {:.1?}", self.title, elapsed),
+            format!("Current:  {}", shorten(&state.current)),
+            format!(
+                "Files:    {} indexed, {} updated, {} unchanged, {} renamed, {} verified ({:.1}/s)",
+                state.files,
+                state.updated,
+                state.unchanged,
+                state.renamed,
+                state.verified,
+                state.seen() as f64 / seconds
+            ),
+            format!(
+                "Bytes:    {} ({}/s)",
+                human_bytes(state.bytes),
+                human_bytes((state.bytes as f64 / seconds) as u64)
+            ),
+            if self.done.load(Ordering::Relaxed) {
+                "ETA:      done".to_string()
+            } else {
+                format!("ETA:      {}", eta(&state, elapsed))
+            },
+            format!("Ignored:  {}", state.ignored),
+            format!("Errors:   {}", state.errors),
+        ];
+        if let Some(error) = &state.last_error {
+            lines.push(format!("  last: {}", error));
+        }
+        lines.push("Threads:".to_string());
+        for (worker, path) in &state.threads {
+            let worker = match worker {
+                Some(index) => format!("#{}", index),
+                None => "main".to_string(),
+            };
+            lines.push(format!("  {:>5}  {}", worker, shorten(path)));
+        }
+        if let Some(saved) = &state.saved {
+            lines.push(format!("BOF saved to {}", saved.display()));
+        }
+        lines
+    }
+}
+
+impl DashboardHandle {
+    pub(crate) fn subscriber(&self) -> Arc<Dashboard> {
+        self.dashboard.clone()
+    }
+
+    pub(crate) fn finish(self) {
+        self.dashboard.done.store(true, Ordering::Relaxed);
+        self.renderer.thread().unpark();
+        let _ = self.renderer.join();
+    }
+}
+
+// Extrapolates the current rate over the files still expected
+fn eta(state: &State, elapsed: Duration) -> String {
+    let Some(expected) = state.expected_files.filter(|files| *files > 0) else {
+        return "--".to_string();
+    };
+    let seen = state.seen();
+    // The estimate can be off, never claim to be done before we are
+    let progress = (seen as f64 / expected as f64).min(0.999);
+    let total = format!("~{} files, {}", expected, human_bytes(state.expected_bytes));
+    if progress <= 0.0 {
+        return format!("-- (0.0% of {})", total);
+    }
+    let remaining = elapsed.mul_f64((1.0 - progress) / progress);
+    format!("{:.0?} ({:.1}% of {})", remaining, progress * 100.0, total)
+}
+
+fn shorten(path: &Path) -> String {
+    let path = path.display().to_string();
+    let count = path.chars().count();
+    if count <= MAX_PATH_WIDTH {
+        return path;
+    }
+    let tail: String = path.chars().skip(count - MAX_PATH_WIDTH + 3).collect();
+    format!("...{}", tail)
+}
@@ -0,0 +1,201 @@
+use crate::bof::{self, BOFConfig, BOFIndex};
+use crate::drift::Tracker;
+use crate::error::{BofError, Result};
+use crate::events::{self, Event};
+use crate::memory::Monitor;
+use crate::session::SessionTally;
+use crate::storage;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// Changes are applied once things have been quiet for this long, or after MAX_BATCH_DELAY
+const QUIET: Duration = Duration::from_millis(250);
+const MAX_BATCH_DELAY: Duration = Duration::from_secs(1);
+
+// Watches `paths` for changes and applies them to the index as they happen, saving it every
+// `flush_every` when something changed and once more when interrupted. The paths are updated
+// first, so whatever changed while nothing was watching is picked up too. What isn't saved yet
+// is counted for `bof prompt-info`, and what the session applied is reported and logged when it
+// stops, however it does.
+pub(crate) fn watch(paths: Vec<PathBuf>, flush_every: Duration, config: &BOFConfig) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed))
+        .map_err(|e| BofError::Io(std::io::Error::other(e)))?;
+
+    let tally = SessionTally::start("watch");
+    let result = apply_changes(paths, flush_every, config, &stop);
+    let ended = match &result {
+        Ok(ended) => ended.to_string(),
+        Err(e) => e.to_string(),
+    };
+    tally.finish(config, &ended);
+    result.map(|_| ())
+}
+
+// Applies changes until stopped, returning why it stopped
+fn apply_changes(
+    paths: Vec<PathBuf>,
+    flush_every: Duration,
+    config: &BOFConfig,
+    stop: &AtomicBool,
+) -> Result<&'static str> {
+    bof::update_directories(paths.clone(), config)?;
+    let mut index = BOFIndex::load(&config.output_dir, config.index_name())?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    // notify may report absolute paths, the index uses them as they were given
+    let mut roots = Vec::new();
+    for path in &paths {
+        watcher
+            .watch(path, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+        roots.push((fs::canonicalize(path)?, path.clone()));
+    }
+    let output_dir = fs::canonicalize(&config.output_dir)?;
+    events::emit(Event::Notice(format!(
+        "Watching {} for changes, press Ctrl-C to stop",
+        paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )));
+
+    let mut pending = BTreeSet::new();
+    let mut rescan = false;
+    let mut first_pending = Instant::now();
+    let mut dirty = false;
+    let mut flushed = Instant::now();
+    let mut ended = "interrupted";
+    let mut monitor = Monitor::new(config);
+    let mut drift = Tracker::new(config);
+    drift.save();
+    while !stop.load(Ordering::Relaxed) {
+        let quiet = match receiver.recv_timeout(QUIET) {
+            Ok(Ok(event)) => {
+                if event.need_rescan() {
+                    rescan = true;
+                }
+                if !matches!(event.kind, EventKind::Access(_)) {
+                    for path in event.paths {
+                        // Saving the index shows up as changes too
+                        if path.starts_with(&output_dir) {
+                            continue;
+                        }
+                        let Some(path) = relative_to_roots(&path, &roots) else {
+                            continue;
+                        };
+                        if path.starts_with(&config.output_dir) {
+                            continue;
+                        }
+                        if pending.is_empty() {
+                            first_pending = Instant::now();
+                        }
+                        drift.changed(path.clone());
+                        pending.insert(path);
+                    }
+                }
+                false
+            }
+            Ok(Err(e)) => {
+                events::emit(Event::Error(format!("Watch error: {}", e)));
+                false
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                ended = "the watcher stopped";
+                break;
+            }
+        };
+
+        if rescan {
+            // Events were lost, only walking everything again can tell what changed
+            for path in &paths {
+                index.apply_change(path, config);
+            }
+            rescan = false;
+            pending.clear();
+            dirty = true;
+        } else if !pending.is_empty() && (quiet || first_pending.elapsed() >= MAX_BATCH_DELAY) {
+            // A parent's change covers its children, they would only be walked twice
+            let mut applied: Vec<PathBuf> = Vec::new();
+            for path in std::mem::take(&mut pending) {
+                if applied.iter().any(|parent| path.starts_with(parent)) {
+                    continue;
+                }
+                index.apply_change(&path, config);
+                if path.is_dir() {
+                    applied.push(path);
+                }
+            }
+            dirty = true;
+        }
+
+        if dirty && flushed.elapsed() >= flush_every {
+            index = flush(index, &paths, config)?;
+            dirty = false;
+            flushed = Instant::now();
+        }
+
+        if monitor.due() && monitor.check(&index) {
+            // Whatever changed goes to disk, and the index starts over from what was saved
+            index = if dirty {
+                flush(index, &paths, config)?
+            } else {
+                BOFIndex::load(&config.output_dir, config.index_name())?
+            };
+            index.compact();
+            monitor.compacted(&index);
+            dirty = false;
+            flushed = Instant::now();
+        }
+        if quiet {
+            drift.save();
+        }
+    }
+
+    for path in std::mem::take(&mut pending) {
+        index.apply_change(&path, config);
+        dirty = true;
+    }
+    if dirty {
+        flush(index, &paths, config)?;
+    }
+    Ok(ended)
+}
+
+// Saves the index and loads it back, so the next save knows which version it started from
+fn flush(mut index: BOFIndex, paths: &[PathBuf], config: &BOFConfig) -> Result<BOFIndex> {
+    // Only for as long as it takes, other commands run while watching
+    let _lock = storage::lock(&config.output_dir, true)?;
+    index.claim_roots(paths);
+    index.save(config)?;
+    BOFIndex::load(&config.output_dir, config.index_name())
+}
+
+pub(crate) fn relative_to_roots(path: &Path, roots: &[(PathBuf, PathBuf)]) -> Option<PathBuf> {
+    roots.iter().find_map(|(canonical, given)| {
+        if path.starts_with(given) {
+            Some(path.to_path_buf())
+        } else {
+            path.strip_prefix(canonical)
+                .ok()
+                .map(|relative| given.join(relative))
+        }
+    })
+}
+
+pub(crate) fn watch_error(e: notify::Error) -> BofError {
+    BofError::Io(std::io::Error::other(e))
+}
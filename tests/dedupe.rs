@@ -0,0 +1,352 @@
+use bof::dedupe::{DedupeAction, Deduped, Keep, Outcome};
+use bof::{Config, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+// A fresh directory per test under `parent`, tests of a file run at the same time
+fn base_in(parent: &Path, test: &str) -> PathBuf {
+    let base = parent.join(format!("bof-dedupe-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+fn base(test: &str) -> PathBuf {
+    base_in(&std::env::temp_dir(), test)
+}
+
+// Indexes `roots` into `base/index`
+fn indexer(base: &Path, roots: &[&Path]) -> Indexer {
+    let config = Config {
+        output_dir: base.join("index"),
+        ..Default::default()
+    };
+    fs::create_dir_all(&config.output_dir).unwrap();
+    let indexer = Indexer::new(config);
+    let roots = roots.iter().map(|root| root.to_path_buf()).collect();
+    indexer.index(roots, false).unwrap();
+    indexer
+}
+
+// Three copies of one content, `a` being the one kept, and a file of its own
+fn build_tree(root: &Path) {
+    fs::create_dir_all(root).unwrap();
+    for file in ["a", "b", "c"] {
+        fs::write(root.join(file), "same content").unwrap();
+    }
+    fs::write(root.join("other"), "other content").unwrap();
+}
+
+fn dedupe(indexer: &Indexer, action: DedupeAction, dry_run: bool) -> Vec<Deduped> {
+    indexer
+        .dedupe(action, Keep::First, 0, 2, dry_run, false)
+        .unwrap()
+}
+
+fn outcomes(deduped: &[Deduped]) -> Vec<(PathBuf, String)> {
+    deduped
+        .iter()
+        .map(|deduped| {
+            let outcome = match &deduped.outcome {
+                Outcome::Planned => "planned".to_string(),
+                Outcome::Done => "done".to_string(),
+                Outcome::Skipped(reason) => format!("skipped: {}", reason),
+                Outcome::Failed(e) => format!("failed: {}", e),
+            };
+            (deduped.matched.path.clone(), outcome)
+        })
+        .collect()
+}
+
+// What a file holds and when it was last written
+fn content(path: &Path) -> (Vec<u8>, SystemTime) {
+    (
+        fs::read(path).unwrap(),
+        fs::metadata(path).unwrap().modified().unwrap(),
+    )
+}
+
+// The files directly in `dir` and what they hold
+fn files_in(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| {
+            let content = fs::read(&path).unwrap();
+            (path, content)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn dedupe_without_apply_changes_nothing() {
+    let base = base("dry-run");
+    let root = base.join("root");
+    build_tree(&root);
+    let indexer = indexer(&base, &[&root]);
+    let index_dir = files_in(&base.join("index"));
+    let files = ["a", "b", "c", "other"].map(|file| content(&root.join(file)));
+
+    let deduped = dedupe(&indexer, DedupeAction::Delete, true);
+    assert_eq!(
+        outcomes(&deduped),
+        vec![
+            (root.join("b"), "planned".to_string()),
+            (root.join("c"), "planned".to_string())
+        ]
+    );
+
+    // Nor does the command line without --apply
+    let output = Command::new(env!("CARGO_BIN_EXE_bof"))
+        .current_dir(&base)
+        .env("XDG_CONFIG_HOME", base.join("config"))
+        .arg("--output-dir")
+        .arg(base.join("index"))
+        .args(["dedupe", "--action", "delete"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("would delete"));
+
+    assert_eq!(
+        ["a", "b", "c", "other"].map(|file| content(&root.join(file))),
+        files
+    );
+    // Neither the index nor dedupe.log were written
+    assert_eq!(files_in(&base.join("index")), index_dir);
+    assert_eq!(indexer.load().unwrap().entries(&root).len(), 4);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn dedupe_never_writes_to_the_kept_copy() {
+    for action in [
+        DedupeAction::Hardlink,
+        DedupeAction::Symlink,
+        DedupeAction::Delete,
+    ] {
+        let base = base(&format!("kept-{}", action));
+        let root = base.join("root");
+        build_tree(&root);
+        let indexer = indexer(&base, &[&root]);
+        let kept = content(&root.join("a"));
+
+        let deduped = dedupe(&indexer, action, false);
+        assert!(
+            deduped
+                .iter()
+                .all(|deduped| matches!(deduped.outcome, Outcome::Done)),
+            "{:?}",
+            outcomes(&deduped)
+        );
+        assert_eq!(content(&root.join("a")), kept, "{}", action);
+        assert!(fs::symlink_metadata(root.join("a")).unwrap().is_file());
+        if action != DedupeAction::Delete {
+            // The copies now read through to it, and still read the same
+            assert_eq!(content(&root.join("b")).0, kept.0);
+        }
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
+
+#[test]
+fn dedupe_skips_copies_modified_after_indexing() {
+    let base = base("modified");
+    let root = base.join("root");
+    build_tree(&root);
+    let indexer = indexer(&base, &[&root]);
+    fs::write(root.join("b"), "changed content").unwrap();
+
+    let deduped = dedupe(&indexer, DedupeAction::Delete, false);
+    assert_eq!(
+        outcomes(&deduped),
+        vec![
+            (
+                root.join("b"),
+                "skipped: changed since it was indexed".to_string()
+            ),
+            (root.join("c"), "done".to_string())
+        ]
+    );
+    assert_eq!(fs::read(root.join("b")).unwrap(), b"changed content");
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn dedupe_skips_hardlinks_on_another_filesystem() {
+    use std::os::unix::fs::MetadataExt;
+
+    // The kept copy has to be on another filesystem than the temporary directory, tmpfs is one
+    let shm = Path::new("/dev/shm");
+    let tmp = std::env::temp_dir();
+    let device = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev());
+    if !matches!((device(shm), device(&tmp)), (Ok(a), Ok(b)) if a != b) {
+        eprintln!("No other filesystem than the temporary directory's, skipped");
+        return;
+    }
+    let base = base("filesystems");
+    let root = base.join("root");
+    fs::create_dir_all(&root).unwrap();
+    let other = base_in(shm, "filesystems");
+    // Kept, coming first in path order
+    fs::write(other.join("a"), "same content").unwrap();
+    fs::write(root.join("b"), "same content").unwrap();
+    let indexer = indexer(&base, &[&other, &root]);
+
+    let deduped = dedupe(&indexer, DedupeAction::Hardlink, false);
+    assert_eq!(
+        outcomes(&deduped),
+        vec![(
+            root.join("b"),
+            "skipped: on another filesystem than the kept copy".to_string()
+        )]
+    );
+    assert_eq!(fs::metadata(root.join("b")).unwrap().nlink(), 1);
+
+    fs::remove_dir_all(base).unwrap();
+    fs::remove_dir_all(other).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn dedupe_skips_hardlinks_of_the_kept_copy() {
+    use std::{fs::File, os::unix::fs::MetadataExt, time::Duration};
+
+    let base = base("hardlinks");
+    let root = base.join("root");
+    build_tree(&root);
+    // Indexed as a hardlink of the kept copy
+    fs::remove_file(root.join("c")).unwrap();
+    fs::hard_link(root.join("a"), root.join("c")).unwrap();
+    // Same modification time as the kept copy, so it still matches the index once it is one
+    let mtime = SystemTime::now() - Duration::from_secs(60);
+    for file in ["a", "b"] {
+        File::options()
+            .write(true)
+            .open(root.join(file))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+    let indexer = indexer(&base, &[&root]);
+    // Made a hardlink of the kept copy after indexing
+    fs::remove_file(root.join("b")).unwrap();
+    fs::hard_link(root.join("a"), root.join("b")).unwrap();
+
+    let deduped = dedupe(&indexer, DedupeAction::Delete, false);
+    assert_eq!(
+        outcomes(&deduped),
+        vec![(
+            root.join("b"),
+            "skipped: already a hardlink of the kept copy".to_string()
+        )]
+    );
+    assert_eq!(fs::metadata(root.join("a")).unwrap().nlink(), 3);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn dedupe_logs_one_line_per_action() {
+    let base = base("log");
+    let root = base.join("root");
+    build_tree(&root);
+    fs::write(root.join("d"), "same content").unwrap();
+    let indexer = indexer(&base, &[&root]);
+    fs::write(root.join("d"), "changed content").unwrap();
+
+    let deduped = dedupe(&indexer, DedupeAction::Delete, false);
+    assert_eq!(deduped.len(), 3);
+    let log = fs::read_to_string(base.join("index/dedupe.log")).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3, "{}", log);
+    for (line, deduped) in lines.iter().zip(&deduped) {
+        let fields = line.splitn(3, '\t').collect::<Vec<_>>();
+        assert_eq!(fields[1], "delete");
+        assert_eq!(fields[2], deduped.to_string());
+    }
+
+    // Another run appends to it
+    dedupe(&indexer, DedupeAction::Delete, false);
+    let log = fs::read_to_string(base.join("index/dedupe.log")).unwrap();
+    assert_eq!(log.lines().count(), 4, "{}", log);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn dedupe_records_hardlinks_in_the_index() {
+    let base = base("index-hardlink");
+    let root = base.join("root");
+    build_tree(&root);
+    let indexer = indexer(&base, &[&root]);
+
+    dedupe(&indexer, DedupeAction::Hardlink, false);
+    let hardlinks = indexer.load().unwrap().hardlinks();
+    assert_eq!(hardlinks.len(), 1);
+    assert_eq!(
+        hardlinks[0].paths,
+        vec![root.join("a"), root.join("b"), root.join("c")]
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn dedupe_records_deletions_in_the_index() {
+    let base = base("index-delete");
+    let root = base.join("root");
+    build_tree(&root);
+    let indexer = indexer(&base, &[&root]);
+
+    dedupe(&indexer, DedupeAction::Delete, false);
+    let paths = indexer
+        .load()
+        .unwrap()
+        .entries(&root)
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect::<Vec<_>>();
+    assert_eq!(paths, vec![root.join("a"), root.join("other")]);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn dedupe_counts_min_copies_per_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = base("min-copies");
+    let root = base.join("root");
+    build_tree(&root);
+    // Another mode sets a copy apart as another owner would, without needing root to chown it
+    fs::set_permissions(root.join("c"), fs::Permissions::from_mode(0o600)).unwrap();
+    let indexer = indexer(&base, &[&root]);
+
+    // Three copies, but only two of the same owner
+    let deduped = indexer
+        .dedupe(DedupeAction::Delete, Keep::First, 0, 3, true, false)
+        .unwrap();
+    assert!(deduped.is_empty(), "{:?}", outcomes(&deduped));
+    let deduped = indexer
+        .dedupe(DedupeAction::Delete, Keep::First, 0, 2, true, false)
+        .unwrap();
+    assert_eq!(
+        outcomes(&deduped),
+        vec![(root.join("b"), "planned".to_string())]
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
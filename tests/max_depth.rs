@@ -0,0 +1,55 @@
+use bof::{Config, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-max-depth-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+// The files indexed under `root` with a given max_depth
+fn indexed(base: &Path, root: &Path, max_depth: usize) -> Vec<PathBuf> {
+    let config = Config {
+        output_dir: base.join(format!("index{}", max_depth)),
+        max_depth,
+        ..Default::default()
+    };
+    fs::create_dir_all(&config.output_dir).unwrap();
+    let indexer = Indexer::new(config);
+    indexer.index(vec![root.to_path_buf()], false).unwrap();
+    indexer
+        .load()
+        .unwrap()
+        .entries(root)
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect()
+}
+
+#[test]
+fn max_depth_counts_the_root_itself() {
+    let base = base("levels");
+    let root = base.join("root");
+    fs::create_dir_all(root.join("a/b")).unwrap();
+    for file in ["f0", "a/f1", "a/b/f2"] {
+        fs::write(root.join(file), file).unwrap();
+    }
+
+    assert_eq!(indexed(&base, &root, 1), vec![root.join("f0")]);
+    assert_eq!(
+        indexed(&base, &root, 2),
+        vec![root.join("a/f1"), root.join("f0")]
+    );
+    // 0 leaves the depth unlimited
+    assert_eq!(
+        indexed(&base, &root, 0),
+        vec![root.join("a/b/f2"), root.join("a/f1"), root.join("f0")]
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
@@ -0,0 +1,132 @@
+use bof::pack::{pack_to, unpack_from};
+use bof::{Config, Index, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8] = b"BOFPACK2";
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-pack-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+// Indexes `root` into an index of its own under `base`
+fn index(base: &Path, name: &str, root: &Path) -> Index {
+    let config = Config {
+        output_dir: base.join(name),
+        ..Default::default()
+    };
+    fs::create_dir_all(&config.output_dir).unwrap();
+    let indexer = Indexer::new(config);
+    indexer.index(vec![root.to_path_buf()], false).unwrap();
+    indexer.load().unwrap()
+}
+
+// Two copies of one content and another content in a subdirectory
+fn build_tree(root: &Path) {
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("a"), "same content").unwrap();
+    fs::write(root.join("b"), "same content").unwrap();
+    fs::write(root.join("sub/c"), "other content").unwrap();
+}
+
+fn assert_same_tree(from: &Path, to: &Path) {
+    for file in ["a", "b", "sub/c"] {
+        assert_eq!(
+            fs::read(from.join(file)).unwrap(),
+            fs::read(to.join(file)).unwrap(),
+            "{}",
+            file
+        );
+        assert_eq!(
+            fs::metadata(from.join(file)).unwrap().modified().unwrap(),
+            fs::metadata(to.join(file)).unwrap().modified().unwrap(),
+            "{}",
+            file
+        );
+    }
+}
+
+#[test]
+fn pack_and_unpack_through_a_pipe() {
+    let base = base("pipe");
+    let src = base.join("src");
+    build_tree(&src);
+    let index = index(&base, "index", &src);
+
+    let mut archive = Vec::new();
+    let packed = pack_to(&index, &src, None, &mut archive).unwrap();
+    assert_eq!(packed.files, 3);
+    assert_eq!(packed.bytes, 12 + 12 + 13);
+    // The second copy is only in the manifest
+    assert_eq!(packed.stored, 12 + 13);
+
+    let out = base.join("out");
+    let unpacked = unpack_from(archive.as_slice(), &out, None).unwrap();
+    assert_eq!(unpacked.files, 3);
+    assert_eq!(unpacked.bytes, 12 + 12 + 13);
+    assert_same_tree(&src, &out);
+
+    // Existing files are never overwritten
+    assert!(unpack_from(archive.as_slice(), &out, None).is_err());
+    // Nor is an archive cut short taken for a whole one
+    let cut = &archive[..archive.len() - 1];
+    assert!(unpack_from(cut, &base.join("cut"), None).is_err());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn unpack_rejects_paths_outside_of_its_directory() {
+    let base = base("traversal");
+    let manifest =
+        br#"{"entries":[{"path":"../escaped","kind":{"Link":{"target":"/etc/passwd"}}}]}"#;
+    let mut archive = MAGIC.to_vec();
+    archive.push(b"sha256".len() as u8);
+    archive.extend_from_slice(b"sha256");
+    archive.push(b'M');
+    archive.extend_from_slice(manifest);
+    archive.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+    archive.extend_from_slice(MAGIC);
+
+    let into = base.join("into");
+    let error = unpack_from(archive.as_slice(), &into, None).unwrap_err();
+    assert!(error.to_string().contains("Invalid path"), "{}", error);
+    assert!(fs::symlink_metadata(base.join("escaped")).is_err());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn pack_against_leaves_out_content_the_destination_has() {
+    let base = base("against");
+    // The destination already has the content of a and b, under another name
+    let dest = base.join("dest");
+    fs::create_dir_all(&dest).unwrap();
+    fs::write(dest.join("kept"), "same content").unwrap();
+    let against = index(&base, "dest-index", &dest);
+    let src = base.join("src");
+    build_tree(&src);
+    let index = index(&base, "index", &src);
+
+    let mut archive = Vec::new();
+    let packed = pack_to(&index, &src, Some(&against), &mut archive).unwrap();
+    assert_eq!(packed.files, 3);
+    assert_eq!(packed.present, 12);
+    assert_eq!(packed.stored, 13);
+
+    // What was left out can only come from the destination's files
+    assert!(unpack_from(archive.as_slice(), &base.join("without"), None).is_err());
+    let out = base.join("out");
+    let unpacked = unpack_from(archive.as_slice(), &out, Some(&against)).unwrap();
+    assert_eq!(unpacked.files, 3);
+    assert_same_tree(&src, &out);
+    assert_eq!(fs::read(dest.join("kept")).unwrap(), b"same content");
+
+    fs::remove_dir_all(base).unwrap();
+}
@@ -0,0 +1,114 @@
+use bof::{Config, Index, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-profiles-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+fn config(output_dir: &Path, index_name: &str) -> Config {
+    Config {
+        output_dir: output_dir.to_path_buf(),
+        index_name: index_name.to_string(),
+        ..Default::default()
+    }
+}
+
+fn indexed(index: &Index, root: &Path) -> Vec<PathBuf> {
+    index
+        .entries(root)
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect()
+}
+
+#[test]
+fn indexes_of_two_names_sit_side_by_side() {
+    let base = base("names");
+    let output_dir = base.join(".bof");
+    for (root, file) in [("home", "h.txt"), ("work", "w.txt")] {
+        fs::create_dir_all(base.join(root)).unwrap();
+        fs::write(base.join(root).join(file), root).unwrap();
+    }
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let home = Indexer::new(config(&output_dir, "home"));
+    home.index(vec![base.join("home")], false).unwrap();
+    let work = Indexer::new(config(&output_dir, "work"));
+    work.index(vec![base.join("work")], false).unwrap();
+    // Saving one again leaves the other as it was
+    let saved = fs::read(output_dir.join("work.json")).unwrap();
+    home.update(vec![base.join("home")]).unwrap();
+    assert_eq!(fs::read(output_dir.join("work.json")).unwrap(), saved);
+
+    let loaded = home.load().unwrap();
+    assert_eq!(indexed(&loaded, &base), vec![base.join("home/h.txt")]);
+    let loaded = Index::load(&output_dir, "work").unwrap();
+    assert_eq!(indexed(&loaded, &base), vec![base.join("work/w.txt")]);
+    assert!(Index::load(&output_dir, "index").is_err());
+
+    // Each keeps its own generations and snapshots
+    let snapshot = home.snapshot().unwrap();
+    assert_eq!(home.snapshots().unwrap().len(), 1);
+    assert!(work.snapshots().unwrap().is_empty());
+    let taken = Index::open(&output_dir, "home", &snapshot.id).unwrap();
+    assert_eq!(indexed(&taken, &base), vec![base.join("home/h.txt")]);
+    assert!(Index::open(&output_dir, "work", &snapshot.id).is_err());
+    let generations = |indexer: &Indexer| {
+        indexer
+            .log()
+            .unwrap()
+            .iter()
+            .map(|generation| generation.generation)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(generations(&home), vec![1, 2]);
+    assert_eq!(generations(&work), vec![1]);
+    assert_eq!(snapshot.generation, Some(2));
+    let first = Index::open(&output_dir, "work", "1").unwrap();
+    assert_eq!(indexed(&first, &base), vec![base.join("work/w.txt")]);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn profiles_keep_their_index_apart() {
+    let base = base("profiles");
+    for root in ["photos", "music"] {
+        fs::create_dir_all(base.join(root)).unwrap();
+        fs::write(base.join(root).join("file"), root).unwrap();
+    }
+    let bof = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_bof"))
+            .current_dir(&base)
+            .env("XDG_CONFIG_HOME", base.join("config"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    bof(&["init"]);
+    bof(&["--profile", "photos", "init"]);
+    bof(&["--profile", "photos", "index", "photos"]);
+    bof(&["index", "music"]);
+    assert!(base.join(".bof/profiles/photos/index.json").is_file());
+    assert!(base.join(".bof/index.json").is_file());
+
+    let found = bof(&["--profile", "photos", "find", "*"]);
+    assert!(found.contains("photos"), "{}", found);
+    assert!(!found.contains("music"), "{}", found);
+    let found = bof(&["find", "*"]);
+    assert!(found.contains("music"), "{}", found);
+    assert!(!found.contains("photos"), "{}", found);
+
+    fs::remove_dir_all(base).unwrap();
+}
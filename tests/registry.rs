@@ -0,0 +1,95 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-registry-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+// Runs bof in `dir`, with the registry kept under `base`
+fn bof(base: &Path, dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_bof"))
+        .current_dir(dir)
+        .env("XDG_CONFIG_HOME", base.join("config"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn init_registers_the_repository() {
+    let base = base("init");
+    let repo = base.join("photos");
+    fs::create_dir_all(&repo).unwrap();
+
+    bof(&base, &repo, &["init"]);
+    let listed = bof(&base, &base, &["repos", "list"]);
+    assert!(listed.starts_with("photos\t"), "{}", listed);
+    // Another init neither fails nor registers it twice
+    bof(&base, &repo, &["init"]);
+    let listed = bof(&base, &base, &["repos", "list"]);
+    let repos = listed.lines().filter(|line| line.contains('\t')).count();
+    assert_eq!(repos, 1, "{}", listed);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+// Indexes `home` and `work`, registered as house and office
+fn two_repos(base: &Path) {
+    for repo in ["home", "work"] {
+        let dir = base.join(repo);
+        fs::create_dir_all(dir.join(".bof")).unwrap();
+        fs::write(dir.join(format!("{}.txt", repo)), repo).unwrap();
+        bof(base, &dir, &["index", dir.to_str().unwrap()]);
+    }
+    bof(base, &base.join("home"), &["repos", "add", "house"]);
+    bof(base, &base.join("work"), &["repos", "add", "office"]);
+}
+
+#[test]
+fn repo_picks_a_registered_index_from_anywhere() {
+    let base = base("repo");
+    two_repos(&base);
+
+    let roots = bof(&base, &base, &["--repo", "office", "roots"]);
+    assert!(roots.contains("work"), "{}", roots);
+    assert!(!roots.contains("home"), "{}", roots);
+
+    bof(&base, &base, &["repos", "remove", "office"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_bof"))
+        .current_dir(&base)
+        .env("XDG_CONFIG_HOME", base.join("config"))
+        .args(["--repo", "office", "roots"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn all_repos_searches_every_registered_index() {
+    let base = base("all-repos");
+    two_repos(&base);
+
+    let found = bof(&base, &base, &["find", "--all-repos", "*.txt"]);
+    assert!(found
+        .lines()
+        .any(|line| line.starts_with("house\t") && line.ends_with("home.txt")));
+    assert!(found
+        .lines()
+        .any(|line| line.starts_with("office\t") && line.ends_with("work.txt")));
+    let found = bof(&base, &base, &["--repo", "office", "find", "*.txt"]);
+    assert!(found.contains("work.txt"), "{}", found);
+    assert!(!found.contains("home.txt"), "{}", found);
+
+    fs::remove_dir_all(base).unwrap();
+}
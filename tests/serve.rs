@@ -0,0 +1,240 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-serve-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+fn bof(base: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bof"));
+    command
+        .current_dir(base)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+// A port nothing listens on, as far as can be told
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// `bof serve` on the index of `base`, killed when dropped
+struct Server {
+    child: Child,
+    address: String,
+}
+
+impl Server {
+    fn start(base: &Path) -> Server {
+        let address = format!("127.0.0.1:{}", free_port());
+        let child = bof(base)
+            .args(["serve", "--listen", &address])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while TcpStream::connect(&address).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "bof serve didn't start");
+            thread::sleep(Duration::from_millis(20));
+        }
+        Server { child, address }
+    }
+
+    // The status code of the answer
+    fn request(&self, method: &str, path: &str, token: Option<&str>) -> u16 {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: 0\r\n",
+            method, path, self.address
+        );
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap_or_default();
+        status.parse().unwrap_or_else(|_| panic!("{}", response))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// An indexed directory with an admin token and a read one
+fn indexed(test: &str, routes: &str) -> PathBuf {
+    let base = base(test);
+    let root = base.join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file"), "content").unwrap();
+    fs::write(
+        base.join("Config.toml"),
+        format!(
+            "[serve_tokens]\n\
+             alice = {{ token = \"admin-secret\", access = \"admin\" }}\n\
+             kiosk = {{ token = \"read-secret\" }}\n\
+             {}",
+            routes
+        ),
+    )
+    .unwrap();
+    for args in [&["init"][..], &["index", "root"]] {
+        let output = bof(&base).args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    }
+    base
+}
+
+#[test]
+fn serve_refuses_other_addresses_without_tokens() {
+    let base = base("loopback");
+    let mut child = bof(&base)
+        .args(["serve", "--listen", &format!("0.0.0.0:{}", free_port())])
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            panic!("bof serve listened on 0.0.0.0 without serve_tokens");
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(!status.success());
+    assert!(stderr.contains("serve_tokens"), "{}", stderr);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn serve_answers_only_known_tokens() {
+    let base = indexed("tokens", "");
+    let server = Server::start(&base);
+
+    assert_eq!(server.request("GET", "/roots", None), 401);
+    assert_eq!(server.request("GET", "/roots", Some("guess")), 401);
+    assert_eq!(server.request("GET", "/roots", Some("read-secret")), 200);
+    assert_eq!(server.request("GET", "/stats", Some("read-secret")), 200);
+    // Previews are only ever read, by a hash
+    assert_eq!(server.request("GET", "/preview", Some("read-secret")), 400);
+    assert_eq!(
+        server.request("GET", "/preview?hash=../x", Some("read-secret")),
+        400
+    );
+    assert_eq!(
+        server.request("GET", "/preview?hash=00ff", Some("read-secret")),
+        404
+    );
+    assert_eq!(server.request("GET", "/roots", Some("admin-secret")), 200);
+    // Reading isn't enough to change the index
+    assert_eq!(server.request("POST", "/update", Some("read-secret")), 403);
+    assert_eq!(server.request("POST", "/update", Some("admin-secret")), 200);
+    assert_eq!(server.request("GET", "/update", Some("admin-secret")), 405);
+
+    drop(server);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn serve_routes_change_the_access_needed() {
+    let base = indexed(
+        "routes",
+        "[serve_routes]\n\"/entries\" = \"admin\"\n\"/update\" = \"read\"\n",
+    );
+    let server = Server::start(&base);
+
+    assert_eq!(server.request("GET", "/entries", Some("read-secret")), 403);
+    assert_eq!(server.request("GET", "/entries", Some("admin-secret")), 200);
+    assert_eq!(server.request("POST", "/update", Some("read-secret")), 200);
+    assert_eq!(server.request("GET", "/roots", Some("read-secret")), 200);
+
+    drop(server);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn serve_answers_the_page_without_a_token() {
+    let base = indexed("page", "");
+    let server = Server::start(&base);
+
+    let page = if cfg!(feature = "web-ui") { 200 } else { 404 };
+    assert_eq!(server.request("GET", "/", None), page);
+    assert_eq!(server.request("GET", "/duplicates", None), 401);
+    assert_eq!(
+        server.request("GET", "/duplicates", Some("read-secret")),
+        200
+    );
+    assert_eq!(server.request("GET", "/search", Some("read-secret")), 400);
+    assert_eq!(
+        server.request("GET", "/search?pattern=f*", Some("read-secret")),
+        200
+    );
+
+    drop(server);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_logs_its_session_when_interrupted() {
+    let base = indexed("session", "");
+    fs::write(base.join("root").join("new"), "more content").unwrap();
+    let mut server = Server::start(&base);
+    assert_eq!(server.request("POST", "/update", Some("admin-secret")), 200);
+
+    let interrupted = Command::new("kill")
+        .args(["-INT", &server.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    let started = Instant::now();
+    while server.child.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < TIMEOUT, "bof serve didn't stop");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let output = bof(&base).arg("sessions").output().unwrap();
+    let sessions = String::from_utf8(output.stdout).unwrap();
+    let session = sessions
+        .lines()
+        .find(|line| line.starts_with("serve\t"))
+        .unwrap_or_else(|| panic!("{}", sessions));
+    assert!(session.contains("1 created"), "{}", session);
+    assert!(session.ends_with("\tinterrupted"), "{}", session);
+
+    drop(server);
+    fs::remove_dir_all(base).unwrap();
+}
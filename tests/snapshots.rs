@@ -0,0 +1,121 @@
+use bof::{Config, Index, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// A fresh directory per test, tests of a file run at the same time
+fn base(test: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("bof-snapshots-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    base
+}
+
+fn indexer(base: &Path, keep_snapshots: usize) -> Indexer {
+    let config = Config {
+        output_dir: base.join(".bof"),
+        keep_snapshots,
+        ..Default::default()
+    };
+    fs::create_dir_all(&config.output_dir).unwrap();
+    Indexer::new(config)
+}
+
+fn indexed(index: &Index, root: &Path) -> Vec<PathBuf> {
+    index
+        .entries(root)
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect()
+}
+
+#[test]
+fn snapshots_keep_the_index_as_it_was() {
+    let base = base("kept");
+    let root = base.join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("a"), "a").unwrap();
+    let indexer = indexer(&base, 2);
+    indexer.index(vec![root.clone()], false).unwrap();
+
+    let first = indexer.snapshot().unwrap();
+    fs::write(root.join("b"), "b").unwrap();
+    indexer.update(vec![root.clone()]).unwrap();
+
+    let snapshot = Index::load_snapshot(&base.join(".bof"), "index", &first.id).unwrap();
+    assert_eq!(indexed(&snapshot, &root), vec![root.join("a")]);
+    assert_eq!(
+        indexed(&indexer.load().unwrap(), &root),
+        vec![root.join("a"), root.join("b")]
+    );
+
+    // Beyond keep_snapshots the oldest go
+    let second = indexer.snapshot().unwrap();
+    let third = indexer.snapshot().unwrap();
+    let ids = indexer
+        .snapshots()
+        .unwrap()
+        .into_iter()
+        .map(|snapshot| snapshot.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![second.id, third.id]);
+    assert!(!first.path.exists());
+    assert!(Index::load_snapshot(&base.join(".bof"), "index", &first.id).is_err());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn generations_count_every_save() {
+    let base = base("generations");
+    let root = base.join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("a"), "a").unwrap();
+    let indexer = indexer(&base, 0);
+    indexer.index(vec![root.clone()], false).unwrap();
+    let snapshot = indexer.snapshot().unwrap();
+    for file in ["b", "c"] {
+        fs::write(root.join(file), file).unwrap();
+        indexer.update(vec![root.clone()]).unwrap();
+    }
+
+    let generations = indexer.log().unwrap();
+    let numbered = generations
+        .iter()
+        .map(|generation| (generation.generation, generation.files))
+        .collect::<Vec<_>>();
+    assert_eq!(numbered, vec![(1, 1), (2, 2), (3, 3)]);
+    assert_eq!(generations[0].snapshots, vec![snapshot.id.clone()]);
+    assert_eq!(snapshot.generation, Some(1));
+
+    // The last two and those with a snapshot can be loaded by their number
+    let output_dir = base.join(".bof");
+    let load = |generation: &str| Index::open(&output_dir, "index", generation);
+    assert_eq!(indexed(&load("1").unwrap(), &root), vec![root.join("a")]);
+    assert_eq!(
+        indexed(&load("2").unwrap(), &root),
+        vec![root.join("a"), root.join("b")]
+    );
+    assert_eq!(indexed(&load("3").unwrap(), &root).len(), 3);
+    fs::remove_file(&snapshot.path).unwrap();
+    assert!(load("1").is_err());
+
+    // bof log lists them the last first
+    let output = Command::new(env!("CARGO_BIN_EXE_bof"))
+        .current_dir(&base)
+        .env("XDG_CONFIG_HOME", base.join("config"))
+        .arg("log")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let log = String::from_utf8(output.stdout).unwrap();
+    let numbers = log
+        .lines()
+        .filter_map(|line| line.split('\t').next())
+        .collect::<Vec<_>>();
+    assert_eq!(numbers, vec!["3", "2", "1"], "{}", log);
+
+    fs::remove_dir_all(base).unwrap();
+}
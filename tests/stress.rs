@@ -0,0 +1,104 @@
+use bof::{Config, Indexer};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+const DEPTH: usize = 48;
+const FILES_PER_DIR: usize = 6;
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+// A chain of nested directories, each with a few files and a sibling subtree
+fn build_tree(root: &Path) {
+    let mut dir = root.to_path_buf();
+    for depth in 0..DEPTH {
+        let side = dir.join(format!("side{}", depth));
+        fs::create_dir_all(&side).unwrap();
+        for i in 0..FILES_PER_DIR {
+            fs::write(dir.join(format!("file{}", i)), format!("{} {}", depth, i)).unwrap();
+            fs::write(
+                side.join(format!("file{}", i)),
+                format!("side {} {}", depth, i),
+            )
+            .unwrap();
+        }
+        dir = dir.join(format!("level{}", depth));
+    }
+    fs::create_dir_all(dir).unwrap();
+}
+
+// Changes, adds and moves files all over the tree
+fn modify_tree(root: &Path) {
+    let mut dir = root.to_path_buf();
+    for depth in 0..DEPTH {
+        fs::write(dir.join("file0"), format!("changed {}", depth)).unwrap();
+        let new_dir = dir.join("new");
+        fs::create_dir_all(&new_dir).unwrap();
+        fs::write(new_dir.join("file"), format!("new {}", depth)).unwrap();
+        fs::rename(dir.join("file1"), dir.join("moved1")).unwrap();
+        dir = dir.join(format!("level{}", depth));
+    }
+}
+
+fn config(output_dir: PathBuf, parallel: bool) -> Config {
+    fs::create_dir_all(&output_dir).unwrap();
+    Config {
+        output_dir,
+        parallel,
+        ..Default::default()
+    }
+}
+
+// Runs the update on another thread so a deadlock fails the test instead of hanging it
+fn update_with_timeout(config: Config, root: PathBuf) {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let result = Indexer::new(config).update(vec![root]);
+        let _ = done.send(result);
+    });
+    finished
+        .recv_timeout(TIMEOUT)
+        .expect("update deadlocked")
+        .expect("update failed");
+}
+
+fn indexed_files(config: &Config) -> Vec<PathBuf> {
+    bof::Index::load(&config.output_dir, config.index_name())
+        .unwrap()
+        .find("*", None, None)
+        .unwrap()
+}
+
+#[test]
+fn parallel_update_of_deep_tree() {
+    let base = std::env::temp_dir().join(format!("bof-stress-{}", std::process::id()));
+    let root = base.join("tree");
+    build_tree(&root);
+
+    let parallel = config(base.join("parallel"), true);
+    let sequential = config(base.join("sequential"), false);
+    for config in [&parallel, &sequential] {
+        Indexer::new(config.clone())
+            .index(vec![root.clone()], false)
+            .unwrap();
+    }
+
+    modify_tree(&root);
+    update_with_timeout(parallel.clone(), root.clone());
+    update_with_timeout(sequential.clone(), root.clone());
+
+    let files = indexed_files(&parallel);
+    assert_eq!(files, indexed_files(&sequential));
+    assert_eq!(files.len(), DEPTH * (FILES_PER_DIR * 2 + 1));
+    let moved = files.iter().filter(|file| file.ends_with("moved1")).count();
+    assert_eq!(moved, DEPTH);
+    assert!(Indexer::new(parallel)
+        .verify(Vec::new(), false)
+        .unwrap()
+        .is_empty());
+
+    fs::remove_dir_all(base).unwrap();
+}